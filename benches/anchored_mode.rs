@@ -0,0 +1,59 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use streamregex::{compile_pattern, MatcherConfig, StreamMatcher};
+
+const CHUNK_SIZE: usize = 64 * 1024; // 64KB chunks
+const STREAM_SIZE: usize = 16 * 1024 * 1024; // 16MB per iteration
+
+// Protocol fingerprints, decided within the first bytes of a connection
+const FINGERPRINTS: [&str; 6] = [
+    "SSH-[12]\\.[0-9]+-",
+    "HTTP/1\\.[01] [0-9]{3} ",
+    "(GET|POST|PUT|HEAD) /",
+    "\\x16\\x03[\\x00-\\x04]",
+    "220[ -]",
+    "\\*OK ",
+];
+
+// An SSH banner followed by random payload
+fn generate_test_data(size: usize) -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(3);
+    let mut data = b"SSH-2.0-OpenSSH_9.6\r\n".to_vec();
+    data.extend((data.len()..size).map(|_| rng.gen_range(0..=u8::MAX)));
+    data
+}
+
+fn run_benchmarks(c: &mut Criterion) {
+    let data = generate_test_data(STREAM_SIZE);
+    let mut group = c.benchmark_group("Anchored Mode");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.sample_size(10);
+
+    // Anchored, the tail after the banner is skipped without being looked at
+    for (name, config) in [
+        ("Anchored", MatcherConfig::new().anchored(true)),
+        ("Unanchored", MatcherConfig::new()),
+    ] {
+        let mut matcher = StreamMatcher::with_config(config);
+        for fingerprint in FINGERPRINTS {
+            matcher.add_pattern(compile_pattern(fingerprint).unwrap());
+        }
+        matcher.add_callback(|name| {
+            black_box(name);
+        });
+        group.bench_with_input(BenchmarkId::new(name, STREAM_SIZE), &data, |b, data| {
+            b.iter(|| {
+                for chunk in data.chunks(CHUNK_SIZE) {
+                    matcher.process_chunk(chunk);
+                }
+                matcher.finish();
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, run_benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,101 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use streamregex::{Pattern, PatternBuilder};
+
+const CORPUS_SIZE: usize = 10_000; // Number of rules compiled per iteration
+
+// Counts allocations, so the churn of both ways of building can be compared
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Signature-like literals, each built by hand as a chain of states
+fn generate_corpus(count: usize) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|i| format!("signature-{:05}-payload", i).into_bytes())
+        .collect()
+}
+
+fn add_literal(builder: &mut PatternBuilder, literal: &[u8]) {
+    let (&last_byte, prefix) = literal.split_last().unwrap();
+    let last = builder.append_literal(0, prefix).unwrap();
+    let end = builder.add_state(true);
+    builder.add_transition(last, last_byte, end).unwrap();
+}
+
+// A new builder for every pattern
+fn build_fresh(corpus: &[Vec<u8>]) -> Vec<Pattern> {
+    corpus
+        .iter()
+        .enumerate()
+        .map(|(i, literal)| {
+            let mut builder = PatternBuilder::new();
+            add_literal(&mut builder, literal);
+            builder.build(format!("rule_{}", i)).unwrap()
+        })
+        .collect()
+}
+
+// One builder for the whole corpus
+fn build_reused(corpus: &[Vec<u8>]) -> Vec<Pattern> {
+    let mut builder = PatternBuilder::new();
+    corpus
+        .iter()
+        .enumerate()
+        .map(|(i, literal)| {
+            builder.reset();
+            add_literal(&mut builder, literal);
+            builder.build_ref(format!("rule_{}", i)).unwrap()
+        })
+        .collect()
+}
+
+fn count_allocations(build: fn(&[Vec<u8>]) -> Vec<Pattern>, corpus: &[Vec<u8>]) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    black_box(build(corpus));
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn run_benchmarks(c: &mut Criterion) {
+    let corpus = generate_corpus(CORPUS_SIZE);
+    println!(
+        "Allocations for {} patterns: {} with fresh builders, {} with one reused builder",
+        CORPUS_SIZE,
+        count_allocations(build_fresh, &corpus),
+        count_allocations(build_reused, &corpus)
+    );
+
+    let mut group = c.benchmark_group("Pattern Compilation");
+    group.bench_function("Fresh builders", |b| {
+        b.iter(|| black_box(build_fresh(&corpus)));
+    });
+    group.bench_function("Reused builder", |b| {
+        b.iter(|| black_box(build_reused(&corpus)));
+    });
+    group.finish();
+}
+
+criterion_group!(benches, run_benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,89 @@
+use std::time::Instant;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use streamregex::{compile_pattern, StreamMatcher};
+
+const CHUNK_SIZE: usize = 64 * 1024; // 64KB chunks
+const STREAM_SIZE: usize = 256 * 1024; // 256KB per iteration
+const PATTERN_SET_SIZE: usize = 1000; // Number of patterns, as in pattern_matching
+
+// The security patterns of the pattern_matching benchmark, repeated up to
+// PATTERN_SET_SIZE like there
+const SECURITY_PATTERNS: [&str; 5] = [
+    "SELECT.*FROM.*WHERE",
+    "admin.*password",
+    "eval\\(.*\\)",
+    "<script.*>.*</script>",
+    "\\b(?:[0-9]{4}-){3}[0-9]{4}\\b", // Credit card pattern
+];
+
+// Printable text with an occasional match for each pattern
+fn generate_test_data(size: usize) -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut data = Vec::with_capacity(size);
+    while data.len() < size {
+        if rng.gen_ratio(1, 4096) {
+            let sample: &[u8] = match rng.gen_range(0..5) {
+                0 => b"SELECT name FROM users WHERE id=1",
+                1 => b"admin:password",
+                2 => b"eval(payload)",
+                3 => b"<script>alert(1)</script>",
+                _ => b" 4111-1111-1111-1111 ",
+            };
+            data.extend_from_slice(sample);
+        } else {
+            data.push(rng.gen_range(b' '..=b'~'));
+        }
+    }
+    data.truncate(size);
+    data
+}
+
+fn build_matcher(compiled: bool) -> StreamMatcher<'static> {
+    let mut matcher = StreamMatcher::new();
+    for i in 0..PATTERN_SET_SIZE {
+        let pattern = SECURITY_PATTERNS[i % SECURITY_PATTERNS.len()];
+        matcher.add_pattern(compile_pattern(pattern).unwrap());
+    }
+    if compiled {
+        let start = Instant::now();
+        matcher.compile().unwrap();
+        println!(
+            "Combined {} patterns into {} states in {:?}, {} bytes in all",
+            PATTERN_SET_SIZE,
+            matcher.combined_state_count().unwrap(),
+            start.elapsed(),
+            matcher.memory_usage()
+        );
+    }
+    matcher.add_callback(|name| {
+        black_box(name);
+    });
+    matcher
+}
+
+fn run_benchmarks(c: &mut Criterion) {
+    let data = generate_test_data(STREAM_SIZE);
+    let mut group = c.benchmark_group("Multi-Pattern Matching");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.sample_size(10);
+
+    for (name, compiled) in [("Per-pattern loop", false), ("Combined automaton", true)] {
+        let mut matcher = build_matcher(compiled);
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for chunk in data.chunks(CHUNK_SIZE) {
+                    matcher.process_chunk(chunk);
+                }
+                matcher.finish();
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, run_benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,74 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use streamregex::{compile_pattern_with, CompileOptions, StreamMatcher};
+
+const CHUNK_SIZE: usize = 64 * 1024; // 64KB chunks
+const STREAM_SIZE: usize = 4 * 1024 * 1024; // 4MB per iteration
+
+// The security patterns of the pattern_matching benchmark
+const SECURITY_PATTERNS: [&str; 5] = [
+    "SELECT.*FROM.*WHERE",
+    "admin.*password",
+    "eval\\(.*\\)",
+    "<script.*>.*</script>",
+    "\\b(?:[0-9]{4}-){3}[0-9]{4}\\b", // Credit card pattern
+];
+
+// Printable text with an occasional match for each pattern
+fn generate_test_data(size: usize) -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut data = Vec::with_capacity(size);
+    while data.len() < size {
+        if rng.gen_ratio(1, 4096) {
+            let sample: &[u8] = match rng.gen_range(0..5) {
+                0 => b"SELECT name FROM users WHERE id=1",
+                1 => b"admin:password",
+                2 => b"eval(payload)",
+                3 => b"<script>alert(1)</script>",
+                _ => b" 4111-1111-1111-1111 ",
+            };
+            data.extend_from_slice(sample);
+        } else {
+            data.push(rng.gen_range(b' '..=b'~'));
+        }
+    }
+    data.truncate(size);
+    data
+}
+
+fn build_matcher(dense: bool) -> StreamMatcher<'static> {
+    let options = CompileOptions::new().dense(dense);
+    let mut matcher = StreamMatcher::new();
+    for pattern in SECURITY_PATTERNS {
+        matcher.add_pattern(compile_pattern_with(pattern, &options).unwrap());
+    }
+    matcher.add_callback(|name| {
+        black_box(name);
+    });
+    matcher
+}
+
+fn run_benchmarks(c: &mut Criterion) {
+    let data = generate_test_data(STREAM_SIZE);
+    let mut group = c.benchmark_group("Transition Tables");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+
+    for (name, dense) in [("Hash maps", false), ("Dense tables", true)] {
+        let mut matcher = build_matcher(dense);
+        println!("{}: {} bytes of states", name, matcher.memory_usage());
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for chunk in data.chunks(CHUNK_SIZE) {
+                    matcher.process_chunk(chunk);
+                }
+                matcher.finish();
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, run_benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,92 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+
+use streamregex::{compile_pattern_bytes, StreamMatcher};
+
+const CHUNK_SIZE: usize = 64 * 1024; // 64KB chunks
+const STREAM_SIZE: usize = 256 * 1024; // 256KB per iteration
+
+// Patterns that start with too many different bytes for a prefilter, so every byte is
+// stepped through and the numbers are raw matching speed
+const TEMPLATES: [&str; 4] = [
+    "[a-z]+@[a-z]+\\.com",
+    "[0-9]{3}-[0-9]{4}",
+    "\\w+=\\d+;",
+    "[A-Z][a-z]+ [A-Z][a-z]+",
+];
+
+// Printable text
+fn generate_test_data(size: usize) -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(7);
+    (0..size).map(|_| rng.gen_range(b' '..=b'~')).collect()
+}
+
+// Patterns named by their index, so that each can be disabled on its own
+fn build_matcher(count: usize) -> StreamMatcher<'static> {
+    let mut matcher = StreamMatcher::new();
+    for i in 0..count {
+        let template = TEMPLATES[i % TEMPLATES.len()];
+        matcher.add_pattern(compile_pattern_bytes(template.as_bytes(), &i.to_string()).unwrap());
+    }
+    assert!(matcher.prefilter().is_none() && matcher.literal_prefilter().is_none());
+    matcher
+}
+
+fn run_benchmarks(c: &mut Criterion) {
+    let data = generate_test_data(STREAM_SIZE);
+    let mut group = c.benchmark_group("Pattern Count");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.sample_size(10);
+
+    for count in [1, 10, 100] {
+        // Stepping every pattern per byte, for callbacks that may stop the scan
+        let mut matcher = build_matcher(count);
+        matcher.add_callback(|name| {
+            black_box(name);
+        });
+        group.bench_with_input(BenchmarkId::new("Callbacks", count), &data, |b, data| {
+            b.iter(|| {
+                for chunk in data.chunks(CHUNK_SIZE) {
+                    matcher.process_chunk(chunk);
+                }
+                matcher.finish();
+            });
+        });
+
+        // Running each pattern through the chunk in turn
+        let mut matcher = build_matcher(count);
+        let mut matches = Vec::new();
+        group.bench_with_input(BenchmarkId::new("Collected", count), &data, |b, data| {
+            b.iter(|| {
+                matches.clear();
+                for chunk in data.chunks(CHUNK_SIZE) {
+                    matcher.process_chunk_into(chunk, &mut matches);
+                }
+                matcher.finish_into(&mut matches);
+                black_box(matches.len());
+            });
+        });
+    }
+
+    // 100 patterns with all but 10 disabled, which should cost about what 10 do
+    let mut matcher = build_matcher(100);
+    for i in 10..100 {
+        assert!(matcher.set_enabled(&i.to_string(), false));
+    }
+    let mut matches = Vec::new();
+    group.bench_with_input(BenchmarkId::new("Collected, 90 disabled", 100), &data, |b, data| {
+        b.iter(|| {
+            matches.clear();
+            for chunk in data.chunks(CHUNK_SIZE) {
+                matcher.process_chunk_into(chunk, &mut matches);
+            }
+            matcher.finish_into(&mut matches);
+            black_box(matches.len());
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, run_benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,484 @@
+// Compact binary format for compiled patterns
+//
+// Layout, all integers little-endian:
+//   magic "SRXP", format version u32, pattern count u32, then per pattern:
+//   id (u32 length + bytes), initial state u32, flags u8 (anchored, nondeterministic),
+//   min match length u32,
+//   max match length and unminimized state count (u8 present + u32 each), unreachable
+//   and final state counts u32, kind (u8, then u32 length + bytes for literals),
+//   metadata entry count u32 and each key and value (u32 length + bytes) in key order,
+//   state count u32, then per state:
+//   flags u8 (match ends with the byte, accepts at end, tagged, default transition,
+//   failure link, match ends before the byte),
+//   tag u32, default target u32 + 32 bytes of excluded bytes, and failure target u32
+//   where the flags say so, for nondeterministic patterns the count of bytes with
+//   targets u16 and per byte in byte order (byte u8, target count u16, targets u32),
+//   then the count of default transitions u16 and each as 32 bytes of excluded bytes
+//   + target u32, and finally transition count u16 and (byte u8, target u32) pairs in
+//   byte order.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+
+use crate::byteset::ByteSet;
+use crate::error::Error;
+use crate::pattern::{Branches, Pattern, PatternKind, PruneStats, State};
+
+const MAGIC: &[u8; 4] = b"SRXP";
+// Bumped whenever the layout changes
+const FORMAT_VERSION: u32 = 5;
+
+const ANCHORED: u8 = 1;
+const NONDETERMINISTIC: u8 = 2;
+
+const FINAL: u8 = 1;
+const ACCEPTS_AT_END: u8 = 2;
+const TAGGED: u8 = 4;
+const DEFAULT_TRANSITION: u8 = 8;
+const FAILURE: u8 = 16;
+const ENDS_BEFORE: u8 = 32;
+
+impl Pattern {
+    // Write the pattern in the binary format, read back by deserialize_from
+    pub fn serialize_into<W: Write>(&self, writer: W) -> Result<(), Error> {
+        Pattern::serialize_all_into(std::slice::from_ref(self), writer)
+    }
+
+    // Read a pattern written by serialize_into
+    // Fails with Error::Internal for data that is not a single valid pattern, such as a
+    // corrupted or truncated file. Reads in small pieces, so pass a BufReader for files.
+    pub fn deserialize_from<R: Read>(reader: R) -> Result<Pattern, Error> {
+        let mut patterns = Pattern::deserialize_all_from(reader)?;
+        if patterns.len() != 1 {
+            return Err(Error::Internal(format!(
+                "Expected one pattern, found {}",
+                patterns.len()
+            )));
+        }
+        Ok(patterns.remove(0))
+    }
+
+    // Write a whole set of patterns, e.g. a compiled rule database
+    pub fn serialize_all_into<W: Write>(patterns: &[Pattern], mut writer: W) -> Result<(), Error> {
+        writer.write_all(MAGIC)?;
+        write_u32(&mut writer, FORMAT_VERSION)?;
+        write_len(&mut writer, patterns.len())?;
+        for pattern in patterns {
+            write_pattern(&mut writer, pattern)?;
+        }
+        Ok(())
+    }
+
+    // Read a set of patterns written by serialize_all_into, checking each like
+    // deserialize_from
+    pub fn deserialize_all_from<R: Read>(mut reader: R) -> Result<Vec<Pattern>, Error> {
+        let mut magic = [0; 4];
+        read_exact(&mut reader, &mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::Internal("Not a StreamRegex pattern file".into()));
+        }
+        let version = read_u32(&mut reader)?;
+        if version != FORMAT_VERSION {
+            return Err(Error::Internal(format!(
+                "Pattern file has format version {}, but this version of StreamRegex reads \
+                 version {}",
+                version, FORMAT_VERSION
+            )));
+        }
+        let count = read_u32(&mut reader)? as usize;
+        // Counts come from the file, so they only bound the capacity reserved up front
+        let mut patterns = Vec::with_capacity(count.min(1024));
+        for _ in 0..count {
+            let mut pattern = read_pattern(&mut reader)?;
+            pattern.validate()?;
+            pattern.update_dense_tables();
+            patterns.push(pattern);
+        }
+        Ok(patterns)
+    }
+}
+
+fn write_pattern<W: Write>(writer: &mut W, pattern: &Pattern) -> Result<(), Error> {
+    write_bytes(writer, pattern.id.as_bytes())?;
+    write_len(writer, pattern.initial_state)?;
+    let nondeterministic = pattern.branches.is_some() as u8 * NONDETERMINISTIC;
+    writer.write_all(&[(pattern.anchored as u8 * ANCHORED) | nondeterministic])?;
+    write_len(writer, pattern.min_match_len)?;
+    write_optional(writer, pattern.max_match_len)?;
+    write_optional(writer, pattern.unminimized_state_count)?;
+    write_len(writer, pattern.prune_stats.unreachable_states)?;
+    write_len(writer, pattern.prune_stats.final_states)?;
+    match &pattern.kind {
+        PatternKind::Automaton => writer.write_all(&[0])?,
+        PatternKind::Literal(literal) => {
+            writer.write_all(&[1])?;
+            write_bytes(writer, literal)?;
+        }
+    }
+    write_len(writer, pattern.metadata.len())?;
+    for (key, value) in &pattern.metadata {
+        write_bytes(writer, key.as_bytes())?;
+        write_bytes(writer, value.as_bytes())?;
+    }
+
+    write_len(writer, pattern.states.len())?;
+    for (index, state) in pattern.states.iter().enumerate() {
+        let flags = [
+            (state.is_final, FINAL),
+            (state.accepts_at_end, ACCEPTS_AT_END),
+            (state.final_tag.is_some(), TAGGED),
+            (state.default_transition.is_some(), DEFAULT_TRANSITION),
+            (state.failure.is_some(), FAILURE),
+            (state.ends_before, ENDS_BEFORE),
+        ];
+        let flags = flags
+            .iter()
+            .filter(|&&(set, _)| set)
+            .fold(0, |flags, &(_, flag)| flags | flag);
+        writer.write_all(&[flags])?;
+        if let Some(tag) = state.final_tag {
+            write_u32(writer, tag)?;
+        }
+        if let Some(target) = state.default_transition {
+            write_len(writer, target)?;
+            write_byte_set(writer, &state.excluded)?;
+        }
+        if let Some(target) = state.failure {
+            write_len(writer, target)?;
+        }
+        if let Some(branches) = &pattern.branches {
+            let exact = &branches.exact[index];
+            write_u16(writer, exact.len())?;
+            for (&byte, targets) in exact {
+                writer.write_all(&[byte])?;
+                write_u16(writer, targets.len())?;
+                for &target in targets {
+                    write_len(writer, target)?;
+                }
+            }
+            write_u16(writer, branches.defaults[index].len())?;
+            for (excluded, target) in &branches.defaults[index] {
+                write_byte_set(writer, excluded)?;
+                write_len(writer, *target)?;
+            }
+        }
+
+        let mut transitions: Vec<(u8, usize)> = state
+            .transitions
+            .iter()
+            .map(|(&byte, &target)| (byte, target))
+            .collect();
+        transitions.sort_unstable();
+        writer.write_all(&(transitions.len() as u16).to_le_bytes())?;
+        for (byte, target) in transitions {
+            writer.write_all(&[byte])?;
+            write_len(writer, target)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_pattern<R: Read>(reader: &mut R) -> Result<Pattern, Error> {
+    let id = read_string(reader)?;
+    let initial_state = read_u32(reader)? as usize;
+    let flags = read_u8(reader)?;
+    let anchored = flags & ANCHORED != 0;
+    let mut branches = (flags & NONDETERMINISTIC != 0).then(|| Branches {
+        exact: Vec::new(),
+        defaults: Vec::new(),
+    });
+    let min_match_len = read_u32(reader)? as usize;
+    let max_match_len = read_optional(reader)?;
+    let unminimized_state_count = read_optional(reader)?;
+    let prune_stats = PruneStats {
+        unreachable_states: read_u32(reader)? as usize,
+        final_states: read_u32(reader)? as usize,
+    };
+    let kind = match read_u8(reader)? {
+        0 => PatternKind::Automaton,
+        1 => PatternKind::Literal(read_bytes(reader)?),
+        other => return Err(Error::Internal(format!("Unknown pattern kind {}", other))),
+    };
+    let mut metadata = BTreeMap::new();
+    for _ in 0..read_u32(reader)? {
+        metadata.insert(read_string(reader)?, read_string(reader)?);
+    }
+
+    let count = read_u32(reader)? as usize;
+    let mut states = Vec::with_capacity(count.min(1 << 16));
+    for _ in 0..count {
+        let flags = read_u8(reader)?;
+        let mut state = State {
+            transitions: Default::default(),
+            default_transition: None,
+            excluded: ByteSet::new(),
+            is_final: flags & FINAL != 0,
+            accepts_at_end: flags & ACCEPTS_AT_END != 0,
+            final_tag: None,
+            ends_before: flags & ENDS_BEFORE != 0,
+            failure: None,
+            dense: None,
+        };
+        if flags & TAGGED != 0 {
+            state.final_tag = Some(read_u32(reader)?);
+        }
+        if flags & DEFAULT_TRANSITION != 0 {
+            state.default_transition = Some(read_u32(reader)? as usize);
+            state.excluded = read_byte_set(reader)?;
+        }
+        if flags & FAILURE != 0 {
+            state.failure = Some(read_u32(reader)? as usize);
+        }
+        if let Some(branches) = &mut branches {
+            let mut exact = BTreeMap::new();
+            for _ in 0..read_byte_count(reader)? {
+                let byte = read_u8(reader)?;
+                let count = read_u16(reader)?;
+                let targets = (0..count)
+                    .map(|_| Ok(read_u32(reader)? as usize))
+                    .collect::<Result<_, Error>>()?;
+                exact.insert(byte, targets);
+            }
+            let mut defaults = Vec::new();
+            for _ in 0..read_u16(reader)? {
+                defaults.push((read_byte_set(reader)?, read_u32(reader)? as usize));
+            }
+            branches.exact.push(exact);
+            branches.defaults.push(defaults);
+        }
+
+        let transitions = read_byte_count(reader)?;
+        for _ in 0..transitions {
+            let byte = read_u8(reader)?;
+            let target = read_u32(reader)? as usize;
+            state.transitions.insert(byte, target);
+        }
+        states.push(state);
+    }
+
+    Ok(Pattern {
+        id,
+        states,
+        initial_state,
+        kind,
+        anchored,
+        min_match_len,
+        max_match_len,
+        unminimized_state_count,
+        prune_stats,
+        metadata,
+        number: None,
+        negative: false,
+        priority: 0,
+        dense: false,
+        class_of: [0; 256],
+        branches: branches.map(Box::new),
+        syntax: None,
+    })
+}
+
+fn write_u16<W: Write>(writer: &mut W, value: usize) -> Result<(), Error> {
+    let value = u16::try_from(value)
+        .map_err(|_| Error::Internal(format!("{} is too large for the binary format", value)))?;
+    writer.write_all(&value.to_le_bytes())?;
+    Ok(())
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> Result<(), Error> {
+    writer.write_all(&value.to_le_bytes())?;
+    Ok(())
+}
+
+// Counts and state indices are stored as u32
+fn write_len<W: Write>(writer: &mut W, value: usize) -> Result<(), Error> {
+    let value = u32::try_from(value)
+        .map_err(|_| Error::Internal(format!("{} is too large for the binary format", value)))?;
+    write_u32(writer, value)
+}
+
+fn write_optional<W: Write>(writer: &mut W, value: Option<usize>) -> Result<(), Error> {
+    writer.write_all(&[value.is_some() as u8])?;
+    write_len(writer, value.unwrap_or(0))
+}
+
+fn write_byte_set<W: Write>(writer: &mut W, set: &ByteSet) -> Result<(), Error> {
+    let mut bits = [0u8; 32];
+    for byte in (0..=255u8).filter(|&byte| set.contains(byte)) {
+        bits[byte as usize / 8] |= 1 << (byte % 8);
+    }
+    writer.write_all(&bits)?;
+    Ok(())
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<(), Error> {
+    write_len(writer, bytes.len())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+// A truncated file is corrupt data rather than a failure to read it
+fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<(), Error> {
+    reader
+        .read_exact(buffer)
+        .map_err(|error| match error.kind() {
+            io::ErrorKind::UnexpectedEof => {
+                Error::Internal("Pattern data ends unexpectedly".into())
+            }
+            _ => Error::Io(error),
+        })
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8, Error> {
+    let mut buffer = [0u8; 1];
+    read_exact(reader, &mut buffer)?;
+    Ok(buffer[0])
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> Result<u16, Error> {
+    let mut buffer = [0u8; 2];
+    read_exact(reader, &mut buffer)?;
+    Ok(u16::from_le_bytes(buffer))
+}
+
+// A count of entries for distinct bytes, which cannot be more than there are bytes
+fn read_byte_count<R: Read>(reader: &mut R) -> Result<u16, Error> {
+    let count = read_u16(reader)?;
+    if count > 256 {
+        return Err(Error::Internal(format!(
+            "State has {} transitions, more than there are bytes",
+            count
+        )));
+    }
+    Ok(count)
+}
+
+fn read_byte_set<R: Read>(reader: &mut R) -> Result<ByteSet, Error> {
+    let mut bits = [0u8; 32];
+    read_exact(reader, &mut bits)?;
+    let mut set = ByteSet::new();
+    for byte in 0..=255u8 {
+        if bits[byte as usize / 8] & (1 << (byte % 8)) != 0 {
+            set.insert(byte);
+        }
+    }
+    Ok(set)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, Error> {
+    let mut buffer = [0u8; 4];
+    read_exact(reader, &mut buffer)?;
+    Ok(u32::from_le_bytes(buffer))
+}
+
+fn read_optional<R: Read>(reader: &mut R) -> Result<Option<usize>, Error> {
+    let present = read_u8(reader)? != 0;
+    let value = read_u32(reader)? as usize;
+    Ok(present.then_some(value))
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let length = read_u32(reader)? as usize;
+    let mut bytes = Vec::new();
+    reader.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != length {
+        return Err(Error::Internal("Pattern data ends unexpectedly".into()));
+    }
+    Ok(bytes)
+}
+
+fn read_string<R: Read>(reader: &mut R) -> Result<String, Error> {
+    String::from_utf8(read_bytes(reader)?)
+        .map_err(|_| Error::Internal("Pattern data has a string that is not valid UTF-8".into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PatternBuilder, compile_pattern};
+
+    fn patterns() -> Vec<Pattern> {
+        let literals: [(&str, &[u8]); 2] = [("get", b"GET"), ("post", b"POST")];
+        // a[^\n]*b kept nondeterministic, a 'b' leading both to the end and back
+        let mut builder = PatternBuilder::new();
+        let (body, end) = (builder.add_state(false), builder.add_state(true));
+        builder.add_transition(0, b'a', body).unwrap();
+        builder.add_negated_transition(body, b"\n", body).unwrap();
+        builder.add_transition(body, b'b', body).unwrap();
+        builder.add_transition(body, b'b', end).unwrap().determinize(false);
+        vec![
+            compile_pattern("(?i)a[0-9]+b$").unwrap(),
+            compile_pattern(r#"^"[^"]*""#).unwrap(),
+            compile_pattern("literal").unwrap().minimize(),
+            compile_pattern("cve")
+                .unwrap()
+                .with_metadata("severity", "high"),
+            PatternBuilder::from_literals(&literals).unwrap(),
+            builder.build("gap".to_string()).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        for pattern in patterns() {
+            let mut data = Vec::new();
+            pattern.serialize_into(&mut data).unwrap();
+            assert!(data.starts_with(b"SRXP\x05\x00\x00\x00"));
+            let loaded = Pattern::deserialize_from(data.as_slice()).unwrap();
+            assert_eq!(loaded.to_dot(), pattern.to_dot());
+            assert_eq!(loaded.kind(), pattern.kind());
+            assert!(loaded.metadata_entries().eq(pattern.metadata_entries()));
+            assert_eq!(loaded.is_anchored(), pattern.is_anchored());
+            assert_eq!(loaded.is_nondeterministic(), pattern.is_nondeterministic());
+            assert_eq!(loaded.max_match_len(), pattern.max_match_len());
+            assert_eq!(
+                loaded.unminimized_state_count(),
+                pattern.unminimized_state_count()
+            );
+            assert_eq!(loaded.prune_stats(), pattern.prune_stats());
+
+            // The same pattern always gives the same bytes
+            let mut again = Vec::new();
+            loaded.serialize_into(&mut again).unwrap();
+            assert_eq!(again, data);
+        }
+
+        let mut data = Vec::new();
+        Pattern::serialize_all_into(&patterns(), &mut data).unwrap();
+        let loaded = Pattern::deserialize_all_from(data.as_slice()).unwrap();
+        assert_eq!(loaded.len(), 6);
+        assert!(loaded[5].is_nondeterministic());
+        assert_eq!(loaded[3].metadata("severity"), Some("high"));
+        assert_eq!(loaded[4].to_dot(), patterns()[4].to_dot());
+        assert!(Pattern::deserialize_from(data.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_binary_rejects_corrupt_data() {
+        let mut data = Vec::new();
+        compile_pattern("abc")
+            .unwrap()
+            .serialize_into(&mut data)
+            .unwrap();
+        let internal =
+            |data: &[u8]| matches!(Pattern::deserialize_from(data), Err(Error::Internal(_)));
+
+        assert!(internal(b"JUNK"));
+        assert!(internal(&data[..data.len() - 1]));
+        let mut other_version = data.clone();
+        other_version[4] = 2;
+        assert!(internal(&other_version));
+
+        // Every truncation and every corrupted transition target fails cleanly
+        for length in 0..data.len() {
+            assert!(internal(&data[..length]), "truncated to {}", length);
+        }
+        let last_target = data.len() - 4;
+        let mut out_of_bounds = data.clone();
+        out_of_bounds[last_target..].copy_from_slice(&1000u32.to_le_bytes());
+        let Err(Error::Internal(message)) = Pattern::deserialize_from(out_of_bounds.as_slice())
+        else {
+            panic!("expected an out-of-bounds target to be rejected");
+        };
+        assert!(message.contains("state 1000"), "{}", message);
+    }
+}
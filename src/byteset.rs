@@ -0,0 +1,62 @@
+// Compact set of byte values, used for character classes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct ByteSet {
+    bits: [u64; 4],
+}
+
+impl ByteSet {
+    pub(crate) fn new() -> Self {
+        ByteSet { bits: [0; 4] }
+    }
+
+    pub(crate) fn insert(&mut self, byte: u8) {
+        self.bits[(byte >> 6) as usize] |= 1 << (byte & 63);
+    }
+
+    pub(crate) fn insert_range(&mut self, start: u8, end: u8) {
+        for byte in start..=end {
+            self.insert(byte);
+        }
+    }
+
+    pub(crate) fn contains(&self, byte: u8) -> bool {
+        self.bits[(byte >> 6) as usize] & (1 << (byte & 63)) != 0
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub(crate) fn union_with(&mut self, other: &ByteSet) {
+        for (word, other) in self.bits.iter_mut().zip(other.bits) {
+            *word |= other;
+        }
+    }
+
+    // Add the other ASCII case of every letter in the set
+    pub(crate) fn fold_ascii_case(&self) -> Self {
+        let mut folded = *self;
+        for byte in 0..=255u8 {
+            if let Some(other) = ascii_other_case(byte).filter(|_| self.contains(byte)) {
+                folded.insert(other);
+            }
+        }
+        folded
+    }
+
+    pub(crate) fn negate(&self) -> Self {
+        ByteSet {
+            bits: self.bits.map(|word| !word),
+        }
+    }
+}
+
+// The other case of an ASCII letter, which case-insensitive matching also accepts
+// Every caseless comparison goes through this, whether from (?i) or PatternBuilder.
+pub(crate) fn ascii_other_case(byte: u8) -> Option<u8> {
+    byte.is_ascii_alphabetic().then_some(byte ^ 0x20)
+}
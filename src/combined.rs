@@ -0,0 +1,259 @@
+// One automaton for all the patterns of a StreamMatcher, see StreamMatcher::compile
+//
+// Each state stands for the states all patterns are in at once, so a byte takes one
+// lookup in a table of states and byte classes instead of a step per pattern. States
+// are found breadth-first from the one where every pattern is at its initial state.
+// Patterns mostly fall back there right away, so each takes part in few states, and
+// literals and bounded patterns combine into about as many states as they have
+// together. Unbounded patterns such as "a.*b" stay away from their initial state and
+// multiply the states of the others.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::error::Error;
+use crate::database::DEAD;
+use crate::pattern::{Pattern, byte_classes};
+
+// A match completing on entering a state of the combined automaton
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Completion {
+    // Index of the pattern in PatternDatabase::patterns
+    pub(crate) index: usize,
+    pub(crate) tag: Option<u32>,
+    pub(crate) ends_before: bool,
+}
+
+// The patterns away from their initial state, as (index, state) pairs sorted by index,
+// with DEAD as u32::MAX to halve their size
+// A pattern at its initial state is left out, unless it got there by a transition that
+// completes a match. Shared with the index of the states while building.
+type Members = Arc<[(u32, u32)]>;
+
+#[derive(Debug)]
+pub(crate) struct CombinedAutomaton {
+    class_of: [u8; 256],
+    class_count: usize,
+    // The next state for each state and byte class, one row per state
+    transitions: Vec<u32>,
+    members: Vec<Members>,
+    completions: Vec<Box<[Completion]>>,
+    // Whether every pattern is dead or unanchored at its initial state in each state,
+    // see Progress::idle
+    idle: Vec<bool>,
+    anchored_count: usize,
+}
+
+impl CombinedAutomaton {
+    // Combine `patterns`, returning the automaton and its state for each of `streams`,
+    // the states the patterns are in in a stream (indexed like `patterns`). The state
+    // where all of them are at their initial state is 0.
+    // Patterns not `enabled` are left out, and never leave their initial state in it.
+    // Fails for nondeterministic patterns, and with PatternTooComplex beyond
+    // `state_limit` states.
+    pub(crate) fn new(
+        patterns: &[Pattern],
+        enabled: &[bool],
+        streams: &[&[usize]],
+        state_limit: usize,
+    ) -> Result<(Self, Vec<usize>), Error> {
+        let running = |&(index, _): &(usize, &Pattern)| enabled[index];
+        if let Some((_, pattern)) = patterns
+            .iter()
+            .enumerate()
+            .filter(running)
+            .find(|(_, pattern)| pattern.is_nondeterministic())
+        {
+            return Err(Error::InvalidPattern(format!(
+                "Pattern '{}' is nondeterministic and cannot be combined with others",
+                pattern.id()
+            )));
+        }
+        let (representatives, class_of) =
+            byte_classes(patterns.iter().flat_map(|pattern| &pattern.states));
+        // What the patterns left out of a state do from their initial state
+        let starts: Vec<Vec<(u32, u32)>> = representatives
+            .iter()
+            .map(|&byte| {
+                let enabled = patterns.iter().enumerate().filter(running);
+                let advanced = enabled.map(|(index, pattern)| {
+                    Some(member(index, advance(pattern, pattern.initial_state, byte)?))
+                });
+                advanced.flatten().collect()
+            })
+            .collect();
+
+        let mut automaton = CombinedAutomaton {
+            class_of,
+            class_count: representatives.len(),
+            transitions: Vec::new(),
+            members: Vec::new(),
+            completions: Vec::new(),
+            idle: Vec::new(),
+            anchored_count: patterns
+                .iter()
+                .enumerate()
+                .filter(running)
+                .filter(|(_, pattern)| pattern.anchored)
+                .count(),
+        };
+        let mut index = HashMap::new();
+        automaton.add_state(patterns, &mut index, Arc::new([]), state_limit)?;
+        let mut current = Vec::with_capacity(streams.len());
+        for states in streams {
+            let members = states
+                .iter()
+                .enumerate()
+                .filter(|&(index, &state)| enabled[index] && state != patterns[index].initial_state)
+                .map(|(index, &state)| member(index, state))
+                .collect();
+            current.push(automaton.add_state(patterns, &mut index, members, state_limit)?);
+        }
+
+        let mut state = 0;
+        while state < automaton.members.len() {
+            for (class, &byte) in representatives.iter().enumerate() {
+                let members = successor(patterns, &automaton.members[state], &starts[class], byte);
+                let target = automaton.add_state(patterns, &mut index, members, state_limit)?;
+                automaton.transitions.push(target as u32);
+            }
+            state += 1;
+        }
+        Ok((automaton, current))
+    }
+
+    // The state for `members`, added if it is new
+    fn add_state(
+        &mut self,
+        patterns: &[Pattern],
+        index: &mut HashMap<Members, usize>,
+        members: Members,
+        state_limit: usize,
+    ) -> Result<usize, Error> {
+        if let Some(&state) = index.get(&members) {
+            return Ok(state);
+        }
+        if self.members.len() >= state_limit.min(u32::MAX as usize) {
+            return Err(Error::PatternTooComplex(format!(
+                "Combining {} patterns takes more than {} states",
+                patterns.len(),
+                state_limit
+            )));
+        }
+        let completions = members
+            .iter()
+            .map(|&(index, state)| (index as usize, unpack_state(state)))
+            .filter(|&(_, state)| state != DEAD)
+            .flat_map(|(index, state)| {
+                let state = &patterns[index].states[state];
+                // The match ending earlier first
+                let ends = [(state.ends_before, true), (state.is_final, false)];
+                let ends = ends.into_iter().filter(|&(completes, _)| completes);
+                ends.map(move |(_, ends_before)| Completion {
+                    index,
+                    tag: state.final_tag,
+                    ends_before,
+                })
+            })
+            .collect();
+        // Only anchored patterns die, so all of them have to be dead members
+        let dead = members.iter().filter(|&&(_, state)| state == u32::MAX).count();
+        self.idle.push(dead == members.len() && dead == self.anchored_count);
+        index.insert(Arc::clone(&members), self.members.len());
+        self.members.push(members);
+        self.completions.push(completions);
+        Ok(self.members.len() - 1)
+    }
+
+    pub(crate) fn next_state(&self, state: usize, byte: u8) -> usize {
+        let class = self.class_of[byte as usize] as usize;
+        self.transitions[state * self.class_count + class] as usize
+    }
+
+    // The matches completing on entering `state`, by increasing pattern index
+    pub(crate) fn completions(&self, state: usize) -> &[Completion] {
+        &self.completions[state]
+    }
+
+    pub(crate) fn is_idle(&self, state: usize) -> bool {
+        self.idle[state]
+    }
+
+    // Patterns away from their initial state at `state`
+    pub(crate) fn active_count(&self, state: usize) -> usize {
+        self.members[state].len()
+    }
+
+    // Write the state every pattern is in at `state` to `states`
+    pub(crate) fn write_states(&self, state: usize, patterns: &[Pattern], states: &mut [usize]) {
+        for (current, pattern) in states.iter_mut().zip(patterns) {
+            *current = pattern.initial_state;
+        }
+        for &(index, member) in self.members[state].iter() {
+            states[index as usize] = unpack_state(member);
+        }
+    }
+
+    pub(crate) fn state_count(&self) -> usize {
+        self.members.len()
+    }
+
+    // Approximate bytes used by the tables
+    pub(crate) fn memory_usage(&self) -> usize {
+        let members: usize = self.members.iter().map(|members| members.len()).sum();
+        let completions: usize =
+            self.completions.iter().map(|completions| completions.len()).sum();
+        let per_state = std::mem::size_of::<(Members, Box<[Completion]>)>();
+        self.transitions.len() * std::mem::size_of::<u32>()
+            + self.members.len() * (per_state + std::mem::size_of::<bool>())
+            + members * std::mem::size_of::<(u32, u32)>()
+            + completions * std::mem::size_of::<Completion>()
+    }
+}
+
+// The state `pattern` goes to from `state` on `byte` as StreamMatcher steps it, or None
+// where that leaves it out of the members
+fn advance(pattern: &Pattern, state: usize, byte: u8) -> Option<usize> {
+    if state == DEAD {
+        return Some(DEAD);
+    }
+    let next = match pattern.search_next_state(state, byte) {
+        Some(next) => next,
+        None if pattern.anchored => DEAD,
+        None => return None,
+    };
+    (next != pattern.initial_state || pattern.states[next].completes()).then_some(next)
+}
+
+fn member(index: usize, state: usize) -> (u32, u32) {
+    let state = if state == DEAD { u32::MAX } else { state as u32 };
+    (index as u32, state)
+}
+
+fn unpack_state(state: u32) -> usize {
+    if state == u32::MAX { DEAD } else { state as usize }
+}
+
+// The members after `byte`, from `members` and from `starts` for the patterns that are
+// not among them
+fn successor(
+    patterns: &[Pattern],
+    members: &[(u32, u32)],
+    starts: &[(u32, u32)],
+    byte: u8,
+) -> Members {
+    let mut next = Vec::with_capacity(members.len() + starts.len());
+    let mut starts = starts.iter().peekable();
+    for &(index, state) in members {
+        while let Some(&start) = starts.next_if(|&&(other, _)| other < index) {
+            next.push(start);
+        }
+        starts.next_if(|&&(other, _)| other == index);
+        let pattern = &patterns[index as usize];
+        if let Some(state) = advance(pattern, unpack_state(state), byte) {
+            next.push(member(index as usize, state));
+        }
+    }
+    next.extend(starts);
+    next.into()
+}
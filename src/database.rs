@@ -0,0 +1,1432 @@
+// Patterns compiled once into a PatternDatabase, which scans any number of streams that
+// each keep their progress in a StreamState, as Hyperscan splits a database from the
+// scratch space of a stream
+//
+// The database does not change while it scans, so one behind an Arc can serve the
+// streams of many threads, while a stream only keeps where each pattern is in it: a few
+// words per pattern instead of a copy of all of them. StreamMatcher bundles a database
+// with one stream, and changes the database as patterns are added and removed.
+
+use std::collections::{HashMap, VecDeque};
+use std::ops::{ControlFlow, Range};
+
+use crate::Result;
+use crate::byteset::ByteSet;
+use crate::combined::CombinedAutomaton;
+use crate::literals::{self, LiteralSet, Output};
+use crate::matcher::{Match, MatchCounts, MatchEvent, MatcherConfig};
+use crate::pattern::{Pattern, State};
+use crate::teddy::{self, Teddy};
+
+// State of an anchored pattern that can no longer match in the current stream
+pub(crate) const DEAD: usize = usize::MAX;
+
+// States compile may combine the patterns into, unless the MatcherConfig says otherwise
+const DEFAULT_COMBINED_STATE_LIMIT: usize = 100_000;
+
+// Most bytes the patterns may start with for PatternDatabase::prefilter, as many as
+// memchr3 looks for
+const MAX_PREFILTER_BYTES: usize = 3;
+
+// The patterns a stream is scanned for, and the automatons that run them
+#[derive(Debug)]
+pub struct PatternDatabase {
+    pub(crate) patterns: Vec<Pattern>,
+    // The automaton of compile, which runs every pattern
+    pub(crate) combined: Option<CombinedAutomaton>,
+    // The Aho-Corasick automaton of the literal patterns, see StreamMatcher::add_pattern.
+    // Left alone while `combined` runs every pattern
+    pub(crate) literals: Option<LiteralSet>,
+    // Whether each pattern runs in `literals` instead of on its own, indexed like
+    // `patterns`. Their states stay initial
+    pub(crate) routed: Vec<bool>,
+    // The indexes of the enabled patterns running on their own, in increasing order
+    pub(crate) unrouted: Vec<usize>,
+    // Whether each pattern runs at all, indexed like `patterns`, see
+    // StreamMatcher::set_enabled. Disabled ones are dead in every stream
+    pub(crate) enabled: Vec<bool>,
+    // The bytes the unanchored patterns start with, see Pattern::first_bytes
+    pub(crate) first_bytes: ByteSet,
+    pub(crate) prefilter: Option<Prefilter>,
+    pub(crate) config: MatcherConfig,
+}
+
+// Where the patterns of a PatternDatabase are in one stream
+#[derive(Debug, Clone)]
+pub struct StreamState {
+    pub(crate) progress: Progress,
+    // Bytes scanned since the stream started
+    pub(crate) position: u64,
+    pub(crate) caps: MatchCaps,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Progress {
+    // The state of each pattern, indexed like PatternDatabase::patterns, or DEAD. Not
+    // kept up to date while the patterns run combined, see
+    // PatternDatabase::write_states
+    pub(crate) states: Vec<usize>,
+    // The sorted set of states each nondeterministic pattern can be in (see
+    // Pattern::is_nondeterministic), indexed the same way, and empty for the others
+    pub(crate) sets: Vec<Vec<usize>>,
+    // Where the next set is collected, kept to save allocating for every byte
+    next: Vec<usize>,
+    // The state of the automaton of compile, when the database has one
+    pub(crate) combined: usize,
+    // The node of the automaton of the literals, when the database has one
+    pub(crate) node: usize,
+    // The literals completing a match at the current byte
+    found: Vec<Output>,
+    // The matches of a chunk run one pattern at a time, see run_patterns
+    hits: Vec<Hit>,
+    // Whether every pattern is dead or unanchored at its initial state, where bytes no
+    // pattern starts with leave it, so the prefilter may skip them. Only step sets it,
+    // and it stays false after other changes until the next byte
+    pub(crate) idle: bool,
+}
+
+// Counts the matches of the current stream against the caps of a MatcherConfig
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MatchCaps {
+    pub(crate) config: MatcherConfig,
+    // Matches reported for each pattern, indexed like PatternDatabase::patterns
+    pub(crate) counts: Vec<u64>,
+    // The same counts by pattern number, see StreamMatcher::match_counts_by_number
+    pub(crate) by_number: HashMap<u32, u64>,
+    pub(crate) total: u64,
+    pub(crate) truncated: bool,
+    // The matches of each pattern held back to be reported as one, indexed the same way,
+    // see MatcherConfig::coalesce_within
+    pub(crate) groups: Vec<Option<Group>>,
+    // The earliest position at which one of the groups is complete, u64::MAX without any
+    pub(crate) deadline: u64,
+    // Where each pattern is in the windows of the rate limit, indexed the same way, see
+    // MatcherConfig::rate_limit
+    pub(crate) rates: Vec<Rate>,
+    // Matches the rate limit or a higher priority dropped for each pattern, indexed the
+    // same way
+    pub(crate) suppressed: Vec<u64>,
+    // The matches held back for MatcherConfig::priority_window, in the order found
+    pub(crate) held: VecDeque<Held>,
+    pub(crate) context: Context,
+    // Matches reported, and dropped by the caps, the rate limit or a higher priority,
+    // since the stream was created: unlike the counts above, reset and finish leave
+    // these alone. See StreamMatcher::stats
+    pub(crate) reported: u64,
+    pub(crate) dropped: u64,
+}
+
+// The bytes of a stream around its matches, see MatcherConfig::context_bytes
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Context {
+    // The last bytes before the current chunk, as many as any match still to be
+    // reported may need, see MatcherConfig::context_capacity
+    pub(crate) ring: VecDeque<u8>,
+    // The matches waiting for the bytes after them, in the order reported
+    pub(crate) pending: VecDeque<Match>,
+    // The context of the events being reported, which they borrow
+    bytes: Vec<u8>,
+}
+
+// A match a higher priority might still suppress, see MatcherConfig::priority_window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Held {
+    pub(crate) index: usize,
+    pub(crate) tag: Option<u32>,
+    pub(crate) end: u64,
+    pub(crate) suppressed: bool,
+}
+
+// The matches of a pattern reported in the current window of the rate limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Rate {
+    // Its offset divided by the bytes of a window
+    pub(crate) window: u64,
+    pub(crate) reported: u64,
+}
+
+// Matches of one pattern coalesced into the event of the first of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Group {
+    pub(crate) tag: Option<u32>,
+    // Of the first match, from which the window counts
+    pub(crate) end: u64,
+    pub(crate) count: u64,
+}
+
+impl MatchCaps {
+    // Whether a match of the pattern at `index` ending at `end` may be reported,
+    // counting it if so. Those of negative patterns never are, but count towards
+    // finding them present
+    fn allow(&mut self, index: usize, pattern: &Pattern, end: u64) -> bool {
+        if pattern.negative {
+            self.count(index, pattern);
+            return false;
+        }
+        let config = self.config;
+        if let Some((max, bytes)) = config.rate_limit {
+            let rate = &mut self.rates[index];
+            let window = end / bytes.max(1);
+            if rate.window != window {
+                *rate = Rate { window, reported: 0 };
+            }
+            if rate.reported >= max {
+                self.suppressed[index] += 1;
+                self.dropped += 1;
+                return false;
+            }
+        }
+        let capped = config.max_matches_per_pattern.is_some_and(|max| self.counts[index] >= max)
+            || config.max_matches_total.is_some_and(|max| self.total >= max);
+        if capped {
+            self.truncated = true;
+            self.dropped += 1;
+            return false;
+        }
+        self.count(index, pattern);
+        self.total += 1;
+        self.reported += 1;
+        self.rates[index].reported += 1;
+        true
+    }
+
+    fn count(&mut self, index: usize, pattern: &Pattern) {
+        self.counts[index] += 1;
+        *self.by_number.entry(pattern.number.unwrap_or_default()).or_default() += 1;
+    }
+
+    // Make room for a pattern added to the database
+    pub(crate) fn push(&mut self, pattern: &Pattern) {
+        self.counts.push(0);
+        self.by_number.insert(pattern.number.unwrap_or_default(), 0);
+        self.groups.push(None);
+        self.rates.push(Rate::default());
+        self.suppressed.push(0);
+    }
+
+    // Drop the entries of `pattern`, removed at `index`
+    pub(crate) fn remove(&mut self, index: usize, pattern: &Pattern) {
+        self.counts.remove(index);
+        self.by_number.remove(&pattern.number.unwrap_or_default());
+        self.groups.remove(index);
+        self.rates.remove(index);
+        self.suppressed.remove(index);
+        self.held.retain(|held| held.index != index);
+        for held in &mut self.held {
+            held.index -= usize::from(held.index > index);
+        }
+    }
+
+    // Report a match of the pattern at `index` to `on_match`, or hold it back while a
+    // higher priority might suppress it. Break if on_match did
+    fn report<'p, F>(
+        &mut self,
+        patterns: &'p [Pattern],
+        index: usize,
+        tag: Option<u32>,
+        end: u64,
+        on_match: &mut F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(&MatchEvent<'p>) -> ControlFlow<()>,
+    {
+        let pattern = &patterns[index];
+        let window = self.config.priority_window.unwrap_or_default();
+        if window == 0 || pattern.negative {
+            return self.admit(patterns, index, tag, end, on_match);
+        }
+        // Matches of this step may still end a byte before this one
+        let flow = self.flush(patterns, end.saturating_sub(1), on_match);
+        // Where a match starts, or may start for patterns of variable length
+        let start = |pattern: &Pattern, end: u64| {
+            MatchEvent::new(pattern, None, end).start.unwrap_or(end.saturating_sub(window))
+        };
+        let mut suppressed = false;
+        for held in &mut self.held {
+            let other = &patterns[held.index];
+            let overlaps = start(other, held.end) < end && start(pattern, end) < held.end;
+            if !overlaps || held.end.abs_diff(end) >= window {
+                continue;
+            }
+            suppressed |= other.priority > pattern.priority;
+            held.suppressed |= pattern.priority > other.priority;
+        }
+        self.held.push_back(Held { index, tag, end, suppressed });
+        flow
+    }
+
+    // Report the held matches no match ending at `position` or later can suppress, in
+    // the order found, and then the groups none can join. Break if on_match did
+    fn flush<'p, F>(
+        &mut self,
+        patterns: &'p [Pattern],
+        position: u64,
+        on_match: &mut F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(&MatchEvent<'p>) -> ControlFlow<()>,
+    {
+        let window = self.config.priority_window.unwrap_or_default();
+        let mut flow = ControlFlow::Continue(());
+        while let Some(&held) = self.held.front()
+            && held.end.saturating_add(window) <= position
+        {
+            self.held.pop_front();
+            if held.suppressed {
+                self.suppressed[held.index] += 1;
+                self.dropped += 1;
+            } else if self.admit(patterns, held.index, held.tag, held.end, on_match).is_break() {
+                flow = ControlFlow::Break(());
+            }
+        }
+        // The matches still held come later, ending a byte before the first at the least
+        let position = match self.held.front() {
+            Some(held) => position.min(held.end.saturating_sub(1)),
+            None => position,
+        };
+        if self.flush_groups(patterns, position, on_match).is_break() {
+            flow = ControlFlow::Break(());
+        }
+        flow
+    }
+
+    // Report a match of the pattern at `index` to `on_match` as far as the caps allow,
+    // or hold it back in a group when the matches coalesce. Break if on_match did
+    fn admit<'p, F>(
+        &mut self,
+        patterns: &'p [Pattern],
+        index: usize,
+        tag: Option<u32>,
+        end: u64,
+        on_match: &mut F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(&MatchEvent<'p>) -> ControlFlow<()>,
+    {
+        let pattern = &patterns[index];
+        let Some(window) = self.config.coalesce_within else {
+            if self.allow(index, pattern, end) {
+                return self.emit(MatchEvent::new(pattern, tag, end), on_match);
+            }
+            return ControlFlow::Continue(());
+        };
+        if !pattern.negative
+            && let Some(group) = &mut self.groups[index]
+            && end < group.end.saturating_add(window)
+        {
+            group.count += 1;
+            return ControlFlow::Continue(());
+        }
+        // Matches of this step may still end a byte before this one
+        let mut flow = self.flush_groups(patterns, end.saturating_sub(1), on_match);
+        if let Some(group) = self.groups[index].take()
+            && self.emit(group.event(pattern), on_match).is_break()
+        {
+            flow = ControlFlow::Break(());
+        }
+        if self.allow(index, pattern, end) {
+            self.groups[index] = Some(Group { tag, end, count: 1 });
+            self.deadline = self.deadline.min(end.saturating_add(window));
+        }
+        flow
+    }
+
+    // Report the groups no match ending at `position` or later can join, by pattern.
+    // Break if on_match did
+    fn flush_groups<'p, F>(
+        &mut self,
+        patterns: &'p [Pattern],
+        position: u64,
+        on_match: &mut F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(&MatchEvent<'p>) -> ControlFlow<()>,
+    {
+        if position < self.deadline {
+            return ControlFlow::Continue(());
+        }
+        let window = self.config.coalesce_within.unwrap_or_default();
+        let mut flow = ControlFlow::Continue(());
+        self.deadline = u64::MAX;
+        for (pattern, slot) in patterns.iter().zip(&mut self.groups) {
+            let Some(group) = *slot else {
+                continue;
+            };
+            let deadline = group.end.saturating_add(window);
+            if deadline > position {
+                self.deadline = self.deadline.min(deadline);
+                continue;
+            }
+            *slot = None;
+            if self.context.emit(&self.config, group.event(pattern), on_match).is_break() {
+                flow = ControlFlow::Break(());
+            }
+        }
+        flow
+    }
+
+    // Pass `event` to `on_match`, unless it waits for its context
+    fn emit<'p, F>(&mut self, event: MatchEvent<'p>, on_match: &mut F) -> ControlFlow<()>
+    where
+        F: FnMut(&MatchEvent<'p>) -> ControlFlow<()>,
+    {
+        self.context.emit(&self.config, event, on_match)
+    }
+
+    // Report the events whose context streamed through with `chunk`, which the stream
+    // is now at the end of, and keep the bytes of it the next chunks may need.
+    // Break if on_match did
+    fn release<F>(
+        &mut self,
+        patterns: &[Pattern],
+        chunk: &[u8],
+        position: u64,
+        on_match: &mut F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(&MatchEvent) -> ControlFlow<()>,
+    {
+        let flow = self.release_ready(patterns, chunk, position, on_match);
+        if self.config.context_bytes.is_some() {
+            self.context.keep(chunk, self.config.context_capacity());
+        }
+        flow
+    }
+
+    // Where the context of the first event waiting for it ends
+    fn due(&self) -> Option<u64> {
+        let (_, after) = self.config.context_bytes?;
+        let found = self.context.pending.front()?;
+        Some(found.end.saturating_add(after as u64))
+    }
+
+    // Report the events whose context streamed through with `chunk`, the part of the
+    // current chunk the stream is at the end of, without keeping any of it. Break if
+    // on_match did
+    fn release_ready<F>(
+        &mut self,
+        patterns: &[Pattern],
+        chunk: &[u8],
+        position: u64,
+        on_match: &mut F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(&MatchEvent) -> ControlFlow<()>,
+    {
+        let Some((before, after)) = self.config.context_bytes else {
+            return ControlFlow::Continue(());
+        };
+        let context = &mut self.context;
+        let mut flow = ControlFlow::Continue(());
+        while let Some(found) = context.ready(position, after) {
+            let Some(pattern) = pattern_numbered(patterns, found.pattern) else {
+                continue;
+            };
+            context.bytes.clear();
+            let range = context.write(chunk, position, found.end, (before, after));
+            let event = MatchEvent {
+                context: &context.bytes[range],
+                ..MatchEvent::from_match(pattern, &found)
+            };
+            if on_match(&event).is_break() {
+                flow = ControlFlow::Break(());
+            }
+        }
+        flow
+    }
+
+    fn reset(&mut self) {
+        self.counts.fill(0);
+        self.by_number.values_mut().for_each(|count| *count = 0);
+        self.total = 0;
+        self.truncated = false;
+        self.groups.fill(None);
+        self.deadline = u64::MAX;
+        self.rates.fill(Rate::default());
+        self.suppressed.fill(0);
+        self.held.clear();
+        self.context.ring.clear();
+        self.context.pending.clear();
+    }
+}
+
+impl Context {
+    // Pass `event` to `on_match`, or keep it until the bytes after it streamed through
+    fn emit<'p, F>(
+        &mut self,
+        config: &MatcherConfig,
+        event: MatchEvent<'p>,
+        on_match: &mut F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(&MatchEvent<'p>) -> ControlFlow<()>,
+    {
+        if config.context_bytes.is_none() {
+            return on_match(&event);
+        }
+        self.pending.push_back(event.to_match());
+        ControlFlow::Continue(())
+    }
+
+    // The first pending match, if the `after` bytes after it are before `position`
+    fn ready(&mut self, position: u64, after: usize) -> Option<Match> {
+        let found = self.pending.front()?;
+        if found.end.saturating_add(after as u64) > position {
+            return None;
+        }
+        self.pending.pop_front()
+    }
+
+    // Append the bytes from `before` bytes before `end` to `after` bytes after it to
+    // the context bytes, as far as they are kept: in the ring, then in `chunk`, which
+    // ends at `position`. Returns where they went
+    fn write(
+        &mut self,
+        chunk: &[u8],
+        position: u64,
+        end: u64,
+        (before, after): (usize, usize),
+    ) -> Range<usize> {
+        let chunk_start = position - chunk.len() as u64;
+        let first = chunk_start - (self.ring.len() as u64).min(chunk_start);
+        let from = end.saturating_sub(before as u64).max(first);
+        let to = end.saturating_add(after as u64).min(position);
+        let start = self.bytes.len();
+        if from < to.min(chunk_start) {
+            let ring_index = |offset: u64| self.ring.len() - (chunk_start - offset) as usize;
+            let range = ring_index(from)..ring_index(to.min(chunk_start));
+            self.bytes.extend(self.ring.range(range));
+        }
+        let from = from.max(chunk_start);
+        if from < to {
+            let range = (from - chunk_start) as usize..(to - chunk_start) as usize;
+            self.bytes.extend_from_slice(&chunk[range]);
+        }
+        start..self.bytes.len()
+    }
+
+    // Keep the last `capacity` bytes of the stream, which now ends with `chunk`
+    fn keep(&mut self, chunk: &[u8], capacity: usize) {
+        let tail = &chunk[chunk.len().saturating_sub(capacity)..];
+        // Dropped first, so the ring never grows past what it was allocated with
+        let excess = (self.ring.len() + tail.len()).saturating_sub(capacity);
+        self.ring.drain(..excess);
+        self.ring.extend(tail);
+    }
+}
+
+impl Group {
+    fn event<'p>(&self, pattern: &'p Pattern) -> MatchEvent<'p> {
+        MatchEvent { count: self.count, ..MatchEvent::new(pattern, self.tag, self.end) }
+    }
+}
+
+// A match found by run_patterns, before the caps decide on it
+#[derive(Debug, Clone, Copy)]
+struct Hit {
+    // Of the byte completing it in the chunk
+    offset: usize,
+    index: usize,
+    tag: Option<u32>,
+    ends_before: bool,
+}
+
+// How the database skips the bytes no match can start at while every pattern is idle,
+// see PatternDatabase::prefilter
+#[derive(Debug)]
+pub(crate) enum Prefilter {
+    // The few bytes the patterns start with, for memchr
+    Bytes(Vec<u8>),
+    // The first bytes of the literals, when every unanchored pattern is one of a few
+    Literals(Box<Teddy>),
+}
+
+impl PatternDatabase {
+    // The database of `patterns`, numbered from 0 in order as StreamMatcher::add_pattern
+    // numbers them, whose streams are capped by `config`
+    pub fn new(patterns: Vec<Pattern>, config: MatcherConfig) -> Self {
+        let mut database = PatternDatabase {
+            patterns: Vec::with_capacity(patterns.len()),
+            combined: None,
+            literals: None,
+            routed: Vec::new(),
+            unrouted: Vec::new(),
+            enabled: Vec::new(),
+            first_bytes: ByteSet::new(),
+            prefilter: None,
+            config,
+        };
+        for (number, mut pattern) in patterns.into_iter().enumerate() {
+            pattern.number = Some(number as u32);
+            if config.anchored {
+                pattern = pattern.into_anchored();
+            }
+            database.push(pattern);
+        }
+        database.update_prefilter();
+        database.route_literals();
+        database
+    }
+
+    pub fn config(&self) -> MatcherConfig {
+        self.config
+    }
+
+    // The patterns, in the order they were added
+    pub fn patterns(&self) -> impl Iterator<Item = &Pattern> {
+        self.patterns.iter()
+    }
+
+    // The string id of the pattern numbered `number`
+    pub fn pattern_name(&self, number: u32) -> Option<&str> {
+        Some(&pattern_numbered(&self.patterns, number)?.id)
+    }
+
+    // Combine all patterns into one automaton, as StreamMatcher::compile does. Streams
+    // opened before are to be reset, as they carry on from the patterns' own states
+    pub fn compile(&mut self) -> Result<()> {
+        let initial: Vec<_> = self.patterns.iter().map(|pattern| pattern.initial_state).collect();
+        self.combine(&[&initial])?;
+        Ok(())
+    }
+
+    // States of the automaton of compile, or None while the patterns run one by one
+    pub fn combined_state_count(&self) -> Option<usize> {
+        self.combined.as_ref().map(CombinedAutomaton::state_count)
+    }
+
+    // The bytes the database looks for with memchr to skip the bytes before them, see
+    // StreamMatcher::prefilter
+    pub fn prefilter(&self) -> Option<&[u8]> {
+        match &self.prefilter {
+            Some(Prefilter::Bytes(bytes)) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    // The instructions the literal prefilter runs on, see
+    // StreamMatcher::literal_prefilter
+    pub fn literal_prefilter(&self) -> Option<&str> {
+        match &self.prefilter {
+            Some(Prefilter::Literals(teddy)) => Some(teddy.backend()),
+            _ => None,
+        }
+    }
+
+    // The numbers of the patterns, in their order
+    pub(crate) fn numbers(&self) -> impl Iterator<Item = u32> + '_ {
+        self.patterns.iter().map(|pattern| pattern.number.unwrap_or_default())
+    }
+
+    // The matches of each pattern in `stream` since it started, see
+    // StreamMatcher::match_counts
+    pub fn match_counts(&self, stream: &StreamState) -> Vec<MatchCounts> {
+        let caps = &stream.caps;
+        let counts = self.patterns.iter().zip(&caps.counts).zip(&caps.suppressed);
+        counts
+            .map(|((pattern, &reported), &suppressed)| MatchCounts {
+                pattern: pattern.number.unwrap_or_default(),
+                reported,
+                suppressed,
+            })
+            .collect()
+    }
+
+    // Patterns partway through a match in `stream`, neither at their initial state nor
+    // dead. The literals running together in one automaton count as one
+    pub fn active_patterns(&self, stream: &StreamState) -> usize {
+        let progress = &stream.progress;
+        if let Some(combined) = &self.combined {
+            return combined.active_count(progress.combined);
+        }
+        let literals = self.literals.is_some() && progress.node != literals::ROOT;
+        let active = |&&index: &&usize| {
+            let (pattern, state) = (&self.patterns[index], progress.states[index]);
+            let at_initial = match pattern.is_nondeterministic() {
+                true => progress.sets[index] == [pattern.initial_state],
+                false => state == pattern.initial_state,
+            };
+            state != DEAD && !at_initial
+        };
+        usize::from(literals) + self.unrouted.iter().filter(active).count()
+    }
+
+    // Approximate bytes used by the patterns and the automatons running them
+    pub fn memory_usage(&self) -> usize {
+        self.patterns.iter().map(Pattern::memory_usage).sum::<usize>()
+            + self.combined.as_ref().map_or(0, CombinedAutomaton::memory_usage)
+            + self.literals.as_ref().map_or(0, LiteralSet::memory_usage)
+    }
+
+    // Scan the next chunk of the stream of `stream`, passing every match to `sink`.
+    // Returns how many bytes were consumed: all of `data`, unless `sink` returned Break,
+    // as StreamMatcher::process_chunk does with its callbacks
+    pub fn scan<F>(&self, stream: &mut StreamState, data: &[u8], sink: F) -> usize
+    where
+        F: FnMut(&MatchEvent) -> ControlFlow<()>,
+    {
+        match self.scan_with(stream, data, sink) {
+            ControlFlow::Break(consumed) => consumed,
+            ControlFlow::Continue(()) => data.len(),
+        }
+    }
+
+    // Like scan, but appends the matches to `matches`
+    // Without a combined automaton or a prefilter to skip bytes with, every pattern
+    // runs through the whole chunk before the next, see run_patterns
+    pub fn scan_into(&self, stream: &mut StreamState, data: &[u8], matches: &mut Vec<Match>) {
+        let mut collect = |event: &MatchEvent| {
+            matches.push(event.to_match());
+            ControlFlow::Continue(())
+        };
+        if self.combined.is_none() && self.prefilter.is_none() && !self.exhausted(stream) {
+            run_patterns(self, stream, data, collect);
+            return;
+        }
+        let _ = self.scan_with(stream, data, &mut collect);
+    }
+
+    // End the stream of `stream`, passing the matches that can only complete at the
+    // end of a stream (patterns ending in '$') to `sink`, then an absent event for each
+    // negative pattern that did not match (see MatchEvent::absent), and reset it for a
+    // new one. Returns the match counts (see match_counts) of the stream that ended,
+    // with those of the matches reported here
+    // The events borrow their context (see MatcherConfig::context_bytes) from `stream`,
+    // where it stays until the next finish
+    pub fn finish<'d, F>(&'d self, stream: &'d mut StreamState, mut sink: F) -> Vec<MatchCounts>
+    where
+        F: FnMut(&MatchEvent<'d>) -> ControlFlow<()>,
+    {
+        let (patterns, position) = (&self.patterns, stream.position);
+        self.write_states(&mut stream.progress);
+        // Gathered first, as the stream is reset before any context is lent out. The
+        // stream ends here anyway, so there is nothing to stop
+        let mut events = Vec::new();
+        let mut gather = |event: &MatchEvent<'d>| {
+            events.push((*event, 0..0));
+            ControlFlow::Continue(())
+        };
+        let caps = &mut stream.caps;
+        for (index, tag) in matches_at_end(patterns, &self.enabled, &stream.progress) {
+            let _ = caps.report(patterns, index, tag, position, &mut gather);
+        }
+        let _ = caps.flush(patterns, u64::MAX, &mut gather);
+        let context = &mut caps.context;
+        context.bytes.clear();
+        if let Some(around) = caps.config.context_bytes {
+            while let Some(found) = context.ready(u64::MAX, 0) {
+                if let Some(pattern) = pattern_numbered(patterns, found.pattern) {
+                    let range = context.write(&[], position, found.end, around);
+                    events.push((MatchEvent::from_match(pattern, &found), range));
+                }
+            }
+        }
+        // A disabled negative pattern was not looked for, so it is not absent either
+        let counts = patterns.iter().zip(&caps.counts).zip(&self.enabled);
+        for ((pattern, &count), &enabled) in counts {
+            if pattern.negative && count == 0 && enabled {
+                events.push((MatchEvent::absent(pattern, position), 0..0));
+            }
+        }
+        let counts = self.match_counts(stream);
+        self.reset(stream);
+
+        let bytes = &stream.caps.context.bytes;
+        for (event, range) in events {
+            let _ = sink(&MatchEvent { context: &bytes[range], ..event });
+        }
+        counts
+    }
+
+    // Like finish, but appends the matches to `matches`
+    pub fn finish_into(&self, stream: &mut StreamState, matches: &mut Vec<Match>) {
+        self.finish(stream, |event| {
+            matches.push(event.to_match());
+            ControlFlow::Continue(())
+        });
+    }
+
+    // Start `stream` over at offset 0 without reporting anything, dropping the progress
+    // of every pattern and the match counts of the caps
+    pub fn reset(&self, stream: &mut StreamState) {
+        self.restart(&mut stream.progress);
+        stream.position = 0;
+        stream.caps.reset();
+    }
+
+    // Move `stream` past `bytes` bytes it does not get to see, as when there was no time
+    // to scan them (see MatcherConfig::chunk_budget). No match can be found across the
+    // gap, so every pattern starts over after it as at the start of a stream, those
+    // anchored with '^' included, while the position and the caps carry on
+    pub fn skip(&self, stream: &mut StreamState, bytes: u64) {
+        self.restart(&mut stream.progress);
+        stream.position += bytes;
+        stream.caps.context.ring.clear();
+    }
+
+    // Put every enabled pattern back at its initial state
+    fn restart(&self, progress: &mut Progress) {
+        let running = self.patterns.iter().zip(&mut progress.states).zip(&mut progress.sets);
+        for (((pattern, state), set), &enabled) in running.zip(&self.enabled) {
+            *state = if enabled { pattern.initial_state } else { DEAD };
+            if pattern.is_nondeterministic() {
+                set.clear();
+                set.push(pattern.initial_state);
+            }
+        }
+        progress.combined = 0;
+        progress.node = literals::ROOT;
+        progress.idle = false;
+    }
+
+    // Like scan, but Break with the bytes consumed when `sink` returned Break
+    pub(crate) fn scan_with<F>(
+        &self,
+        stream: &mut StreamState,
+        data: &[u8],
+        mut sink: F,
+    ) -> ControlFlow<usize>
+    where
+        F: FnMut(&MatchEvent) -> ControlFlow<()>,
+    {
+        let mut index = 0;
+        if self.exhausted(stream) {
+            index = data.len();
+            stream.position += data.len() as u64;
+        }
+        while index < data.len() {
+            // Events wait for their context (see MatcherConfig::context_bytes), and are
+            // reported right after the byte that completes it, not past it
+            let until = stream.caps.due().map_or(data.len(), |due| {
+                let ahead = usize::try_from(due.saturating_sub(stream.position));
+                index.saturating_add(ahead.unwrap_or(usize::MAX)).min(data.len())
+            });
+            index += self.skip_idle(stream, &data[index..until]);
+            if let Some(&byte) = data[..until].get(index) {
+                index += 1;
+                stream.position += 1;
+                if step(self, stream, byte, &mut sink).is_break() {
+                    return ControlFlow::Break(index);
+                }
+            }
+            if stream.caps.due().is_some_and(|due| due <= stream.position) {
+                let (caps, seen) = (&mut stream.caps, &data[..index]);
+                if caps.release_ready(&self.patterns, seen, stream.position, &mut sink).is_break() {
+                    // Resuming with the rest of the chunk needs the bytes before it
+                    caps.context.keep(seen, caps.config.context_capacity());
+                    return ControlFlow::Break(index);
+                }
+            }
+        }
+        // The matches held back whose time came within the chunk
+        let caps = &mut stream.caps;
+        let flushed = caps.flush(&self.patterns, stream.position, &mut sink);
+        let released = caps.release(&self.patterns, data, stream.position, &mut sink);
+        match flushed.is_break() || released.is_break() {
+            true => ControlFlow::Break(data.len()),
+            false => ControlFlow::Continue(()),
+        }
+    }
+
+    // Add `pattern` to run on its own, see StreamMatcher::add_pattern
+    pub(crate) fn push(&mut self, pattern: Pattern) {
+        self.routed.push(false);
+        self.unrouted.push(self.patterns.len());
+        self.enabled.push(true);
+        let anchored = pattern.anchored;
+        if !anchored {
+            self.first_bytes.union_with(&self.first_bytes_of(&pattern));
+        }
+        self.patterns.push(pattern);
+        if !anchored {
+            self.update_prefilter();
+        }
+    }
+
+    // Remove the pattern at `index`. The automaton of the literals skips its number
+    // until it is rebuilt
+    pub(crate) fn remove(&mut self, index: usize) -> Pattern {
+        let pattern = self.patterns.remove(index);
+        self.routed.remove(index);
+        self.enabled.remove(index);
+        self.update_unrouted();
+        self.update_prefilter();
+        pattern
+    }
+
+    // Run the pattern at `index` or not, see StreamMatcher::set_enabled. The automaton of
+    // the literals is to be rebuilt for it, and the states of the streams set
+    pub(crate) fn set_enabled(&mut self, index: usize, enabled: bool) {
+        self.enabled[index] = enabled;
+        self.update_unrouted();
+        self.update_prefilter();
+    }
+
+    // Combine the patterns into the automaton of compile, and return its state for each
+    // of `streams`, the states of the patterns in a stream
+    pub(crate) fn combine(&mut self, streams: &[&[usize]]) -> Result<Vec<usize>> {
+        let limit = self.config.combined_state_limit.unwrap_or(DEFAULT_COMBINED_STATE_LIMIT);
+        let (combined, states) =
+            CombinedAutomaton::new(&self.patterns, &self.enabled, streams, limit)?;
+        self.combined = Some(combined);
+        Ok(states)
+    }
+
+    // Run every literal pattern that can (see literals::routable) in a new automaton of
+    // the literals, starting at its root
+    pub(crate) fn route_literals(&mut self) {
+        let mut routed = Vec::new();
+        let enabled = self.patterns.iter().zip(&self.enabled).filter(|&(_, &enabled)| enabled);
+        for (pattern, _) in enabled {
+            if let Some((bytes, state)) = literals::routable(pattern) {
+                routed.push((pattern.number.unwrap_or_default(), bytes, state));
+            }
+        }
+        let literals = (!routed.is_empty()).then(|| LiteralSet::new(&routed));
+        let routable = self.patterns.iter().zip(&self.enabled);
+        let routable =
+            routable.map(|(pattern, &enabled)| enabled && literals::routable(pattern).is_some());
+        self.routed = routable.collect();
+        self.update_unrouted();
+        self.literals = literals;
+    }
+
+    pub(crate) fn update_unrouted(&mut self) {
+        let running = |&index: &usize| !self.routed[index] && self.enabled[index];
+        self.unrouted = (0..self.routed.len()).filter(running).collect();
+    }
+
+    // The enabled patterns that are not anchored, which the prefilter looks for
+    fn unanchored(&self) -> impl Iterator<Item = &Pattern> {
+        let enabled = self.patterns.iter().zip(&self.enabled).filter(|&(_, &enabled)| enabled);
+        enabled.map(|(pattern, _)| pattern).filter(|pattern| !pattern.anchored)
+    }
+
+    // The bytes of the stream `pattern` may start at, see Pattern::first_bytes. Folded
+    // to lowercase they are the same bytes, see MatcherConfig::ascii_case_insensitive
+    fn first_bytes_of(&self, pattern: &Pattern) -> ByteSet {
+        let mut bytes = pattern.first_bytes();
+        if self.config.ascii_case_insensitive {
+            for byte in b'a'..=b'z' {
+                if bytes.contains(byte) {
+                    bytes.insert(byte.to_ascii_uppercase());
+                }
+            }
+        }
+        bytes
+    }
+
+    // A byte of the stream as the patterns see it, see MatcherConfig::ascii_case_insensitive
+    #[inline]
+    fn fold(&self, byte: u8) -> u8 {
+        match self.config.ascii_case_insensitive {
+            true => byte.to_ascii_lowercase(),
+            false => byte,
+        }
+    }
+
+    fn update_prefilter(&mut self) {
+        let mut first_bytes = ByteSet::new();
+        for pattern in self.unanchored() {
+            first_bytes.union_with(&self.first_bytes_of(pattern));
+        }
+        self.first_bytes = first_bytes;
+        let bytes: Vec<u8> = (0..=255).filter(|&byte| self.first_bytes.contains(byte)).collect();
+        if bytes.len() <= MAX_PREFILTER_BYTES {
+            self.prefilter = Some(Prefilter::Bytes(bytes));
+            return;
+        }
+        // The literal prefilter compares the bytes as they are
+        if self.config.ascii_case_insensitive {
+            self.prefilter = None;
+            return;
+        }
+        let literals: Option<Vec<&[u8]>> = self
+            .unanchored()
+            .take(teddy::MAX_LITERALS + 1)
+            .map(|pattern| literals::routable(pattern).map(|(literal, _)| literal))
+            .collect();
+        self.prefilter = literals
+            .filter(|literals| literals.len() <= teddy::MAX_LITERALS)
+            .map(|literals| Prefilter::Literals(Box::new(Teddy::new(&literals))));
+    }
+
+    // Bring the states of `progress` up to date with the combined automaton, if there is
+    // one
+    pub(crate) fn write_states(&self, progress: &mut Progress) {
+        if let Some(combined) = &self.combined {
+            combined.write_states(progress.combined, &self.patterns, &mut progress.states);
+            // which leaves out the disabled patterns
+            for (state, &enabled) in progress.states.iter_mut().zip(&self.enabled) {
+                if !enabled {
+                    *state = DEAD;
+                }
+            }
+        }
+    }
+
+    // Whether nothing can match in the rest of the stream, as in anchored mode (see
+    // MatcherConfig::anchored) once every pattern died: they are all anchored, so
+    // being idle leaves them dead
+    fn exhausted(&self, stream: &StreamState) -> bool {
+        self.config.anchored && stream.progress.idle
+    }
+
+    // Skip the bytes at the start of `data` before the next one the prefilter finds, as
+    // long as every pattern is idle (see Progress::idle), returning how many
+    // Bytes the literal prefilter skips may take literals away from their initial
+    // state, but only towards a match that does not come, so the patterns step on from
+    // there as if they had stayed.
+    fn skip_idle(&self, stream: &mut StreamState, data: &[u8]) -> usize {
+        let Some(prefilter) = self.prefilter.as_ref().filter(|_| stream.progress.idle) else {
+            return 0;
+        };
+        let next = match prefilter {
+            Prefilter::Bytes(bytes) => match **bytes {
+                [] => None,
+                [first] => memchr::memchr(first, data),
+                [first, second] => memchr::memchr2(first, second, data),
+                [first, second, third] => memchr::memchr3(first, second, third, data),
+                _ => Some(0),
+            },
+            Prefilter::Literals(teddy) => Some(teddy.find(data)),
+        };
+        let skipped = next.unwrap_or(data.len());
+        stream.position += skipped as u64;
+        skipped
+    }
+}
+
+impl StreamState {
+    // A new stream for `database`, at offset 0. Scanning it with another database
+    // finds wrong matches or panics
+    pub fn new(database: &PatternDatabase) -> Self {
+        let count = database.patterns.len();
+        let mut stream = StreamState {
+            progress: Progress {
+                states: vec![0; count],
+                sets: vec![Vec::new(); count],
+                ..Progress::default()
+            },
+            position: 0,
+            caps: MatchCaps {
+                config: database.config,
+                counts: vec![0; count],
+                by_number: database.numbers().map(|number| (number, 0)).collect(),
+                groups: vec![None; count],
+                context: Context {
+                    ring: VecDeque::with_capacity(database.config.context_capacity()),
+                    ..Context::default()
+                },
+                rates: vec![Rate::default(); count],
+                suppressed: vec![0; count],
+                ..MatchCaps::default()
+            },
+        };
+        database.reset(&mut stream);
+        stream
+    }
+
+    // Bytes scanned since the stream started, the offset the next byte will be at
+    pub fn bytes_processed(&self) -> u64 {
+        self.position
+    }
+
+    // Whether the caps of the MatcherConfig dropped any match since the stream started
+    pub fn truncated(&self) -> bool {
+        self.caps.truncated
+    }
+
+    // Matches reported since the stream started, of all patterns together. Matches of
+    // negative patterns are not reported, so they do not count
+    pub fn total_matches(&self) -> u64 {
+        self.caps.total
+    }
+
+    // The matches of each pattern since the stream started by its number, see
+    // StreamMatcher::match_counts_by_number
+    pub fn match_counts_by_number(&self) -> &HashMap<u32, u64> {
+        &self.caps.by_number
+    }
+
+    // Approximate bytes used by the stream
+    pub fn memory_usage(&self) -> usize {
+        let progress = &self.progress;
+        let sets: usize = progress.sets.iter().map(Vec::capacity).sum();
+        std::mem::size_of::<Self>()
+            + (progress.states.capacity() + sets + progress.next.capacity())
+                * std::mem::size_of::<usize>()
+            + progress.sets.capacity() * std::mem::size_of::<Vec<usize>>()
+            + progress.found.capacity() * std::mem::size_of::<Output>()
+            + progress.hits.capacity() * std::mem::size_of::<Hit>()
+            + self.caps.counts.capacity() * std::mem::size_of::<u64>()
+            + self.caps.by_number.capacity() * std::mem::size_of::<(u32, u64)>()
+            + self.caps.groups.capacity() * std::mem::size_of::<Option<Group>>()
+            + self.caps.rates.capacity() * std::mem::size_of::<Rate>()
+            + self.caps.suppressed.capacity() * std::mem::size_of::<u64>()
+            + self.caps.held.capacity() * std::mem::size_of::<Held>()
+            + self.caps.context.ring.capacity()
+            + self.caps.context.pending.capacity() * std::mem::size_of::<Match>()
+            + self.caps.context.bytes.capacity()
+    }
+}
+
+// Advance every pattern by `byte`, the byte just before the position of `stream`,
+// passing each match the caps allow to `on_match`. Break if on_match did
+// Nondeterministic patterns advance their set of states instead, and their entry in
+// the states only tells whether they are dead. With an automaton from compile, that
+// takes the step for all patterns, and otherwise the automaton of the literals takes it
+// for them.
+fn step<F>(
+    database: &PatternDatabase,
+    stream: &mut StreamState,
+    byte: u8,
+    mut on_match: F,
+) -> ControlFlow<()>
+where
+    F: FnMut(&MatchEvent) -> ControlFlow<()>,
+{
+    let (patterns, position) = (&database.patterns, stream.position);
+    let (progress, caps) = (&mut stream.progress, &mut stream.caps);
+    let byte = database.fold(byte);
+    let mut flow = ControlFlow::Continue(());
+    if let Some(combined) = &database.combined {
+        progress.combined = combined.next_state(progress.combined, byte);
+        progress.idle = combined.is_idle(progress.combined);
+        for completion in combined.completions(progress.combined) {
+            let end = position - u64::from(completion.ends_before);
+            let (index, tag) = (completion.index, completion.tag);
+            if caps.report(patterns, index, tag, end, &mut on_match).is_break() {
+                flow = ControlFlow::Break(());
+            }
+        }
+        return flow;
+    }
+
+    progress.found.clear();
+    if let Some(literals) = &database.literals {
+        progress.node = literals.next_node(progress.node, byte);
+        literals.outputs(progress.node, &mut progress.found);
+    }
+    let mut idle = database.literals.is_none() || progress.node == literals::ROOT;
+    let mut found = progress
+        .found
+        .iter()
+        .filter_map(|output| {
+            let number = Some(output.number);
+            let index = patterns.binary_search_by_key(&number, |pattern| pattern.number);
+            Some((index.ok()?, output.state))
+        })
+        .peekable();
+    let mut unrouted = database.unrouted.iter().copied().peekable();
+    loop {
+        // The literals matching here come in among the other patterns by index
+        let before = |&(index, _): &(usize, usize)| {
+            unrouted.peek().is_none_or(|&next| index < next)
+        };
+        let literal = found.next_if(before);
+        let Some(index) = literal.map(|(index, _)| index).or_else(|| unrouted.next()) else {
+            break;
+        };
+        let pattern = &patterns[index];
+        let current_state = &mut progress.states[index];
+        if *current_state == DEAD {
+            continue;
+        }
+
+        let set = &mut progress.sets[index];
+        let completed = match literal {
+            Some((_, state)) => completion(&pattern.states[state]),
+            None => advance(pattern, current_state, set, &mut progress.next, byte),
+        };
+        if let Some((tag, ends)) = completed {
+            for ends_before in ends {
+                let end = position - u64::from(ends_before);
+                if caps.report(patterns, index, tag, end, &mut on_match).is_break() {
+                    flow = ControlFlow::Break(());
+                }
+            }
+        }
+
+        // The literals in the automaton rest with it
+        let at_initial = match pattern.is_nondeterministic() {
+            true => *set == [pattern.initial_state],
+            false => *current_state == pattern.initial_state,
+        };
+        idle &= literal.is_some() || *current_state == DEAD || (at_initial && !pattern.anchored);
+    }
+    progress.idle = idle;
+    flow
+}
+
+// Like stepping through `data` byte by byte, but running one pattern at a time over all
+// of it, which keeps its state and tables at hand
+// The matches are gathered and sorted into the order step reports them in before the
+// caps see them, as the caps count across patterns. Nothing can stop the run, so this
+// is only for matches that are collected
+fn run_patterns<F>(
+    database: &PatternDatabase,
+    stream: &mut StreamState,
+    data: &[u8],
+    mut on_match: F,
+) where
+    F: FnMut(&MatchEvent) -> ControlFlow<()>,
+{
+    let patterns = &database.patterns;
+    let progress = &mut stream.progress;
+    let hits = &mut progress.hits;
+    hits.clear();
+    if let Some(literals) = &database.literals {
+        for (offset, &byte) in data.iter().enumerate() {
+            progress.node = literals.next_node(progress.node, database.fold(byte));
+            literals.outputs(progress.node, &mut progress.found);
+            for output in &progress.found {
+                let number = Some(output.number);
+                // Removed patterns stay in the automaton until it is rebuilt
+                let Ok(index) = patterns.binary_search_by_key(&number, |pattern| pattern.number)
+                else {
+                    continue;
+                };
+                let state = &patterns[index].states[output.state];
+                if let Some((tag, ends)) = completion(state) {
+                    hits.extend(ends.map(|ends_before| Hit { offset, index, tag, ends_before }));
+                }
+            }
+        }
+    }
+
+    let running = patterns.iter().zip(&mut progress.states).zip(&mut progress.sets);
+    let running = running.zip(&database.routed).enumerate();
+    for (index, (((pattern, state), set), &routed)) in running {
+        if routed || *state == DEAD {
+            continue;
+        }
+        for (offset, &byte) in data.iter().enumerate() {
+            match advance(pattern, state, set, &mut progress.next, database.fold(byte)) {
+                Some((tag, ends)) => {
+                    hits.extend(ends.map(|ends_before| Hit { offset, index, tag, ends_before }));
+                }
+                None if *state == DEAD => break,
+                None => {}
+            }
+        }
+    }
+
+    hits.sort_unstable_by_key(|hit| (hit.offset, hit.index, !hit.ends_before));
+    for hit in hits.iter() {
+        let end = stream.position + hit.offset as u64 + 1 - u64::from(hit.ends_before);
+        let _ = stream.caps.report(patterns, hit.index, hit.tag, end, &mut on_match);
+    }
+    stream.position += data.len() as u64;
+    let _ = stream.caps.flush(patterns, stream.position, &mut on_match);
+    let _ = stream.caps.release(patterns, data, stream.position, &mut on_match);
+    // Nothing tracked whether every pattern is idle along the way, but dead ones are
+    progress.idle = progress.states.iter().all(|&state| state == DEAD);
+}
+
+// The pattern numbered `number` among `patterns`
+fn pattern_numbered(patterns: &[Pattern], number: u32) -> Option<&Pattern> {
+    // Numbers are handed out in increasing order, so the patterns are sorted by them
+    let index = patterns.binary_search_by_key(&Some(number), |pattern| pattern.number).ok()?;
+    Some(&patterns[index])
+}
+
+// Take the step for `byte` of a pattern running on its own, from `state` (or `set` for
+// a nondeterministic pattern). Without a transition it restarts from its initial state,
+// or is DEAD if anchored. Returns the completion of the state it reached
+fn advance(
+    pattern: &Pattern,
+    state: &mut usize,
+    set: &mut Vec<usize>,
+    next: &mut Vec<usize>,
+    byte: u8,
+) -> Option<(Option<u32>, Ends)> {
+    if pattern.is_nondeterministic() {
+        pattern.search_next_states(set, byte, next);
+        if !next.is_empty() {
+            std::mem::swap(set, next);
+            return completion(&pattern.set_finality(set));
+        }
+        set.clear();
+        set.push(pattern.initial_state);
+    } else if let Some(next) = pattern.search_next_state(*state, byte) {
+        *state = next;
+        return completion(&pattern.states[next]);
+    }
+    *state = if pattern.anchored { DEAD } else { pattern.initial_state };
+    None
+}
+
+// Whether each match completing on entering a state ended before the byte leading there
+// (see State::ends_before), the earlier end first
+type Ends = std::iter::Flatten<std::array::IntoIter<Option<bool>, 2>>;
+
+// The tag of the matches completing on entering `state`, and their Ends
+fn completion(state: &State) -> Option<(Option<u32>, Ends)> {
+    let ends = [state.ends_before.then_some(true), state.is_final.then_some(false)];
+    state.completes().then(|| (state.final_tag, ends.into_iter().flatten()))
+}
+
+// The indexes of the enabled patterns whose current state (or set of states) completes
+// a match at the end of the stream, with the tag of that state
+// A state where a match ended with the last byte already reported one at the end.
+// The states of `progress` have to be up to date, see PatternDatabase::write_states.
+fn matches_at_end<'s>(
+    patterns: &'s [Pattern],
+    enabled: &'s [bool],
+    progress: &'s Progress,
+) -> impl Iterator<Item = (usize, Option<u32>)> + 's {
+    let sets = &progress.sets;
+    patterns
+        .iter()
+        .zip(&progress.states)
+        .enumerate()
+        .filter(|&(index, (_, &state))| state != DEAD && enabled[index])
+        .filter_map(move |(index, (pattern, &state))| {
+            let merged;
+            let finality = match pattern.is_nondeterministic() {
+                true => {
+                    merged = pattern.set_finality(&sets[index]);
+                    &merged
+                }
+                false => &pattern.states[state],
+            };
+            let reports = finality.accepts_at_end && !finality.is_final;
+            reports.then_some((index, finality.final_tag))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{StreamMatcher, compile_pattern};
+
+    const PATTERNS: [&str; 6] = ["she", "he", "a[0-9]+b", "^GET", "x.*y$", r"\bfoo\b"];
+
+    fn noise(seed: u32, len: usize) -> Vec<u8> {
+        let mut seed = seed;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                b"sheGETa01b xyfo\n"[(seed >> 16) as usize % 16]
+            })
+            .collect()
+    }
+
+    fn patterns() -> Vec<Pattern> {
+        PATTERNS.iter().map(|pattern| compile_pattern(pattern).unwrap()).collect()
+    }
+
+    // The matches a StreamMatcher of its own finds in `data`
+    fn expected(data: &[u8]) -> Vec<Match> {
+        let mut matcher = StreamMatcher::new();
+        for pattern in patterns() {
+            matcher.add_pattern(pattern);
+        }
+        let mut matches = matcher.process_chunk_collect(data);
+        matcher.finish_into(&mut matches);
+        matches
+    }
+
+    #[test]
+    fn test_shared_database() {
+        fn assert_shareable<T: Send + Sync>(_: &T) {}
+        let inputs: Vec<Vec<u8>> = (0..8).map(|seed| noise(seed, 20_000)).collect();
+        for compiled in [false, true] {
+            let mut database = PatternDatabase::new(patterns(), MatcherConfig::new());
+            if compiled {
+                database.compile().unwrap();
+            }
+            assert_eq!(database.combined_state_count().is_some(), compiled);
+            assert_eq!(database.pattern_name(3), Some("^GET"));
+            let database = Arc::new(database);
+            assert_shareable(&database);
+
+            // Streams of several threads, each fed in chunks of its own size
+            let found: Vec<Vec<Match>> = std::thread::scope(|scope| {
+                let threads: Vec<_> = inputs
+                    .iter()
+                    .enumerate()
+                    .map(|(index, input)| {
+                        let database = database.clone();
+                        scope.spawn(move || {
+                            let mut stream = StreamState::new(&database);
+                            let mut matches = Vec::new();
+                            for chunk in input.chunks(index + 1) {
+                                database.scan_into(&mut stream, chunk, &mut matches);
+                            }
+                            database.finish_into(&mut stream, &mut matches);
+                            matches
+                        })
+                    })
+                    .collect();
+                threads.into_iter().map(|thread| thread.join().unwrap()).collect()
+            });
+            for (input, matches) in inputs.iter().zip(&found) {
+                assert_eq!(*matches, expected(input));
+                assert!(matches.len() > 50);
+            }
+        }
+
+        // Streams interleaved on one thread keep apart
+        let database = PatternDatabase::new(patterns(), MatcherConfig::new());
+        let mut streams = [StreamState::new(&database), StreamState::new(&database)];
+        let mut found = [Vec::new(), Vec::new()];
+        for (first, second) in inputs[0].chunks(10).zip(inputs[1].chunks(10)) {
+            database.scan_into(&mut streams[0], first, &mut found[0]);
+            database.scan_into(&mut streams[1], second, &mut found[1]);
+        }
+        assert_eq!(streams[0].bytes_processed(), 20_000);
+        for (stream, matches) in streams.iter_mut().zip(&mut found) {
+            database.finish_into(stream, matches);
+            assert_eq!(stream.bytes_processed(), 0);
+        }
+        assert_eq!(found[0], expected(&inputs[0]));
+        assert_eq!(found[1], expected(&inputs[1]));
+        // A stream takes a fraction of what the patterns take
+        assert!(streams[0].memory_usage() * 10 < database.memory_usage());
+    }
+
+    #[test]
+    fn test_scan() {
+        let database = PatternDatabase::new(patterns(), MatcherConfig::new().max_matches_total(5));
+        let mut stream = StreamState::new(&database);
+        let data = b"GET she said he xay";
+
+        // The sink sees events and may stop the scan, which resumes where it stopped
+        let mut events = Vec::new();
+        let consumed = database.scan(&mut stream, data, |event| {
+            events.push((event.name.to_string(), event.to_match()));
+            ControlFlow::Break(())
+        });
+        assert_eq!(consumed, 3);
+        assert_eq!(events[0].0, "^GET");
+        let consumed = database.scan(&mut stream, &data[3..], |event| {
+            events.push((event.name.to_string(), event.to_match()));
+            ControlFlow::Continue(())
+        });
+        assert_eq!(consumed, data.len() - 3);
+        database.finish(&mut stream, |event| {
+            events.push((event.name.to_string(), event.to_match()));
+            ControlFlow::Continue(())
+        });
+        let names: Vec<_> = events.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["^GET", "she", "he", "he", "x.*y$"]);
+        assert_eq!(events[4].1.end, 19);
+
+        // The caps count per stream
+        let matches = expected(data);
+        let mut collected = Vec::new();
+        database.scan_into(&mut stream, b"he he he he he he", &mut collected);
+        assert_eq!(collected.len(), 5);
+        assert!(stream.truncated());
+        database.reset(&mut stream);
+        assert!(!stream.truncated());
+        collected.clear();
+        database.scan_into(&mut stream, data, &mut collected);
+        database.finish_into(&mut stream, &mut collected);
+        assert_eq!(collected, matches);
+    }
+}
@@ -0,0 +1,555 @@
+// Graphviz DOT export of compiled patterns, and import of the same subset of DOT
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::byteset::ByteSet;
+use crate::error::Error;
+use crate::pattern::{Pattern, PatternBuilder};
+
+impl Pattern {
+    // Describe the automaton as a DOT digraph, e.g. for `dot -Tsvg`
+    // Nodes are numbered like the states, final states are double circles, and an
+    // arrow from a point marks the initial state. All bytes leading to the same state
+    // share one edge, labelled with a single byte, a class such as [a-z_] or [^"], or
+    // "any". Non-printable bytes are written as \xNN. States matching at the end of
+    // the stream are labelled with a '$', states completing a match that ended before
+    // the byte leading to them (after a trailing \b) with a '<', which a final state
+    // can have as well, tagged states with their tag, and failure links are dashed
+    // edges. Anchored patterns carry comment="anchored".
+    // In nondeterministic patterns a byte has an edge to every state it leads to.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph \"{}\" {{", escape_quoted(&self.id)).unwrap();
+        writeln!(dot, "    rankdir=LR;").unwrap();
+        if self.anchored {
+            writeln!(dot, "    comment=\"anchored\";").unwrap();
+        }
+        writeln!(dot, "    start [shape=point];").unwrap();
+        writeln!(dot, "    start -> {};", self.initial_state).unwrap();
+
+        for (index, state) in self.states.iter().enumerate() {
+            let shape = if state.is_final {
+                "doublecircle"
+            } else {
+                "circle"
+            };
+            let mut label = index.to_string();
+            if state.accepts_at_end {
+                label.push('$');
+            }
+            if state.ends_before {
+                label.push('<');
+            }
+            if let Some(tag) = state.final_tag {
+                write!(label, " #{}", tag).unwrap();
+            }
+            writeln!(dot, "    {} [shape={}, label=\"{}\"];", index, shape, label).unwrap();
+        }
+
+        for (index, state) in self.states.iter().enumerate() {
+            let mut targets: Vec<(usize, ByteSet)> = Vec::new();
+            let mut next = Vec::new();
+            for byte in 0..=255u8 {
+                match &self.branches {
+                    Some(branches) => branches.step(&[index], byte, &mut next),
+                    None => {
+                        next.clear();
+                        next.extend(state.next_state(byte));
+                    }
+                }
+                for &target in &next {
+                    match targets.iter_mut().find(|(other, _)| *other == target) {
+                        Some((_, bytes)) => bytes.insert(byte),
+                        None => {
+                            let mut bytes = ByteSet::new();
+                            bytes.insert(byte);
+                            targets.push((target, bytes));
+                        }
+                    }
+                }
+            }
+            targets.sort_by_key(|&(target, _)| target);
+            for (target, bytes) in targets {
+                let label = escape_quoted(&byte_label(&bytes));
+                writeln!(dot, "    {} -> {} [label=\"{}\"];", index, target, label).unwrap();
+            }
+            if let Some(failure) = state.failure {
+                writeln!(dot, "    {} -> {} [style=dashed];", index, failure).unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    // Build a pattern from a digraph in the format written by to_dot
+    // One statement per line: node lines give the shape (doublecircle for final
+    // states) and a label of the state number with an optional '$', '<' and " #tag", edge
+    // lines give the bytes as a label or are dashed failure links, and "start -> N"
+    // names the initial state. Other graph attributes are ignored. Errors name the
+    // offending line.
+    pub fn from_dot(dot: &str) -> Result<Pattern, Error> {
+        let mut id = None;
+        let mut initial = 0;
+        let mut anchored = false;
+        let mut nodes = BTreeMap::new();
+        let mut edges = Vec::new();
+        for (index, line) in dot.lines().enumerate() {
+            let error =
+                |message: String| Error::InvalidPattern(format!("Line {}: {}", index + 1, message));
+            let line = line.trim();
+            let line = line.strip_suffix(';').unwrap_or(line).trim_end();
+            if line.is_empty() || line == "}" {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix("digraph") {
+                let name = name.trim().strip_suffix('{').map(str::trim);
+                let name = name.ok_or_else(|| error("Expected '{' after the graph name".into()))?;
+                id = Some(unquote(name).map_err(error)?);
+                continue;
+            }
+            if id.is_none() {
+                return Err(error(
+                    "Expected 'digraph' before the first statement".into(),
+                ));
+            }
+            // Graph attributes such as rankdir=LR
+            if let Some((key, value)) = line.split_once('=')
+                && !key.contains(['[', '-'])
+            {
+                anchored |= key.trim() == "comment" && value.trim() == "\"anchored\"";
+                continue;
+            }
+
+            let (head, attributes) = match line.split_once('[') {
+                Some((head, attributes)) => {
+                    let attributes = attributes.strip_suffix(']');
+                    let attributes =
+                        attributes.ok_or_else(|| error("Expected ']' at the end".into()))?;
+                    (head.trim(), parse_attributes(attributes).map_err(error)?)
+                }
+                None => (line, Vec::new()),
+            };
+            let attribute = |key: &str| {
+                attributes
+                    .iter()
+                    .find(|(name, _)| name == key)
+                    .map(|(_, value)| value.as_str())
+            };
+
+            if let Some((from, to)) = head.split_once("->") {
+                let to = parse_state(to.trim()).map_err(error)?;
+                if from.trim() == "start" {
+                    initial = to;
+                    continue;
+                }
+                let from = parse_state(from.trim()).map_err(error)?;
+                let edge = if attribute("style") == Some("dashed") {
+                    None
+                } else {
+                    let label =
+                        attribute("label").ok_or_else(|| error("Edge without a label".into()));
+                    Some(parse_label(label?).map_err(error)?)
+                };
+                edges.push((index + 1, from, to, edge));
+            } else if head == "start" {
+                // The point marking the initial state
+            } else {
+                let state = parse_state(head).map_err(error)?;
+                let is_final = attribute("shape") == Some("doublecircle");
+                let label = attribute("label").unwrap_or("");
+                let accepts_at_end = label.contains('$');
+                let ends_before = label.contains('<');
+                let tag = match label.split_once('#') {
+                    Some((_, tag)) => Some(tag.trim().parse::<u32>().map_err(|_| {
+                        error(format!("Invalid tag '{}' in the label", tag.trim()))
+                    })?),
+                    None => None,
+                };
+                if nodes
+                    .insert(state, (is_final, accepts_at_end, ends_before, tag))
+                    .is_some()
+                {
+                    return Err(error(format!("State {} is declared twice", state)));
+                }
+            }
+        }
+
+        let id = id.ok_or_else(|| Error::InvalidPattern("Missing 'digraph' header".into()))?;
+        if let Some(missing) = (0..nodes.len()).find(|state| !nodes.contains_key(state)) {
+            return Err(Error::InvalidPattern(format!(
+                "States must be numbered from 0, but state {} is not declared",
+                missing
+            )));
+        }
+        if !nodes.contains_key(&initial) {
+            return Err(Error::InvalidPattern(format!(
+                "Initial state {} is not declared",
+                initial
+            )));
+        }
+
+        let mut builder = PatternBuilder::new();
+        for _ in 1..nodes.len() {
+            builder.add_state(false);
+        }
+        for (&state, &(is_final, accepts_at_end, ends_before, tag)) in &nodes {
+            builder.set_finality(state, is_final, accepts_at_end, tag)?;
+            if ends_before {
+                builder.set_ends_before(state)?;
+            }
+        }
+        builder.anchored(anchored).set_initial_state(initial)?;
+        for (line, from, to, edge) in edges {
+            for state in [from, to] {
+                if !nodes.contains_key(&state) {
+                    return Err(Error::InvalidPattern(format!(
+                        "Line {}: State {} is not declared",
+                        line, state
+                    )));
+                }
+            }
+            match edge {
+                None => builder.set_failure(from, to),
+                // Mostly full sets become default transitions, as they were built
+                Some(bytes) if bytes.len() > 128 => {
+                    builder.add_default_transition(from, bytes.negate(), to)
+                }
+                Some(bytes) => {
+                    let bytes: Vec<u8> = (0..=255).filter(|&byte| bytes.contains(byte)).collect();
+                    builder.add_transition_set(from, &bytes, to)
+                }
+            }?;
+        }
+        builder.build(id)
+    }
+}
+
+fn parse_state(text: &str) -> Result<usize, String> {
+    text.parse()
+        .map_err(|_| format!("Expected a state number, found '{}'", text))
+}
+
+// The text of a quoted DOT string
+fn unquote(text: &str) -> Result<String, String> {
+    let inner = text
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'));
+    let inner = inner.ok_or_else(|| format!("Expected a quoted string, found {}", text))?;
+    let mut unquoted = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unquoted.push(chars.next().ok_or("Unterminated escape in a string")?),
+            '"' => return Err(format!("Unescaped quote in {}", text)),
+            c => unquoted.push(c),
+        }
+    }
+    Ok(unquoted)
+}
+
+// Attributes such as `shape=circle, label="0"` as key and unquoted value
+fn parse_attributes(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut attributes = Vec::new();
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        let (key, value) = rest
+            .split_once('=')
+            .ok_or_else(|| format!("Expected key=value, found '{}'", rest))?;
+        let value = value.trim_start();
+        let end = if let Some(quoted) = value.strip_prefix('"') {
+            // The closing quote is the first one not escaped by a backslash
+            let mut escaped = false;
+            let close = quoted.char_indices().find(|&(_, c)| {
+                let close = c == '"' && !escaped;
+                escaped = c == '\\' && !escaped;
+                close
+            });
+            let (close, _) = close.ok_or_else(|| format!("Unterminated string in '{}'", value))?;
+            close + 2
+        } else {
+            value.find(',').unwrap_or(value.len())
+        };
+        let raw = value[..end].trim();
+        let parsed = if raw.starts_with('"') {
+            unquote(raw)?
+        } else {
+            raw.to_string()
+        };
+        attributes.push((key.trim().to_string(), parsed));
+        rest = value[end..].trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+    Ok(attributes)
+}
+
+// The bytes of an edge label written by byte_label
+fn parse_label(label: &str) -> Result<ByteSet, String> {
+    let invalid = || format!("Invalid edge label '{}'", label);
+    let mut bytes = ByteSet::new();
+    if label == "any" {
+        return Ok(bytes.negate());
+    }
+    let Some(class) = label.strip_prefix('[') else {
+        let (byte, rest) = parse_byte(label.as_bytes()).ok_or_else(invalid)?;
+        if !rest.is_empty() {
+            return Err(invalid());
+        }
+        bytes.insert(byte);
+        return Ok(bytes);
+    };
+    let class = class.strip_suffix(']').ok_or_else(invalid)?;
+    let (negated, mut rest) = match class.strip_prefix('^') {
+        Some(class) => (true, class.as_bytes()),
+        None => (false, class.as_bytes()),
+    };
+    while !rest.is_empty() {
+        let (low, after) = parse_byte(rest).ok_or_else(invalid)?;
+        rest = after;
+        let high = match rest.strip_prefix(b"-") {
+            Some(after) => {
+                let (high, after) = parse_byte(after).ok_or_else(invalid)?;
+                rest = after;
+                high
+            }
+            None => low,
+        };
+        if high < low {
+            return Err(invalid());
+        }
+        bytes.insert_range(low, high);
+    }
+    Ok(if negated { bytes.negate() } else { bytes })
+}
+
+// One byte written by escape_byte, and the text after it
+fn parse_byte(text: &[u8]) -> Option<(u8, &[u8])> {
+    match text {
+        [b'\\', b'x', high, low, rest @ ..] => {
+            let hex = std::str::from_utf8(&[*high, *low]).ok()?.to_string();
+            Some((u8::from_str_radix(&hex, 16).ok()?, rest))
+        }
+        [b'\\', byte, rest @ ..] => Some((*byte, rest)),
+        [byte, rest @ ..] if byte.is_ascii_graphic() => Some((*byte, rest)),
+        _ => None,
+    }
+}
+
+// Label for the bytes of one edge
+fn byte_label(bytes: &ByteSet) -> String {
+    match bytes.len() {
+        256 => "any".to_string(),
+        1 => {
+            let byte = (0..=255u8).find(|&byte| bytes.contains(byte)).unwrap();
+            escape_byte(byte)
+        }
+        // Mostly full sets read better as the bytes they leave out
+        129.. => format!("[^{}]", byte_ranges(&bytes.negate())),
+        _ => format!("[{}]", byte_ranges(bytes)),
+    }
+}
+
+// The bytes of a set as runs such as "a-z0-9_"
+fn byte_ranges(bytes: &ByteSet) -> String {
+    let mut ranges = String::new();
+    let mut byte = 0usize;
+    while byte < 256 {
+        if !bytes.contains(byte as u8) {
+            byte += 1;
+            continue;
+        }
+        let start = byte;
+        while byte < 256 && bytes.contains(byte as u8) {
+            byte += 1;
+        }
+        let end = byte - 1;
+        ranges.push_str(&escape_byte(start as u8));
+        if end > start + 1 {
+            ranges.push('-');
+        }
+        if end > start {
+            ranges.push_str(&escape_byte(end as u8));
+        }
+    }
+    ranges
+}
+
+// Printable ASCII as itself, with a backslash before the characters that structure
+// labels, and everything else as \xNN
+fn escape_byte(byte: u8) -> String {
+    match byte {
+        b'\\' | b'[' | b']' | b'^' | b'-' => format!("\\{}", byte as char),
+        _ if byte.is_ascii_graphic() => (byte as char).to_string(),
+        _ => format!("\\x{:02x}", byte),
+    }
+}
+
+// Text inside a DOT string, where quotes and backslashes need a backslash
+fn escape_quoted(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{StreamMatcher, compile_pattern};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn count_matches(pattern: Pattern, input: &[u8]) -> usize {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_callback(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        matcher.add_pattern(pattern);
+        matcher.process_chunk(input);
+        matcher.finish();
+        count.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let dot = compile_pattern("ab").unwrap().to_dot();
+        assert!(dot.starts_with("digraph \"ab\" {\n"), "{}", dot);
+        assert!(dot.contains("    start -> 0;\n"), "{}", dot);
+        assert!(dot.contains("    0 -> 1 [label=\"a\"];\n"), "{}", dot);
+        assert!(dot.contains("    1 -> 2 [label=\"b\"];\n"), "{}", dot);
+        assert!(
+            dot.contains("    2 [shape=doublecircle, label=\"2\"];\n"),
+            "{}",
+            dot
+        );
+        assert!(
+            dot.contains("    0 [shape=circle, label=\"0\"];\n"),
+            "{}",
+            dot
+        );
+        assert!(dot.ends_with("}\n"));
+
+        // Ranges, wildcards and exclusions collapse into one edge per target
+        let mut builder = PatternBuilder::new();
+        let (word, any, quoted) = (
+            builder.add_state(false),
+            builder.add_state(false),
+            builder.add_state(false),
+        );
+        let end = builder.add_state_tagged(7);
+        builder.add_transition_range(0, b'a'..=b'z', word).unwrap();
+        builder.add_transition(0, b'_', word).unwrap();
+        builder.add_transition(0, b'\x00', any).unwrap();
+        builder.add_wildcard_transition(any, end).unwrap();
+        builder.add_transition(0, b'"', quoted).unwrap();
+        builder.add_negated_transition(quoted, b"\"\\", quoted).unwrap();
+        builder.add_transition(quoted, b'"', end).unwrap();
+        builder.add_transition(word, b' ', end).unwrap();
+        let dot = builder.build("cl\"ass".to_string()).unwrap().to_dot();
+        assert!(dot.starts_with("digraph \"cl\\\"ass\" {"), "{}", dot);
+        assert!(
+            dot.contains(&format!("0 -> {} [label=\"[_a-z]\"]", word)),
+            "{}",
+            dot
+        );
+        assert!(
+            dot.contains(&format!("0 -> {} [label=\"\\\\x00\"]", any)),
+            "{}",
+            dot
+        );
+        assert!(
+            dot.contains(&format!("{} -> {} [label=\"any\"]", any, end)),
+            "{}",
+            dot
+        );
+        assert!(
+            dot.contains(&format!("{} -> {} [label=\"\\\\x20\"]", word, end)),
+            "{}",
+            dot
+        );
+        let negated = format!("{0} -> {0} [label=\"[^\\\"\\\\\\\\]\"]", quoted);
+        assert!(dot.contains(&negated), "{}", dot);
+        assert!(dot.contains(&format!(
+            "{} [shape=doublecircle, label=\"{} #7\"]",
+            end, end
+        )));
+
+        let dot = PatternBuilder::from_literals(&[("abab", b"abab")])
+            .unwrap()
+            .to_dot();
+        assert!(dot.contains("    4 -> 2 [style=dashed];\n"), "{}", dot);
+    }
+
+    #[test]
+    fn test_from_dot_round_trip() {
+        let literals: [(&str, &[u8]); 2] = [("she", b"she"), ("he", b"he")];
+        let patterns = [
+            compile_pattern(r"a[0-9]+\x00b").unwrap(),
+            compile_pattern(r#"^GET /[^"\]\\]*"#).unwrap(),
+            compile_pattern("(?i)html?$").unwrap(),
+            PatternBuilder::from_literals(&literals).unwrap(),
+        ];
+        let input = b"a12\x00b GET /x\"] she HTML";
+        for pattern in patterns {
+            let dot = pattern.to_dot();
+            let imported = Pattern::from_dot(&dot).unwrap();
+            assert_eq!(imported.to_dot(), dot);
+            assert_eq!(imported.is_anchored(), pattern.is_anchored());
+            assert_eq!(
+                count_matches(imported, input),
+                count_matches(pattern, input)
+            );
+        }
+    }
+
+    #[test]
+    fn test_from_dot() {
+        // Hand-written, starting from state 2 and with graph attributes to skip
+        let dot = r#"
+            digraph "x[ab]" {
+                size="4,4";
+                start -> 2;
+                2 [shape=circle];
+                1 [shape=doublecircle, label="1 #3"];
+                0 [shape=circle, label="0"];
+                2 -> 0 [label="x"];
+                0 -> 1 [label="[ab]"];
+            }
+        "#;
+        let pattern = Pattern::from_dot(dot).unwrap();
+        assert_eq!(pattern.id, "x[ab]");
+        assert_eq!(pattern.state_count(), 3);
+        assert_eq!(pattern.states[1].final_tag, Some(3));
+        // States keep their numbers, so the export starts from state 2 as well
+        assert_eq!(pattern.initial_state(), 2);
+        assert!(pattern.to_dot().contains("    start -> 2;\n"));
+        assert_eq!(count_matches(pattern, b"xa xb xc ax"), 2);
+
+        let error = |dot: &str| Pattern::from_dot(dot).unwrap_err().to_string();
+        let message = error("digraph \"a\" {\n0 [shape=circle];\n0 -> 0 [label=\"[z-a]\"];\n}");
+        assert!(
+            message.contains("Line 3: Invalid edge label '[z-a]'"),
+            "{}",
+            message
+        );
+        let message = error("digraph \"a\" {\n0 [shape=circle];\n0 -> 1 [label=\"a\"];\n}");
+        assert!(
+            message.contains("Line 3: State 1 is not declared"),
+            "{}",
+            message
+        );
+        let message = error("digraph \"a\" {\n0 [shape=circle];\nzero -> 0 [label=\"a\"];\n}");
+        assert!(
+            message.contains("Line 3: Expected a state number"),
+            "{}",
+            message
+        );
+        let message = error("0 [shape=circle];");
+        assert!(
+            message.contains("Line 1: Expected 'digraph'"),
+            "{}",
+            message
+        );
+        let message = error("digraph \"a\" {\n1 [shape=circle];\n}");
+        assert!(message.contains("state 0 is not declared"), "{}", message);
+    }
+}
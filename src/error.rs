@@ -1,16 +1,103 @@
+use std::fmt;
+
 use thiserror::Error;
 
+use crate::matcher::StreamId;
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Invalid pattern: {0}")]
     InvalidPattern(String),
 
+    // A pattern that could not be parsed, with the position of the problem
+    #[error("Invalid pattern: {0}")]
+    Syntax(SyntaxError),
+
+    // A builder call naming a state that has not been added
+    #[error("Invalid state index {index}: the pattern has {state_count} states")]
+    InvalidState { index: usize, state_count: usize },
+
     #[error("Pattern too complex: {0}")]
     PatternTooComplex(String),
 
+    // A stream id that close_stream already closed, see StreamMatcher::open_stream
+    #[error("Unknown stream {0:?}")]
+    UnknownStream(StreamId),
+
+    // A stream snapshot taken with other patterns, see StreamMatcher::restore_state
+    #[error(
+        "Stream snapshot was taken with another pattern set (hash {found:016x}, the matcher \
+         has {expected:016x})"
+    )]
+    PatternSetMismatch { expected: u64, found: u64 },
+
+    // A chunk that took longer to scan than MatcherConfig::chunk_budget allows, with
+    // the bytes of it scanned and the stream offset the rest of it starts at
+    #[error("Chunk budget exceeded after {scanned} bytes, at stream offset {offset}")]
+    BudgetExceeded { scanned: usize, offset: u64 },
+
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
     #[error("Internal error: {0}")]
     Internal(String),
-}
\ No newline at end of file
+}
+
+impl Error {
+    // An error at `offset` in a pattern whose id is filled in by the caller
+    pub(crate) fn syntax(offset: usize, message: impl Into<String>) -> Self {
+        Error::Syntax(SyntaxError {
+            id: String::new(),
+            offset,
+            message: message.into(),
+        })
+    }
+
+    pub(crate) fn with_pattern_id(self, id: &str) -> Self {
+        match self {
+            Error::Syntax(error) => Error::Syntax(SyntaxError {
+                id: id.to_string(),
+                ..error
+            }),
+            other => other,
+        }
+    }
+
+    // Byte offset of the problem within the pattern, for errors that have one
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            Error::Syntax(error) => Some(error.offset),
+            _ => None,
+        }
+    }
+}
+
+// Where and why a pattern failed to parse
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    // The id the pattern would have been reported with
+    pub id: String,
+    // Byte offset of the offending character within the pattern
+    pub offset: usize,
+    // What was found, and what was expected instead where that is clear
+    pub message: String,
+}
+
+impl SyntaxError {
+    // Two lines showing the pattern with a caret under the offending byte
+    // Only meaningful when the id is the pattern text, as with compile_pattern.
+    pub fn caret(&self) -> String {
+        let prefix = self.id.get(..self.offset).unwrap_or(&self.id);
+        format!("{}\n{}^", self.id, " ".repeat(prefix.chars().count()))
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at offset {} in '{}'",
+            self.message, self.offset, self.id
+        )
+    }
+}
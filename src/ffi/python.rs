@@ -6,7 +6,7 @@ use crate::{StreamMatcher, Pattern, PatternBuilder};
 /// Python wrapper for StreamMatcher
 #[pyclass]
 pub struct PyStreamMatcher {
-    matcher: Arc<StreamMatcher>,
+    matcher: Arc<StreamMatcher<'static>>,
 }
 
 #[pymethods]
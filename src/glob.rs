@@ -0,0 +1,164 @@
+// Frontend for shell-style wildcard patterns
+
+use crate::byteset::ByteSet;
+use crate::error::Error;
+use crate::parser::Node;
+use crate::pattern::{DEFAULT_STATE_LIMIT, Pattern, compile_node};
+
+// Compile a shell-style glob into a Pattern
+// '*' matches any run of bytes (including none and including '/' and newlines), '?'
+// matches any single byte, and '[...]' matches one byte from a set of bytes and
+// ranges, negated by a leading '!' or '^'. A ']' right after the opening bracket is
+// literal, and '\' makes the next byte literal. Every other byte matches itself.
+// Like compile_pattern, the glob is found anywhere in the stream and each match is
+// reported at its earliest end, so a trailing '*' never extends a match.
+pub fn compile_glob(pattern: &str) -> Result<Pattern, Error> {
+    let node = parse(pattern.as_bytes()).map_err(|error| error.with_pattern_id(pattern))?;
+    compile_node(&node, pattern.to_string(), DEFAULT_STATE_LIMIT)
+}
+
+fn parse(pattern: &[u8]) -> Result<Node, Error> {
+    let mut nodes = Vec::new();
+    let mut pos = 0;
+    while pos < pattern.len() {
+        let node = match pattern[pos] {
+            b'*' => Node::Repeat {
+                node: Box::new(Node::Class(ByteSet::new().negate())),
+                min: 0,
+                max: None,
+            },
+            b'?' => Node::Class(ByteSet::new().negate()),
+            b'[' => {
+                let (class, end) = parse_bracket(pattern, pos)?;
+                pos = end;
+                nodes.push(Node::Class(class));
+                continue;
+            }
+            b'\\' => {
+                pos += 1;
+                match pattern.get(pos) {
+                    Some(&byte) => Node::Byte(byte),
+                    None => {
+                        return Err(Error::syntax(
+                            pos - 1,
+                            "Trailing backslash, expected an escaped character",
+                        ));
+                    }
+                }
+            }
+            byte => Node::Byte(byte),
+        };
+        nodes.push(node);
+        pos += 1;
+    }
+    Ok(Node::Concat(nodes))
+}
+
+// Parse the bracket expression opening at `start`
+// Returns the set of bytes and the offset just past the closing ']'
+fn parse_bracket(pattern: &[u8], start: usize) -> Result<(ByteSet, usize), Error> {
+    let unterminated = || Error::syntax(start, "Unterminated bracket expression, expected ']'");
+    let mut pos = start + 1;
+    let negated = matches!(pattern.get(pos), Some(b'!' | b'^'));
+    if negated {
+        pos += 1;
+    }
+
+    let mut class = ByteSet::new();
+    let mut first = true;
+    loop {
+        let low = match pattern.get(pos) {
+            None => return Err(unterminated()),
+            Some(b']') if !first => break,
+            Some(b'\\') => {
+                pos += 1;
+                *pattern.get(pos).ok_or_else(unterminated)?
+            }
+            Some(&byte) => byte,
+        };
+        first = false;
+        pos += 1;
+
+        // A '-' before the closing bracket is literal
+        if pattern.get(pos) == Some(&b'-') && !matches!(pattern.get(pos + 1), None | Some(b']')) {
+            pos += 1;
+            let mut high = pattern[pos];
+            if high == b'\\' {
+                pos += 1;
+                high = *pattern.get(pos).ok_or_else(unterminated)?;
+            }
+            if high < low {
+                return Err(Error::syntax(
+                    pos,
+                    format!(
+                        "Invalid range '{}-{}' in bracket expression",
+                        low.escape_ascii(),
+                        high.escape_ascii()
+                    ),
+                ));
+            }
+            class.insert_range(low, high);
+            pos += 1;
+        } else {
+            class.insert(low);
+        }
+    }
+    Ok((if negated { class.negate() } else { class }, pos + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StreamMatcher;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Offsets of the bytes at which matches are reported
+    fn match_ends(glob: &str, input: &[u8]) -> Vec<usize> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_callback(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        matcher.add_pattern(compile_glob(glob).unwrap());
+
+        let mut ends = Vec::new();
+        for (offset, byte) in input.iter().enumerate() {
+            let before = count.load(Ordering::Relaxed);
+            matcher.process_chunk(std::slice::from_ref(byte));
+            ends.extend((before..count.load(Ordering::Relaxed)).map(|_| offset));
+        }
+        ends
+    }
+
+    #[test]
+    fn test_glob_wildcards() {
+        assert_eq!(match_ends("*.log", b"app.log"), vec![6]);
+        assert_eq!(match_ends("a*c", b"ac abc a/\nc"), vec![1, 5, 10]);
+        assert_eq!(match_ends("a?c", b"ac abc a\nc"), vec![5, 9]);
+        assert_eq!(match_ends("x?", b"x"), Vec::<usize>::new());
+        // Regex metacharacters are literal
+        assert_eq!(match_ends("a.b+", b"axb+ a.b+"), vec![8]);
+        assert_eq!(match_ends(r"\*\?", b"*x *?"), vec![4]);
+    }
+
+    #[test]
+    fn test_glob_brackets() {
+        assert_eq!(match_ends("file[0-9]", b"fileA file7"), vec![10]);
+        assert_eq!(match_ends("file[!0-9]", b"fileA file7"), vec![4]);
+        assert_eq!(match_ends("[^a]b", b"ab cb"), vec![4]);
+        assert_eq!(match_ends("[]]", b"a]"), vec![1]);
+        assert_eq!(match_ends("[!]]", b"]a"), vec![1]);
+        assert_eq!(match_ends("[a-]", b"x-a"), vec![1, 2]);
+        assert_eq!(match_ends(r"[\]x]", b"]x"), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_glob_errors() {
+        assert!(matches!(compile_glob("[abc"), Err(Error::Syntax(_))));
+        assert!(matches!(compile_glob("[]"), Err(Error::Syntax(_))));
+        assert!(matches!(compile_glob("[z-a]"), Err(Error::Syntax(_))));
+        assert!(matches!(compile_glob("ab\\"), Err(Error::Syntax(_))));
+    }
+}
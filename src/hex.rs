@@ -0,0 +1,129 @@
+// Frontend for YARA-style hex-string signatures
+
+use crate::byteset::ByteSet;
+use crate::error::Error;
+use crate::parser::Node;
+use crate::pattern::{DEFAULT_STATE_LIMIT, Pattern, compile_node};
+
+// Compile a hex string such as "4D 5A 90 00 ?? ?? FF" into a Pattern
+// Each byte is written as two hex digits (either case) and "??" matches any byte.
+// Whitespace between bytes is ignored. A lone digit or any other character fails
+// with Error::Syntax naming its offset.
+pub fn compile_hex(pattern: &str) -> Result<Pattern, Error> {
+    let node = parse(pattern.as_bytes()).map_err(|error| error.with_pattern_id(pattern))?;
+    compile_node(&node, pattern.to_string(), DEFAULT_STATE_LIMIT)
+}
+
+fn parse(pattern: &[u8]) -> Result<Node, Error> {
+    let mut nodes = Vec::new();
+    let mut pos = 0;
+    while pos < pattern.len() {
+        if pattern[pos].is_ascii_whitespace() {
+            pos += 1;
+            continue;
+        }
+        let pair = (pattern[pos], pattern.get(pos + 1).copied());
+        nodes.push(match pair {
+            (b'?', Some(b'?')) => Node::Class(ByteSet::new().negate()),
+            (high, Some(low)) if high.is_ascii_hexdigit() && low.is_ascii_hexdigit() => {
+                Node::Byte((hex_digit(high) << 4) | hex_digit(low))
+            }
+            (first, second) => {
+                let (byte, offset) = if !first.is_ascii_hexdigit() && first != b'?' {
+                    (first, pos)
+                } else {
+                    match second {
+                        Some(byte) if !byte.is_ascii_whitespace() => (byte, pos + 1),
+                        _ => {
+                            return Err(Error::syntax(
+                                pos,
+                                "Odd number of hex digits, expected a second digit",
+                            ));
+                        }
+                    }
+                };
+                return Err(Error::syntax(
+                    offset,
+                    format!(
+                        "Invalid character '{}', expected a hex digit or '?'",
+                        byte.escape_ascii()
+                    ),
+                ));
+            }
+        });
+        pos += 2;
+    }
+    if nodes.is_empty() {
+        return Err(Error::syntax(
+            0,
+            "Empty hex pattern, expected at least one byte",
+        ));
+    }
+    Ok(Node::Concat(nodes))
+}
+
+fn hex_digit(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        _ => digit.to_ascii_lowercase() - b'a' + 10,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StreamMatcher;
+    use crate::error::SyntaxError;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn count_matches(pattern: &str, input: &[u8]) -> usize {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_callback(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        matcher.add_pattern(compile_hex(pattern).unwrap());
+        for chunk in input.chunks(3) {
+            matcher.process_chunk(chunk);
+        }
+        count.load(Ordering::Relaxed)
+    }
+
+    fn syntax_error(pattern: &str) -> SyntaxError {
+        match compile_hex(pattern) {
+            Err(Error::Syntax(error)) => error,
+            other => panic!("expected an error for {:?}, got {:?}", pattern, other),
+        }
+    }
+
+    #[test]
+    fn test_compile_hex() {
+        let header = b"\x00\x4d\x5a\x90\x00\x03\x00\xff\xff\x4d\x5a";
+        assert_eq!(count_matches("4D 5A 90 00 ?? ?? FF", header), 1);
+        assert_eq!(count_matches("4d5a", header), 2);
+        assert_eq!(count_matches(" 4D\t5A\n?? ", header), 1);
+        assert_eq!(count_matches("FF ?? 5A", header), 1);
+        assert_eq!(count_matches("00 ??", b"\x00\x00\x00"), 2);
+        assert!(compile_hex("4D 5A").unwrap().is_literal());
+    }
+
+    #[test]
+    fn test_compile_hex_errors() {
+        let cases = [
+            ("4D 5", 3, "Odd number of hex digits"),
+            ("4 D", 0, "Odd number of hex digits"),
+            ("4D 5G", 4, "Invalid character 'G'"),
+            ("4D ?A", 4, "Invalid character 'A'"),
+            ("4D, 5A", 2, "Invalid character ','"),
+            ("  ", 0, "Empty hex pattern"),
+        ];
+        for (pattern, offset, message) in cases {
+            let error = syntax_error(pattern);
+            assert_eq!(error.offset, offset, "{}", error);
+            assert!(error.message.starts_with(message), "{}", error);
+            assert_eq!(error.id, pattern);
+        }
+    }
+}
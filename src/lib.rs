@@ -5,16 +5,54 @@
 
 #![warn(missing_docs)]
 
+mod binary;
+mod byteset;
+mod combined;
+mod database;
+mod dot;
 mod error;
+mod glob;
+mod hex;
+mod literals;
 mod matcher;
+mod nfa;
+mod parser;
 mod pattern;
+mod redact;
+#[cfg(feature = "serde")]
+mod serialize;
+mod snapshot;
+mod split;
+mod starts;
+#[cfg(feature = "syntax")]
+mod syntax;
+mod teddy;
+#[cfg(feature = "unicode")]
+#[rustfmt::skip]
+mod unicode_tables;
+mod utf8;
 
 #[cfg(feature = "python")]
 pub mod ffi;
 
-pub use error::Error;
-pub use matcher::StreamMatcher;
-pub use pattern::{Pattern, PatternBuilder, compile_pattern};
+pub use database::{PatternDatabase, StreamState};
+pub use error::{Error, SyntaxError};
+pub use glob::compile_glob;
+pub use hex::compile_hex;
+pub use matcher::{
+    ComplexitySummary, GroupCounts, Match, MatchCounts, MatchEvent, MatchIter, MatcherConfig,
+    OverBudget, Stats, StreamId, StreamMatcher,
+};
+pub use pattern::{
+    CompileOptions, ComplexityReport, CostClass, DEFAULT_STATE_LIMIT, Pattern, PatternBuilder,
+    PatternKind, PruneStats, compile_pattern, compile_pattern_bytes, compile_pattern_with,
+    compile_pattern_with_limit,
+};
+pub use redact::RedactingMatcher;
+pub use split::{Record, StreamSplitter};
+
+#[cfg(feature = "syntax")]
+pub use syntax::compile_regex;
 
 /// Result type for StreamRegex operations
 pub type Result<T> = std::result::Result<T, Error>;
@@ -27,6 +65,14 @@ pub mod prelude {
     pub use crate::Result;
     pub use crate::Error;
     pub use crate::compile_pattern;
+    pub use crate::compile_pattern_bytes;
+    pub use crate::compile_pattern_with_limit;
+    pub use crate::compile_pattern_with;
+    pub use crate::CompileOptions;
+    pub use crate::compile_glob;
+    pub use crate::compile_hex;
+    #[cfg(feature = "syntax")]
+    pub use crate::compile_regex;
 }
 
 #[cfg(test)]
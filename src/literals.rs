@@ -0,0 +1,198 @@
+// One Aho-Corasick automaton for the literal patterns of a StreamMatcher, see
+// StreamMatcher::add_pattern
+//
+// A literal pattern runs as an automaton of its own like every other pattern, taking
+// a step per byte, so a list of thousands of literals takes thousands of steps per
+// byte. Here they share one trie, where a byte is mostly a single lookup: the root has
+// a transition for every byte, and the other nodes keep their few transitions sorted,
+// falling back along failure links for the rest. Lists of many literals would take too
+// much memory with a transition for every byte class at every node.
+
+use std::collections::VecDeque;
+
+use crate::pattern::{Pattern, PatternKind};
+
+pub(crate) const ROOT: usize = 0;
+const NONE: u32 = u32::MAX;
+
+// A literal pattern completing a match on reaching a node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Output {
+    // Pattern::number of the pattern, which stays valid when patterns before it are
+    // removed
+    pub(crate) number: u32,
+    // The final state of the pattern after its literal, whose tag the match reports
+    pub(crate) state: usize,
+}
+
+#[derive(Debug)]
+pub(crate) struct LiteralSet {
+    root: [u32; 256],
+    // The transitions of node n are edges[offsets[n]..offsets[n + 1]], sorted by byte
+    offsets: Vec<u32>,
+    edges: Vec<(u8, u32)>,
+    // The node for the longest proper suffix of the bytes of each node that is in the
+    // trie, as in PatternBuilder::from_literals
+    failure: Vec<u32>,
+    // The parent of each node and the byte leading from it, to spell out its bytes
+    parents: Vec<(u32, u8)>,
+    // The outputs of literals ending at node n are outputs[output_offsets[n]..][..],
+    // sorted by number
+    output_offsets: Vec<u32>,
+    outputs: Vec<Output>,
+    // The nearest node along the failure links with outputs of its own, or NONE
+    dictionary: Vec<u32>,
+}
+
+impl LiteralSet {
+    // The automaton for `literals`, as (Pattern::number, bytes, final state) sorted by
+    // number
+    pub(crate) fn new(literals: &[(u32, &[u8], usize)]) -> Self {
+        // The trie, with the outputs of the literals ending at each node
+        let mut children: Vec<Vec<(u8, u32)>> = vec![Vec::new()];
+        let mut parents = vec![(NONE, 0)];
+        let mut ends: Vec<Vec<Output>> = vec![Vec::new()];
+        for &(number, bytes, state) in literals {
+            let mut node = ROOT;
+            for &byte in bytes {
+                node = match children[node].iter().find(|&&(other, _)| other == byte) {
+                    Some(&(_, child)) => child as usize,
+                    None => {
+                        let child = children.len();
+                        children.push(Vec::new());
+                        parents.push((node as u32, byte));
+                        ends.push(Vec::new());
+                        children[node].push((byte, child as u32));
+                        child
+                    }
+                };
+            }
+            ends[node].push(Output { number, state });
+        }
+
+        let mut set = LiteralSet {
+            root: [ROOT as u32; 256],
+            offsets: Vec::with_capacity(children.len() + 1),
+            edges: Vec::new(),
+            failure: vec![ROOT as u32; children.len()],
+            parents,
+            output_offsets: Vec::with_capacity(children.len() + 1),
+            outputs: Vec::new(),
+            dictionary: vec![NONE; children.len()],
+        };
+        for (node, mut row) in children.into_iter().enumerate() {
+            row.sort_unstable();
+            if node == ROOT {
+                for &(byte, child) in &row {
+                    set.root[byte as usize] = child;
+                }
+            }
+            set.offsets.push(set.edges.len() as u32);
+            set.edges.extend(row);
+        }
+        set.offsets.push(set.edges.len() as u32);
+        for outputs in ends {
+            set.output_offsets.push(set.outputs.len() as u32);
+            set.outputs.extend(outputs);
+        }
+        set.output_offsets.push(set.outputs.len() as u32);
+
+        // Failure links in breadth-first order, from the links of shallower nodes
+        let mut queue: VecDeque<usize> = set.children(ROOT).map(|(_, child)| child).collect();
+        while let Some(node) = queue.pop_front() {
+            let children: Vec<_> = set.children(node).collect();
+            for (byte, child) in children {
+                set.failure[child] = set.next_node(set.failure[node] as usize, byte) as u32;
+                queue.push_back(child);
+            }
+            let failure = set.failure[node] as usize;
+            set.dictionary[node] = match set.own_outputs(failure).is_empty() {
+                true => set.dictionary[failure],
+                false => failure as u32,
+            };
+        }
+        set
+    }
+
+    fn children(&self, node: usize) -> impl Iterator<Item = (u8, usize)> + '_ {
+        let edges = &self.edges[self.offsets[node] as usize..self.offsets[node + 1] as usize];
+        edges.iter().map(|&(byte, child)| (byte, child as usize))
+    }
+
+    fn own_outputs(&self, node: usize) -> &[Output] {
+        &self.outputs[self.output_offsets[node] as usize..self.output_offsets[node + 1] as usize]
+    }
+
+    pub(crate) fn next_node(&self, mut node: usize, byte: u8) -> usize {
+        loop {
+            if node == ROOT {
+                return self.root[byte as usize] as usize;
+            }
+            let edges = &self.edges[self.offsets[node] as usize..self.offsets[node + 1] as usize];
+            if let Ok(found) = edges.binary_search_by_key(&byte, |&(other, _)| other) {
+                return edges[found].1 as usize;
+            }
+            node = self.failure[node] as usize;
+        }
+    }
+
+    // Replace `found` with the outputs of the literals ending at `node`, sorted by number
+    pub(crate) fn outputs(&self, node: usize, found: &mut Vec<Output>) {
+        found.clear();
+        found.extend_from_slice(self.own_outputs(node));
+        let mut suffix = self.dictionary[node];
+        if suffix == NONE {
+            return;
+        }
+        while suffix != NONE {
+            found.extend_from_slice(self.own_outputs(suffix as usize));
+            suffix = self.dictionary[suffix as usize];
+        }
+        // Literals ending at different nodes interleave by number
+        found.sort_unstable_by_key(|output| output.number);
+    }
+
+    // The bytes leading from the root to `node`
+    pub(crate) fn bytes(&self, mut node: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while node != ROOT {
+            let (parent, byte) = self.parents[node];
+            bytes.push(byte);
+            node = parent as usize;
+        }
+        bytes.reverse();
+        bytes
+    }
+
+    // Approximate bytes used by the tables
+    pub(crate) fn memory_usage(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + (self.offsets.len() + self.output_offsets.len()) * std::mem::size_of::<u32>()
+            + self.edges.len() * std::mem::size_of::<(u8, u32)>()
+            + (self.failure.len() + self.dictionary.len()) * std::mem::size_of::<u32>()
+            + self.parents.len() * std::mem::size_of::<(u32, u8)>()
+            + self.outputs.len() * std::mem::size_of::<Output>()
+    }
+}
+
+// The literal of a pattern that can run in a LiteralSet, and the final state it leads
+// to: a deterministic, unanchored pattern flagged as literal that runs into a final
+// state at the end of its literal
+pub(crate) fn routable(pattern: &Pattern) -> Option<(&[u8], usize)> {
+    let PatternKind::Literal(literal) = &pattern.kind else {
+        return None;
+    };
+    if pattern.anchored || pattern.is_nondeterministic() {
+        return None;
+    }
+    let state = run(pattern, pattern.initial_state, literal);
+    pattern.states[state].is_final.then_some((literal, state))
+}
+
+// The state `pattern` is in after `bytes` from `state`, as StreamMatcher steps an
+// unanchored pattern
+pub(crate) fn run(pattern: &Pattern, state: usize, bytes: &[u8]) -> usize {
+    bytes.iter().fold(state, |state, &byte| {
+        pattern.search_next_state(state, byte).unwrap_or(pattern.initial_state)
+    })
+}
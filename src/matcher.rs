@@ -1,76 +1,2822 @@
-use std::collections::HashMap;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{ErrorKind, Read};
+use std::ops::{ControlFlow, Range};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
-// Core types for pattern matching
-#[derive(Debug, Clone)]
-pub struct Pattern {
-    pub(crate) id: String,
-    pub(crate) states: Vec<State>,
-    pub(crate) initial_state: usize,
+use crate::Result;
+use crate::combined::CombinedAutomaton;
+use crate::database::{DEAD, PatternDatabase, StreamState};
+use crate::error::Error;
+use crate::literals::{self, LiteralSet};
+use crate::pattern::{CostClass, Pattern};
+use crate::snapshot;
+
+// Pattern::complexity summed up over the patterns of a StreamMatcher, to check a
+// deployment against a budget
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ComplexitySummary {
+    pub pattern_count: usize,
+    // States of all patterns together
+    pub state_count: usize,
+    pub max_out_degree: usize,
+    // Patterns in each CostClass
+    pub literal_patterns: usize,
+    pub bounded_patterns: usize,
+    pub unbounded_patterns: usize,
+    // The most expensive class of any pattern, None without patterns
+    pub worst_class: Option<CostClass>,
+}
+
+// What a StreamMatcher did since it was created, over all its streams, see
+// StreamMatcher::stats. Neither reset nor finish clears these
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub bytes_processed: u64,
+    // Calls scanning bytes: process_byte, process_chunk and the like
+    pub chunks_processed: u64,
+    pub matches_reported: u64,
+    // Matches the caps, the rate limit or the priority window of the MatcherConfig dropped
+    pub matches_suppressed: u64,
+    // Spent in those calls, only measured with MatcherConfig::timing
+    pub processing_time: Duration,
+    // Patterns partway through a match right now, in all open streams together, see
+    // PatternDatabase::active_patterns
+    pub active_patterns: usize,
+}
+
+const FIND_BUFFER_SIZE: usize = 64 * 1024; // Bytes find_first reads at a time
+const BUDGET_CHECK_SIZE: usize = 1024; // Bytes scanned between checks of the chunk budget
+
+// What process_chunk does with the rest of a chunk it ran out of time for, see
+// MatcherConfig::chunk_budget
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverBudget {
+    // Leave the rest unscanned and carry on after it (fail open)
+    Skip,
+    // Stop there, with the stream left ready to resume with the rest (fail closed)
+    Fail,
 }
 
-#[derive(Debug, Clone)]
-pub(crate) struct State {
-    transitions: HashMap<u8, usize>,
-    is_final: bool,
+// A stream running through a StreamMatcher next to its own, see open_stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StreamId(u64);
+
+impl StreamId {
+    // The stream of process_chunk, which is always open and ends with finish
+    pub const MAIN: StreamId = StreamId(0);
+}
+
+// A match as delivered to the callbacks of add_match_callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatchEvent<'a> {
+    // The number add_pattern returned for the pattern, cheaper to compare or look up
+    // than its name
+    pub pattern: u32,
+    // The string id of the pattern, as add_callback callbacks receive it
+    pub name: &'a str,
+    // Tag of the final state the match ended in (see PatternBuilder::add_state_tagged)
+    pub tag: Option<u32>,
+    // The metadata of the pattern, see Pattern::metadata
+    pub metadata: &'a BTreeMap<String, String>,
+    // The group of the pattern, see Pattern::group
+    pub group: Option<&'a str>,
+    // Offset in the stream just past the last byte of the match, counted from the start
+    // of the stream across process_chunk calls. A match confirmed by the byte after it
+    // (a trailing '\b', or '$' before a newline) still ends before that byte
+    pub end: u64,
+    // Offset of the first byte of the match, for patterns whose matches all have the
+    // same length. The matcher keeps no history, so for variable-length patterns this
+    // is None: buffer the stream and run Pattern::reverse backwards from `end` to find
+    // where the match starts
+    pub start: Option<u64>,
+    // The stream the match is in: StreamId::MAIN, unless it was scanned with
+    // process_chunk_for
+    pub stream: StreamId,
+    // Not a match but its absence: finish reports one for each negative pattern (see
+    // StreamMatcher::add_negative_pattern) that did not match in the stream, ending
+    // at the end of the stream
+    pub absent: bool,
+    // The matches this event stands for: more than 1 when later matches coalesced into
+    // it, see MatcherConfig::coalesce_within
+    pub count: u64,
+    // The bytes of the stream around the match, see MatcherConfig::context_bytes, and
+    // empty without it
+    pub context: &'a [u8],
+}
+
+impl<'a> MatchEvent<'a> {
+    pub(crate) fn new(pattern: &'a Pattern, tag: Option<u32>, end: u64) -> Self {
+        MatchEvent {
+            pattern: pattern.number.unwrap_or_default(),
+            name: &pattern.id,
+            tag,
+            metadata: &pattern.metadata,
+            group: pattern.group(),
+            end,
+            start: match_start(pattern, end),
+            stream: StreamId::MAIN,
+            absent: false,
+            count: 1,
+            context: &[],
+        }
+    }
+
+    // The event of `found`, a match of `pattern`
+    pub(crate) fn from_match(pattern: &'a Pattern, found: &Match) -> Self {
+        MatchEvent {
+            start: found.start,
+            absent: found.absent,
+            count: found.count,
+            ..MatchEvent::new(pattern, found.tag, found.end)
+        }
+    }
+
+    // The absence of a negative pattern from a stream ending at `end`
+    pub(crate) fn absent(pattern: &'a Pattern, end: u64) -> Self {
+        MatchEvent {
+            start: None,
+            absent: true,
+            ..MatchEvent::new(pattern, None, end)
+        }
+    }
+
+    // The parts of the event that do not borrow from the matcher
+    pub fn to_match(&self) -> Match {
+        Match {
+            pattern: self.pattern,
+            tag: self.tag,
+            end: self.end,
+            start: self.start,
+            absent: self.absent,
+            count: self.count,
+        }
+    }
+}
+
+// A match as process_chunk_collect returns it: a MatchEvent without the name and
+// metadata, so it does not hold on to the matcher. See pattern_name for the name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Match {
+    pub pattern: u32,
+    pub tag: Option<u32>,
+    pub end: u64,
+    pub start: Option<u64>,
+    pub absent: bool,
+    pub count: u64,
+}
+
+// How many matches of a pattern a stream had, see StreamMatcher::match_counts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MatchCounts {
+    pub pattern: u32,
+    // Matches reported, or for a negative pattern found
+    pub reported: u64,
+    // Matches the rate limit or the priority window of the MatcherConfig dropped
+    pub suppressed: u64,
+}
+
+// How many matches the patterns of a group had together in a stream, see
+// StreamMatcher::match_counts_by_group
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct GroupCounts {
+    pub group: String,
+    pub reported: u64,
+    pub suppressed: u64,
+}
+
+// Settings of a StreamMatcher, see StreamMatcher::with_config
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MatcherConfig {
+    // Matches reported for each pattern per stream, after which its matches are dropped
+    pub max_matches_per_pattern: Option<u64>,
+    // Matches reported for all patterns together per stream
+    pub max_matches_total: Option<u64>,
+    // States StreamMatcher::compile may take, DEFAULT_COMBINED_STATE_LIMIT when None
+    pub combined_state_limit: Option<usize>,
+    // Match every pattern from the start of the stream alone, see anchored
+    pub anchored: bool,
+    // Fold the stream to lowercase for the patterns, see ascii_case_insensitive
+    pub ascii_case_insensitive: bool,
+    // Bytes within which later matches of a pattern coalesce, see coalesce_within
+    pub coalesce_within: Option<u64>,
+    // Matches reported for each pattern per window of bytes, see rate_limit
+    pub rate_limit: Option<(u64, u64)>,
+    // Bytes matches are held back for higher priorities to suppress, see priority_window
+    pub priority_window: Option<u64>,
+    // Bytes before and after each match to report with it, see context_bytes
+    pub context_bytes: Option<(usize, usize)>,
+    // Measure the time spent scanning, see timing
+    pub timing: bool,
+    // Time a chunk may take to scan and what to do past it, see chunk_budget
+    pub chunk_budget: Option<(Duration, OverBudget)>,
+}
+
+impl MatcherConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn max_matches_per_pattern(mut self, max: u64) -> Self {
+        self.max_matches_per_pattern = Some(max);
+        self
+    }
+
+    pub fn max_matches_total(mut self, max: u64) -> Self {
+        self.max_matches_total = Some(max);
+        self
+    }
+
+    pub fn combined_state_limit(mut self, limit: usize) -> Self {
+        self.combined_state_limit = Some(limit);
+        self
+    }
+
+    // Run every pattern anchored, as if compiled with '^': it only matches from the
+    // start of the stream, and the first byte it has no transition for ends it. Once
+    // all have ended, scanning the rest of the stream is a no-op that leaves the data
+    // alone, as for protocol fingerprints that are decided within the first bytes.
+    // Patterns are compiled again from their syntax tree for it, so none matches
+    // after the start. Those assembled with a PatternBuilder run as built, dead after
+    // the first byte they have no transition for
+    pub fn anchored(mut self, enabled: bool) -> Self {
+        self.anchored = enabled;
+        self
+    }
+
+    // Match ASCII letters regardless of case, without recompiling the patterns: every
+    // byte of the stream goes to the patterns folded to lowercase, so patterns are to
+    // be compiled in lowercase for this to be meaningful ("select" then finds "SeLeCt",
+    // while "SELECT" finds nothing). Other bytes, including non-ASCII ones, are left
+    // as they are. Match offsets are those of the stream. The literal prefilter is not
+    // used, as it compares the bytes as they are
+    pub fn ascii_case_insensitive(mut self, enabled: bool) -> Self {
+        self.ascii_case_insensitive = enabled;
+        self
+    }
+
+    // Report the matches of each pattern ending fewer than `bytes` bytes after the
+    // first one as that one event, with MatchEvent::count telling how many there were,
+    // as for "\d{4}" matching at every byte of a long run of digits. For patterns
+    // whose matches all have the same length, that is their starts being that close.
+    // The window is counted from the first match alone, so a run longer than it comes
+    // as one event per window, and it spans process_chunk calls. An event is held back
+    // until no later match can join it, and reported by the process_chunk call scanning
+    // past its window, or by finish. The caps count the events
+    pub fn coalesce_within(mut self, bytes: u64) -> Self {
+        self.coalesce_within = Some(bytes);
+        self
+    }
+
+    // Report at most `matches` matches of each pattern per `bytes` bytes of the stream,
+    // dropping the rest, so a noisy pattern does not flood the callbacks. The windows
+    // are counted in stream offsets (the first is 0 to bytes - 1, and a match belongs
+    // to the one it ends in) rather than in time, and each stream has its own. See
+    // StreamMatcher::match_counts for how many were dropped. Coalesced matches (see
+    // coalesce_within) are limited as the one event they are reported as
+    pub fn rate_limit(mut self, matches: u64, bytes: u64) -> Self {
+        self.rate_limit = Some((matches, bytes));
+        self
+    }
+
+    // Drop each match that overlaps a match of a pattern with a higher priority (see
+    // StreamMatcher::add_pattern_with_priority) ending fewer than `bytes` bytes before
+    // or after it, as when a generic rule and a specific one find the same request.
+    // Patterns of variable length are taken to start `bytes` bytes before their end,
+    // as where they start is unknown (see MatchEvent::start). For this, every match is
+    // held back until the stream is `bytes` bytes past its end: it comes from the
+    // process_chunk call scanning that far, or from finish, instead of right away.
+    // 0 turns this off, with matches reported as they are found. The dropped matches
+    // count as suppressed in StreamMatcher::match_counts, and not towards the caps
+    pub fn priority_window(mut self, bytes: u64) -> Self {
+        self.priority_window = Some(bytes);
+        self
+    }
+
+    // Report the `before` bytes of the stream before the end of each match and the
+    // `after` bytes following it with the match, as MatchEvent::context, fewer where
+    // the stream starts or ends first. For the bytes after, every match is held back
+    // until they streamed through, and reported right after the last of them (where a
+    // callback can stop the scan), or by finish. Every stream keeps the bytes any match
+    // to come may need in a ring, allocated up front: `before` and `after` together,
+    // plus the windows of coalesce_within and priority_window, which hold matches back
+    // as well
+    pub fn context_bytes(mut self, before: usize, after: usize) -> Self {
+        self.context_bytes = Some((before, after));
+        self
+    }
+
+    // Add up the time spent scanning chunks as Stats::processing_time. Off by default,
+    // as it reads the clock twice per call
+    pub fn timing(mut self, enabled: bool) -> Self {
+        self.timing = enabled;
+        self
+    }
+
+    // Spend at most about `budget` scanning each chunk passed to process_chunk or
+    // process_chunk_for, so a pathological mix of patterns and data cannot stall the
+    // caller. The clock is checked every kilobyte, so a chunk may take that long past
+    // the budget. What is left of the chunk then is skipped, with every pattern starting
+    // over after it (see PatternDatabase::skip), or fails the call with BudgetExceeded,
+    // depending on `over`. Either way StreamMatcher::unscanned tells which bytes of the
+    // stream were not inspected
+    pub fn chunk_budget(mut self, budget: Duration, over: OverBudget) -> Self {
+        self.chunk_budget = Some((budget, over));
+        self
+    }
+
+    // The bytes a stream keeps for context_bytes
+    pub(crate) fn context_capacity(&self) -> usize {
+        let Some((before, after)) = self.context_bytes else {
+            return 0;
+        };
+        let delays = [self.coalesce_within, self.priority_window];
+        let delay = delays.iter().flatten().fold(0u64, |total, &bytes| total.saturating_add(bytes));
+        before.saturating_add(after).saturating_add(usize::try_from(delay).unwrap_or(usize::MAX))
+    }
 }
 
+// Callbacks may keep state and borrow from the caller for 'a. Break stops the scan,
+// see add_control_flow_callback
+type MatchCallback<'a> = Box<dyn FnMut(&MatchEvent) -> ControlFlow<()> + Send + 'a>;
+
 // StreamMatcher is the main interface for pattern matching
-pub struct StreamMatcher {
-    patterns: Vec<Pattern>,
-    current_states: Vec<usize>,
+// It runs its own stream through a PatternDatabase of its own, which it changes as
+// patterns are added and removed, and any streams opened with open_stream
+pub struct StreamMatcher<'a> {
+    database: PatternDatabase,
+    stream: StreamState,
+    streams: HashMap<StreamId, StreamState>,
+    next_stream: u64,
+    // Whether compile was called, so the automaton is rebuilt after changes
+    combine: bool,
+    // Set when the automaton of the literals is to be rebuilt, as patterns that could
+    // run in it were added or it still runs removed ones
+    reroute: bool,
     memory_usage: Arc<AtomicUsize>,
-    callbacks: Vec<Box<dyn Fn(&str) + Send + Sync>>,
+    // Every kind of callback, in the order they were added. They are only called
+    // through &mut self, but the Mutex keeps the matcher Sync for sharing it read-only
+    callbacks: Vec<Mutex<MatchCallback<'a>>>,
+    next_pattern: u32,
+    // The counts of Stats the matcher keeps itself, with the matches of closed streams.
+    // The streams count the rest
+    stats: Stats,
+    // The stream offsets of the last chunk the budget left unscanned, see unscanned
+    unscanned: Option<Range<u64>>,
 }
 
-impl StreamMatcher {
+impl<'a> StreamMatcher<'a> {
     pub fn new() -> Self {
+        Self::with_config(MatcherConfig::default())
+    }
+
+    pub fn with_config(config: MatcherConfig) -> Self {
+        let database = PatternDatabase::new(Vec::new(), config);
         StreamMatcher {
-            patterns: Vec::new(),
-            current_states: Vec::new(),
+            stream: StreamState::new(&database),
+            streams: HashMap::new(),
+            next_stream: 1,
+            database,
+            combine: false,
+            reroute: false,
             memory_usage: Arc::new(AtomicUsize::new(0)),
             callbacks: Vec::new(),
+            next_pattern: 0,
+            stats: Stats::default(),
+            unscanned: None,
         }
     }
 
-    pub fn add_pattern(&mut self, pattern: Pattern) {
-        self.current_states.push(pattern.initial_state);
-        self.patterns.push(pattern);
+    pub fn config(&self) -> MatcherConfig {
+        self.database.config
+    }
+
+    // Whether the caps of the MatcherConfig dropped any match since the stream started,
+    // so this is to be checked before finish
+    pub fn truncated(&self) -> bool {
+        self.stream.truncated()
+    }
+
+    // The matches of each pattern in the matcher's own stream since it started, in the
+    // order they were added
+    pub fn match_counts(&self) -> Vec<MatchCounts> {
+        self.database.match_counts(&self.stream)
     }
 
-    pub fn add_callback<F>(&mut self, callback: F)
+    // The same reported counts by the number add_pattern returned for each pattern, in a
+    // map kept up to date as matches are found. Like match_counts they start over with
+    // finish and reset
+    pub fn match_counts_by_number(&self) -> &HashMap<u32, u64> {
+        self.stream.match_counts_by_number()
+    }
+
+    // The match counts of the matcher's own stream summed over the patterns of each
+    // group, in the order the groups first appear in. Patterns without a group are
+    // left out
+    pub fn match_counts_by_group(&self) -> Vec<GroupCounts> {
+        let mut groups: Vec<GroupCounts> = Vec::new();
+        let counts = self.database.patterns.iter().zip(self.match_counts());
+        for (pattern, counts) in counts {
+            let Some(group) = pattern.group() else {
+                continue;
+            };
+            let index = match groups.iter().position(|other| other.group == group) {
+                Some(index) => index,
+                None => {
+                    let group = group.to_string();
+                    groups.push(GroupCounts { group, reported: 0, suppressed: 0 });
+                    groups.len() - 1
+                }
+            };
+            groups[index].reported += counts.reported;
+            groups[index].suppressed += counts.suppressed;
+        }
+        groups
+    }
+
+    // The matches reported in the matcher's own stream since it started, of all
+    // patterns together, whether to callbacks or not
+    pub fn total_matches(&self) -> u64 {
+        self.stream.total_matches()
+    }
+
+    // Returns the number matches of this pattern are reported with to
+    // add_match_callback callbacks, see pattern_name
+    // Literal patterns (see Pattern::is_literal) that are not anchored all run in one
+    // Aho-Corasick automaton instead of one by one, which reports the same matches.
+    // One added in the middle of a stream joins it once no literal is partly matched,
+    // at the latest when the next stream starts.
+    // In the middle of a stream, be it the matcher's own or an open one, the pattern
+    // starts at its initial state where the stream is, as if the stream started there
+    // for it, while the other patterns carry on with the matches they are partway
+    // through. One anchored with '^' can no longer match from the start, so it stays
+    // dead until the next stream. After compile, the automaton is dropped and rebuilt
+    // from where every pattern is before the next byte is scanned, so adding several
+    // patterns at once rebuilds it once.
+    pub fn add_pattern(&mut self, mut pattern: Pattern) -> u32 {
+        if self.database.config.anchored {
+            pattern = pattern.into_anchored();
+        }
+        let number = self.next_pattern;
+        self.next_pattern += 1;
+        pattern.number = Some(number);
+        self.uncombine();
+        self.memory_usage.fetch_add(pattern.memory_usage(), Ordering::Relaxed);
+        let set = match pattern.is_nondeterministic() {
+            true => vec![pattern.initial_state],
+            false => Vec::new(),
+        };
+        for stream in all_streams(&mut self.stream, &mut self.streams) {
+            let started = pattern.anchored && stream.position > 0;
+            stream.progress.states.push(if started { DEAD } else { pattern.initial_state });
+            stream.progress.sets.push(set.clone());
+            stream.progress.idle = false;
+            stream.caps.push(&pattern);
+        }
+        self.reroute |= literals::routable(&pattern).is_some();
+        self.database.push(pattern);
+        number
+    }
+
+    // Like add_pattern, with a priority for MatcherConfig::priority_window to drop the
+    // overlapping matches of lower ones by. Other patterns have priority 0
+    pub fn add_pattern_with_priority(&mut self, mut pattern: Pattern, priority: u32) -> u32 {
+        pattern.priority = priority;
+        self.add_pattern(pattern)
+    }
+
+    // Like add_pattern, with the pattern in `group` (see Pattern::group) to enable,
+    // disable, remove or count its matches together with the others in it
+    pub fn add_pattern_in_group(&mut self, pattern: Pattern, group: &str) -> u32 {
+        self.add_pattern(pattern.with_metadata("group", group))
+    }
+
+    // Like add_pattern, for a rule that fires when the pattern is never seen: its
+    // matches are not reported, and instead finish reports its absence, as a
+    // MatchEvent with `absent` set, for each stream it did not match in
+    pub fn add_negative_pattern(&mut self, mut pattern: Pattern) -> u32 {
+        pattern.negative = true;
+        self.add_pattern(pattern)
+    }
+
+    // Removes the first pattern added with this id, and returns whether there was one.
+    // The other patterns keep their numbers and their progress in the stream
+    pub fn remove_pattern(&mut self, id: &str) -> bool {
+        let patterns = &self.database.patterns;
+        let Some(index) = patterns.iter().position(|pattern| pattern.id == id) else {
+            return false;
+        };
+        self.remove_at(index);
+        true
+    }
+
+    // Removes every pattern of `group`, see remove_pattern, and returns how many
+    pub fn remove_group(&mut self, group: &str) -> usize {
+        let indexes = self.group_indexes(group);
+        for &index in indexes.iter().rev() {
+            self.remove_at(index);
+        }
+        indexes.len()
+    }
+
+    fn remove_at(&mut self, index: usize) {
+        // The progress and the counts are indexed like patterns, so all lose the same
+        // entry
+        self.uncombine();
+        self.reroute |= self.database.routed[index];
+        let pattern = self.database.remove(index);
+        for stream in all_streams(&mut self.stream, &mut self.streams) {
+            stream.progress.states.remove(index);
+            stream.progress.sets.remove(index);
+            stream.caps.remove(index, &pattern);
+        }
+        self.memory_usage.fetch_sub(pattern.memory_usage(), Ordering::Relaxed);
+    }
+
+    // Stops or resumes running the first pattern added with this id, and returns whether
+    // there was one. A disabled pattern costs nothing per byte and reports nothing, not
+    // even its absence at finish, and loses its progress in every stream: enabled
+    // again, it starts over from the next byte, where an anchored one has no match
+    // left unless no stream has started yet. Compiled patterns are combined again
+    // without it before the next byte
+    pub fn set_enabled(&mut self, id: &str, enabled: bool) -> bool {
+        let patterns = &self.database.patterns;
+        let Some(index) = patterns.iter().position(|pattern| pattern.id == id) else {
+            return false;
+        };
+        self.set_enabled_at(index, enabled);
+        true
+    }
+
+    // Enables every pattern of `group`, see set_enabled, and returns how many it has
+    pub fn enable_group(&mut self, group: &str) -> usize {
+        let indexes = self.group_indexes(group);
+        for &index in &indexes {
+            self.set_enabled_at(index, true);
+        }
+        indexes.len()
+    }
+
+    // Disables every pattern of `group`, see set_enabled, and returns how many it has
+    pub fn disable_group(&mut self, group: &str) -> usize {
+        let indexes = self.group_indexes(group);
+        for &index in &indexes {
+            self.set_enabled_at(index, false);
+        }
+        indexes.len()
+    }
+
+    // The indexes of the patterns in `group`, in increasing order
+    fn group_indexes(&self, group: &str) -> Vec<usize> {
+        let patterns = self.database.patterns.iter();
+        let indexes = patterns.enumerate().filter(|(_, pattern)| pattern.group() == Some(group));
+        indexes.map(|(index, _)| index).collect()
+    }
+
+    fn set_enabled_at(&mut self, index: usize, enabled: bool) {
+        if self.database.enabled[index] == enabled {
+            return;
+        }
+        self.uncombine();
+        self.unroute();
+        self.database.set_enabled(index, enabled);
+        let pattern = &self.database.patterns[index];
+        for stream in all_streams(&mut self.stream, &mut self.streams) {
+            let fresh = enabled && !(pattern.anchored && stream.position > 0);
+            let progress = &mut stream.progress;
+            progress.states[index] = if fresh { pattern.initial_state } else { DEAD };
+            if pattern.is_nondeterministic() {
+                progress.sets[index] = vec![pattern.initial_state];
+            }
+            progress.idle = false;
+        }
+    }
+
+    // Whether the first pattern added with this id runs, see set_enabled. None when
+    // there is none
+    pub fn is_enabled(&self, id: &str) -> Option<bool> {
+        let index = self.database.patterns.iter().position(|pattern| pattern.id == id)?;
+        Some(self.database.enabled[index])
+    }
+
+    // The bytes the matcher looks for with memchr to skip the bytes before them, while
+    // every pattern is dead or unanchored at its initial state. None when the patterns
+    // can start with too many bytes to skip any. Only the bytes unanchored patterns
+    // start with count: an anchored pattern is past its start after the first byte
+    pub fn prefilter(&self) -> Option<&[u8]> {
+        self.database.prefilter()
+    }
+
+    // The instructions the literal prefilter runs on: "avx2", "ssse3" or "scalar", as
+    // the simd feature and the CPU allow. It takes over from prefilter when the
+    // patterns start with more bytes, but every unanchored one is a literal (see
+    // Pattern::is_literal) and there are at most 64 of them. It then compares windows
+    // of up to three bytes with the starts of all literals at once, and the patterns
+    // only step through the bytes from where a window may start one
+    pub fn literal_prefilter(&self) -> Option<&str> {
+        self.database.literal_prefilter()
+    }
+
+    // Combine all patterns into one automaton, so that a byte takes one table lookup
+    // however many patterns there are instead of a step for each. Matches are reported
+    // exactly as without it. Works for deterministic patterns only, and fails with
+    // PatternTooComplex when the combination takes more states than
+    // MatcherConfig::combined_state_limit; the patterns then keep running one by one.
+    // Adding or removing a pattern afterwards drops the automaton, and it is rebuilt
+    // with the next byte processed. Should that fail, the matcher logs a warning and
+    // goes back to running the patterns one by one until compile is called again.
+    // Can be called in the middle of a stream, which carries on where it was.
+    pub fn compile(&mut self) -> Result<()> {
+        self.uncombine();
+        self.unroute();
+        self.combine = false;
+        let streams = std::iter::once(&self.stream).chain(self.streams.values());
+        let states: Vec<&[usize]> = streams.map(|stream| &stream.progress.states[..]).collect();
+        let combined = self.database.combine(&states)?;
+        // The map is unchanged, so it lists the streams in the same order again
+        for (stream, state) in all_streams(&mut self.stream, &mut self.streams).zip(combined) {
+            stream.progress.combined = state;
+        }
+        let combined = self.database.combined.as_ref().map_or(0, CombinedAutomaton::memory_usage);
+        self.memory_usage.fetch_add(combined, Ordering::Relaxed);
+        self.combine = true;
+        Ok(())
+    }
+
+    // States of the automaton of compile, or None while the patterns run one by one
+    pub fn combined_state_count(&self) -> Option<usize> {
+        self.database.combined_state_count()
+    }
+
+    // Rebuild the automaton of compile after patterns were added or removed
+    fn recombine(&mut self) {
+        if !self.combine || self.database.combined.is_some() {
+            return;
+        }
+        if let Err(error) = self.compile() {
+            tracing::warn!("Running the patterns one by one, as combining them failed: {}", error);
+        }
+    }
+
+    // Drop the automaton of compile, if there is one, and go back to running the
+    // patterns one by one from where they are
+    fn uncombine(&mut self) {
+        for stream in all_streams(&mut self.stream, &mut self.streams) {
+            self.database.write_states(&mut stream.progress);
+        }
+        if let Some(combined) = self.database.combined.take() {
+            self.memory_usage.fetch_sub(combined.memory_usage(), Ordering::Relaxed);
+        }
+    }
+
+    // Run the literal patterns in one automaton, if it is to be rebuilt and that keeps
+    // the matches as they are: where the old one is at its root and the literal
+    // patterns left out of it are at their initial state, which they cannot all be
+    // with a literal partly matched. That goes for every stream
+    fn route_literals(&mut self) {
+        let database = &self.database;
+        if !self.reroute || database.combined.is_some() {
+            return;
+        }
+        let waiting = |stream: &StreamState| {
+            let progress = &stream.progress;
+            if database.literals.is_some() && progress.node != literals::ROOT {
+                return true;
+            }
+            let running = database.patterns.iter().zip(&progress.states).zip(&database.routed);
+            let running = running.zip(&database.enabled);
+            running.filter(|&((_, &routed), &enabled)| !routed && enabled).any(
+                |(((pattern, &state), _), _)| {
+                    state != pattern.initial_state && literals::routable(pattern).is_some()
+                },
+            )
+        };
+        if std::iter::once(&self.stream).chain(self.streams.values()).any(waiting) {
+            return;
+        }
+        let before = database.literals.as_ref().map_or(0, LiteralSet::memory_usage);
+        self.memory_usage.fetch_sub(before, Ordering::Relaxed);
+        self.database.route_literals();
+        let after = self.database.literals.as_ref().map_or(0, LiteralSet::memory_usage);
+        self.memory_usage.fetch_add(after, Ordering::Relaxed);
+        for stream in all_streams(&mut self.stream, &mut self.streams) {
+            stream.progress.node = literals::ROOT;
+        }
+        self.reroute = false;
+    }
+
+    // Drop the automaton of the literal patterns, if there is one, and go back to
+    // running them one by one from where they are
+    fn unroute(&mut self) {
+        let Some(literals) = self.database.literals.take() else {
+            return;
+        };
+        // Each literal is as far as the longest end of the bytes of the node that it
+        // starts with
+        for stream in all_streams(&mut self.stream, &mut self.streams) {
+            let progress = &mut stream.progress;
+            let bytes = literals.bytes(progress.node);
+            let running = self.database.patterns.iter().zip(&mut progress.states);
+            for ((pattern, state), &routed) in running.zip(&self.database.routed) {
+                if routed {
+                    *state = literals::run(pattern, pattern.initial_state, &bytes);
+                }
+            }
+            progress.node = literals::ROOT;
+        }
+        self.database.routed.fill(false);
+        self.database.update_unrouted();
+        self.reroute = true;
+        self.memory_usage.fetch_sub(literals.memory_usage(), Ordering::Relaxed);
+    }
+
+    // The string id of the pattern add_pattern returned `number` for
+    pub fn pattern_name(&self, number: u32) -> Option<&str> {
+        self.database.pattern_name(number)
+    }
+
+    // Callbacks can count or collect into variables they borrow, which the borrow
+    // checker hands back once the matcher is dropped
+    pub fn add_callback<F>(&mut self, mut callback: F)
     where
-        F: Fn(&str) + Send + Sync + 'static,
+        F: FnMut(&str) + Send + 'a,
     {
-        self.callbacks.push(Box::new(callback));
+        self.add_match_callback(move |event| callback(event.name));
     }
 
-    pub fn process_byte(&mut self, byte: u8) {
-        for (pattern_idx, current_state) in self.current_states.iter_mut().enumerate() {
-            let pattern = &self.patterns[pattern_idx];
+    // Like add_callback, but also receives the tag of the final state the match ended
+    // in (see PatternBuilder::add_state_tagged), or None for untagged states
+    pub fn add_tagged_callback<F>(&mut self, mut callback: F)
+    where
+        F: FnMut(&str, Option<u32>) + Send + 'a,
+    {
+        self.add_match_callback(move |event| callback(event.name, event.tag));
+    }
 
-            if let Some(next_state) = pattern.states[*current_state].transitions.get(&byte) {
-                *current_state = *next_state;
+    // Called with every match as a MatchEvent, which identifies the pattern by number
+    pub fn add_match_callback<F>(&mut self, mut callback: F)
+    where
+        F: FnMut(&MatchEvent) + Send + 'a,
+    {
+        self.add_control_flow_callback(move |event| {
+            callback(event);
+            ControlFlow::Continue(())
+        });
+    }
+
+    // Like add_match_callback, but returning Break stops process_chunk after the byte
+    // the match ended on. Every match ending on that byte is still reported, and the
+    // matcher can resume with the rest of the chunk, see process_chunk
+    pub fn add_control_flow_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(&MatchEvent) -> ControlFlow<()> + Send + 'a,
+    {
+        self.callbacks.push(Mutex::new(Box::new(callback)));
+    }
+
+    // Break when a callback asked to stop, see add_control_flow_callback
+    pub fn process_byte(&mut self, byte: u8) -> ControlFlow<()> {
+        let started = self.start_timer();
+        self.recombine();
+        self.route_literals();
+        let callbacks = &mut self.callbacks;
+        let report = |event: &MatchEvent| report(callbacks, event);
+        let flow = self.database.scan_with(&mut self.stream, &[byte], report).map_break(|_| ());
+        self.count_chunk(1, started);
+        flow
+    }
+
+    // Returns how many bytes were consumed: all of `data`, unless a callback asked to
+    // stop (see add_control_flow_callback). Scanning then stopped right after the byte
+    // that completed the match, and the matcher is left ready to resume: passing
+    // `&data[consumed..]` next carries on as if it had never stopped, while reset
+    // abandons the stream
+    // With MatcherConfig::chunk_budget set to fail, it is also how many bytes were
+    // scanned in time, as this cannot fail with BudgetExceeded
+    pub fn process_chunk(&mut self, data: &[u8]) -> usize {
+        match self.scan_stream(StreamId::MAIN, data) {
+            Ok(consumed) => consumed,
+            Err(Error::BudgetExceeded { scanned, .. }) => scanned,
+            // The stream of the matcher itself is never closed
+            Err(_) => 0,
+        }
+    }
+
+    // The stream offsets of what the last process_chunk or process_chunk_for call left
+    // unscanned as it ran out of time, see MatcherConfig::chunk_budget. None when it
+    // scanned all it was to
+    pub fn unscanned(&self) -> Option<Range<u64>> {
+        self.unscanned.clone()
+    }
+
+    // Open a stream that runs through the patterns and callbacks of the matcher next
+    // to its own, starting at offset 0. The matches in it carry the returned id, and
+    // it keeps its own progress, position and caps until close_stream. Patterns added,
+    // removed or compiled later apply to it as to the matcher's own stream
+    pub fn open_stream(&mut self) -> StreamId {
+        let id = StreamId(self.next_stream);
+        self.next_stream += 1;
+        self.streams.insert(id, StreamState::new(&self.database));
+        id
+    }
 
-                if pattern.states[*current_state].is_final {
-                    for callback in &self.callbacks {
-                        callback(&pattern.id);
-                    }
+    // Like process_chunk, for a stream open_stream returned. Fails with UnknownStream
+    // for one that is closed, and with BudgetExceeded as MatcherConfig::chunk_budget says
+    pub fn process_chunk_for(&mut self, stream: StreamId, data: &[u8]) -> Result<usize> {
+        self.scan_stream(stream, data)
+    }
+
+    // End a stream open_stream returned, like finish ends the matcher's own: report the
+    // matches that complete at the end of the stream, then drop the stream
+    pub fn close_stream(&mut self, id: StreamId) -> Result<()> {
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return Err(Error::UnknownStream(id));
+        };
+        let callbacks = &mut self.callbacks;
+        let report = |event: &MatchEvent| report(callbacks, &MatchEvent { stream: id, ..*event });
+        self.database.finish(stream, report);
+        // Its matches still count towards the stats
+        self.stats.matches_reported += stream.caps.reported;
+        self.stats.matches_suppressed += stream.caps.dropped;
+        self.streams.remove(&id);
+        Ok(())
+    }
+
+    fn scan_stream(&mut self, id: StreamId, data: &[u8]) -> Result<usize> {
+        let started = self.start_timer();
+        self.unscanned = None;
+        let Some((budget, over)) = self.database.config.chunk_budget else {
+            let consumed = self.scan_chunk(id, data)?;
+            self.count_chunk(consumed, started);
+            return Ok(consumed);
+        };
+        let clock = Instant::now();
+        let mut scanned = 0;
+        for part in data.chunks(BUDGET_CHECK_SIZE) {
+            let consumed = self.scan_chunk(id, part)?;
+            scanned += consumed;
+            // Stopped by a callback
+            if consumed < part.len() {
+                break;
+            }
+            if scanned == data.len() || clock.elapsed() <= budget {
+                continue;
+            }
+            self.count_chunk(scanned, started);
+            let stream = match id {
+                StreamId::MAIN => &mut self.stream,
+                id => self.streams.get_mut(&id).ok_or(Error::UnknownStream(id))?,
+            };
+            let (offset, rest) = (stream.position, (data.len() - scanned) as u64);
+            self.unscanned = Some(offset..offset + rest);
+            return match over {
+                OverBudget::Skip => {
+                    self.database.skip(stream, rest);
+                    Ok(data.len())
                 }
-            } else {
-                *current_state = pattern.initial_state;
+                OverBudget::Fail => Err(Error::BudgetExceeded { scanned, offset }),
+            };
+        }
+        self.count_chunk(scanned, started);
+        Ok(scanned)
+    }
+
+    fn scan_chunk(&mut self, id: StreamId, data: &[u8]) -> Result<usize> {
+        if id != StreamId::MAIN && !self.streams.contains_key(&id) {
+            return Err(Error::UnknownStream(id));
+        }
+        self.recombine();
+        let mut index = 0;
+        while index < data.len() {
+            // Patterns back at their initial state can join the literals mid-chunk, so
+            // until they do it takes a byte at a time. Other streams get them between
+            // chunks, rather than checking all of them at every byte
+            self.route_literals();
+            let waiting =
+                self.reroute && self.database.combined.is_none() && self.streams.is_empty();
+            let end = if waiting { index + 1 } else { data.len() };
+            let stream = match id {
+                StreamId::MAIN => &mut self.stream,
+                id => self.streams.get_mut(&id).ok_or(Error::UnknownStream(id))?,
+            };
+            let callbacks = &mut self.callbacks;
+            let report =
+                |event: &MatchEvent| report(callbacks, &MatchEvent { stream: id, ..*event });
+            if let ControlFlow::Break(consumed) =
+                self.database.scan_with(stream, &data[index..end], report)
+            {
+                return Ok(index + consumed);
             }
+            index = end;
         }
+        Ok(data.len())
     }
 
-    pub fn process_chunk(&mut self, data: &[u8]) {
-        for &byte in data {
-            self.process_byte(byte);
+    // Like process_chunk, but returns the matches instead of calling the callbacks, for
+    // code that cannot be called back in the middle of a scan
+    pub fn process_chunk_collect(&mut self, data: &[u8]) -> Vec<Match> {
+        let mut matches = Vec::new();
+        self.process_chunk_into(data, &mut matches);
+        matches
+    }
+
+    // Like process_chunk_collect, but appends to `matches`, which can be reused across
+    // chunks to save allocating
+    pub fn process_chunk_into(&mut self, data: &[u8], matches: &mut Vec<Match>) {
+        let started = self.start_timer();
+        self.recombine();
+        self.route_literals();
+        self.database.scan_into(&mut self.stream, data, matches);
+        self.count_chunk(data.len(), started);
+    }
+
+    // What the matcher did since it was created, see Stats
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.stats;
+        for stream in std::iter::once(&self.stream).chain(self.streams.values()) {
+            stats.matches_reported += stream.caps.reported;
+            stats.matches_suppressed += stream.caps.dropped;
+            stats.active_patterns += self.database.active_patterns(stream);
+        }
+        stats
+    }
+
+    // The time a scan starts at, when MatcherConfig::timing asks for it
+    fn start_timer(&self) -> Option<Instant> {
+        self.database.config.timing.then(Instant::now)
+    }
+
+    // Count a call that scanned `bytes` bytes in the stats
+    fn count_chunk(&mut self, bytes: usize, started: Option<Instant>) {
+        self.stats.bytes_processed += bytes as u64;
+        self.stats.chunks_processed += 1;
+        if let Some(started) = started {
+            self.stats.processing_time += started.elapsed();
+        }
+    }
+
+    // Bytes processed since the stream started, the offset the next byte will be at.
+    // Keeps counting across process_byte and process_chunk calls until finish
+    pub fn bytes_processed(&self) -> u64 {
+        self.stream.bytes_processed()
+    }
+
+    // Continue counting from `position`, for a stream that resumes at a known offset
+    // (say from a saved checkpoint), so match offsets stay absolute
+    pub fn set_position(&mut self, position: u64) {
+        self.stream.position = position;
+        // The bytes kept for MatcherConfig::context_bytes no longer lead up to it
+        self.stream.caps.context.ring.clear();
+    }
+
+    // A snapshot of where the matcher's own stream is, to carry it on in another
+    // process: the state of every pattern, the position and the match counts of the
+    // caps, but not the patterns themselves. See restore_state
+    pub fn save_state(&self) -> Vec<u8> {
+        snapshot::save(&self.database, &self.stream)
+    }
+
+    // Carry on with the stream where save_state took `bytes`, so matches spanning the
+    // move are still found. The matcher is to have the same patterns, added in the
+    // same order: the snapshot has a hash of them, and fails with PatternSetMismatch
+    // for others, or with Internal when damaged. Either way the stream stays as it was
+    pub fn restore_state(&mut self, bytes: &[u8]) -> Result<()> {
+        let snapshot = snapshot::read(&self.database.patterns, bytes)?;
+        // The automatons of compile and of the literals come back from the states
+        self.uncombine();
+        self.unroute();
+        snapshot.apply(&self.database, &mut self.stream);
+        Ok(())
+    }
+
+    // Signal the end of the stream
+    // Reports matches that can only complete at the end of the stream (patterns
+    // ending in '$'), then the absence of each negative pattern that did not match
+    // (see add_negative_pattern), both to the callbacks and as the returned events.
+    // Every pattern is then reset: the next chunk implicitly starts a new stream at
+    // offset 0, with the counts of the caps and of the negative patterns cleared
+    pub fn finish(&mut self) -> Vec<MatchEvent<'_>> {
+        self.finish_with_counts().0
+    }
+
+    // Like finish, but also returns what match_counts would have just before the reset,
+    // with the matches finish reported counted
+    pub fn finish_with_counts(&mut self) -> (Vec<MatchEvent<'_>>, Vec<MatchCounts>) {
+        let mut events = Vec::new();
+        let callbacks = &mut self.callbacks;
+        let counts = self.database.finish(&mut self.stream, |event| {
+            events.push(*event);
+            report(callbacks, event)
+        });
+        (events, counts)
+    }
+
+    // Like finish, but appends the matches to `matches` instead of calling the callbacks
+    pub fn finish_into(&mut self, matches: &mut Vec<Match>) {
+        self.database.finish_into(&mut self.stream, matches);
+    }
+
+    // The first match in what `reader` produces, scanning from the start of a new
+    // stream. Nothing is read once a read turned up a match, and no byte after the
+    // match is scanned. Either way the matcher is left reset, since the rest of the
+    // last read is gone. Matches are not passed to the callbacks
+    pub fn find_first<R: Read>(&mut self, mut reader: R) -> Result<Option<Match>> {
+        self.reset();
+        self.recombine();
+        self.route_literals();
+        let mut buffer = vec![0; FIND_BUFFER_SIZE];
+        let mut found = None;
+        loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(error) => {
+                    self.reset();
+                    return Err(Error::Io(error));
+                }
+            };
+            let first = |event: &MatchEvent| {
+                found.get_or_insert(event.to_match());
+                ControlFlow::Break(())
+            };
+            let started = self.start_timer();
+            let scanned = self.database.scan_with(&mut self.stream, &buffer[..read], first);
+            if let ControlFlow::Break(consumed) = scanned {
+                self.count_chunk(consumed, started);
+                self.reset();
+                return Ok(found);
+            }
+            self.count_chunk(read, started);
+        }
+        // The end of the stream can still complete a match
+        let mut matches = Vec::new();
+        self.finish_into(&mut matches);
+        Ok(matches.into_iter().find(|found| !found.absent))
+    }
+
+    // Iterates over the matches in everything `reader` produces, read `buf_size` bytes
+    // at a time. Matches are found across reads like across process_chunk calls, and
+    // the end of the reader is the end of the stream: it reports the matches finish
+    // would, and leaves the matcher reset. Matches go to the iterator instead of the
+    // callbacks, and read errors come out of it as Error::Io
+    pub fn matches_in_reader<R: Read>(
+        &mut self,
+        reader: R,
+        buf_size: usize,
+    ) -> MatchIter<'_, 'a, R> {
+        MatchIter {
+            matcher: self,
+            reader,
+            buffer: vec![0; buf_size.max(1)],
+            pending: Vec::new(),
+            next: 0,
+            done: false,
         }
     }
 
+    // Start a new stream at offset 0 without reporting anything, dropping the progress
+    // of every pattern and the match counts of the caps. Patterns and callbacks stay
+    // registered
+    pub fn reset(&mut self) {
+        self.database.reset(&mut self.stream);
+    }
+
+    // The registered patterns, in the order they were added
+    pub fn patterns(&self) -> impl Iterator<Item = &Pattern> {
+        self.database.patterns()
+    }
+
+    pub fn pattern_ids(&self) -> impl Iterator<Item = &str> {
+        self.patterns().map(Pattern::id)
+    }
+
+    // Fewest bytes a match of any registered pattern can span, or None without patterns
+    pub fn min_match_len(&self) -> Option<usize> {
+        self.patterns().map(Pattern::min_match_len).min()
+    }
+
     pub fn memory_usage(&self) -> usize {
-        self.memory_usage.load(Ordering::Relaxed)
+        let streams = std::iter::once(&self.stream).chain(self.streams.values());
+        // The rings of MatcherConfig::context_bytes, one per stream
+        let rings: usize = streams.map(|stream| stream.caps.context.ring.capacity()).sum();
+        self.memory_usage.load(Ordering::Relaxed) + rings
+    }
+
+    // The complexity of all registered patterns, see Pattern::complexity
+    pub fn complexity(&self) -> ComplexitySummary {
+        let mut summary = ComplexitySummary::default();
+        for report in self.patterns().map(Pattern::complexity) {
+            summary.pattern_count += 1;
+            summary.state_count += report.state_count;
+            summary.max_out_degree = summary.max_out_degree.max(report.max_out_degree);
+            match report.cost_class {
+                CostClass::Literal => summary.literal_patterns += 1,
+                CostClass::Bounded => summary.bounded_patterns += 1,
+                CostClass::Unbounded => summary.unbounded_patterns += 1,
+            }
+            summary.worst_class = summary.worst_class.max(Some(report.cost_class));
+        }
+        summary
+    }
+}
+
+// Iterator over the matches in a reader, see StreamMatcher::matches_in_reader
+pub struct MatchIter<'m, 'a, R> {
+    matcher: &'m mut StreamMatcher<'a>,
+    reader: R,
+    buffer: Vec<u8>,
+    // Matches found in the last read, handed out from `next` on
+    pending: Vec<Match>,
+    next: usize,
+    done: bool,
+}
+
+impl<R: Read> Iterator for MatchIter<'_, '_, R> {
+    type Item = Result<Match>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next == self.pending.len() {
+            if self.done {
+                return None;
+            }
+            self.pending.clear();
+            self.next = 0;
+            match self.reader.read(&mut self.buffer) {
+                Ok(0) => {
+                    self.done = true;
+                    self.matcher.finish_into(&mut self.pending);
+                }
+                Ok(read) => self
+                    .matcher
+                    .process_chunk_into(&self.buffer[..read], &mut self.pending),
+                Err(error) if error.kind() == ErrorKind::Interrupted => {}
+                Err(error) => return Some(Err(Error::Io(error))),
+            }
+        }
+        self.next += 1;
+        Some(Ok(self.pending[self.next - 1]))
     }
-}
\ No newline at end of file
+}
+
+// The stream of the matcher itself and those opened with open_stream
+fn all_streams<'s>(
+    main: &'s mut StreamState,
+    opened: &'s mut HashMap<StreamId, StreamState>,
+) -> impl Iterator<Item = &'s mut StreamState> {
+    std::iter::once(main).chain(opened.values_mut())
+}
+
+// Where a match of `pattern` ending at `end` starts, if all its matches are as long
+fn match_start(pattern: &Pattern, end: u64) -> Option<u64> {
+    match pattern.max_match_len {
+        Some(len) if len == pattern.min_match_len => end.checked_sub(len as u64),
+        _ => None,
+    }
+}
+
+// Call every callback with `event`, Break if any of them returned Break
+fn report(callbacks: &mut [Mutex<MatchCallback>], event: &MatchEvent) -> ControlFlow<()> {
+    let mut flow = ControlFlow::Continue(());
+    for callback in callbacks {
+        // Never locked, so never poisoned either
+        let callback = callback.get_mut().unwrap_or_else(PoisonError::into_inner);
+        if callback(event).is_break() {
+            flow = ControlFlow::Break(());
+        }
+    }
+    flow
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompileOptions, PatternBuilder, compile_pattern, compile_pattern_with};
+
+    #[test]
+    fn test_numbered_patterns() {
+        let mut matcher = StreamMatcher::new();
+        let get = matcher.add_pattern(compile_pattern("GET").unwrap());
+        let post = compile_pattern("POST").unwrap().with_metadata("severity", "low");
+        let post = matcher.add_pattern(post);
+        assert_eq!((get, post), (0, 1));
+        assert_eq!(matcher.pattern_name(post), Some("POST"));
+        assert_eq!(matcher.pattern_name(2), None);
+        let numbers: Vec<_> = matcher.patterns().map(Pattern::number).collect();
+        assert_eq!(numbers, vec![Some(get), Some(post)]);
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        matcher.add_match_callback(move |event| sink.lock().unwrap().push(event.pattern));
+        let severities = Arc::new(Mutex::new(Vec::new()));
+        let sink = severities.clone();
+        matcher.add_match_callback(move |event| {
+            let severity = event.metadata.get("severity").cloned();
+            sink.lock().unwrap().push(severity);
+        });
+        // Callbacks taking the name still work alongside
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let sink = names.clone();
+        matcher.add_callback(move |name| sink.lock().unwrap().push(name.to_string()));
+        matcher.process_chunk(b"POST GET POST");
+
+        assert_eq!(*events.lock().unwrap(), vec![post, get, post]);
+        let low = Some("low".to_string());
+        assert_eq!(*severities.lock().unwrap(), vec![low.clone(), None, low]);
+        assert_eq!(*names.lock().unwrap(), vec!["POST", "GET", "POST"]);
+    }
+
+    #[test]
+    fn test_complexity_summary() {
+        let mut matcher = StreamMatcher::new();
+        assert_eq!(matcher.complexity(), ComplexitySummary::default());
+
+        let patterns = ["GET", "POST", "id=[0-9]{2,4}", "admin.*password"];
+        for pattern in patterns {
+            matcher.add_pattern(compile_pattern(pattern).unwrap());
+        }
+        let reports: Vec<_> = matcher.patterns().map(Pattern::complexity).collect();
+        let summary = matcher.complexity();
+        assert_eq!(summary.pattern_count, 4);
+        let state_count: usize = reports.iter().map(|report| report.state_count).sum();
+        assert_eq!(summary.state_count, state_count);
+        assert_eq!(
+            summary.max_out_degree,
+            reports.iter().map(|report| report.max_out_degree).max().unwrap()
+        );
+        assert_eq!(
+            (summary.literal_patterns, summary.bounded_patterns, summary.unbounded_patterns),
+            (2, 1, 1)
+        );
+        assert_eq!(summary.worst_class, Some(CostClass::Unbounded));
+    }
+
+    #[test]
+    fn test_match_offsets() {
+        let mut matcher = StreamMatcher::new();
+        matcher.add_pattern(compile_pattern("needle").unwrap());
+        matcher.add_pattern(compile_pattern("ab+c").unwrap());
+        matcher.add_pattern(compile_pattern(r"\bend\b").unwrap());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        matcher.add_match_callback(move |event| {
+            sink.lock().unwrap().push((event.pattern, event.start, event.end));
+        });
+
+        // Offsets carry on across chunks, even for a match split between them
+        matcher.process_chunk(b"xxnee");
+        matcher.process_chunk(b"dle abbbc");
+        // The byte confirming the '\b' is not part of the match
+        matcher.process_chunk(b" end.");
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![(0, Some(2), 8), (1, None, 14), (2, Some(15), 18)]
+        );
+
+        // A new stream starts at offset 0
+        events.lock().unwrap().clear();
+        matcher.finish();
+        matcher.process_chunk(b"needle");
+        assert_eq!(*events.lock().unwrap(), vec![(0, Some(0), 6)]);
+    }
+
+    #[test]
+    fn test_set_position() {
+        let mut matcher = StreamMatcher::new();
+        matcher.add_pattern(compile_pattern("needle").unwrap());
+        let ends = Arc::new(Mutex::new(Vec::new()));
+        let sink = ends.clone();
+        matcher.add_match_callback(move |event| sink.lock().unwrap().push(event.end));
+
+        // Resume just below 4 GiB, so the match crosses the range of a u32
+        let resumed = u64::from(u32::MAX) - 2;
+        matcher.set_position(resumed);
+        matcher.process_chunk(b"xneedle");
+        assert!(matcher.process_byte(b'x').is_continue());
+        assert_eq!(matcher.bytes_processed(), resumed + 8);
+        assert_eq!(*ends.lock().unwrap(), vec![resumed + 7]);
+
+        matcher.finish();
+        assert_eq!(matcher.bytes_processed(), 0);
+    }
+
+    // Takes over a minute even in release builds, run with --ignored
+    #[test]
+    #[ignore]
+    fn test_five_gigabyte_stream() {
+        const CHUNK_SIZE: usize = 64 * 1024 * 1024;
+        const CHUNKS: u64 = 80;
+
+        let mut matcher = StreamMatcher::new();
+        matcher.add_pattern(compile_pattern("needle").unwrap());
+        let ends = Arc::new(Mutex::new(Vec::new()));
+        let sink = ends.clone();
+        matcher.add_match_callback(move |event| sink.lock().unwrap().push(event.end));
+
+        // Every chunk ends in a match, so a drifting counter shows in the offsets
+        let mut chunk = vec![b'x'; CHUNK_SIZE];
+        chunk[CHUNK_SIZE - 6..].copy_from_slice(b"needle");
+        for _ in 0..CHUNKS {
+            matcher.process_chunk(&chunk);
+        }
+        let total = CHUNKS * CHUNK_SIZE as u64;
+        assert_eq!(total, 5 * 1024 * 1024 * 1024);
+        assert_eq!(matcher.bytes_processed(), total);
+        let expected: Vec<u64> = (1..=CHUNKS).map(|i| i * CHUNK_SIZE as u64).collect();
+        assert_eq!(*ends.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_mutable_callbacks() {
+        let mut count = 0;
+        let mut tags = Vec::new();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_pattern(compile_pattern("ab").unwrap());
+        matcher.add_callback(|_| count += 1);
+        matcher.add_tagged_callback(|_, tag| tags.push(tag));
+        // A callback can also keep state of its own
+        let mut seen = 0;
+        let firsts = Arc::new(AtomicUsize::new(0));
+        let sink = firsts.clone();
+        matcher.add_match_callback(move |_| {
+            seen += 1;
+            if seen == 1 {
+                sink.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        matcher.process_chunk(b"ab ab ab");
+        drop(matcher);
+
+        assert_eq!(count, 3);
+        assert_eq!(tags, vec![None; 3]);
+        assert_eq!(firsts.load(Ordering::Relaxed), 1);
+
+        // Callbacks that borrow nothing leave the matcher 'static, and it stays Sync
+        fn assert_sync<T: Send + Sync + 'static>(_: &T) {}
+        let mut matcher = StreamMatcher::new();
+        matcher.add_callback(|name| assert_eq!(name, "ab"));
+        assert_sync(&matcher);
+    }
+
+    #[test]
+    fn test_add_pattern_mid_stream() {
+        let found = |matches: Vec<Match>| -> Vec<(u32, u64)> {
+            matches.iter().map(|found| (found.pattern, found.end)).collect()
+        };
+        for compiled in [false, true] {
+            let mut matcher = StreamMatcher::new();
+            matcher.add_pattern(compile_pattern("abcd").unwrap());
+            matcher.add_pattern(compile_pattern("b[a-z]+e").unwrap());
+            if compiled {
+                matcher.compile().unwrap();
+            }
+            let other = matcher.open_stream();
+            matcher.process_chunk_for(other, b"c").unwrap();
+            let mut matches = matcher.process_chunk_collect(b"xab");
+            assert!(matches.is_empty());
+            // "bcd" started before it was added, and "^c" is past the start
+            matcher.add_pattern(compile_pattern("cd").unwrap());
+            matcher.add_pattern(compile_pattern("bcd").unwrap());
+            matcher.add_pattern(compile_pattern("^c").unwrap());
+            assert_eq!(matcher.stream.progress.states.len(), 5);
+            matches.extend(matcher.process_chunk_collect(b"cdbcde"));
+            assert_eq!(matcher.combined_state_count().is_some(), compiled);
+            let expected = [(0, 5), (2, 5), (2, 8), (3, 8), (1, 9)];
+            assert_eq!(found(matches), expected, "compiled: {}", compiled);
+
+            // Another stream that already started gets them the same way
+            let ends = Arc::new(Mutex::new(Vec::new()));
+            let sink = ends.clone();
+            matcher.add_match_callback(move |event| sink.lock().unwrap().push(event.pattern));
+            matcher.process_chunk_for(other, b"cd").unwrap();
+            assert_eq!(*ends.lock().unwrap(), [2]);
+
+            // and the next stream gets them from its start
+            matcher.finish();
+            assert_eq!(found(matcher.process_chunk_collect(b"cd")), [(4, 1), (2, 2)]);
+        }
+    }
+
+    #[test]
+    fn test_set_enabled() {
+        let found = |matches: Vec<Match>| -> Vec<(u32, u64)> {
+            matches.iter().map(|found| (found.pattern, found.end)).collect()
+        };
+        for compiled in [false, true] {
+            let mut matcher = StreamMatcher::new();
+            matcher.add_pattern(compile_pattern("abcd").unwrap());
+            matcher.add_pattern(compile_pattern("b[a-z]+e").unwrap());
+            matcher.add_pattern(compile_pattern("cd").unwrap());
+            matcher.add_negative_pattern(compile_pattern("zz").unwrap());
+            matcher.add_pattern(compile_pattern("^a").unwrap());
+            if compiled {
+                matcher.compile().unwrap();
+            }
+            let mut matches = matcher.process_chunk_collect(b"ab");
+            assert_eq!(found(matches), [(4, 1)]);
+            assert!(matcher.set_enabled("abcd", false));
+            assert!(matcher.set_enabled("b[a-z]+e", false));
+            assert!(matcher.set_enabled("^a", false));
+            assert!(!matcher.set_enabled("missing", false));
+            assert_eq!(matcher.is_enabled("abcd"), Some(false));
+            assert_eq!(matcher.is_enabled("cd"), Some(true));
+            assert_eq!(matcher.is_enabled("missing"), None);
+            assert!(!matcher.database.unrouted.contains(&1));
+            matches = matcher.process_chunk_collect(b"cdbxe");
+            assert_eq!(found(matches), [(2, 4)], "compiled: {}", compiled);
+
+            // Enabled again, they start over and the anchored one stays past its start
+            matcher.set_enabled("abcd", true);
+            matcher.set_enabled("b[a-z]+e", true);
+            matcher.set_enabled("^a", true);
+            matches = matcher.process_chunk_collect(b"cde abcd");
+            matches.extend(matcher.process_chunk_collect(b"e"));
+            assert_eq!(matcher.combined_state_count().is_some(), compiled);
+            let expected = [(2, 9), (0, 15), (2, 15), (1, 16)];
+            assert_eq!(found(matches), expected, "compiled: {}", compiled);
+
+            // A disabled negative pattern is not reported absent, and the next stream
+            // leaves the disabled patterns out from its start
+            matcher.set_enabled("zz", false);
+            matcher.set_enabled("cd", false);
+            assert!(matcher.finish().is_empty());
+            assert_eq!(found(matcher.process_chunk_collect(b"abcd")), [(4, 1), (0, 4)]);
+            // and they stay out when the patterns are combined again for another one
+            matcher.set_enabled("^a", false);
+            assert!(matcher.process_chunk_collect(b"cd").is_empty());
+        }
+    }
+
+    #[test]
+    fn test_pattern_groups() {
+        let mut matcher = StreamMatcher::new();
+        matcher.add_pattern_in_group(compile_pattern("SELECT").unwrap(), "sql");
+        matcher.add_pattern_in_group(compile_pattern("UNION").unwrap(), "sql");
+        matcher.add_pattern_in_group(compile_pattern("<script").unwrap(), "xss");
+        matcher.add_pattern(compile_pattern(r"\d{3}-\d{4}").unwrap().with_metadata("group", "pii"));
+        matcher.add_pattern(compile_pattern("plain").unwrap());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        matcher.add_match_callback(move |event| {
+            sink.lock().unwrap().push((event.name.to_string(), event.group.map(str::to_string)));
+        });
+        let taken = || std::mem::take(&mut *events.lock().unwrap());
+
+        matcher.process_chunk(b"UNION SELECT <script> 555-1234 plain");
+        let group = |name: &str, group: Option<&str>| (name.to_string(), group.map(String::from));
+        let expected = [
+            group("UNION", Some("sql")),
+            group("SELECT", Some("sql")),
+            group("<script", Some("xss")),
+            group(r"\d{3}-\d{4}", Some("pii")),
+            group("plain", None),
+        ];
+        assert_eq!(taken(), expected);
+        let counts = |group: &str, reported| GroupCounts {
+            group: group.to_string(),
+            reported,
+            suppressed: 0,
+        };
+        let expected = [counts("sql", 2), counts("xss", 1), counts("pii", 1)];
+        assert_eq!(matcher.match_counts_by_group(), expected);
+
+        assert_eq!(matcher.disable_group("sql"), 2);
+        assert_eq!(matcher.disable_group("missing"), 0);
+        assert_eq!(matcher.is_enabled("UNION"), Some(false));
+        matcher.process_chunk(b" SELECT UNION <script");
+        assert_eq!(taken(), [group("<script", Some("xss"))]);
+        assert_eq!(matcher.enable_group("sql"), 2);
+        matcher.process_chunk(b" UNION");
+        assert_eq!(taken(), [group("UNION", Some("sql"))]);
+
+        assert_eq!(matcher.remove_group("xss"), 1);
+        assert_eq!(matcher.remove_group("xss"), 0);
+        assert_eq!(matcher.database.patterns.len(), 4);
+        let expected = [counts("sql", 3), counts("pii", 1)];
+        assert_eq!(matcher.match_counts_by_group(), expected);
+    }
+
+    #[test]
+    fn test_remove_pattern() {
+        let mut matcher = StreamMatcher::new();
+        let first = matcher.add_pattern(compile_pattern("first").unwrap());
+        matcher.add_pattern(compile_pattern("middle").unwrap());
+        let last = matcher.add_pattern(compile_pattern("last").unwrap());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        matcher.add_match_callback(move |event| {
+            sink.lock().unwrap().push((event.pattern, event.name.to_string()));
+        });
+
+        // A partial match of a pattern after the removed one survives the removal
+        matcher.process_chunk(b"first las");
+        // Taken after the literals were combined on the first chunk
+        let memory = matcher.memory_usage();
+        assert!(matcher.remove_pattern("middle"));
+        assert!(!matcher.remove_pattern("middle"));
+        assert!(matcher.memory_usage() < memory);
+        matcher.process_chunk(b"t middle first");
+
+        let expected = vec![
+            (first, "first".to_string()),
+            (last, "last".to_string()),
+            (first, "first".to_string()),
+        ];
+        assert_eq!(*events.lock().unwrap(), expected);
+        assert_eq!(matcher.pattern_ids().collect::<Vec<_>>(), vec!["first", "last"]);
+        assert_eq!(matcher.pattern_name(last), Some("last"));
+        assert_eq!(matcher.pattern_name(1), None);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut matcher = StreamMatcher::new();
+        matcher.add_pattern(compile_pattern("needle").unwrap());
+        matcher.add_pattern(compile_pattern("^GET").unwrap());
+        matcher.add_pattern(compile_pattern("end$").unwrap());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        matcher.add_match_callback(move |event| {
+            sink.lock().unwrap().push((event.name.to_string(), event.end));
+        });
+
+        // Nothing of stream A carries over into stream B, not even a match at its end
+        matcher.process_chunk(b"x need");
+        matcher.process_chunk(b"end");
+        matcher.reset();
+        assert_eq!(matcher.bytes_processed(), 0);
+        matcher.finish();
+        matcher.process_chunk(b"le GET needle");
+        matcher.reset();
+        matcher.process_chunk(b"GET");
+
+        let expected = vec![("needle".to_string(), 13), ("^GET".to_string(), 3)];
+        assert_eq!(*events.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_stop_scanning() {
+        let mut ends = Vec::new();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_pattern(compile_pattern("virus").unwrap());
+        matcher.add_pattern(compile_pattern("rus").unwrap());
+        matcher.add_control_flow_callback(|event| {
+            ends.push(event.end);
+            if event.name == "virus" {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+
+        // Both matches ending on the byte that stopped the scan are reported
+        let data = b"a virus, rus and virus";
+        let consumed = matcher.process_chunk(data);
+        assert_eq!(consumed, 7);
+        assert_eq!(matcher.bytes_processed(), 7);
+        // Resuming carries on up to the next stop
+        let consumed = consumed + matcher.process_chunk(&data[consumed..]);
+        assert_eq!(consumed, data.len());
+        assert_eq!(matcher.process_chunk(b""), 0);
+        drop(matcher);
+        assert_eq!(ends, vec![7, 7, 12, 22, 22]);
+    }
+
+    #[test]
+    fn test_stop_scanning_with_context() {
+        let mut seen = Vec::new();
+        let mut matcher = StreamMatcher::with_config(MatcherConfig::new().context_bytes(8, 3));
+        matcher.add_pattern(compile_pattern("virus").unwrap());
+        matcher.add_control_flow_callback(|event| {
+            seen.push((event.end, event.context.to_vec()));
+            ControlFlow::Break(())
+        });
+
+        // The scan stops right after the bytes following the match streamed through
+        let data = b"a virus, virus!";
+        let consumed = matcher.process_chunk(data);
+        assert_eq!(consumed, 10);
+        // The context of the next match reaches back before where it resumed
+        assert_eq!(matcher.process_chunk(&data[consumed..]), 5);
+        matcher.finish();
+        drop(matcher);
+        assert_eq!(seen, [(7, b"a virus, v".to_vec()), (14, b"s, virus!".to_vec())]);
+    }
+
+    #[test]
+    fn test_collect_matches() {
+        let mut matcher = StreamMatcher::new();
+        let needle = matcher.add_pattern(compile_pattern("needle").unwrap());
+        let digits = matcher.add_pattern(compile_pattern("[0-9]+x").unwrap());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        matcher.add_match_callback(move |event| sink.lock().unwrap().push(event.to_match()));
+
+        // The same matches as the callbacks get, with offsets across chunks
+        let chunks: [&[u8]; 3] = [b"a nee", b"dle 12", b"3x needle"];
+        let mut collected = Vec::new();
+        for chunk in chunks {
+            collected.extend(matcher.process_chunk_collect(chunk));
+        }
+        assert!(events.lock().unwrap().is_empty());
+        matcher.reset();
+        for chunk in chunks {
+            matcher.process_chunk(chunk);
+        }
+        assert_eq!(*events.lock().unwrap(), collected);
+
+        let found = |pattern, end, start| Match {
+            pattern,
+            tag: None,
+            end,
+            start,
+            absent: false,
+            count: 1,
+        };
+        let expected =
+            vec![found(needle, 8, Some(2)), found(digits, 13, None), found(needle, 20, Some(14))];
+        assert_eq!(collected, expected);
+
+        // A reused buffer is appended to
+        let mut matches = Vec::new();
+        matcher.process_chunk_into(b"needle", &mut matches);
+        matcher.process_chunk_into(b" 7x", &mut matches);
+        let ends: Vec<_> = matches.iter().map(|found| found.end).collect();
+        assert_eq!(ends, vec![26, 29]);
+    }
+
+    #[test]
+    fn test_collect_runs_patterns_in_turn() {
+        // Collected matches come from running one pattern at a time through the chunk,
+        // and have to come out as the callbacks get them byte by byte
+        let config = MatcherConfig::new().max_matches_per_pattern(40).max_matches_total(100);
+        let mut matcher = StreamMatcher::with_config(config);
+        for pattern in ["needle", "ne+d", "^ne", "\\bdle\\b", "(ne|d)+l", "[0-9]+x"] {
+            matcher.add_pattern(compile_pattern(pattern).unwrap());
+        }
+        let mut builder = PatternBuilder::new();
+        let (body, end) = (builder.add_state(false), builder.add_state(true));
+        builder.add_transition(0, b'n', body).unwrap();
+        builder.add_transition_set(body, b"ed", body).unwrap();
+        builder.add_transition(body, b'e', end).unwrap();
+        builder.determinize(false);
+        matcher.add_pattern(builder.build("n[ed]*e".into()).unwrap());
+        assert!(matcher.prefilter().is_none());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        matcher.add_match_callback(move |event| sink.lock().unwrap().push(event.to_match()));
+
+        let mut seed = 3u32;
+        let data: Vec<u8> = (0..3000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                b"needl 7x"[(seed >> 16) as usize % 8]
+            })
+            .collect();
+        let mut collected = Vec::new();
+        for chunk in data.chunks(97) {
+            matcher.process_chunk_into(chunk, &mut collected);
+        }
+        matcher.finish_into(&mut collected);
+        for chunk in data.chunks(97) {
+            matcher.process_chunk(chunk);
+        }
+        matcher.finish();
+        assert_eq!(collected.len(), 100);
+        assert_eq!(*events.lock().unwrap(), collected);
+    }
+
+    #[test]
+    fn test_matches_in_reader() {
+        let mut matcher = StreamMatcher::new();
+        let needle = matcher.add_pattern(compile_pattern("needle").unwrap());
+        let end = matcher.add_pattern(compile_pattern("end$").unwrap());
+        let data: &[u8] = b"a needle, another needle and the end";
+
+        // Every match straddles reads of 3 bytes, and the last one needs the end
+        let found: Vec<_> = matcher
+            .matches_in_reader(data, 3)
+            .map(|found| found.map(|found| (found.pattern, found.end)))
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(found, vec![(needle, 8), (needle, 24), (end, 36)]);
+        assert_eq!(matcher.bytes_processed(), 0);
+
+        // Read errors are passed on, and reading carries on after them
+        struct Failing<'d>(&'d [u8], bool);
+        impl Read for Failing<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.1 = !self.1;
+                if self.1 {
+                    return Err(std::io::Error::other("flaky"));
+                }
+                self.0.read(buf)
+            }
+        }
+        let mut results = matcher.matches_in_reader(Failing(b"needle", false), 4);
+        assert!(matches!(results.next(), Some(Err(Error::Io(_)))));
+        assert!(matches!(results.next(), Some(Err(Error::Io(_)))));
+        assert_eq!(results.next().unwrap().unwrap().end, 6);
+    }
+
+    #[test]
+    fn test_find_first() {
+        let mut matcher = StreamMatcher::new();
+        let needle = matcher.add_pattern(compile_pattern("needle").unwrap());
+        let end = matcher.add_pattern(compile_pattern("end$").unwrap());
+        let count = Arc::new(AtomicUsize::new(0));
+        let sink = count.clone();
+        matcher.add_callback(move |_| {
+            sink.fetch_add(1, Ordering::Relaxed);
+        });
+
+        // The first read has the match, so the failing reader after it is never read
+        struct Unreadable;
+        impl Read for Unreadable {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("read past the match"))
+            }
+        }
+        let reader = (&b"a needle, another needle"[..]).chain(Unreadable);
+        let found = matcher.find_first(reader).unwrap().unwrap();
+        assert_eq!((found.pattern, found.start, found.end), (needle, Some(2), 8));
+        assert_eq!(matcher.bytes_processed(), 0);
+        assert!(matches!(matcher.find_first(Unreadable), Err(Error::Io(_))));
+
+        // The match can come from the end of the stream too, or not at all
+        let found = matcher.find_first(&b"the end"[..]).unwrap().unwrap();
+        assert_eq!((found.pattern, found.end), (end, 7));
+        assert_eq!(matcher.find_first(&b"nothing here"[..]).unwrap(), None);
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_match_caps() {
+        let config = MatcherConfig::new().max_matches_per_pattern(2);
+        let mut matcher = StreamMatcher::with_config(config);
+        let noisy = matcher.add_pattern(compile_pattern("a").unwrap());
+        let rare = matcher.add_pattern(compile_pattern("rare").unwrap());
+        let found = matcher.process_chunk_collect(b"aaaa rare");
+        let patterns: Vec<_> = found.iter().map(|found| found.pattern).collect();
+        // The capped pattern no longer reports, the other one still does
+        assert_eq!(patterns, vec![noisy, noisy, rare]);
+        assert!(matcher.truncated());
+
+        // The caps start over with the stream, and the total counts all patterns
+        matcher.reset();
+        assert!(!matcher.truncated());
+        assert_eq!(matcher.process_chunk_collect(b"a").len(), 1);
+        assert!(!matcher.truncated());
+
+        let config = MatcherConfig::new().max_matches_total(3);
+        let mut matcher = StreamMatcher::with_config(config);
+        matcher.add_pattern(compile_pattern("a").unwrap());
+        matcher.add_pattern(compile_pattern("b$").unwrap());
+        let count = Arc::new(AtomicUsize::new(0));
+        let sink = count.clone();
+        matcher.add_callback(move |_| {
+            sink.fetch_add(1, Ordering::Relaxed);
+        });
+        matcher.process_chunk(b"aab");
+        matcher.process_chunk(b"ab");
+        assert!(!matcher.truncated());
+        // Not even the end of the stream gets past the cap
+        matcher.finish();
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+        matcher.process_chunk(b"aaaa");
+        assert!(matcher.truncated());
+        assert_eq!(count.load(Ordering::Relaxed), 6);
+    }
+
+    #[test]
+    fn test_coalesce_within() {
+        let matcher = |compiled: bool, config: MatcherConfig| {
+            let mut matcher = StreamMatcher::with_config(config.coalesce_within(32));
+            matcher.add_pattern(compile_pattern(r"\d{4}").unwrap());
+            matcher.add_pattern(compile_pattern("x$").unwrap());
+            if compiled {
+                matcher.compile().unwrap();
+            }
+            matcher
+        };
+        let mut input = b"x".to_vec();
+        input.extend((0..100).map(|digit| b'0' + digit % 10));
+        input.extend_from_slice(&[b' '; 40]);
+        input.extend_from_slice(b"1234 x");
+        // Digits at 1 to 100: a match ends at each of 5 to 101, then one at 145
+        let expected: Vec<_> = [(5, 32), (37, 32), (69, 32), (101, 1), (145, 1)]
+            .iter()
+            .map(|&(end, count)| (0, end, Some(end - 4), count))
+            .chain([(1, 147, Some(146), 1)])
+            .collect();
+        let summary = |matches: &[Match]| -> Vec<_> {
+            let summary = |found: &Match| (found.pattern, found.end, found.start, found.count);
+            matches.iter().map(summary).collect()
+        };
+
+        for compiled in [false, true] {
+            let mut whole = matcher(compiled, MatcherConfig::new());
+            let mut matches = whole.process_chunk_collect(&input);
+            whole.finish_into(&mut matches);
+            assert_eq!(summary(&matches), expected);
+            // The counts carry across chunks, down to single bytes
+            for size in [1, 7, 40] {
+                let mut chunked = matcher(compiled, MatcherConfig::new());
+                let mut matches = Vec::new();
+                for chunk in input.chunks(size) {
+                    chunked.process_chunk_into(chunk, &mut matches);
+                }
+                chunked.finish_into(&mut matches);
+                assert_eq!(summary(&matches), expected, "chunks of {}", size);
+            }
+
+            // An event comes once a chunk scans past its window, and through snapshots
+            let mut first = matcher(compiled, MatcherConfig::new());
+            assert_eq!(first.process_chunk_collect(&input[..20]), Vec::new());
+            let mut second = matcher(compiled, MatcherConfig::new());
+            second.restore_state(&first.save_state()).unwrap();
+            let matches = second.process_chunk_collect(&input[20..40]);
+            assert_eq!(summary(&matches), expected[..1]);
+            // Without a later match to report it on
+            let matches = second.process_chunk_collect(&input[40..140]);
+            assert_eq!(summary(&matches), expected[1..4]);
+        }
+
+        // The caps count the events instead of the matches
+        let mut capped = matcher(true, MatcherConfig::new().max_matches_total(3));
+        let mut matches = capped.process_chunk_collect(&input);
+        capped.finish_into(&mut matches);
+        assert_eq!(summary(&matches), expected[..3]);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let config = MatcherConfig::new().rate_limit(2, 10);
+        let matcher = || {
+            let mut matcher = StreamMatcher::with_config(config);
+            matcher.add_pattern(compile_pattern("a").unwrap());
+            matcher.add_pattern(compile_pattern("b").unwrap());
+            matcher
+        };
+        let input = [b"a".repeat(30), b"bb".to_vec()].concat();
+        let ends = |matches: &[Match]| matches.iter().map(|found| found.end).collect::<Vec<_>>();
+        // The reported and suppressed matches of "a", then of "b"
+        let counts = |a, b| {
+            let counts = |pattern, (reported, suppressed)| MatchCounts {
+                pattern,
+                reported,
+                suppressed,
+            };
+            vec![counts(0, a), counts(1, b)]
+        };
+
+        // Two per window of 10 bytes, by where the matches end, and per pattern
+        let mut whole = matcher();
+        let expected = whole.process_chunk_collect(&input);
+        assert_eq!(ends(&expected), [1, 2, 10, 11, 20, 21, 30, 31, 32]);
+        assert_eq!(whole.match_counts(), counts((7, 23), (2, 0)));
+        let mut chunked = matcher();
+        let mut matches = Vec::new();
+        for byte in &input {
+            chunked.process_chunk_into(std::slice::from_ref(byte), &mut matches);
+            // The limit carries over into a restored stream
+            let mut restored = matcher();
+            restored.restore_state(&chunked.save_state()).unwrap();
+            chunked = restored;
+        }
+        assert_eq!(matches, expected);
+        assert_eq!(chunked.match_counts(), whole.match_counts());
+        whole.reset();
+        assert_eq!(whole.match_counts(), counts((0, 0), (0, 0)));
+
+        // Each stream has a limit of its own
+        let streams = Arc::new(Mutex::new(Vec::new()));
+        let sink = streams.clone();
+        let mut shared = matcher();
+        shared.add_match_callback(move |event| sink.lock().unwrap().push(event.stream));
+        let other = shared.open_stream();
+        shared.process_chunk(b"aa");
+        shared.process_chunk_for(other, b"aaaa").unwrap();
+        shared.process_chunk(b"a");
+        assert_eq!(*streams.lock().unwrap(), [StreamId::MAIN, StreamId::MAIN, other, other]);
+        assert_eq!(shared.match_counts(), counts((2, 1), (0, 0)));
+    }
+
+    #[test]
+    fn test_total_matches() {
+        let mut matcher = StreamMatcher::new();
+        let ab = matcher.add_pattern(compile_pattern("ab").unwrap());
+        let end = matcher.add_pattern(compile_pattern("c$").unwrap());
+        let absent = matcher.add_negative_pattern(compile_pattern("zz").unwrap());
+        let counts = |ab_count, end_count| {
+            let counts = |pattern, reported| MatchCounts { pattern, reported, suppressed: 0 };
+            vec![counts(ab, ab_count), counts(end, end_count), counts(absent, 0)]
+        };
+        // Counted without any callback
+        matcher.process_chunk(b"abxab");
+        matcher.process_chunk(b"c");
+        assert_eq!((matcher.match_counts(), matcher.total_matches()), (counts(2, 0), 2));
+        // The counts finish returns include what it reported, then they start over
+        let (events, finished) = matcher.finish_with_counts();
+        assert_eq!(events.len(), 2);
+        assert_eq!(finished, counts(2, 1));
+        assert_eq!((matcher.match_counts(), matcher.total_matches()), (counts(0, 0), 0));
+        matcher.process_chunk(b"ab");
+        assert_eq!(matcher.total_matches(), 1);
+        matcher.reset();
+        assert_eq!(matcher.total_matches(), 0);
+    }
+
+    #[test]
+    fn test_match_counts_by_number() {
+        let mut matcher = StreamMatcher::new();
+        let ab = matcher.add_pattern(compile_pattern("ab").unwrap());
+        let cd = matcher.add_pattern(compile_pattern("cd").unwrap());
+        let counts = |matcher: &StreamMatcher| {
+            let mut counts: Vec<_> = matcher.match_counts_by_number().iter().collect();
+            counts.sort();
+            counts.into_iter().map(|(&number, &count)| (number, count)).collect::<Vec<_>>()
+        };
+        // Every pattern has an entry, matched or not
+        matcher.process_chunk(b"ab ab");
+        assert_eq!(counts(&matcher), [(ab, 2), (cd, 0)]);
+        let saved = matcher.save_state();
+
+        let ef = matcher.add_pattern(compile_pattern("ef").unwrap());
+        matcher.process_chunk(b" cd ef");
+        assert_eq!(counts(&matcher), [(ab, 2), (cd, 1), (ef, 1)]);
+        matcher.remove_pattern("ab");
+        assert_eq!(counts(&matcher), [(cd, 1), (ef, 1)]);
+        matcher.reset();
+        assert_eq!(counts(&matcher), [(cd, 0), (ef, 0)]);
+
+        let mut restored = StreamMatcher::new();
+        restored.add_pattern(compile_pattern("ab").unwrap());
+        restored.add_pattern(compile_pattern("cd").unwrap());
+        restored.restore_state(&saved).unwrap();
+        assert_eq!(counts(&restored), [(ab, 2), (cd, 0)]);
+        restored.process_chunk(b"cd");
+        restored.finish();
+        assert_eq!(counts(&restored), [(ab, 0), (cd, 0)]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let build = |timing| {
+            let mut matcher =
+                StreamMatcher::with_config(MatcherConfig::new().rate_limit(1, 100).timing(timing));
+            matcher.add_pattern(compile_pattern("ab").unwrap());
+            matcher.add_pattern(compile_pattern("xyz").unwrap());
+            matcher
+        };
+        let mut matcher = build(true);
+        // The second "ab" is over the rate limit
+        matcher.process_chunk(b"abab");
+        assert_eq!(matcher.process_byte(b'x'), ControlFlow::Continue(()));
+        let stats = matcher.stats();
+        let counts = (stats.bytes_processed, stats.chunks_processed);
+        assert_eq!((counts, stats.matches_reported, stats.matches_suppressed), ((5, 2), 1, 1));
+        assert_eq!(stats.active_patterns, 1);
+        assert!(stats.processing_time > Duration::ZERO);
+
+        // Other streams count too, closed or not, and nothing is cleared at the end of
+        // a stream
+        let other = matcher.open_stream();
+        matcher.process_chunk_for(other, b"ab").unwrap();
+        matcher.close_stream(other).unwrap();
+        let other = matcher.open_stream();
+        matcher.process_chunk_for(other, b"xy").unwrap();
+        matcher.process_chunk_collect(b"yz");
+        matcher.finish();
+        let stats = matcher.stats();
+        let counts = (stats.bytes_processed, stats.chunks_processed);
+        assert_eq!((counts, stats.matches_reported, stats.matches_suppressed), ((11, 5), 3, 1));
+        assert_eq!(stats.active_patterns, 1);
+        matcher.reset();
+        assert_eq!(matcher.stats().matches_reported, 3);
+
+        let mut untimed = build(false);
+        untimed.process_chunk(b"abab");
+        assert_eq!(untimed.stats().processing_time, Duration::ZERO);
+        // Partway in the automaton of compile as well
+        untimed.compile().unwrap();
+        untimed.process_chunk(b"xy");
+        assert_eq!(untimed.stats().active_patterns, 1);
+    }
+
+    #[test]
+    fn test_chunk_budget() {
+        let ends = Arc::new(Mutex::new(Vec::new()));
+        let build = |budget, over| {
+            let sink = ends.clone();
+            let config = MatcherConfig::new().chunk_budget(budget, over);
+            let mut matcher = StreamMatcher::with_config(config);
+            matcher.add_pattern(compile_pattern("ab").unwrap());
+            matcher.add_match_callback(move |event| sink.lock().unwrap().push(event.end));
+            matcher
+        };
+        let data = [b"x".repeat(1000), b"ab".to_vec(), b"x".repeat(1000), b"ab".to_vec()].concat();
+
+        // Out of time after the first check, a kilobyte in, and ready to resume from there
+        let mut closed = build(Duration::ZERO, OverBudget::Fail);
+        assert_eq!(closed.process_chunk(&data), 1024);
+        assert_eq!(closed.unscanned(), Some(1024..2004));
+        assert_eq!(closed.process_chunk(&data[1024..]), 980);
+        assert_eq!((closed.unscanned(), closed.bytes_processed()), (None, 2004));
+        assert_eq!(*ends.lock().unwrap(), [1002, 2004]);
+        let other = closed.open_stream();
+        let failed = closed.process_chunk_for(other, &data);
+        assert!(matches!(failed, Err(Error::BudgetExceeded { scanned: 1024, offset: 1024 })));
+
+        // The rest is skipped, and no match spans the gap
+        ends.lock().unwrap().clear();
+        let mut open = build(Duration::ZERO, OverBudget::Skip);
+        let straddling = [b"x".repeat(1023), b"ab".to_vec()].concat();
+        assert_eq!(open.process_chunk(&straddling), 1025);
+        assert_eq!(open.unscanned(), Some(1024..1025));
+        open.process_chunk(b"b ab");
+        assert_eq!((open.unscanned(), open.bytes_processed()), (None, 1029));
+        assert_eq!(*ends.lock().unwrap(), [1029]);
+
+        let mut ample = build(Duration::from_secs(3600), OverBudget::Fail);
+        assert_eq!(ample.process_chunk(&data), data.len());
+        assert_eq!(ample.unscanned(), None);
+    }
+
+    #[test]
+    fn test_priority_window() {
+        let matcher = |compiled: bool, window| {
+            let config = MatcherConfig::new().priority_window(window);
+            let mut matcher = StreamMatcher::with_config(config);
+            matcher.add_pattern(compile_pattern("SELECT.*FROM").unwrap());
+            let specific = compile_pattern("SELECT.*FROM users WHERE admin").unwrap();
+            matcher.add_pattern_with_priority(specific, 10);
+            matcher.add_pattern_with_priority(compile_pattern("users").unwrap(), 1);
+            matcher.add_pattern(compile_pattern("rs WH").unwrap());
+            matcher.add_pattern(compile_pattern("DROP").unwrap());
+            if compiled {
+                matcher.compile().unwrap();
+            }
+            matcher
+        };
+        let mut input = b"xx SELECT * FROM users WHERE admin".to_vec();
+        input.extend_from_slice(&[b' '; 100]);
+        input.extend_from_slice(b"SELECT id FROM t DROP users WHERE");
+        let all = matcher(false, 0).process_chunk_collect(&input);
+        let found: Vec<_> = all.iter().map(|found| (found.pattern, found.end)).collect();
+        let first = [(0, 16), (2, 22), (3, 25), (1, 34)];
+        assert_eq!(found, [&first[..], &[(0, 148), (4, 155), (2, 161), (3, 164)]].concat());
+        // The specific rule wins over the others it may overlap, whose starts are taken
+        // to be up to the window before their end. Past the window, the generic rule is
+        // back, and "users" wins over "rs WH" overlapping it, but not over "DROP"
+        let expected: Vec<_> = [3, 4, 5, 6].iter().map(|&index| all[index]).collect();
+
+        for compiled in [false, true] {
+            let mut whole = matcher(compiled, 64);
+            let mut matches = whole.process_chunk_collect(&input[..80]);
+            assert_eq!(matches, Vec::new());
+            whole.process_chunk_into(&input[80..], &mut matches);
+            whole.finish_into(&mut matches);
+            assert_eq!(matches, expected);
+
+            // Held matches carry over into a restored stream
+            let mut chunked = matcher(compiled, 64);
+            let mut matches = Vec::new();
+            for chunk in input.chunks(7) {
+                chunked.process_chunk_into(chunk, &mut matches);
+                let mut restored = matcher(compiled, 64);
+                restored.restore_state(&chunked.save_state()).unwrap();
+                chunked = restored;
+            }
+            // The last match of "rs WH" is still held, so not counted yet
+            let counts = chunked.match_counts();
+            let suppressed: Vec<_> = counts.iter().map(|counts| counts.suppressed).collect();
+            assert_eq!(suppressed, [1, 0, 1, 1, 0]);
+            chunked.finish_into(&mut matches);
+            assert_eq!(matches, expected);
+        }
+    }
+
+    #[test]
+    fn test_context_bytes() {
+        type Seen = Arc<Mutex<Vec<(u64, Vec<u8>)>>>;
+        let matcher = |config: MatcherConfig, seen: &Seen| {
+            let mut matcher = StreamMatcher::with_config(config.context_bytes(4, 3));
+            matcher.add_pattern(compile_pattern("ab").unwrap());
+            matcher.add_pattern(compile_pattern("cve").unwrap());
+            let sink = seen.clone();
+            matcher.add_match_callback(move |event| {
+                sink.lock().unwrap().push((event.end, event.context.to_vec()));
+            });
+            matcher
+        };
+        let input = b"ab cd cve-2024 x cve";
+        let context = |from: usize, to: usize| input[from..to].to_vec();
+        // Fewer bytes where the stream starts and ends
+        let expected = [(2, context(0, 5)), (9, context(5, 12)), (20, context(16, 20))];
+
+        for size in [1, 3, 8, input.len()] {
+            let seen = Seen::default();
+            let mut chunked = matcher(MatcherConfig::new(), &seen);
+            for (index, chunk) in input.chunks(size).enumerate() {
+                chunked.process_chunk(chunk);
+                // The ring and the matches waiting for their context carry over
+                let mut restored = matcher(MatcherConfig::new(), &seen);
+                restored.restore_state(&chunked.save_state()).unwrap();
+                if index % 2 == 0 {
+                    chunked = restored;
+                }
+            }
+            // A match waits for the bytes after it
+            assert_eq!(*seen.lock().unwrap(), expected[..2], "chunks of {}", size);
+            let events = chunked.finish();
+            assert_eq!((events[0].end, events[0].context), (20, &expected[2].1[..]));
+            assert_eq!(*seen.lock().unwrap(), expected, "chunks of {}", size);
+        }
+
+        // Matches held back for coalescing still get their context, with the second "cve"
+        // coalesced into the first
+        let seen = Seen::default();
+        let mut coalescing = matcher(MatcherConfig::new().coalesce_within(12), &seen);
+        for byte in input {
+            coalescing.process_chunk(&[*byte]);
+        }
+        coalescing.finish();
+        assert_eq!(*seen.lock().unwrap(), [(2, context(0, 5)), (9, context(5, 12))]);
+
+        // The ring is allocated with the stream
+        let plain = StreamMatcher::new().memory_usage();
+        let config = MatcherConfig::new().context_bytes(1000, 24);
+        let mut with_context = StreamMatcher::with_config(config);
+        assert!(with_context.memory_usage() >= plain + 1024);
+        with_context.open_stream();
+        assert!(with_context.memory_usage() >= plain + 2048);
+    }
+
+    #[test]
+    fn test_nondeterministic_patterns() {
+        // Automatons where a byte leads from one state to several
+        let words = |builder: &mut PatternBuilder| {
+            // GET|GEM|POST|PUT as one tagged branch per word
+            for (tag, word) in [&b"GET"[..], b"GEM", b"POST", b"PUT"].into_iter().enumerate() {
+                let (&last, prefix) = word.split_last().unwrap();
+                let from = builder.append_literal(0, prefix).unwrap();
+                let end = builder.add_state_tagged(tag as u32);
+                builder.add_transition(from, last, end).unwrap();
+            }
+        };
+        let gap = |builder: &mut PatternBuilder| {
+            // a[^\n]*b, where a 'b' both ends a match and continues the gap
+            let (body, end) = (builder.add_state(false), builder.add_state(true));
+            builder.add_transition(0, b'a', body).unwrap();
+            builder.add_negated_transition(body, b"\n", body).unwrap();
+            builder.add_transition(body, b'b', body).unwrap();
+            builder.add_transition(body, b'b', end).unwrap();
+        };
+        let branches = |builder: &mut PatternBuilder| {
+            // (a|ab)c through epsilon transitions
+            let states: Vec<usize> = (1..=5).map(|state| builder.add_state(state == 5)).collect();
+            let [a, after_a, ab, after_ab, end] = states[..] else {
+                unreachable!()
+            };
+            builder.add_epsilon_transition(0, a).unwrap().add_epsilon_transition(0, ab).unwrap();
+            builder.add_transition(a, b'a', after_a).unwrap();
+            builder.add_transition(after_a, b'c', end).unwrap();
+            builder.add_transition(ab, b'a', after_ab).unwrap();
+            builder.add_transition(after_ab, b'b', after_a).unwrap();
+        };
+        let at_end = |builder: &mut PatternBuilder| {
+            // x[xy]*y$
+            let (body, end) = (builder.add_state(false), builder.add_state(false));
+            builder.add_transition(0, b'x', body).unwrap();
+            builder.add_transition_set(body, b"xy", body).unwrap();
+            builder.add_transition(body, b'y', end).unwrap().set_accepts_at_end(end).unwrap();
+        };
+        let suffix = |builder: &mut PatternBuilder| {
+            // [ab]*a[ab]{8}, which determinized has to remember the last nine bytes
+            let mut last = builder.add_state(false);
+            builder.add_transition_set(0, b"ab", 0).unwrap();
+            builder.add_transition(0, b'a', last).unwrap();
+            for index in 0..8 {
+                let next = builder.add_state(index == 7);
+                builder.add_transition_set(last, b"ab", next).unwrap();
+                last = next;
+            }
+        };
+
+        let mut seed = 7u32;
+        let noise: Vec<u8> = (0..4000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                b"abcxyGEMTPOSU\n "[(seed >> 16) as usize % 15]
+            })
+            .collect();
+        let inputs: [&[u8]; 5] = [
+            b"GET GEM GEX POST PUT PAT POS PUTT GEGET",
+            b"abcb ab\nb aac abc",
+            b"xxyxy",
+            b"abaabbbabababbbaab",
+            &noise,
+        ];
+        // Chunked, and twice in a row to check that finish starts over
+        let run = |pattern: Pattern, input: &[u8]| {
+            let mut matcher = StreamMatcher::new();
+            matcher.add_pattern(pattern);
+            let mut matches = Vec::new();
+            for _ in 0..2 {
+                for chunk in input.chunks(7) {
+                    matcher.process_chunk_into(chunk, &mut matches);
+                }
+                matcher.finish_into(&mut matches);
+            }
+            matches
+        };
+
+        let automatons: [&dyn Fn(&mut PatternBuilder); 5] =
+            [&words, &gap, &branches, &at_end, &suffix];
+        let mut found = 0;
+        for (add, anchored) in automatons.iter().flat_map(|add| [(add, false), (add, true)]) {
+            let build = |determinize: bool| {
+                let mut builder = PatternBuilder::new();
+                add(&mut builder);
+                builder.anchored(anchored).determinize(determinize);
+                builder.build("automaton".to_string()).unwrap()
+            };
+            let (determinized, nondeterministic) = (build(true), build(false));
+            assert!(!determinized.is_nondeterministic());
+            assert!(nondeterministic.is_nondeterministic());
+            for input in inputs {
+                let expected = run(determinized.clone(), input);
+                assert_eq!(run(nondeterministic.clone(), input), expected, "{:?}", input);
+                found += expected.len();
+            }
+        }
+        assert!(found > 100, "{}", found);
+
+        // Kept nondeterministic, the pattern stays within a state limit that its
+        // determinized form exceeds
+        let mut builder = PatternBuilder::new();
+        suffix(&mut builder);
+        builder.state_limit(100);
+        let determinized = builder.build_ref("suffix".to_string());
+        assert!(matches!(determinized, Err(Error::PatternTooComplex(_))));
+        builder.determinize(false);
+        let pattern = builder.build("suffix".to_string()).unwrap();
+        assert_eq!(pattern.state_count(), 10);
+        assert!(pattern.reverse().is_err());
+    }
+
+    #[test]
+    fn test_compile() {
+        let literals: [(&str, &[u8]); 3] = [("he", b"he"), ("she", b"she"), ("hers", b"hers")];
+        let patterns = || {
+            vec![
+                compile_pattern("a[0-9]+b$").unwrap(),
+                compile_pattern("cve").unwrap(),
+                compile_pattern("^GET").unwrap(),
+                compile_pattern(r"foo\b").unwrap(),
+                compile_pattern("x.*y").unwrap(),
+                PatternBuilder::from_literals(&literals).unwrap(),
+            ]
+        };
+        let mut seed = 11u32;
+        let noise: Vec<u8> = (0..4000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                b"a0b\ncvefoGETxyshr "[(seed >> 16) as usize % 18]
+            })
+            .collect();
+        let inputs: [&[u8]; 3] = [b"GET cve a12b\nfoo fool ushers x..y", b"a1b", &noise];
+        let config = MatcherConfig::new().max_matches_per_pattern(300);
+        let run = |compiled: bool, input: &[u8]| {
+            let mut matcher = StreamMatcher::with_config(config);
+            for pattern in patterns() {
+                matcher.add_pattern(pattern);
+            }
+            if compiled {
+                matcher.compile().unwrap();
+                assert!(matcher.combined_state_count().is_some());
+            }
+            let mut matches = Vec::new();
+            for chunk in input.chunks(5) {
+                matcher.process_chunk_into(chunk, &mut matches);
+            }
+            matcher.finish_into(&mut matches);
+            matches
+        };
+        for input in inputs {
+            assert_eq!(run(true, input), run(false, input));
+        }
+
+        // Compiling mid-stream carries on, and adding a pattern rebuilds the automaton
+        let mut matcher = StreamMatcher::with_config(config);
+        let mut expected = StreamMatcher::with_config(config);
+        for pattern in patterns() {
+            matcher.add_pattern(pattern.clone());
+            expected.add_pattern(pattern);
+        }
+        let (first, second) = noise.split_at(2000);
+        let mut matches = matcher.process_chunk_collect(first);
+        matcher.compile().unwrap();
+        matches.extend(matcher.process_chunk_collect(second));
+        matcher.add_pattern(compile_pattern("ab").unwrap());
+        assert_eq!(matcher.combined_state_count(), None);
+        matches.extend(matcher.process_chunk_collect(first));
+        assert!(matcher.combined_state_count().is_some());
+        let mut all = expected.process_chunk_collect(&noise);
+        expected.add_pattern(compile_pattern("ab").unwrap());
+        all.extend(expected.process_chunk_collect(first));
+        assert_eq!(matches, all);
+        let first = matcher.find_first(&b"xxy"[..]).unwrap();
+        assert_eq!(first, expected.find_first(&b"xxy"[..]).unwrap());
+
+        // Beyond the state limit, or with a nondeterministic pattern, the patterns keep
+        // running one by one
+        let mut matcher = StreamMatcher::with_config(MatcherConfig::new().combined_state_limit(3));
+        for pattern in patterns() {
+            matcher.add_pattern(pattern);
+        }
+        assert!(matches!(matcher.compile(), Err(Error::PatternTooComplex(_))));
+        assert_eq!(matcher.combined_state_count(), None);
+        let mut builder = PatternBuilder::new();
+        let (a, aa) = (builder.add_state(true), builder.add_state(false));
+        builder.add_transition(0, b'a', a).unwrap().add_transition(0, b'a', aa).unwrap();
+        builder.determinize(false);
+        let mut matcher = StreamMatcher::new();
+        matcher.add_pattern(builder.build("a".to_string()).unwrap());
+        assert!(matches!(matcher.compile(), Err(Error::InvalidPattern(_))));
+        assert_eq!(matcher.process_chunk_collect(b"aa").len(), 2);
+    }
+
+    #[test]
+    fn test_literal_patterns() {
+        let literals: [(&str, &[u8]); 2] = [("he", b"he"), ("hers", b"hers")];
+        let patterns = |routed: bool| {
+            let patterns = [
+                "she", "he", "a0", "hers", "ershe", "cve", "she", "aa", r"foo\b", "x.*y",
+                "^GET", "a[0-9]+b$",
+            ];
+            let mut patterns: Vec<_> =
+                patterns.iter().map(|pattern| compile_pattern(pattern).unwrap()).collect();
+            patterns.push(PatternBuilder::from_literals(&literals).unwrap());
+            for pattern in patterns.iter_mut().filter(|_| !routed) {
+                pattern.kind = crate::pattern::PatternKind::Automaton;
+            }
+            patterns
+        };
+        let mut seed = 5u32;
+        let noise: Vec<u8> = (0..6000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                b"a0b\ncvefoGETxyshr "[(seed >> 16) as usize % 18]
+            })
+            .collect();
+        let config = MatcherConfig::new().max_matches_per_pattern(400);
+        let new_matcher = |routed: bool| {
+            let mut matcher = StreamMatcher::with_config(config);
+            for pattern in patterns(routed) {
+                matcher.add_pattern(pattern);
+            }
+            matcher
+        };
+
+        // Literals added, removed or compiled in the middle of a stream
+        let run = |routed: bool, input: &[u8]| {
+            let mut matcher = new_matcher(routed);
+            let mut matches = Vec::new();
+            let (first, rest) = input.split_at(input.len() / 4);
+            matcher.process_chunk_into(first, &mut matches);
+            if routed {
+                assert_eq!(matcher.database.unrouted.len(), 5);
+            }
+            let (second, rest) = rest.split_at(rest.len() / 3);
+            matcher.add_pattern(compile_pattern("sh").unwrap().with_metadata("a", "b"));
+            for chunk in second.chunks(7) {
+                matcher.process_chunk_into(chunk, &mut matches);
+            }
+            matcher.remove_pattern("she");
+            let (third, rest) = rest.split_at(rest.len() / 2);
+            matcher.process_chunk_into(third, &mut matches);
+            matcher.compile().unwrap();
+            matcher.process_chunk_into(rest, &mut matches);
+            matcher.finish_into(&mut matches);
+            matches
+        };
+        let inputs: [&[u8]; 3] = [b"ushers she said a0a0b\nfoo GET cve", b"hershe", &noise];
+        for input in inputs {
+            assert_eq!(run(true, input), run(false, input));
+        }
+        let matches = new_matcher(true).process_chunk_collect(&noise);
+        assert_eq!(matches, new_matcher(false).process_chunk_collect(&noise));
+        assert!(matches.len() > 50);
+
+        // Callbacks get the same events, and the literals join again once nothing
+        // is partly matched
+        let events = |routed: bool| {
+            let mut matcher = new_matcher(routed);
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let sink = events.clone();
+            matcher.add_match_callback(move |event| {
+                sink.lock().unwrap().push((event.to_match(), event.name.to_string()));
+            });
+            matcher.process_chunk(b"xxsh");
+            matcher.add_pattern(compile_pattern("hex").unwrap());
+            matcher.process_chunk(b"e");
+            if routed {
+                assert_eq!(matcher.database.unrouted.len(), 6);
+            }
+            matcher.process_chunk(b"x. hexy he");
+            if routed {
+                assert_eq!(matcher.database.unrouted.len(), 5);
+            }
+            drop(matcher);
+            Arc::try_unwrap(events).unwrap().into_inner().unwrap()
+        };
+        assert_eq!(events(true), events(false));
+    }
+
+    #[test]
+    fn test_prefilter() {
+        let patterns = ["\\x7fELF", "MZ", "<scr", "^GET", "MZ\\x90", "(?i)<sC"];
+        let new_matcher = || {
+            let mut matcher = StreamMatcher::new();
+            for pattern in patterns {
+                matcher.add_pattern(compile_pattern(pattern).unwrap());
+            }
+            matcher
+        };
+        let mut matcher = new_matcher();
+        assert_eq!(matcher.prefilter(), Some(&b"<M\x7f"[..]));
+
+        // Partial matches carry across chunks, and anchored patterns still get the start
+        let chunks: [&[u8]; 4] = [b"GET xxM", b"Z\x90yy<sc", b"r\x7fEL", b"F"];
+        let mut matches = Vec::new();
+        for chunk in chunks {
+            matcher.process_chunk_into(chunk, &mut matches);
+        }
+        let ends: Vec<_> = matches.iter().map(|found| (found.pattern, found.end)).collect();
+        assert_eq!(ends, vec![(3, 3), (1, 8), (4, 9), (5, 14), (2, 15), (0, 19)]);
+
+        // Skipping finds what stepping through every byte finds
+        let mut seed = 3u32;
+        let noise: Vec<u8> = (0..20_000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                b"MZ\x90<sCr\x7fELF GET.x"[(seed >> 16) as usize % 16]
+            })
+            .collect();
+        let mut expected = new_matcher();
+        expected.database.prefilter = None;
+        let mut matcher = new_matcher();
+        let mut matches = Vec::new();
+        for chunk in noise.chunks(5) {
+            matcher.process_chunk_into(chunk, &mut matches);
+        }
+        assert_eq!(matches, expected.process_chunk_collect(&noise));
+        assert!(matches.len() > 50);
+        matcher.reset();
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        matcher.add_callback(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(matcher.process_chunk(&noise), noise.len());
+        assert_eq!(count.load(Ordering::Relaxed), matches.len());
+        let mut matcher = new_matcher();
+        let first = matcher.find_first(&noise[100..]).unwrap();
+        assert_eq!(first, expected.find_first(&noise[100..]).unwrap());
+
+        // Patterns that can start with many bytes turn it off
+        let mut matcher = new_matcher();
+        matcher.add_pattern(compile_pattern("[a-z]+x").unwrap());
+        assert_eq!(matcher.prefilter(), None);
+        matcher.remove_pattern("[a-z]+x");
+        matcher.remove_pattern("<scr");
+        assert_eq!(matcher.prefilter(), Some(&b"<M\x7f"[..]));
+        matcher.remove_pattern("(?i)<sC");
+        assert_eq!(matcher.prefilter(), Some(&b"M\x7f"[..]));
+    }
+
+    #[test]
+    fn test_literal_prefilter() {
+        let patterns =
+            ["GET /admin", "POST", "\\x7fELF", "MZ\\x90", "<script", "eval\\(", "^HTTP"];
+        let new_matcher = || {
+            let mut matcher = StreamMatcher::new();
+            for pattern in patterns {
+                matcher.add_pattern(compile_pattern(pattern).unwrap());
+            }
+            matcher
+        };
+        let mut matcher = new_matcher();
+        assert_eq!(matcher.prefilter(), None);
+        assert!(matcher.literal_prefilter().is_some());
+
+        // Partial matches carry across chunks
+        let chunks: [&[u8]; 4] = [b"HTTP/1.1 PO", b"ST eval", b"(x) GET", b" /admin"];
+        let mut matches = Vec::new();
+        for chunk in chunks {
+            matcher.process_chunk_into(chunk, &mut matches);
+        }
+        let ends: Vec<_> = matches.iter().map(|found| (found.pattern, found.end)).collect();
+        assert_eq!(ends, vec![(6, 4), (1, 13), (5, 19), (0, 32)]);
+
+        // Literals, their starts and other bytes in random order
+        let tokens: [&[u8]; 12] = [
+            b"GET /admin", b"POST", b"\x7fELF", b"MZ\x90", b"<script", b"eval(", b"GET", b"PO",
+            b"\x7f", b"<scr", b" ", b"xyz",
+        ];
+        let mut seed = 7u32;
+        let noise: Vec<u8> = (0..5000)
+            .flat_map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                tokens[(seed >> 16) as usize % tokens.len()].iter().copied()
+            })
+            .collect();
+        let mut expected = new_matcher();
+        expected.database.prefilter = None;
+        let mut matcher = new_matcher();
+        let mut matches = Vec::new();
+        for chunk in noise.chunks(7) {
+            matcher.process_chunk_into(chunk, &mut matches);
+        }
+        assert_eq!(matches, expected.process_chunk_collect(&noise));
+        assert!(matches.len() > 1000);
+
+        // A pattern that is not a literal turns it off
+        matcher.add_pattern(compile_pattern("a+b").unwrap());
+        assert_eq!(matcher.literal_prefilter(), None);
+        matcher.remove_pattern("a+b");
+        assert!(matcher.literal_prefilter().is_some());
+    }
+
+    #[test]
+    fn test_open_streams() {
+        let literals: [(&str, &[u8]); 3] = [("he", b"he"), ("she", b"she"), ("hers", b"hers")];
+        let patterns = || {
+            vec![
+                compile_pattern("a[0-9]+b$").unwrap(),
+                compile_pattern("cve").unwrap(),
+                compile_pattern(r"foo\b").unwrap(),
+                compile_pattern("x.*y").unwrap(),
+                PatternBuilder::from_literals(&literals).unwrap(),
+            ]
+        };
+        let inputs: Vec<Vec<u8>> = (0..3u32)
+            .map(|mut seed| {
+                let mut input: Vec<u8> = (0..3000)
+                    .map(|_| {
+                        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                        b"a0b\ncvefoxyshr "[(seed >> 16) as usize % 15]
+                    })
+                    .collect();
+                input.extend_from_slice(b"a12b");
+                input
+            })
+            .collect();
+        // Patterns change after the 100th and compile after the 200th chunk of a stream
+        let change = |matcher: &mut StreamMatcher, round: usize| match round {
+            100 => {
+                matcher.add_pattern(compile_pattern("ab").unwrap());
+                matcher.remove_pattern("cve");
+            }
+            200 => matcher.compile().unwrap(),
+            _ => {}
+        };
+        let expected: Vec<Vec<Match>> = inputs
+            .iter()
+            .map(|input| {
+                let mut matcher = StreamMatcher::new();
+                for pattern in patterns() {
+                    matcher.add_pattern(pattern);
+                }
+                let mut matches = Vec::new();
+                for (round, chunk) in input.chunks(7).enumerate() {
+                    change(&mut matcher, round);
+                    matcher.process_chunk_into(chunk, &mut matches);
+                }
+                matcher.finish_into(&mut matches);
+                matches
+            })
+            .collect();
+
+        // The matcher's own stream runs the first input, two opened ones the others
+        let mut events = Vec::new();
+        {
+            let mut matcher = StreamMatcher::new();
+            for pattern in patterns() {
+                matcher.add_pattern(pattern);
+            }
+            matcher.add_match_callback(|event| events.push((event.stream, event.to_match())));
+            let ids = [StreamId::MAIN, matcher.open_stream(), matcher.open_stream()];
+            let chunks: Vec<Vec<&[u8]>> =
+                inputs.iter().map(|input| input.chunks(7).collect()).collect();
+            for round in 0..chunks[0].len() {
+                change(&mut matcher, round);
+                assert_eq!(matcher.process_chunk(chunks[0][round]), chunks[0][round].len());
+                for (&id, chunks) in ids.iter().zip(&chunks).skip(1) {
+                    let consumed = matcher.process_chunk_for(id, chunks[round]).unwrap();
+                    assert_eq!(consumed, chunks[round].len());
+                }
+            }
+            assert!(matcher.combined_state_count().is_some());
+            matcher.finish();
+            matcher.close_stream(ids[1]).unwrap();
+            matcher.close_stream(ids[2]).unwrap();
+            assert!(matches!(matcher.close_stream(ids[1]), Err(Error::UnknownStream(_))));
+            let closed = matcher.process_chunk_for(ids[2], b"cve");
+            assert!(matches!(closed, Err(Error::UnknownStream(id)) if id == ids[2]));
+            assert_ne!(ids[1], ids[2]);
+        }
+        for (index, expected) in expected.iter().enumerate() {
+            let stream = events.iter().filter(|&&(stream, _)| stream == StreamId(index as u64));
+            let found: Vec<Match> = stream.map(|&(_, found)| found).collect();
+            // The end of each stream completes a match of a[0-9]+b$
+            assert_eq!(found.last().map(|found| found.end), Some(3004));
+            assert_eq!(&found, expected);
+        }
+    }
+
+    #[test]
+    fn test_finish() {
+        for compiled in [false, true] {
+            let mut absent = Vec::new();
+            let mut matcher = StreamMatcher::new();
+            let cve = matcher.add_pattern(compile_pattern("cve").unwrap());
+            let done = matcher.add_pattern(compile_pattern("done$").unwrap());
+            let heartbeat = matcher.add_negative_pattern(compile_pattern("heartbeat").unwrap());
+            let digits = matcher.add_negative_pattern(compile_pattern("x[0-9]+y").unwrap());
+            let end = matcher.add_negative_pattern(compile_pattern("end$").unwrap());
+            assert!(matcher.patterns().nth(2).unwrap().is_negative());
+            matcher.add_match_callback(|event| {
+                if event.absent {
+                    absent.push((event.pattern, event.end));
+                }
+            });
+            if compiled {
+                matcher.compile().unwrap();
+            }
+
+            // Negative patterns that matched stay quiet, the others are absent at the end
+            let matches = matcher.process_chunk_collect(b"cve heartbeat x12y done");
+            assert_eq!(matches.iter().map(|found| found.pattern).collect::<Vec<_>>(), [cve]);
+            let events = matcher.finish();
+            let found: Vec<_> = events.iter().map(|event| (event.pattern, event.absent)).collect();
+            assert_eq!(found, [(done, false), (end, true)]);
+            assert_eq!((events[1].name, events[1].end, events[1].start), ("end$", 23, None));
+
+            // The next chunk starts a new stream, where nothing was seen yet
+            assert_eq!(matcher.bytes_processed(), 0);
+            assert_eq!(matcher.process_chunk_collect(b"xy cve")[0].end, 6);
+            let events = matcher.finish();
+            let found: Vec<_> = events.iter().map(|event| event.pattern).collect();
+            assert_eq!(found, [heartbeat, digits, end]);
+            let mut matches = Vec::new();
+            matcher.process_chunk_into(b"the end", &mut matches);
+            matcher.finish_into(&mut matches);
+            let found: Vec<_> = matches.iter().map(|found| (found.pattern, found.end)).collect();
+            assert_eq!(found, [(heartbeat, 7), (digits, 7)]);
+            assert_eq!(matcher.find_first(&b"heartbeat"[..]).unwrap(), None);
+            drop(matcher);
+            assert_eq!(absent, [(end, 23), (heartbeat, 6), (digits, 6), (end, 6)]);
+        }
+    }
+
+    #[test]
+    fn test_anchored_mode() {
+        let patterns = || {
+            vec![
+                compile_pattern("SSH-").unwrap(),
+                compile_pattern(r"HTTP/1\.[01] ").unwrap(),
+                compile_pattern("^GET").unwrap(),
+                compile_pattern("a[bc]").unwrap(),
+                compile_pattern_with("x[yz]", &CompileOptions::new().anchored(true)).unwrap(),
+            ]
+        };
+        let run = |config: MatcherConfig, chunks: &[&[u8]]| {
+            let mut matcher = StreamMatcher::with_config(config);
+            for pattern in patterns() {
+                matcher.add_pattern(pattern);
+            }
+            let mut collected = Vec::new();
+            let mut expected = StreamMatcher::with_config(config);
+            for pattern in patterns() {
+                expected.add_pattern(pattern);
+            }
+            for chunk in chunks {
+                matcher.process_chunk_into(chunk, &mut collected);
+            }
+            let found = expected.process_chunk_collect(&chunks.concat());
+            assert_eq!(collected, found);
+            assert_eq!(matcher.bytes_processed(), chunks.concat().len() as u64);
+            let names = found.iter().map(|found| (matcher.pattern_name(found.pattern), found.end));
+            names.map(|(name, end)| (name.unwrap().to_string(), end)).collect::<Vec<_>>()
+        };
+        let anchored = MatcherConfig::new().anchored(true);
+        let names = |found: &[(&str, u64)]| {
+            found.iter().map(|&(name, end)| (name.to_string(), end)).collect::<Vec<_>>()
+        };
+
+        // Only matches from the start of the stream count, across chunks too
+        let chunks: [&[u8]; 3] = [b"SS", b"H-2.0 SSH-", b"HTTP/1.1 "];
+        assert_eq!(run(anchored, &chunks), names(&[("SSH-", 4)]));
+        let unanchored = run(MatcherConfig::new(), &chunks);
+        assert_eq!(unanchored, names(&[("SSH-", 4), ("SSH-", 12), (r"HTTP/1\.[01] ", 21)]));
+        assert_eq!(run(anchored, &[b"HTTP/1.0 GET"]), names(&[(r"HTTP/1\.[01] ", 9)]));
+        assert_eq!(run(anchored, &[b"xSSH-HTTP/1.0 "]), names(&[]));
+        assert_eq!(run(anchored, &[b"GETa", b"b"]), names(&[("^GET", 3)]));
+
+        // No match starts after the start, even while an attempt from it is in progress
+        assert_eq!(run(anchored, &[b"aab xxy"]), names(&[]));
+        assert_eq!(run(anchored, &[b"xxy aab"]), names(&[]));
+        assert_eq!(run(anchored, &[b"a", b"c"]), names(&[("a[bc]", 2)]));
+        let mut matcher = StreamMatcher::with_config(anchored);
+        matcher.add_pattern(compile_pattern("x[0-9]+y").unwrap());
+        assert!(matcher.process_chunk_collect(b"xx1y").is_empty());
+        matcher.finish();
+        let found = matcher.process_chunk_collect(b"x12y");
+        assert_eq!(found.iter().map(|found| found.end).collect::<Vec<_>>(), [4]);
+
+        // Once every pattern has died, the rest of the stream is skipped
+        let mut matcher = StreamMatcher::with_config(anchored);
+        matcher.add_pattern(compile_pattern("SSH-").unwrap());
+        matcher.add_pattern(compile_pattern("a[0-9]+b").unwrap());
+        assert_eq!(matcher.process_chunk_collect(b"a1"), Vec::new());
+        assert!(!matcher.stream.progress.idle);
+        assert_eq!(matcher.process_chunk(b"x"), 1);
+        assert!(matcher.stream.progress.idle);
+        assert_eq!(matcher.process_chunk_collect(b"SSH-a1b"), Vec::new());
+        assert_eq!(matcher.bytes_processed(), 10);
+        matcher.finish();
+        assert_eq!(matcher.process_chunk_collect(b"SSH-").len(), 1);
+    }
+
+    #[test]
+    fn test_ascii_case_insensitive() {
+        let caseless = MatcherConfig::new().ascii_case_insensitive(true);
+        let mut matcher = StreamMatcher::with_config(caseless);
+        let select = matcher.add_pattern(compile_pattern("select").unwrap());
+        assert_eq!(matcher.prefilter(), Some(&b"Ss"[..]));
+        let found = matcher.process_chunk_collect(b"SeLeCt * from t; SELECT; select");
+        assert_eq!(found.iter().map(|found| found.end).collect::<Vec<_>>(), [6, 23, 31]);
+        assert_eq!(found[0].pattern, select);
+        let mut matcher = StreamMatcher::new();
+        matcher.add_pattern(compile_pattern("select").unwrap());
+        assert_eq!(matcher.process_chunk_collect(b"SeLeCt").len(), 0);
+
+        // The literal prefilter would miss the literals in other cases
+        for config in [MatcherConfig::new(), caseless] {
+            let mut matcher = StreamMatcher::with_config(config);
+            for literal in ["select", "union", "insert", "delete"] {
+                matcher.add_pattern(compile_pattern(literal).unwrap());
+            }
+            assert_eq!(matcher.literal_prefilter().is_none(), config.ascii_case_insensitive);
+        }
+
+        // Any way of running the patterns finds in the stream what they find lowercased
+        let patterns = || {
+            ["select", "union", "insert", "delete", r"drop\s+table", "x[0-9]+y", "SELECT"]
+                .map(|pattern| compile_pattern(pattern).unwrap())
+        };
+        let words: [&[u8]; 10] = [
+            b"SeLeCt", b"UNION", b"union", b"Inse", b"rt", b"dElEtE", b"drop  TABLE", b"x12Y",
+            b"\xc4 ", b" ",
+        ];
+        let mut seed = 3u32;
+        let input: Vec<u8> = (0..600)
+            .flat_map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                words[(seed >> 16) as usize % words.len()].iter().copied()
+            })
+            .collect();
+        let mut expected = StreamMatcher::new();
+        for pattern in patterns() {
+            expected.add_pattern(pattern);
+        }
+        let expected = expected.process_chunk_collect(&input.to_ascii_lowercase());
+        assert!(expected.len() > 20);
+        for compiled in [false, true] {
+            let found = Mutex::new(Vec::new());
+            let mut matcher = StreamMatcher::with_config(caseless);
+            for pattern in patterns() {
+                matcher.add_pattern(pattern);
+            }
+            if compiled {
+                matcher.compile().unwrap();
+            }
+            let mut events = Vec::new();
+            for chunk in input.chunks(100) {
+                matcher.process_chunk_into(chunk, &mut events);
+            }
+            assert_eq!(events, expected);
+            matcher.reset();
+            matcher.add_match_callback(|event| found.lock().unwrap().push(event.to_match()));
+            for chunk in input.chunks(100) {
+                matcher.process_chunk(chunk);
+            }
+            drop(matcher);
+            assert_eq!(found.into_inner().unwrap(), expected);
+        }
+    }
+}
@@ -0,0 +1,471 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::byteset::ByteSet;
+use crate::error::Error;
+use crate::parser::{Assertion, Node};
+use crate::pattern::PatternBuilder;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum NfaState {
+    // Consume one byte from the set, then continue at the target
+    Bytes(ByteSet, usize),
+    // Epsilon transitions to every target
+    Split(Vec<usize>),
+    // Epsilon transition that is only taken when the assertion holds
+    Assert(Assertion, usize),
+    Match,
+}
+
+// What an assertion can observe on either side of a stream position
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Context {
+    // Start of the stream (before a position) or end of the stream (after it)
+    Edge,
+    // A byte matching \w; only distinguished when the pattern uses \b or \B
+    Word,
+    // '\n' and '\r'; only distinguished when the pattern uses line anchors
+    LineFeed,
+    CarriageReturn,
+    Byte,
+}
+
+impl Assertion {
+    // `None` when the outcome depends on the byte after the position, which is not known yet
+    fn check(self, before: Context, after: Option<Context>) -> Option<bool> {
+        match self {
+            Assertion::StartText => Some(before == Context::Edge),
+            Assertion::EndText => after.map(|after| after == Context::Edge),
+            // Lines end at "\n", "\r" or "\r\n", so there is no line start between
+            // the '\r' and the '\n' of a "\r\n"
+            Assertion::StartLine => match before {
+                Context::Edge | Context::LineFeed => Some(true),
+                Context::CarriageReturn => after.map(|after| after != Context::LineFeed),
+                _ => Some(false),
+            },
+            Assertion::EndLine => after.map(|after| match after {
+                Context::Edge | Context::CarriageReturn => true,
+                Context::LineFeed => before != Context::CarriageReturn,
+                _ => false,
+            }),
+            Assertion::WordBoundary => {
+                after.map(|after| (before == Context::Word) != (after == Context::Word))
+            }
+            Assertion::NotWordBoundary => {
+                after.map(|after| (before == Context::Word) == (after == Context::Word))
+            }
+        }
+    }
+}
+
+// Thompson NFA built from a syntax tree
+#[derive(Debug)]
+pub(crate) struct Nfa {
+    states: Vec<NfaState>,
+    start: usize,
+    state_limit: usize,
+    // Without assertions the context of a position never matters
+    has_assertions: bool,
+    // Whether word bytes need their own context
+    has_word_boundaries: bool,
+    // Whether '\n' and '\r' need their own contexts
+    has_line_anchors: bool,
+    // Index of every state by its contents, so identical fragments are shared
+    interned: HashMap<NfaState, usize>,
+}
+
+// A state of the determinized automaton
+//
+// Every match attempt still in progress is tracked as the set of NFA states it
+// occupies. Attempts occupying identical sets have identical futures and are
+// merged. An attempt is dropped as soon as it reaches a match, so each start
+// position reports only its earliest match while later, overlapping attempts
+// carry on.
+//
+// Assertions that depend on the following byte stay in the attempt's set until
+// that byte (or the end of the stream) arrives, so matches ending in one are
+// reported one byte late, or by StreamMatcher::finish.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct DfaKey {
+    attempts: BTreeSet<Vec<usize>>,
+    // Context of the last byte consumed
+    before: Context,
+    // A match completed with the byte consumed last
+    matched: bool,
+    // A match completed before the byte consumed last, which only confirmed an
+    // assertion after it, such as a trailing \b. Kept apart from `matched`, as both
+    // can happen on one byte.
+    matched_before: bool,
+}
+
+impl Nfa {
+    // Both the NFA and the determinized automaton are limited to `state_limit` states
+    pub(crate) fn new(node: &Node, state_limit: usize) -> Result<Self, Error> {
+        // Counted repetition is expanded into copies, so check before allocating
+        check_expansion(node_size(node).saturating_add(1), state_limit)?;
+
+        let mut nfa = Nfa {
+            states: vec![NfaState::Match],
+            start: 0,
+            state_limit,
+            has_assertions: false,
+            has_word_boundaries: false,
+            has_line_anchors: false,
+            interned: HashMap::new(),
+        };
+        nfa.start = nfa.compile(node, 0);
+        for state in &nfa.states {
+            if let NfaState::Assert(assertion, _) = state {
+                nfa.has_assertions = true;
+                nfa.has_word_boundaries |= matches!(
+                    assertion,
+                    Assertion::WordBoundary | Assertion::NotWordBoundary
+                );
+                nfa.has_line_anchors |=
+                    matches!(assertion, Assertion::StartLine | Assertion::EndLine);
+            }
+        }
+        Ok(nfa)
+    }
+
+    // Add a state, reusing an identical one
+    // Fragments are built back to front, so this also shares common suffixes, which
+    // keeps the alternatives of large UTF-8 classes from multiplying DFA states.
+    fn push(&mut self, state: NfaState) -> usize {
+        if let Some(&idx) = self.interned.get(&state) {
+            return idx;
+        }
+        self.states.push(state.clone());
+        self.interned.insert(state, self.states.len() - 1);
+        self.states.len() - 1
+    }
+
+    // Build the fragment for `node` back to front, returning its entry state
+    fn compile(&mut self, node: &Node, next: usize) -> usize {
+        match node {
+            Node::Byte(byte) => {
+                let mut set = ByteSet::new();
+                set.insert(*byte);
+                self.push(NfaState::Bytes(set, next))
+            }
+            Node::Class(class) => self.push(NfaState::Bytes(*class, next)),
+            Node::Assert(assertion) => self.push(NfaState::Assert(*assertion, next)),
+            Node::Concat(nodes) => nodes
+                .iter()
+                .rev()
+                .fold(next, |next, node| self.compile(node, next)),
+            Node::Alternate(branches) => {
+                let entries = branches
+                    .iter()
+                    .map(|branch| self.compile(branch, next))
+                    .collect();
+                self.push(NfaState::Split(entries))
+            }
+            Node::Repeat { node, min, max } => {
+                let mut entry = next;
+                match max {
+                    None => {
+                        // Filled in below, so it must not be shared
+                        self.states.push(NfaState::Split(Vec::new()));
+                        let loop_state = self.states.len() - 1;
+                        let body = self.compile(node, loop_state);
+                        self.states[loop_state] = NfaState::Split(vec![body, next]);
+                        entry = loop_state;
+                    }
+                    Some(max) => {
+                        for _ in *min..*max {
+                            let body = self.compile(node, entry);
+                            entry = self.push(NfaState::Split(vec![body, next]));
+                        }
+                    }
+                }
+                for _ in 0..*min {
+                    entry = self.compile(node, entry);
+                }
+                entry
+            }
+        }
+    }
+
+    // Follow epsilon transitions from `seeds` at a position between `before` and `after`
+    // Returns the states left waiting for input and whether a match was reached.
+    // Assertions that need the unknown following byte are kept as waiting states.
+    fn closure(
+        &self,
+        seeds: impl IntoIterator<Item = usize>,
+        before: Context,
+        after: Option<Context>,
+    ) -> (Vec<usize>, bool) {
+        let mut visited = vec![false; self.states.len()];
+        let mut stack: Vec<usize> = seeds.into_iter().collect();
+        let mut waiting = Vec::new();
+        let mut is_match = false;
+
+        while let Some(idx) = stack.pop() {
+            if std::mem::replace(&mut visited[idx], true) {
+                continue;
+            }
+            match &self.states[idx] {
+                NfaState::Bytes(..) => waiting.push(idx),
+                NfaState::Split(targets) => stack.extend(targets.iter().rev()),
+                NfaState::Assert(assertion, next) => match assertion.check(before, after) {
+                    Some(true) => stack.push(*next),
+                    Some(false) => {}
+                    None => waiting.push(idx),
+                },
+                NfaState::Match => is_match = true,
+            }
+        }
+
+        waiting.sort_unstable();
+        (waiting, is_match)
+    }
+
+    // Partition all bytes into classes the NFA cannot tell apart
+    // Returns one representative byte per class and the class of every byte
+    fn byte_classes(&self) -> (Vec<u8>, [usize; 256]) {
+        let mut class_of = [0usize; 256];
+        let mut class_count = 1;
+
+        // Bytes leading to different contexts must not share a class
+        let mut word = ByteSet::new();
+        if self.has_word_boundaries {
+            (0..=255u8)
+                .filter(|&byte| is_word_byte(byte))
+                .for_each(|byte| word.insert(byte));
+        }
+        let (mut line_feed, mut carriage_return) = (ByteSet::new(), ByteSet::new());
+        if self.has_line_anchors {
+            line_feed.insert(b'\n');
+            carriage_return.insert(b'\r');
+        }
+
+        // Large classes repeat the same sets many times
+        let sets: HashSet<&ByteSet> = self
+            .states
+            .iter()
+            .filter_map(|state| match state {
+                NfaState::Bytes(set, _) => Some(set),
+                _ => None,
+            })
+            .chain([&word, &line_feed, &carriage_return])
+            .collect();
+        for set in sets {
+            let mut split: HashMap<(usize, bool), usize> = HashMap::new();
+            for byte in 0..=255u8 {
+                let key = (class_of[byte as usize], set.contains(byte));
+                let next_id = split.len();
+                class_of[byte as usize] = *split.entry(key).or_insert(next_id);
+            }
+            class_count = split.len();
+        }
+
+        let mut representatives = vec![None; class_count];
+        for byte in 0..=255u8 {
+            representatives[class_of[byte as usize]].get_or_insert(byte);
+        }
+        let representatives = representatives.into_iter().flatten().collect();
+        (representatives, class_of)
+    }
+
+    // Context of the position after `byte`, as far as the NFA can tell
+    fn context(&self, key: &DfaKey, byte: u8) -> Context {
+        if self.has_word_boundaries && is_word_byte(byte) {
+            Context::Word
+        } else if self.has_line_anchors && byte == b'\n' {
+            Context::LineFeed
+        } else if self.has_line_anchors && byte == b'\r' {
+            Context::CarriageReturn
+        } else if self.has_assertions {
+            Context::Byte
+        } else {
+            // Keep the initial context when it cannot matter, so the idle state stays state 0
+            key.before
+        }
+    }
+
+    // `fresh` holds the states of a new attempt for every pair of contexts
+    fn step(
+        &self,
+        key: &DfaKey,
+        byte: u8,
+        fresh: &HashMap<(Context, Context), Vec<usize>>,
+    ) -> DfaKey {
+        let after = self.context(key, byte);
+        let mut next = DfaKey {
+            attempts: BTreeSet::new(),
+            before: after,
+            matched: false,
+            matched_before: false,
+        };
+
+        // A new attempt begins at every byte; it can only match the empty string
+        // before consuming anything, which is never reported
+        let fresh = &fresh[&(key.before, after)];
+
+        for (attempt, is_fresh) in key
+            .attempts
+            .iter()
+            .map(|attempt| (attempt.as_slice(), false))
+            .chain(Some((fresh.as_slice(), true)))
+        {
+            // Resolve assertions that were waiting for this byte
+            let resolved;
+            let mut attempt = attempt;
+            if !is_fresh
+                && attempt
+                    .iter()
+                    .any(|&idx| matches!(self.states[idx], NfaState::Assert(..)))
+            {
+                let is_match;
+                (resolved, is_match) =
+                    self.closure(attempt.iter().copied(), key.before, Some(after));
+                // Unless a match already ended there, with the byte before
+                if is_match {
+                    next.matched_before |= !key.matched;
+                    continue;
+                }
+                attempt = &resolved;
+            }
+
+            let moved = attempt.iter().filter_map(|&idx| match &self.states[idx] {
+                NfaState::Bytes(set, target) if set.contains(byte) => Some(*target),
+                _ => None,
+            });
+            let (states, is_match) = self.closure(moved, after, None);
+            if is_match {
+                next.matched = true;
+            } else if !states.is_empty() {
+                next.attempts.insert(states);
+            }
+        }
+
+        next
+    }
+
+    // Whether an attempt in progress completes if the stream ends now
+    fn matches_at_end(&self, key: &DfaKey) -> bool {
+        key.attempts.iter().any(|attempt| {
+            self.closure(attempt.iter().copied(), key.before, Some(Context::Edge))
+                .1
+        })
+    }
+
+    // Subset construction into a deterministic, unanchored automaton
+    //
+    // State 0 is the state at the start of the stream. Unless the pattern contains
+    // assertions, it is also the idle state with no attempt in progress, and
+    // transitions back to it are left out, which is exactly the matcher's
+    // behavior when no transition exists.
+    pub(crate) fn determinize(&self) -> Result<PatternBuilder, Error> {
+        let (representatives, class_of) = self.byte_classes();
+
+        let initial = DfaKey {
+            attempts: BTreeSet::new(),
+            before: Context::Edge,
+            matched: false,
+            matched_before: false,
+        };
+        let contexts = [
+            Context::Edge,
+            Context::Word,
+            Context::LineFeed,
+            Context::CarriageReturn,
+            Context::Byte,
+        ];
+        let fresh: HashMap<_, _> = contexts
+            .iter()
+            .flat_map(|&before| contexts.map(|after| (before, after)))
+            .map(|(before, after)| {
+                let states = self.closure([self.start], before, Some(after)).0;
+                ((before, after), states)
+            })
+            .collect();
+        // When no new attempt can begin after the first byte (e.g. "^abc"), the
+        // pattern is anchored: states without attempts are dead and left out
+        let anchored = fresh
+            .iter()
+            .all(|(&(before, _), states)| before == Context::Edge || states.is_empty());
+        let mut builder = PatternBuilder::new();
+        builder.anchored(anchored).state_limit(self.state_limit);
+        let mut keys = vec![initial.clone()];
+        let mut index = HashMap::from([(initial, 0)]);
+        let mut current = 0;
+
+        while current < keys.len() {
+            let mut class_targets = Vec::with_capacity(representatives.len());
+            for &byte in &representatives {
+                let key = self.step(&keys[current], byte, &fresh);
+                if anchored && key.attempts.is_empty() && !key.matched && !key.matched_before {
+                    class_targets.push(0);
+                    continue;
+                }
+                let target = match index.get(&key) {
+                    Some(&target) => target,
+                    None => {
+                        check_expansion(keys.len() + 1, self.state_limit)?;
+                        let target = builder.add_state(key.matched);
+                        if self.matches_at_end(&key) {
+                            builder.set_accepts_at_end(target)?;
+                        }
+                        if key.matched_before {
+                            builder.set_ends_before(target)?;
+                        }
+                        index.insert(key.clone(), target);
+                        keys.push(key);
+                        target
+                    }
+                };
+                class_targets.push(target);
+            }
+
+            // Transitions back to state 0 are left out, see above
+            let targets =
+                class_of.map(|class| Some(class_targets[class]).filter(|&target| target != 0));
+            builder.add_state_transitions(current, &targets)?;
+            current += 1;
+        }
+
+        Ok(builder)
+    }
+}
+
+// Bytes matched by \w
+fn is_word_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+// Number of NFA states `node` compiles to
+fn node_size(node: &Node) -> usize {
+    match node {
+        Node::Byte(_) | Node::Class(_) | Node::Assert(_) => 1,
+        Node::Concat(nodes) => nodes
+            .iter()
+            .fold(0, |total, node| total.saturating_add(node_size(node))),
+        Node::Alternate(branches) => branches
+            .iter()
+            .fold(1, |total, node| total.saturating_add(node_size(node))),
+        Node::Repeat { node, min, max } => repeat_size(node_size(node), *min, *max),
+    }
+}
+
+// Number of states `min` to `max` copies of `size` states expand to, with one more
+// state per optional copy (or for the loop) to skip the rest
+pub(crate) fn repeat_size(size: usize, min: usize, max: Option<usize>) -> usize {
+    let optional = match max {
+        None => size.saturating_add(1),
+        Some(max) => (max - min).saturating_mul(size.saturating_add(1)),
+    };
+    min.saturating_mul(size).saturating_add(optional)
+}
+
+// Fail before expanding a pattern into more than `state_limit` states
+// The error names both numbers, so a caller can tell how far over the limit it was.
+pub(crate) fn check_expansion(size: usize, state_limit: usize) -> Result<(), Error> {
+    if size > state_limit {
+        return Err(Error::PatternTooComplex(format!(
+            "Pattern expands to {} states, exceeding the limit of {}",
+            size, state_limit
+        )));
+    }
+    Ok(())
+}
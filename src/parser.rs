@@ -0,0 +1,1467 @@
+use crate::byteset::{ByteSet, ascii_other_case};
+use crate::error::Error;
+#[cfg(feature = "unicode")]
+use crate::unicode_tables;
+use crate::utf8;
+
+// Inclusive codepoint ranges of multi-byte characters in a class
+type CharRanges = Vec<(u32, u32)>;
+
+// Zero-width conditions on the position in the stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Assertion {
+    // '^': start of the stream
+    StartText,
+    // '$': end of the stream
+    EndText,
+    // '^' in multi-line mode: start of the stream or of a line
+    StartLine,
+    // '$' in multi-line mode: end of the stream or of a line
+    EndLine,
+    // '\b': between a word byte and a non-word byte, the stream edges counting as non-word
+    WordBoundary,
+    // '\B': anywhere '\b' does not match
+    NotWordBoundary,
+}
+
+// Syntax tree of a parsed pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Node {
+    Byte(u8),
+    Class(ByteSet),
+    Assert(Assertion),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+    },
+}
+
+// Flags that change the meaning of the rest of a pattern (or group), set either
+// from compile options or inline with (?flags) and (?flags:...)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Flags {
+    // 'i': ASCII letters match both cases
+    pub(crate) case_insensitive: bool,
+    // 's': '.' also matches '\n'
+    pub(crate) dot_matches_newline: bool,
+    // 'm': '^' and '$' also match at the start and end of every line
+    pub(crate) multi_line: bool,
+    // 'x': whitespace outside classes is ignored and '#' starts a comment
+    pub(crate) ignore_whitespace: bool,
+}
+
+// Parse a pattern into a syntax tree, starting with `flags`
+pub(crate) fn parse(pattern: &[u8], flags: Flags) -> Result<Node, Error> {
+    let mut parser = Parser {
+        pattern,
+        pos: 0,
+        flags,
+    };
+    let node = parser.parse_alternation()?;
+
+    // parse_alternation only stops early at a ')' without a matching '('
+    if parser.pos < pattern.len() {
+        return Err(Error::syntax(parser.pos, "Unmatched ')'"));
+    }
+    Ok(node)
+}
+
+// Recursive-descent parser over the raw pattern bytes
+struct Parser<'a> {
+    pattern: &'a [u8],
+    pos: usize,
+    flags: Flags,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.pattern.get(self.pos).copied()
+    }
+
+    // In verbose mode, step over whitespace and comments running to the end of the line
+    fn skip_ignored(&mut self) {
+        if !self.flags.ignore_whitespace {
+            return;
+        }
+        while let Some(byte) = self.peek() {
+            match byte {
+                b'#' => {
+                    let rest = &self.pattern[self.pos..];
+                    self.pos += rest
+                        .iter()
+                        .position(|&byte| byte == b'\n')
+                        .map_or(rest.len(), |end| end + 1);
+                }
+                byte if byte.is_ascii_whitespace() => self.pos += 1,
+                _ => break,
+            }
+        }
+    }
+
+    // A literal byte, matching both ASCII cases in case-insensitive mode
+    fn literal(&self, byte: u8) -> Node {
+        if let Some(other) = ascii_other_case(byte).filter(|_| self.flags.case_insensitive) {
+            let mut class = ByteSet::new();
+            class.insert(byte);
+            class.insert(other);
+            return Node::Class(class);
+        }
+        Node::Byte(byte)
+    }
+
+    // alternation := concat ('|' concat)*
+    // Alternation binds loosest: "abc|def" is "abc" or "def"
+    fn parse_alternation(&mut self) -> Result<Node, Error> {
+        let mut branches = vec![self.parse_concat()?];
+        while self.peek() == Some(b'|') {
+            self.pos += 1;
+            branches.push(self.parse_concat()?);
+        }
+
+        if branches.len() == 1 {
+            return Ok(branches.pop().unwrap());
+        }
+        Ok(Node::Alternate(branches))
+    }
+
+    // concat := (atom quantifier?)*, ending at '|', ')' or the end of the pattern
+    fn parse_concat(&mut self) -> Result<Node, Error> {
+        let mut nodes = Vec::new();
+
+        loop {
+            self.skip_ignored();
+            let Some(byte) = self.peek() else {
+                break;
+            };
+            let start = self.pos;
+            match byte {
+                b'|' | b')' => break,
+                b'(' if self.pattern.get(start + 1) == Some(&b'?') => {
+                    if let Some(group) = self.parse_flag_group(start)? {
+                        nodes.push(group);
+                    }
+                }
+                b'(' => {
+                    self.pos += 1;
+                    let group = self.parse_group_body(start)?;
+                    nodes.push(group);
+                }
+                b'[' => {
+                    let (class, end) =
+                        parse_class(self.pattern, start, self.flags.case_insensitive)?;
+                    nodes.push(class);
+                    self.pos = end;
+                }
+                b'.' => {
+                    let class = if self.flags.dot_matches_newline {
+                        ByteSet::new().negate()
+                    } else {
+                        any_except_newline()
+                    };
+                    nodes.push(Node::Class(class));
+                    self.pos += 1;
+                }
+                b'^' => {
+                    nodes.push(Node::Assert(if self.flags.multi_line {
+                        Assertion::StartLine
+                    } else {
+                        Assertion::StartText
+                    }));
+                    self.pos += 1;
+                }
+                b'$' => {
+                    nodes.push(Node::Assert(if self.flags.multi_line {
+                        Assertion::EndLine
+                    } else {
+                        Assertion::EndText
+                    }));
+                    self.pos += 1;
+                }
+                b'*' | b'+' | b'?' => {
+                    let node = quantifier_operand(&mut nodes, start)?;
+                    let (min, max) = match byte {
+                        b'*' => (0, None),
+                        b'+' => (1, None),
+                        _ => (0, Some(1)),
+                    };
+                    nodes.push(Node::Repeat {
+                        node: Box::new(node),
+                        min,
+                        max,
+                    });
+                    self.pos += 1;
+                    self.skip_lazy_suffix();
+                }
+                b'{' => {
+                    let node = quantifier_operand(&mut nodes, start)?;
+                    let (min, max, end) =
+                        parse_repetition(self.pattern, start, self.flags.ignore_whitespace)?;
+                    nodes.push(Node::Repeat {
+                        node: Box::new(node),
+                        min,
+                        max,
+                    });
+                    self.pos = end;
+                    self.skip_lazy_suffix();
+                }
+                b'\\' => {
+                    let node = self.parse_escape()?;
+                    nodes.push(node);
+                }
+                byte => match utf8::decode(self.pattern, start) {
+                    // A multi-byte character is a single atom, so quantifiers apply to all of it
+                    Some((_, end)) => {
+                        let bytes = self.pattern[start..end].iter();
+                        nodes.push(Node::Concat(bytes.map(|&byte| Node::Byte(byte)).collect()));
+                        self.pos = end;
+                    }
+                    None => {
+                        nodes.push(self.literal(byte));
+                        self.pos += 1;
+                    }
+                },
+            }
+        }
+
+        Ok(Node::Concat(nodes))
+    }
+
+    // A lazy quantifier (*?, +?, ??, {m,n}?) compiles like the greedy one: every
+    // match is already reported at its earliest end, which is where a lazy
+    // quantifier would stop
+    fn skip_lazy_suffix(&mut self) {
+        self.skip_ignored();
+        if self.peek() == Some(b'?') {
+            self.pos += 1;
+        }
+    }
+
+    // The rest of a group opened at `start`, up to and including its ')'
+    // Flags set inside the group end with it.
+    fn parse_group_body(&mut self, start: usize) -> Result<Node, Error> {
+        let flags = self.flags;
+        let group = self.parse_alternation()?;
+        if self.peek() != Some(b')') {
+            return Err(Error::syntax(start, "Unclosed group, expected ')'"));
+        }
+        self.pos += 1;
+        self.flags = flags;
+        Ok(group)
+    }
+
+    // flag group := '(?' flags ')' | '(?' flags ':' alternation ')'
+    // flags := [ismx]* ('-' [ismx]*)?
+    // The first form changes the flags for the rest of the enclosing group and yields
+    // no node; the second is a non-capturing group with its own flags.
+    fn parse_flag_group(&mut self, start: usize) -> Result<Option<Node>, Error> {
+        if let Some(error) = unsupported_group(self.pattern, start) {
+            return Err(error);
+        }
+
+        let mut flags = self.flags;
+        let mut enable = true;
+        let mut pos = start + 2;
+        loop {
+            match self.pattern.get(pos) {
+                Some(b'i') => flags.case_insensitive = enable,
+                Some(b's') => flags.dot_matches_newline = enable,
+                Some(b'm') => flags.multi_line = enable,
+                Some(b'x') => flags.ignore_whitespace = enable,
+                Some(b'-') if enable => enable = false,
+                Some(b')') if pos > start + 2 => {
+                    self.flags = flags;
+                    self.pos = pos + 1;
+                    return Ok(None);
+                }
+                Some(b':') => {
+                    let outer = self.flags;
+                    self.flags = flags;
+                    self.pos = pos + 1;
+                    let group = self.parse_group_body(start)?;
+                    self.flags = outer;
+                    return Ok(Some(group));
+                }
+                Some(&byte) => {
+                    return Err(Error::syntax(
+                        pos,
+                        format!(
+                            "Unknown flag '{}', expected one of 'i', 's', 'm', 'x'",
+                            byte.escape_ascii()
+                        ),
+                    ));
+                }
+                None => {
+                    return Err(Error::syntax(start, "Unclosed group, expected ')'"));
+                }
+            }
+            pos += 1;
+        }
+    }
+
+    // escape := '\' (shorthand class | byte escape | property class | word boundary | metacharacter)
+    fn parse_escape(&mut self) -> Result<Node, Error> {
+        let next = self.pattern.get(self.pos + 1).copied();
+        if let Some(class) = next.and_then(shorthand_class) {
+            self.pos += 2;
+            return Ok(Node::Class(class));
+        }
+        if let Some((byte, end)) = byte_escape(self.pattern, self.pos)? {
+            self.pos = end;
+            return Ok(self.literal(byte));
+        }
+        if let Some((bytes, chars, end)) = parse_property(self.pattern, self.pos)? {
+            self.pos = end;
+            return Ok(class_node(bytes, chars, false));
+        }
+        if let Some(assertion) = match next {
+            Some(b'b') => Some(Assertion::WordBoundary),
+            Some(b'B') => Some(Assertion::NotWordBoundary),
+            _ => None,
+        } {
+            self.pos += 2;
+            return Ok(Node::Assert(assertion));
+        }
+
+        if let Some(digit @ b'1'..=b'9') = next {
+            return Err(Error::syntax(
+                self.pos,
+                format!("Backreference \\{} is not supported", digit as char),
+            ));
+        }
+        if next == Some(b'k') {
+            return Err(Error::syntax(self.pos, "Named backreference is not supported"));
+        }
+
+        // Verbose mode keeps escaped whitespace, e.g. "\ " for a literal space
+        let byte = match next {
+            Some(byte) if self.flags.ignore_whitespace && byte.is_ascii_whitespace() => byte,
+            _ => escaped_metacharacter(self.pattern, self.pos)?,
+        };
+        self.pos += 2;
+        Ok(Node::Byte(byte))
+    }
+}
+
+// The error for a group starting with "(?" at `start` that uses a construct we
+// recognize but cannot match in a single forward pass
+fn unsupported_group(pattern: &[u8], start: usize) -> Option<Error> {
+    let rest = &pattern[start + 2..];
+    let construct = [
+        (&b"="[..], "Lookahead (?=...)"),
+        (b"!", "Negative lookahead (?!...)"),
+        (b"<=", "Lookbehind (?<=...)"),
+        (b"<!", "Negative lookbehind (?<!...)"),
+        (b">", "Atomic group (?>...)"),
+    ]
+    .into_iter()
+    .find(|(prefix, _)| rest.starts_with(prefix));
+
+    construct.map(|(_, name)| Error::syntax(start, format!("{} is not supported", name)))
+}
+
+// Parse a Unicode property class \p{Name}, its one-letter form \pL or a negated \P
+// form at `pos` (the '\\')
+// Returns the ASCII bytes and the ranges of multi-byte characters it matches, and the
+// offset just past the escape, or None for other escapes
+fn parse_property(
+    pattern: &[u8],
+    pos: usize,
+) -> Result<Option<(ByteSet, CharRanges, usize)>, Error> {
+    let negated = match pattern.get(pos + 1) {
+        Some(b'p') => false,
+        Some(b'P') => true,
+        _ => return Ok(None),
+    };
+    let (name, end) = match pattern.get(pos + 2) {
+        Some(b'{') => {
+            let close = pattern[pos + 3..]
+                .iter()
+                .position(|&byte| byte == b'}')
+                .map(|offset| pos + 3 + offset)
+                .ok_or_else(|| {
+                    Error::syntax(pos, "Unterminated Unicode property class, expected '}'")
+                })?;
+            (&pattern[pos + 3..close], close + 1)
+        }
+        Some(byte) if byte.is_ascii_alphabetic() => (&pattern[pos + 2..pos + 3], pos + 3),
+        _ => {
+            return Err(Error::syntax(pos, "Missing Unicode property name"));
+        }
+    };
+    let name = String::from_utf8_lossy(name);
+
+    let Some(ranges) = property_ranges(&name, pos)? else {
+        return Err(Error::syntax(pos, format!("Unsupported Unicode property '{}'", name)));
+    };
+    let ranges = if negated {
+        complement(ranges.to_vec(), 0)
+    } else {
+        ranges.to_vec()
+    };
+
+    let (bytes, chars) = split_ascii(&ranges);
+    Ok(Some((bytes, chars, end)))
+}
+
+// Split codepoint ranges into the ASCII part, which is matched by single bytes, and
+// the ranges of multi-byte characters
+fn split_ascii(ranges: &[(u32, u32)]) -> (ByteSet, CharRanges) {
+    let mut bytes = ByteSet::new();
+    let mut chars = Vec::new();
+    for &(first, last) in ranges {
+        if first < 0x80 {
+            bytes.insert_range(first as u8, last.min(0x7f) as u8);
+        }
+        if last >= 0x80 {
+            chars.push((first.max(0x80), last));
+        }
+    }
+    (bytes, chars)
+}
+
+// The node matching any character in the codepoint `ranges`
+#[cfg(feature = "syntax")]
+pub(crate) fn codepoint_class(ranges: &[(u32, u32)]) -> Node {
+    let (bytes, chars) = split_ascii(ranges);
+    class_node(bytes, chars, false)
+}
+
+#[cfg(feature = "unicode")]
+fn property_ranges(name: &str, _pos: usize) -> Result<Option<&'static [(u32, u32)]>, Error> {
+    Ok(match name {
+        "L" | "Letter" => Some(unicode_tables::LETTER),
+        "N" | "Number" => Some(unicode_tables::NUMBER),
+        "Alphabetic" => Some(unicode_tables::ALPHABETIC),
+        _ => None,
+    })
+}
+
+#[cfg(not(feature = "unicode"))]
+fn property_ranges(_name: &str, pos: usize) -> Result<Option<&'static [(u32, u32)]>, Error> {
+    Err(Error::syntax(pos, "Unicode property class requires the `unicode` feature"))
+}
+
+// Bytes with a special meaning somewhere in a pattern; escaping one yields the literal byte
+fn is_metacharacter(byte: u8) -> bool {
+    matches!(
+        byte,
+        b'\\'
+            | b'.'
+            | b'+'
+            | b'*'
+            | b'?'
+            | b'('
+            | b')'
+            | b'|'
+            | b'['
+            | b']'
+            | b'{'
+            | b'}'
+            | b'^'
+            | b'$'
+            | b'-'
+            | b'#'
+    )
+}
+
+// The literal byte of an escaped metacharacter at `pos` (the '\')
+// Any other escape is rejected, so that it cannot silently change meaning later
+fn escaped_metacharacter(pattern: &[u8], pos: usize) -> Result<u8, Error> {
+    match pattern.get(pos + 1) {
+        Some(&byte) if is_metacharacter(byte) => Ok(byte),
+        Some(&byte) => Err(Error::syntax(
+            pos,
+            format!("Unknown escape '\\{}'", byte.escape_ascii()),
+        )),
+        None => Err(Error::syntax(
+            pos,
+            "Trailing backslash, expected an escaped character",
+        )),
+    }
+}
+
+// Parse an escape for a single raw byte (\xNN, \n, \r, \t or \0) at `pos` (the '\')
+// Returns the byte and the offset just past the escape, or None for other escapes
+fn byte_escape(pattern: &[u8], pos: usize) -> Result<Option<(u8, usize)>, Error> {
+    let byte = match pattern.get(pos + 1) {
+        Some(b'n') => b'\n',
+        Some(b'r') => b'\r',
+        Some(b't') => b'\t',
+        Some(b'0') => 0,
+        Some(b'x') => {
+            let value = pattern
+                .get(pos + 2..pos + 4)
+                .filter(|digits| digits.iter().all(u8::is_ascii_hexdigit))
+                .map(|digits| (hex_value(digits[0]) << 4) | hex_value(digits[1]));
+            return match value {
+                Some(value) => Ok(Some((value, pos + 4))),
+                None => Err(Error::syntax(
+                    pos,
+                    "Invalid hex escape, expected two hex digits after \\x",
+                )),
+            };
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some((byte, pos + 2)))
+}
+
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        _ => (digit | 0x20) - b'a' + 10,
+    }
+}
+
+// Take the node a quantifier at `pos` applies to
+fn quantifier_operand(nodes: &mut Vec<Node>, pos: usize) -> Result<Node, Error> {
+    match nodes.pop() {
+        Some(Node::Repeat { .. } | Node::Assert(_)) | None => {
+            Err(Error::syntax(pos, "Quantifier has nothing to repeat"))
+        }
+        Some(node) => Ok(node),
+    }
+}
+
+// Parse a counted repetition {m}, {m,} or {m,n} starting at `start` (the '{')
+// Returns the bounds and the offset just past the closing '}'
+// With `ignore_whitespace`, spaces around the bounds are allowed, as in "{ 2, 5 }".
+fn parse_repetition(
+    pattern: &[u8],
+    start: usize,
+    ignore_whitespace: bool,
+) -> Result<(usize, Option<usize>, usize), Error> {
+    let invalid = || Error::syntax(start, "Invalid repetition, expected {m}, {m,} or {m,n}");
+
+    let close = pattern[start..]
+        .iter()
+        .position(|&byte| byte == b'}')
+        .map(|offset| start + offset)
+        .ok_or_else(invalid)?;
+    let body: Vec<u8> = pattern[start + 1..close]
+        .iter()
+        .copied()
+        .filter(|byte| !(ignore_whitespace && byte.is_ascii_whitespace()))
+        .collect();
+    let body = std::str::from_utf8(&body).map_err(|_| invalid())?;
+    let parse_bound = |text: &str| {
+        if text.is_empty() || !text.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        text.parse::<usize>().map_err(|_| invalid())
+    };
+
+    let (min, max) = match body.split_once(',') {
+        None => {
+            let count = parse_bound(body)?;
+            (count, Some(count))
+        }
+        Some((min, "")) => (parse_bound(min)?, None),
+        Some((min, max)) => (parse_bound(min)?, Some(parse_bound(max)?)),
+    };
+
+    if max.is_some_and(|max| max < min) {
+        return Err(Error::syntax(
+            start,
+            "Repetition has a maximum below its minimum",
+        ));
+    }
+
+    Ok((min, max, close + 1))
+}
+
+// The set matched by '.'
+fn any_except_newline() -> ByteSet {
+    let mut class = ByteSet::new();
+    class.insert(b'\n');
+    class.negate()
+}
+
+// The ASCII set for a shorthand class escape such as \d
+// Uppercase forms (\D, \W, \S) match every other byte, including bytes >= 0x80
+fn shorthand_class(letter: u8) -> Option<ByteSet> {
+    if letter.is_ascii_uppercase() {
+        return shorthand_class(letter.to_ascii_lowercase()).map(|class| class.negate());
+    }
+
+    let mut class = ByteSet::new();
+    match letter {
+        b'd' => class.insert_range(b'0', b'9'),
+        b'w' => {
+            class.insert_range(b'0', b'9');
+            class.insert_range(b'a', b'z');
+            class.insert_range(b'A', b'Z');
+            class.insert(b'_');
+        }
+        b's' => {
+            for byte in [b' ', b'\t', b'\n', b'\r', 0x0b, 0x0c] {
+                class.insert(byte);
+            }
+        }
+        _ => return None,
+    }
+    Some(class)
+}
+
+// The ASCII set for a POSIX class name such as "alpha" in [[:alpha:]]
+fn posix_class(name: &[u8]) -> Option<ByteSet> {
+    let mut class = ByteSet::new();
+    match name {
+        b"alnum" => {
+            class.insert_range(b'0', b'9');
+            class.insert_range(b'a', b'z');
+            class.insert_range(b'A', b'Z');
+        }
+        b"alpha" => {
+            class.insert_range(b'a', b'z');
+            class.insert_range(b'A', b'Z');
+        }
+        b"ascii" => class.insert_range(0x00, 0x7f),
+        b"blank" => {
+            class.insert(b' ');
+            class.insert(b'\t');
+        }
+        b"cntrl" => {
+            class.insert_range(0x00, 0x1f);
+            class.insert(0x7f);
+        }
+        b"digit" => return shorthand_class(b'd'),
+        b"graph" => class.insert_range(0x21, 0x7e),
+        b"lower" => class.insert_range(b'a', b'z'),
+        b"print" => class.insert_range(0x20, 0x7e),
+        b"punct" => {
+            class.insert_range(b'!', b'/');
+            class.insert_range(b':', b'@');
+            class.insert_range(b'[', b'`');
+            class.insert_range(b'{', b'~');
+        }
+        b"space" => return shorthand_class(b's'),
+        b"upper" => class.insert_range(b'A', b'Z'),
+        b"word" => return shorthand_class(b'w'),
+        b"xdigit" => {
+            class.insert_range(b'0', b'9');
+            class.insert_range(b'a', b'f');
+            class.insert_range(b'A', b'F');
+        }
+        _ => return None,
+    }
+    Some(class)
+}
+
+// Parse a POSIX class [:name:] or its negation [:^name:] at `pos` (the inner '[')
+// Returns None when there is no closing ":]", leaving the '[' a literal member
+fn parse_posix_class(pattern: &[u8], pos: usize) -> Result<Option<(ByteSet, usize)>, Error> {
+    if pattern.get(pos + 1) != Some(&b':') {
+        return Ok(None);
+    }
+    let Some(close) = pattern[pos + 2..]
+        .windows(2)
+        .position(|window| window == b":]")
+        .map(|offset| pos + 2 + offset)
+    else {
+        return Ok(None);
+    };
+
+    let name = &pattern[pos + 2..close];
+    let (negated, name) = match name.strip_prefix(b"^") {
+        Some(name) => (true, name),
+        None => (false, name),
+    };
+    let class = posix_class(name).ok_or_else(|| {
+        Error::syntax(
+            pos,
+            format!(
+                "Unknown POSIX class '[:{}:]'",
+                String::from_utf8_lossy(name)
+            ),
+        )
+    })?;
+    let class = if negated { class.negate() } else { class };
+    Ok(Some((class, close + 2)))
+}
+
+// A single member of a bracket expression
+enum ClassItem {
+    Byte(u8),
+    // A multi-byte character
+    Char(u32),
+    // Single bytes and ranges of multi-byte characters
+    Set(ByteSet, CharRanges),
+}
+
+// Read one class member at `pos`, returning it and the offset just past it
+fn parse_class_item(pattern: &[u8], pos: usize) -> Result<(ClassItem, usize), Error> {
+    if pattern[pos] == b'\\' {
+        let next = pattern.get(pos + 1).copied();
+        if let Some(class) = next.and_then(shorthand_class) {
+            return Ok((ClassItem::Set(class, Vec::new()), pos + 2));
+        }
+        if let Some((byte, end)) = byte_escape(pattern, pos)? {
+            return Ok((ClassItem::Byte(byte), end));
+        }
+        if let Some((bytes, ranges, end)) = parse_property(pattern, pos)? {
+            return Ok((ClassItem::Set(bytes, ranges), end));
+        }
+        return Ok((
+            ClassItem::Byte(escaped_metacharacter(pattern, pos)?),
+            pos + 2,
+        ));
+    }
+    if pattern[pos] == b'['
+        && let Some((class, end)) = parse_posix_class(pattern, pos)?
+    {
+        return Ok((ClassItem::Set(class, Vec::new()), end));
+    }
+    if let Some((c, end)) = utf8::decode(pattern, pos) {
+        return Ok((ClassItem::Char(c), end));
+    }
+    Ok((ClassItem::Byte(pattern[pos]), pos + 1))
+}
+
+// Parse a bracket expression starting at `start` (the '[')
+// Returns the class and the offset just past the closing ']'
+// With `case_insensitive`, members match both ASCII cases before any negation
+// Classes with multi-byte characters match whole characters; otherwise they match
+// single bytes, so "[^a]" also matches every byte of a multi-byte character.
+fn parse_class(
+    pattern: &[u8],
+    start: usize,
+    case_insensitive: bool,
+) -> Result<(Node, usize), Error> {
+    let mut class = ByteSet::new();
+    let mut chars = CharRanges::new();
+    let negated = pattern.get(start + 1) == Some(&b'^');
+    let body_start = if negated { start + 2 } else { start + 1 };
+    let mut pos = body_start;
+
+    if negated && pattern.get(body_start) == Some(&b']') && body_start + 1 == pattern.len() {
+        return Err(Error::syntax(start, "Empty negated character class"));
+    }
+
+    loop {
+        let Some(&byte) = pattern.get(pos) else {
+            return Err(Error::syntax(
+                start,
+                "Unterminated character class, expected ']'",
+            ));
+        };
+
+        // A ']' directly after the '[' (or '[^') is a literal member
+        if byte == b']' && pos > body_start {
+            let class = if case_insensitive {
+                class.fold_ascii_case()
+            } else {
+                class
+            };
+            return Ok((class_node(class, chars, negated), pos + 1));
+        }
+
+        let (item, end) = parse_class_item(pattern, pos)?;
+        let (first, first_is_char) = match item {
+            ClassItem::Set(set, ranges) => {
+                class.union_with(&set);
+                chars.extend(ranges);
+                pos = end;
+                continue;
+            }
+            ClassItem::Byte(byte) => (byte as u32, false),
+            ClassItem::Char(c) => (c, true),
+        };
+
+        let is_range = pattern.get(end) == Some(&b'-')
+            && pattern.get(end + 1).is_some_and(|&next| next != b']');
+        if !is_range {
+            if first_is_char {
+                chars.push((first, first));
+            } else {
+                class.insert(first as u8);
+            }
+            pos = end;
+            continue;
+        }
+
+        let (last, last_is_char, after) = match parse_class_item(pattern, end + 1)? {
+            (ClassItem::Byte(byte), after) => (byte as u32, false, after),
+            (ClassItem::Char(c), after) => (c, true, after),
+            (ClassItem::Set(..), _) => {
+                return Err(Error::syntax(
+                    end + 1,
+                    "Invalid range ending in a class escape",
+                ));
+            }
+        };
+        let display = |value: u32| char::from_u32(value).unwrap_or(char::REPLACEMENT_CHARACTER);
+        if last < first {
+            return Err(Error::syntax(
+                pos,
+                format!(
+                    "Invalid range '{}-{}' in character class",
+                    display(first),
+                    display(last)
+                ),
+            ));
+        }
+        if !last_is_char {
+            class.insert_range(first as u8, last as u8);
+        } else if first_is_char || first < 0x80 {
+            // An ASCII start contributes its bytes, the rest are characters
+            if first < 0x80 {
+                class.insert_range(first as u8, 0x7f);
+            }
+            chars.push((first.max(0x80), last));
+        } else {
+            return Err(Error::syntax(
+                pos,
+                format!(
+                    "Invalid range from the raw byte \\x{:02x} to a character",
+                    first
+                ),
+            ));
+        }
+        pos = after;
+    }
+}
+
+// The codepoints from `first` on that are in none of `ranges`
+fn complement(mut ranges: CharRanges, first: u32) -> CharRanges {
+    ranges.sort_unstable();
+    let mut next = first;
+    let mut complement = Vec::new();
+    for (start, end) in ranges {
+        if start > next {
+            complement.push((next, start - 1));
+        }
+        next = next.max(end + 1);
+    }
+    if next <= char::MAX as u32 {
+        complement.push((next, char::MAX as u32));
+    }
+    complement
+}
+
+// Build the node for a bracket expression from its single-byte members and its
+// ranges of multi-byte characters
+fn class_node(class: ByteSet, mut chars: CharRanges, negated: bool) -> Node {
+    if chars.is_empty() {
+        return Node::Class(if negated { class.negate() } else { class });
+    }
+
+    let mut bytes = class;
+    if negated {
+        // Match whole characters: ASCII bytes outside the class and every other
+        // multi-byte character
+        bytes = ByteSet::new();
+        for byte in (0..0x80).filter(|&byte| !class.contains(byte)) {
+            bytes.insert(byte);
+        }
+        chars = complement(chars, 0x80);
+    }
+
+    let sequences: Vec<_> = chars
+        .into_iter()
+        .flat_map(|(first, last)| utf8::sequences(first, last))
+        .collect();
+    let mut branches = vec![Node::Class(bytes)];
+    branches.extend(sequence_trie(&sequences));
+    Node::Alternate(branches)
+}
+
+// Alternatives matching the byte range sequences, with common prefixes shared so
+// that large classes such as \p{L} stay small
+fn sequence_trie(sequences: &[Vec<(u8, u8)>]) -> Vec<Node> {
+    let mut sorted: Vec<&[(u8, u8)]> = sequences.iter().map(Vec::as_slice).collect();
+    sorted.sort_unstable();
+
+    let mut branches = Vec::new();
+    for group in sorted.chunk_by(|a, b| a.first() == b.first()) {
+        let Some(&(low, high)) = group[0].first() else {
+            continue;
+        };
+        let mut set = ByteSet::new();
+        set.insert_range(low, high);
+
+        let tails: Vec<Vec<(u8, u8)>> = group
+            .iter()
+            .filter(|sequence| sequence.len() > 1)
+            .map(|sequence| sequence[1..].to_vec())
+            .collect();
+        let rest = match sequence_trie(&tails) {
+            rest if rest.is_empty() => {
+                branches.push(Node::Class(set));
+                continue;
+            }
+            mut rest if rest.len() == 1 => rest.pop().unwrap(),
+            rest => Node::Alternate(rest),
+        };
+        branches.push(Node::Concat(vec![Node::Class(set), rest]));
+    }
+    branches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::SyntaxError;
+
+    fn parse(pattern: &[u8]) -> Result<Node, Error> {
+        super::parse(pattern, Flags::default())
+    }
+
+    fn syntax_error(pattern: &[u8]) -> SyntaxError {
+        match parse(pattern) {
+            Err(Error::Syntax(error)) => error,
+            other => panic!("expected a syntax error, got {:?}", other),
+        }
+    }
+
+    fn parse_atoms(pattern: &[u8]) -> Result<Vec<Node>, Error> {
+        match parse(pattern)? {
+            Node::Concat(nodes) => Ok(nodes),
+            node => Ok(vec![node]),
+        }
+    }
+
+    fn class_bytes(node: &Node) -> Vec<u8> {
+        match node {
+            Node::Class(class) => (0..=255u8).filter(|&byte| class.contains(byte)).collect(),
+            Node::Byte(byte) => vec![*byte],
+            other => panic!("expected an atom, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_class() {
+        let atoms = parse_atoms(b"[abc]x").unwrap();
+        assert_eq!(atoms.len(), 2);
+        assert_eq!(class_bytes(&atoms[0]), b"abc");
+        assert_eq!(atoms[1], Node::Byte(b'x'));
+    }
+
+    #[test]
+    fn test_parse_class_ranges() {
+        let atoms = parse_atoms(b"[a-zA-Z0-9_]").unwrap();
+        let bytes = class_bytes(&atoms[0]);
+        assert_eq!(bytes.len(), 26 + 26 + 10 + 1);
+        assert!(bytes.contains(&b'q') && bytes.contains(&b'Q') && bytes.contains(&b'7'));
+        assert!(bytes.contains(&b'_'));
+        assert!(!bytes.contains(&b'-'));
+    }
+
+    #[test]
+    fn test_parse_class_literal_edges() {
+        // ']' first and '-' last are ordinary members
+        let atoms = parse_atoms(b"[]a-]").unwrap();
+        assert_eq!(class_bytes(&atoms[0]), b"-]a");
+    }
+
+    #[test]
+    fn test_parse_negated_class() {
+        let atoms = parse_atoms(b"[^\r\n]").unwrap();
+        let bytes = class_bytes(&atoms[0]);
+        assert_eq!(bytes.len(), 254);
+        assert!(!bytes.contains(&b'\r') && !bytes.contains(&b'\n'));
+        assert!(bytes.contains(&0xff));
+
+        // ']' directly after '[^' is excluded rather than closing the class
+        let atoms = parse_atoms(b"[^]a]").unwrap();
+        let bytes = class_bytes(&atoms[0]);
+        assert_eq!(bytes.len(), 254);
+        assert!(!bytes.contains(&b']') && !bytes.contains(&b'a'));
+    }
+
+    #[test]
+    fn test_parse_dot() {
+        let atoms = parse_atoms(b"a.c").unwrap();
+        let bytes = class_bytes(&atoms[1]);
+        assert_eq!(bytes.len(), 255);
+        assert!(!bytes.contains(&b'\n'));
+
+        assert_eq!(
+            parse_atoms(br"a\.c").unwrap(),
+            vec![Node::Byte(b'a'), Node::Byte(b'.'), Node::Byte(b'c')]
+        );
+        assert_eq!(parse_atoms(br"\[").unwrap(), vec![Node::Byte(b'[')]);
+    }
+
+    #[test]
+    fn test_parse_class_errors() {
+        assert!(matches!(parse_atoms(b"[^]"), Err(Error::Syntax(_))));
+        assert!(matches!(parse_atoms(b"[abc"), Err(Error::Syntax(_))));
+        assert!(matches!(parse_atoms(b"x[]"), Err(Error::Syntax(_))));
+        assert!(matches!(parse_atoms(b"[z-a]"), Err(Error::Syntax(_))));
+    }
+
+    #[test]
+    fn test_parse_star() {
+        let atoms = parse_atoms(b"ab*c").unwrap();
+        assert_eq!(atoms.len(), 3);
+        assert_eq!(
+            atoms[1],
+            Node::Repeat {
+                node: Box::new(Node::Byte(b'b')),
+                min: 0,
+                max: None,
+            }
+        );
+        assert_eq!(
+            parse_atoms(br"a\*").unwrap(),
+            vec![Node::Byte(b'a'), Node::Byte(b'*')]
+        );
+    }
+
+    #[test]
+    fn test_parse_dangling_star() {
+        assert!(matches!(parse(b"*a"), Err(Error::Syntax(_))));
+        assert!(matches!(parse(b"a**"), Err(Error::Syntax(_))));
+    }
+
+    #[test]
+    fn test_parse_plus() {
+        let atoms = parse_atoms(b"a+").unwrap();
+        assert_eq!(
+            atoms[0],
+            Node::Repeat {
+                node: Box::new(Node::Byte(b'a')),
+                min: 1,
+                max: None,
+            }
+        );
+        assert_eq!(parse_atoms(br"\+").unwrap(), vec![Node::Byte(b'+')]);
+        assert!(matches!(parse(b"+a"), Err(Error::Syntax(_))));
+    }
+
+    #[test]
+    fn test_parse_optional() {
+        let atoms = parse_atoms(b"https?").unwrap();
+        assert_eq!(atoms.len(), 5);
+        assert_eq!(
+            atoms[4],
+            Node::Repeat {
+                node: Box::new(Node::Byte(b's')),
+                min: 0,
+                max: Some(1),
+            }
+        );
+        assert_eq!(parse_atoms(br"\?").unwrap(), vec![Node::Byte(b'?')]);
+        assert!(matches!(parse(b"?a"), Err(Error::Syntax(_))));
+    }
+
+    #[test]
+    fn test_parse_repetition() {
+        let repeat = |min, max| Node::Repeat {
+            node: Box::new(Node::Byte(b'a')),
+            min,
+            max,
+        };
+        assert_eq!(parse_atoms(b"a{3}").unwrap(), vec![repeat(3, Some(3))]);
+        assert_eq!(parse_atoms(b"a{2,}").unwrap(), vec![repeat(2, None)]);
+        assert_eq!(parse_atoms(b"a{2,5}").unwrap(), vec![repeat(2, Some(5))]);
+        assert_eq!(
+            parse_atoms(br"a\{").unwrap(),
+            vec![Node::Byte(b'a'), Node::Byte(b'{')]
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_repetition() {
+        for pattern in [
+            &b"a{"[..],
+            b"a{}",
+            b"a{x}",
+            b"a{,3}",
+            b"a{3,1}",
+            b"{3}",
+            b"a{1,2,3}",
+        ] {
+            assert!(
+                matches!(parse(pattern), Err(Error::Syntax(_))),
+                "{:?}",
+                String::from_utf8_lossy(pattern)
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_alternation() {
+        let literal =
+            |text: &[u8]| Node::Concat(text.iter().map(|&byte| Node::Byte(byte)).collect());
+        assert_eq!(
+            parse(b"abc|def").unwrap(),
+            Node::Alternate(vec![literal(b"abc"), literal(b"def")])
+        );
+        assert_eq!(
+            parse(b"a|bc|").unwrap(),
+            Node::Alternate(vec![literal(b"a"), literal(b"bc"), literal(b"")])
+        );
+        assert_eq!(
+            parse_atoms(br"a\|b").unwrap(),
+            vec![Node::Byte(b'a'), Node::Byte(b'|'), Node::Byte(b'b')]
+        );
+        // A quantifier cannot start a branch
+        assert!(matches!(parse(b"a|*b"), Err(Error::Syntax(_))));
+    }
+
+    #[test]
+    fn test_parse_groups() {
+        let literal =
+            |text: &[u8]| Node::Concat(text.iter().map(|&byte| Node::Byte(byte)).collect());
+        assert_eq!(
+            parse(b"foo(bar|baz)qux").unwrap(),
+            Node::Concat(vec![
+                Node::Byte(b'f'),
+                Node::Byte(b'o'),
+                Node::Byte(b'o'),
+                Node::Alternate(vec![literal(b"bar"), literal(b"baz")]),
+                Node::Byte(b'q'),
+                Node::Byte(b'u'),
+                Node::Byte(b'x'),
+            ])
+        );
+        assert_eq!(
+            parse(b"(ab)+").unwrap(),
+            Node::Concat(vec![Node::Repeat {
+                node: Box::new(literal(b"ab")),
+                min: 1,
+                max: None,
+            }])
+        );
+        assert_eq!(
+            parse_atoms(br"\(\)").unwrap(),
+            vec![Node::Byte(b'('), Node::Byte(b')')]
+        );
+    }
+
+    #[test]
+    fn test_parse_unbalanced_groups() {
+        let error = |pattern: &[u8]| {
+            let error = syntax_error(pattern);
+            (error.message, error.offset)
+        };
+        assert_eq!(
+            error(b"ab(cd"),
+            ("Unclosed group, expected ')'".to_string(), 2)
+        );
+        assert_eq!(
+            error(b"a((b)"),
+            ("Unclosed group, expected ')'".to_string(), 1)
+        );
+        assert_eq!(error(b"ab)cd"), ("Unmatched ')'".to_string(), 2));
+        assert_eq!(error(b"(a|b))"), ("Unmatched ')'".to_string(), 5));
+    }
+
+    #[test]
+    fn test_parse_anchors() {
+        assert_eq!(
+            parse_atoms(b"^a$").unwrap(),
+            vec![
+                Node::Assert(Assertion::StartText),
+                Node::Byte(b'a'),
+                Node::Assert(Assertion::EndText),
+            ]
+        );
+        assert_eq!(
+            parse_atoms(br"\^\$").unwrap(),
+            vec![Node::Byte(b'^'), Node::Byte(b'$')]
+        );
+        assert!(matches!(parse(b"^*a"), Err(Error::Syntax(_))));
+    }
+
+    #[test]
+    fn test_parse_shorthand_classes() {
+        let atoms = parse_atoms(br"\d\w\s").unwrap();
+        assert_eq!(class_bytes(&atoms[0]), b"0123456789");
+        assert_eq!(class_bytes(&atoms[1]).len(), 63);
+        assert_eq!(class_bytes(&atoms[2]), b"\t\n\x0b\x0c\r ");
+    }
+
+    #[test]
+    fn test_parse_shorthand_inside_class() {
+        let atoms = parse_atoms(br"[\w.-]").unwrap();
+        let bytes = class_bytes(&atoms[0]);
+        assert_eq!(bytes.len(), 65);
+        assert!(bytes.contains(&b'.') && bytes.contains(&b'-') && bytes.contains(&b'_'));
+
+        let atoms = parse_atoms(br"[\d\]]").unwrap();
+        assert_eq!(class_bytes(&atoms[0]), b"0123456789]");
+
+        assert!(matches!(parse(br"[a-\d]"), Err(Error::Syntax(_))));
+    }
+
+    #[test]
+    fn test_parse_negated_shorthand_classes() {
+        let atoms = parse_atoms(br"\D\W\S").unwrap();
+        for (atom, positive) in atoms.iter().zip([&b"0"[..], b"_", b" "]) {
+            let bytes = class_bytes(atom);
+            assert!(!bytes.contains(&positive[0]));
+            assert!(bytes.contains(&0x80) && bytes.contains(&0xff));
+        }
+        assert_eq!(class_bytes(&atoms[0]).len(), 246);
+        assert_eq!(class_bytes(&atoms[1]).len(), 193);
+        assert_eq!(class_bytes(&atoms[2]).len(), 250);
+    }
+
+    #[test]
+    fn test_parse_case_insensitive() {
+        let atoms = parse_atoms(b"(?i)s1[a-c]").unwrap();
+        assert_eq!(class_bytes(&atoms[0]), b"Ss");
+        assert_eq!(atoms[1], Node::Byte(b'1'));
+        assert_eq!(class_bytes(&atoms[2]), b"ABCabc");
+
+        // Folding happens before negation
+        let atoms = parse_atoms(b"(?i)[^a]").unwrap();
+        let bytes = class_bytes(&atoms[0]);
+        assert!(!bytes.contains(&b'a') && !bytes.contains(&b'A'));
+        assert_eq!(bytes.len(), 254);
+
+        // A flag applies from where it is set to the end of its group
+        let atoms = parse_atoms(b"a(?i)b").unwrap();
+        assert_eq!(atoms[0], Node::Byte(b'a'));
+        assert_eq!(class_bytes(&atoms[1]), b"Bb");
+        assert_eq!(parse(b"((?i)a)a").unwrap(), parse(b"(?i:a)a").unwrap());
+        assert_ne!(parse(b"((?i)a)a").unwrap(), parse(b"((?i)a)(?i)a").unwrap());
+    }
+
+    #[test]
+    fn test_parse_flag_groups() {
+        let atoms = parse_atoms(b"(?i:a)a(?s).(?-s).").unwrap();
+        let Node::Concat(group) = &atoms[0] else {
+            panic!("expected a group, got {:?}", atoms[0]);
+        };
+        assert_eq!(class_bytes(&group[0]), b"Aa");
+        assert_eq!(atoms[1], Node::Byte(b'a'));
+        assert_eq!(class_bytes(&atoms[2]).len(), 256);
+        assert_eq!(class_bytes(&atoms[3]).len(), 255);
+
+        let atoms = parse_atoms(b"(?is)a.").unwrap();
+        assert_eq!(class_bytes(&atoms[0]), b"Aa");
+        assert_eq!(class_bytes(&atoms[1]).len(), 256);
+
+        // A non-capturing group without flags
+        assert_eq!(parse_atoms(b"(?:ab)+").unwrap().len(), 1);
+
+        // Options passed to the parser are the starting flags
+        let flags = Flags {
+            dot_matches_newline: true,
+            ..Flags::default()
+        };
+        let node = super::parse(b".(?-s).", flags).unwrap();
+        let Node::Concat(atoms) = node else {
+            panic!("expected a concatenation");
+        };
+        assert_eq!(class_bytes(&atoms[0]).len(), 256);
+        assert_eq!(class_bytes(&atoms[1]).len(), 255);
+    }
+
+    #[test]
+    fn test_parse_invalid_flag_groups() {
+        for pattern in [&b"(?z)a"[..], b"(?)a", b"(?i", b"(?i:a", b"(?--i)a"] {
+            assert!(
+                matches!(parse(pattern), Err(Error::Syntax(_))),
+                "{:?}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_byte_escapes() {
+        let atoms = parse_atoms(br"\x00\x01\xfF\n\r\t\0").unwrap();
+        let expected = [0x00, 0x01, 0xff, b'\n', b'\r', b'\t', 0x00];
+        assert_eq!(atoms, expected.map(Node::Byte));
+
+        let atoms = parse_atoms(br"[\x00-\x1f\t]").unwrap();
+        assert_eq!(class_bytes(&atoms[0]), (0x00..=0x1f).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_parse_invalid_hex_escape() {
+        for pattern in [&br"\xZ1"[..], br"ab\x1", br"ab\x", br"[\xg0]"] {
+            let error = syntax_error(pattern);
+            let offset = pattern.iter().position(|&byte| byte == b'\\').unwrap();
+            assert_eq!(error.offset, offset, "{}", error);
+            assert!(
+                error.message.contains("expected two hex digits"),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_word_boundaries() {
+        let atoms = parse_atoms(br"\bx\B").unwrap();
+        assert_eq!(atoms[0], Node::Assert(Assertion::WordBoundary));
+        assert_eq!(atoms[2], Node::Assert(Assertion::NotWordBoundary));
+        assert!(matches!(parse(br"\b+"), Err(Error::Syntax(_))));
+    }
+
+    #[test]
+    fn test_parse_escaped_metacharacters() {
+        let metacharacters = br"\.+*?()|[]{}^$-";
+        let escaped: Vec<u8> = metacharacters
+            .iter()
+            .flat_map(|&byte| [b'\\', byte])
+            .collect();
+        let atoms = parse_atoms(&escaped).unwrap();
+        assert_eq!(atoms, metacharacters.map(Node::Byte));
+
+        let atoms = parse_atoms(br"[\.\]\-]").unwrap();
+        assert_eq!(class_bytes(&atoms[0]), b"-.]");
+    }
+
+    #[test]
+    fn test_parse_unknown_escape() {
+        let error = syntax_error(br"ab\q");
+        assert_eq!(
+            (error.message.as_str(), error.offset),
+            (r"Unknown escape '\q'", 2)
+        );
+        assert_eq!(syntax_error(br"[a\%]").offset, 2);
+
+        let error = syntax_error(br"ab\");
+        assert!(error.message.starts_with("Trailing backslash"), "{}", error);
+        assert_eq!(error.offset, 2);
+    }
+
+    #[test]
+    fn test_parse_multibyte_literal() {
+        let atoms = parse_atoms("é+".as_bytes()).unwrap();
+        let Node::Repeat { node, .. } = &atoms[0] else {
+            panic!("expected a repetition, got {:?}", atoms[0]);
+        };
+        assert_eq!(
+            **node,
+            Node::Concat(vec![Node::Byte(0xc3), Node::Byte(0xa9)])
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_mixed_range() {
+        assert!(matches!(
+            parse(r"[\xff-é]".as_bytes()),
+            Err(Error::Syntax(_))
+        ));
+        assert!(matches!(parse("[ü-é]".as_bytes()), Err(Error::Syntax(_))));
+    }
+
+    #[test]
+    fn test_parse_malformed_property_class() {
+        for pattern in [&br"\p{L"[..], br"\p", br"\p{"] {
+            assert!(
+                matches!(parse(pattern), Err(Error::Syntax(_))),
+                "{:?}",
+                pattern
+            );
+        }
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn test_parse_unsupported_property_class() {
+        let Err(Error::Syntax(error)) = parse(br"a\p{Greek}") else {
+            panic!("expected an error");
+        };
+        assert_eq!(error.offset, 1);
+        assert!(error.message.contains("'Greek'"), "{}", error.message);
+    }
+
+    #[cfg(not(feature = "unicode"))]
+    #[test]
+    fn test_parse_property_class_without_feature() {
+        let error = parse(br"a\p{L}").unwrap_err();
+        assert!(matches!(error, Error::Syntax(_)));
+        assert_eq!(error.offset(), Some(1));
+    }
+
+    #[test]
+    fn test_parse_rejects_unsupported_constructs() {
+        let cases = [
+            (&br"(a)\1"[..], 3, "Backreference \\1 is not supported"),
+            (br"(?<n>a)\k<n>", 2, "Unknown flag '<'"),
+            (br"x\k<n>", 1, "Named backreference is not supported"),
+            (br"foo(?=bar)", 3, "Lookahead (?=...) is not supported"),
+            (br"foo(?!bar)", 3, "Negative lookahead (?!...) is not supported"),
+            (br"(?<=foo)bar", 0, "Lookbehind (?<=...) is not supported"),
+            (br"(?<!foo)bar", 0, "Negative lookbehind (?<!...) is not supported"),
+            (br"a(?>bc|b)c", 1, "Atomic group (?>...) is not supported"),
+        ];
+        for (pattern, offset, expected) in cases {
+            let Err(Error::Syntax(error)) = parse(pattern) else {
+                panic!("expected a syntax error for {:?}", pattern);
+            };
+            assert_eq!(error.offset, offset, "{:?}", pattern);
+            assert!(error.message.contains(expected), "{}", error.message);
+        }
+    }
+
+    #[test]
+    fn test_parse_posix_classes() {
+        let atoms = parse_atoms(b"[[:digit:]a-f][[:xdigit:]][[:blank:]]").unwrap();
+        assert_eq!(class_bytes(&atoms[0]), b"0123456789abcdef");
+        assert_eq!(class_bytes(&atoms[1]), b"0123456789ABCDEFabcdef");
+        assert_eq!(class_bytes(&atoms[2]), b"\t ");
+
+        let atoms = parse_atoms(b"[[:^alpha:]][^[:space:][:punct:]]").unwrap();
+        assert_eq!(class_bytes(&atoms[0]).len(), 256 - 52);
+        let bytes = class_bytes(&atoms[1]);
+        assert!(bytes.contains(&b'a') && bytes.contains(&b'7'));
+        assert!(!bytes.contains(&b' ') && !bytes.contains(&b'!') && !bytes.contains(&b'~'));
+
+        // Without a closing ":]" the '[' is an ordinary member
+        let atoms = parse_atoms(b"[[:a]").unwrap();
+        assert_eq!(class_bytes(&atoms[0]), b":[a");
+    }
+
+    #[test]
+    fn test_parse_unknown_posix_class() {
+        let error = syntax_error(b"x[[:alpah:]]");
+        assert_eq!(error.message, "Unknown POSIX class '[:alpah:]'");
+        assert_eq!(error.offset, 2);
+    }
+
+    #[test]
+    fn test_parse_multi_line_anchors() {
+        let atoms = parse_atoms(b"^(?m)^$(?-m)$").unwrap();
+        assert_eq!(atoms[0], Node::Assert(Assertion::StartText));
+        assert_eq!(atoms[1], Node::Assert(Assertion::StartLine));
+        assert_eq!(atoms[2], Node::Assert(Assertion::EndLine));
+        assert_eq!(atoms[3], Node::Assert(Assertion::EndText));
+    }
+
+    #[test]
+    fn test_parse_lazy_quantifiers() {
+        for (lazy, greedy) in [
+            (&b"a*?b"[..], &b"a*b"[..]),
+            (b"a+?b", b"a+b"),
+            (b"a??b", b"a?b"),
+            (b"a{2,3}?b", b"a{2,3}b"),
+        ] {
+            assert_eq!(parse(lazy).unwrap(), parse(greedy).unwrap());
+        }
+        assert!(matches!(parse(b"a*??"), Err(Error::Syntax(_))));
+    }
+
+    #[test]
+    fn test_parse_verbose() {
+        let verbose = br"(?x)
+            ^ (GET | POST) \  # method, then a literal space
+            / [a-z ]+         # spaces inside brackets still count
+            \# x{ 2 , 3 } ?   # quantifiers may be spaced from their atom
+        ";
+        let compact = br"^(GET|POST) /[a-z ]+\#x{2,3}?";
+        assert_eq!(parse(verbose).unwrap(), parse(compact).unwrap());
+
+        // Verbose mode ends with its group and can be switched off
+        let atoms = parse_atoms(b"(?x: a b )c d(?x)e # f").unwrap();
+        assert_eq!(atoms.len(), 5);
+        assert_eq!(parse_atoms(b"(?x)a b(?-x) c").unwrap().len(), 4);
+        assert!(matches!(parse(br"a\ b"), Err(Error::Syntax(_))));
+        assert_eq!(
+            parse_atoms(br"a\#").unwrap(),
+            vec![Node::Byte(b'a'), Node::Byte(b'#')]
+        );
+    }
+}
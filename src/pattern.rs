@@ -1,116 +1,4219 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ops::{Range, RangeInclusive};
+use std::sync::Arc;
+use crate::byteset::{ByteSet, ascii_other_case};
 use crate::error::Error;
+use crate::nfa::{self, Nfa};
+use crate::parser::{self, Assertion, Flags, Node};
+#[cfg(feature = "serde")]
+use crate::serialize::{self, SerializedPattern};
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(into = "SerializedPattern", try_from = "SerializedPattern")
+)]
 pub struct Pattern {
     pub(crate) id: String,
     pub(crate) states: Vec<State>,
     pub(crate) initial_state: usize,
+    pub(crate) kind: PatternKind,
+    // Once no transition exists the pattern stays dead until the stream ends,
+    // instead of restarting from the initial state
+    pub(crate) anchored: bool,
+    pub(crate) min_match_len: usize,
+    // None when matches can be arbitrarily long
+    pub(crate) max_match_len: Option<usize>,
+    // States before minimization, for patterns that were minimized
+    pub(crate) unminimized_state_count: Option<usize>,
+    pub(crate) prune_stats: PruneStats,
+    // Free-form key-value pairs for the application, e.g. a rule's severity
+    pub(crate) metadata: BTreeMap<String, String>,
+    // Assigned by StreamMatcher::add_pattern
+    pub(crate) number: Option<u32>,
+    // Set by StreamMatcher::add_negative_pattern
+    pub(crate) negative: bool,
+    // Set by StreamMatcher::add_pattern_with_priority
+    pub(crate) priority: u32,
+    // Every state gets a dense table, see PatternBuilder::dense
+    pub(crate) dense: bool,
+    // The class of every byte, which dense tables are indexed by
+    // Bytes in one class lead to the same state from every state, so a table needs
+    // one entry per class. Only computed when some state has a dense table.
+    pub(crate) class_of: [u8; 256],
+    // The transitions of a nondeterministic pattern, whose states then have none of
+    // their own, see PatternBuilder::determinize
+    pub(crate) branches: Option<Box<Branches>>,
+    // The syntax tree the pattern was compiled from, to rebuild it anchored (see
+    // anchored_rebuild). None for patterns from a PatternBuilder or loaded from a file
+    pub(crate) syntax: Option<Arc<Node>>,
 }
 
+// What PatternBuilder::build found while removing states that cannot be reached
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PruneStats {
+    // States removed because no path leads to them from the initial state
+    pub unreachable_states: usize,
+    // Reachable states where a match completes, including at the end of the stream
+    // Zero means the pattern can never match.
+    pub final_states: usize,
+}
+
+// What running a pattern costs, as far as its automaton tells, see Pattern::complexity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ComplexityReport {
+    pub state_count: usize,
+    // Most distinct states one state leads to, counting failure links
+    pub max_out_degree: usize,
+    // A loop lets matches grow arbitrarily long, e.g. through '*' or '+'
+    pub unbounded: bool,
+    pub min_match_len: usize,
+    pub cost_class: CostClass,
+}
+
+// Rough classes of scanning cost, from cheapest to most expensive
+// Every byte is one transition per pattern, but literals can be prefiltered and
+// bounded patterns keep few attempts alive, while unbounded ones (".*" between two
+// words) keep attempts alive for the rest of the stream and match most often.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CostClass {
+    // A fixed byte string, the fastest to scan for
+    Literal,
+    // Matches of bounded length
+    Bounded,
+    // Matches of any length
+    Unbounded,
+}
+
+// What a compiled pattern matches, as far as compilation could tell
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PatternKind {
+    // Matches exactly this non-empty byte string (e.g. "abc" or "cmd\.exe")
+    Literal(Vec<u8>),
+    // Anything else, including patterns assembled with PatternBuilder
+    Automaton,
+}
+
+impl Pattern {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    // The number StreamMatcher::add_pattern returned for this pattern, or None before
+    // it was added to a matcher
+    pub fn number(&self) -> Option<u32> {
+        self.number
+    }
+
+    // Whether the pattern was added with StreamMatcher::add_negative_pattern
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    // The priority StreamMatcher::add_pattern_with_priority gave the pattern, 0 unless
+    // it was added with one
+    pub fn priority(&self) -> u32 {
+        self.priority
+    }
+
+    // The value stored for `key` with PatternBuilder::metadata or with_metadata
+    pub fn metadata(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).map(String::as_str)
+    }
+
+    // The group of rules the pattern belongs to, which is its "group" metadata, see
+    // StreamMatcher::add_pattern_in_group
+    pub fn group(&self) -> Option<&str> {
+        self.metadata("group")
+    }
+
+    // All metadata as key-value pairs, in key order
+    pub fn metadata_entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.metadata.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    // The pattern with `value` stored for `key`, replacing any earlier value
+    // For compiled patterns, which have no builder to set metadata on.
+    pub fn with_metadata(mut self, key: &str, value: &str) -> Pattern {
+        self.metadata.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn kind(&self) -> &PatternKind {
+        &self.kind
+    }
+
+    // True when the pattern matches a single fixed byte string
+    pub fn is_literal(&self) -> bool {
+        matches!(self.kind, PatternKind::Literal(_))
+    }
+
+    // True when the pattern can only match from the start of the stream
+    pub fn is_anchored(&self) -> bool {
+        self.anchored
+    }
+
+    // Fewest bytes a match can span
+    pub fn min_match_len(&self) -> usize {
+        self.min_match_len
+    }
+
+    // Most bytes a match can span, or None for patterns with unbounded repetition
+    // such as '*' or '+'
+    pub fn max_match_len(&self) -> Option<usize> {
+        self.max_match_len
+    }
+
+    pub fn state_count(&self) -> usize {
+        self.states.len()
+    }
+
+    // The state matching starts and restarts from, see PatternBuilder::set_initial_state
+    pub fn initial_state(&self) -> usize {
+        self.initial_state
+    }
+
+    // Exact byte transitions plus one for each default transition
+    pub fn transition_count(&self) -> usize {
+        let branches = self.branches.as_ref().map_or(0, |branches| branches.transition_count());
+        self.states
+            .iter()
+            .map(|state| state.transitions.len() + state.default_transition.is_some() as usize)
+            .sum::<usize>()
+            + branches
+    }
+
+    // States where a match completes, including those matching at the end of the stream
+    pub fn final_state_count(&self) -> usize {
+        self.states.iter().filter(|state| state.accepts()).count()
+    }
+
+    // Approximate bytes used by the whole pattern, the states as in memory_usage plus
+    // the id and the pattern itself
+    pub fn estimated_memory(&self) -> usize {
+        let literal = match &self.kind {
+            PatternKind::Literal(literal) => literal.capacity(),
+            PatternKind::Automaton => 0,
+        };
+        std::mem::size_of::<Pattern>() + self.id.capacity() + literal + self.memory_usage()
+    }
+
+    pub fn prune_stats(&self) -> PruneStats {
+        self.prune_stats
+    }
+
+    // How many states the pattern had before minimize(), or None if it was not minimized
+    pub fn unminimized_state_count(&self) -> Option<usize> {
+        self.unminimized_state_count
+    }
+
+    // Predict the cost of scanning for this pattern, e.g. to vet a rule pack before
+    // deploying it. See StreamMatcher::complexity for all patterns of a matcher.
+    pub fn complexity(&self) -> ComplexityReport {
+        let branches = self.branches.as_deref();
+        let max_out_degree = (0..self.states.len())
+            .map(|state| {
+                let successors = successors_of(&self.states, branches, state);
+                successors.collect::<HashSet<_>>().len()
+            })
+            .max()
+            .unwrap_or(0);
+        let unbounded = self.max_match_len.is_none();
+        let cost_class = match (&self.kind, unbounded) {
+            (PatternKind::Literal(_), _) => CostClass::Literal,
+            (_, false) => CostClass::Bounded,
+            (_, true) => CostClass::Unbounded,
+        };
+        ComplexityReport {
+            state_count: self.states.len(),
+            max_out_degree,
+            unbounded,
+            min_match_len: self.min_match_len,
+            cost_class,
+        }
+    }
+
+    // An equivalent pattern with the fewest states
+    // Hopcroft's partition refinement merges states that are final alike and whose
+    // transitions lead to merged states for every byte, such as the shared tails of
+    // alternatives. Unreachable states are dropped, and failure links are replaced by
+    // the transitions they lead to.
+    pub fn minimize(&self) -> Pattern {
+        // Nondeterministic patterns are kept as they were built
+        if self.branches.is_some() {
+            return self.clone();
+        }
+        let resolved;
+        let states = if self.states.iter().any(|state| state.failure.is_some()) {
+            resolved = self.resolve_failures();
+            &resolved
+        } else {
+            &self.states
+        };
+        let count = states.len();
+        let (representatives, _) = byte_classes(states);
+        let class_count = representatives.len();
+        // A missing transition restarts the pattern (or ends it when anchored), unlike
+        // any transition, so it leads to a sink state that is never merged
+        let sink = count;
+
+        let mut inverse = vec![Vec::new(); count + 1];
+        for (state, current) in states.iter().enumerate() {
+            for (class, &byte) in representatives.iter().enumerate() {
+                inverse[current.next_state(byte).unwrap_or(sink)].push((class, state));
+            }
+        }
+        inverse[sink].extend((0..class_count).map(|class| (class, sink)));
+
+        // Start from the states that differ in finality
+        let mut block_of = vec![0; count + 1];
+        let mut blocks: Vec<Vec<usize>> = Vec::new();
+        let mut finality = HashMap::new();
+        for (state, current) in states.iter().enumerate() {
+            let key = (
+                current.is_final,
+                current.accepts_at_end,
+                current.final_tag,
+                current.ends_before,
+            );
+            let block = *finality.entry(key).or_insert_with(|| {
+                blocks.push(Vec::new());
+                blocks.len() - 1
+            });
+            blocks[block].push(state);
+            block_of[state] = block;
+        }
+        block_of[sink] = blocks.len();
+        blocks.push(vec![sink]);
+
+        // Split every block whose states disagree on whether a byte leads into the
+        // splitter, until no block can be split
+        let mut pending: Vec<usize> = (0..blocks.len()).collect();
+        let mut is_pending = vec![true; blocks.len()];
+        while let Some(splitter) = pending.pop() {
+            is_pending[splitter] = false;
+            let mut preimages = vec![Vec::new(); class_count];
+            for &state in &blocks[splitter] {
+                for &(class, source) in &inverse[state] {
+                    preimages[class].push(source);
+                }
+            }
+            for sources in preimages {
+                let mut touched: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+                for source in sources {
+                    touched.entry(block_of[source]).or_default().push(source);
+                }
+                for (block, inside) in touched {
+                    if inside.len() == blocks[block].len() {
+                        continue;
+                    }
+                    let split = blocks.len();
+                    for &state in &inside {
+                        block_of[state] = split;
+                    }
+                    blocks[block].retain(|&state| block_of[state] == block);
+                    blocks.push(inside);
+                    is_pending.push(false);
+                    // Only the smaller half needs to split others, unless both do
+                    let smaller = blocks[split].len() <= blocks[block].len();
+                    let next = if is_pending[block] || smaller { split } else { block };
+                    pending.push(next);
+                    is_pending[next] = true;
+                }
+            }
+        }
+
+        // Number the merged states in breadth-first order from the initial state
+        let representative = |block: usize| blocks[block].iter().copied().min().unwrap();
+        let mut index = vec![None; blocks.len()];
+        index[block_of[self.initial_state]] = Some(0);
+        let mut order = vec![block_of[self.initial_state]];
+        let mut current = 0;
+        while current < order.len() {
+            let state = &states[representative(order[current])];
+            for &byte in &representatives {
+                if let Some(next) = state.next_state(byte)
+                    && index[block_of[next]].is_none()
+                {
+                    index[block_of[next]] = Some(order.len());
+                    order.push(block_of[next]);
+                }
+            }
+            current += 1;
+        }
+        let merged = |state: usize| index[block_of[state]].unwrap();
+        let states = order
+            .iter()
+            .map(|&block| {
+                let state = &states[representative(block)];
+                State {
+                    transitions: state
+                        .transitions
+                        .iter()
+                        .map(|(&byte, &to)| (byte, merged(to)))
+                        .collect(),
+                    default_transition: state.default_transition.map(merged),
+                    excluded: state.excluded,
+                    is_final: state.is_final,
+                    accepts_at_end: state.accepts_at_end,
+                    final_tag: state.final_tag,
+                    ends_before: state.ends_before,
+                    failure: None,
+                    dense: None,
+                }
+            })
+            .collect();
+
+        let mut pattern = Pattern {
+            id: self.id.clone(),
+            states,
+            initial_state: 0,
+            kind: self.kind.clone(),
+            anchored: self.anchored,
+            min_match_len: self.min_match_len,
+            max_match_len: self.max_match_len,
+            unminimized_state_count: Some(self.unminimized_state_count.unwrap_or(count)),
+            prune_stats: self.prune_stats,
+            metadata: self.metadata.clone(),
+            number: self.number,
+            negative: self.negative,
+            priority: self.priority,
+            dense: self.dense,
+            class_of: [0; 256],
+            branches: None,
+            syntax: self.syntax.clone(),
+        };
+        pattern.update_dense_tables();
+        pattern
+    }
+
+    // A pattern matching where `other` matches right after a match of this pattern
+    // Every match of this pattern starts `other` from its initial state, and that
+    // attempt ends at the first byte `other` has no transition for, so `other` runs
+    // anchored at the junction. Literals are used as their bytes, but other patterns
+    // from compile_pattern already look for a match at every offset and should be
+    // compiled anchored ('^' or CompileOptions::anchored) to start right there.
+    // Matches are reported where `other` completes, with its tags.
+    pub fn concat(self, other: Pattern, new_id: String) -> Result<Pattern, Error> {
+        let size = self.states.len().saturating_add(other.states.len());
+        nfa::check_expansion(size.saturating_add(1), DEFAULT_STATE_LIMIT)?;
+        let mut builder = PatternBuilder::new();
+        builder.anchored(self.anchored).minimize(true);
+        let (initial, copied) = builder.add_pattern(&self, true)?;
+        builder.add_epsilon_transition(0, initial)?;
+        let (next, _) = builder.add_pattern(&other, false)?;
+        for state in copied {
+            if builder.states[state].completes() {
+                builder.add_epsilon_transition(state, next)?;
+            }
+            builder.set_finality(state, false, false, None)?;
+        }
+
+        let mut pattern = builder.build(new_id)?;
+        if let (PatternKind::Literal(first), PatternKind::Literal(second)) = (self.kind, other.kind)
+        {
+            pattern.kind = PatternKind::Literal([first, second].concat());
+        }
+        pattern.min_match_len = self.min_match_len.saturating_add(other.min_match_len);
+        pattern.max_match_len = self
+            .max_match_len
+            .zip(other.max_match_len)
+            .map(|(first, second)| first.saturating_add(second));
+        Ok(pattern)
+    }
+
+    // A pattern matching wherever any of `patterns` matches, each as it would on its own
+    // Matches are tagged with the index in `patterns` of the pattern that completed
+    // them, replacing their own tags, so StreamMatcher::add_tagged_callback can tell
+    // which one matched. Where several complete at the same byte only the first of
+    // them is reported. The result is anchored only if all of them are.
+    pub fn union(patterns: Vec<Pattern>, new_id: String) -> Result<Pattern, Error> {
+        if patterns.is_empty() {
+            return Err(Error::InvalidPattern("At least one pattern is required".into()));
+        }
+        let size = patterns.iter().fold(1, |size: usize, pattern| {
+            size.saturating_add(pattern.states.len())
+        });
+        nfa::check_expansion(size, DEFAULT_STATE_LIMIT)?;
+        let mut builder = PatternBuilder::new();
+        builder.anchored(patterns.iter().all(Pattern::is_anchored)).minimize(true);
+        for (tag, pattern) in patterns.iter().enumerate() {
+            let (initial, copied) = builder.add_pattern(pattern, true)?;
+            builder.add_epsilon_transition(0, initial)?;
+            for state in copied {
+                let state = &mut builder.states[state];
+                if state.accepts() {
+                    state.final_tag = Some(tag as u32);
+                }
+            }
+        }
+
+        let mut pattern = builder.build(new_id)?;
+        pattern.min_match_len = patterns.iter().map(Pattern::min_match_len).min().unwrap_or(0);
+        pattern.max_match_len = patterns
+            .iter()
+            .map(Pattern::max_match_len)
+            .collect::<Option<Vec<_>>>()
+            .map(|lengths| lengths.into_iter().max().unwrap_or(0));
+        Ok(pattern)
+    }
+
+    // A pattern matching `min` to `max` (or any number of) matches of this pattern in
+    // a row, like {m,n} after a group
+    // Every repetition after the first starts right where the one before matched, as
+    // `other` does in concat. The copies count toward the same state limit as counted
+    // repetition in compile_pattern. Like there, matching zero times is never reported,
+    // so `min` 0 behaves like 1.
+    pub fn repeat(self, min: usize, max: Option<usize>, id: String) -> Result<Pattern, Error> {
+        if max.is_some_and(|max| max < min.max(1)) {
+            return Err(Error::InvalidPattern(format!(
+                "Cannot repeat pattern '{}' between {} and {:?} times",
+                self.id, min, max
+            )));
+        }
+        let size = nfa::repeat_size(self.states.len(), min, max);
+        nfa::check_expansion(size.saturating_add(1), DEFAULT_STATE_LIMIT)?;
+
+        // Chain the copies from their final states, looping on the last one when the
+        // repetition is unbounded
+        let required = min.max(1);
+        let mut builder = PatternBuilder::new();
+        builder.anchored(self.anchored).minimize(true);
+        let mut finals = vec![0];
+        for copy in 1..=max.unwrap_or(required) {
+            let (initial, copied) = builder.add_pattern(&self, copy == 1)?;
+            for &state in &finals {
+                builder.add_epsilon_transition(state, initial)?;
+            }
+            finals = copied.clone().filter(|&state| builder.states[state].completes()).collect();
+            if copy < required {
+                for state in copied {
+                    builder.set_finality(state, false, false, None)?;
+                }
+            }
+            if max.is_none() && copy == required {
+                for &state in &finals {
+                    builder.add_epsilon_transition(state, initial)?;
+                }
+            }
+        }
+
+        let mut pattern = builder.build(id)?;
+        (pattern.min_match_len, pattern.max_match_len) =
+            repeat_len_bounds((self.min_match_len, self.max_match_len), min, max);
+        Ok(pattern)
+    }
+
+    // A pattern matching the byte-reversed matches of this one, to scan backwards
+    // from where a match may end, e.g. to confirm a prefilter hit or find where a
+    // match started
+    // Fed the bytes before an end position in reverse order, it matches once, where the
+    // nearest match ending there starts. It is anchored at that end position.
+    // States where a match completes (at the end of the stream too) become initial
+    // states and the initial state becomes the final one, so the reversed automaton is
+    // nondeterministic and has to be determinized. For unbounded patterns that can
+    // take exponentially many states, e.g. "^[ab]{13}a[ab]*" reversed must remember
+    // the last 14 bytes; beyond DEFAULT_STATE_LIMIT it fails with PatternTooComplex.
+    pub fn reverse(&self) -> Result<Pattern, Error> {
+        self.require_deterministic("reverse")?;
+        let finals: Vec<usize> =
+            (0..self.states.len()).filter(|&state| self.states[state].accepts()).collect();
+        if finals.is_empty() {
+            return Err(Error::InvalidPattern(format!(
+                "Pattern '{}' has no final state to reverse from",
+                self.id
+            )));
+        }
+        let (representatives, class_of) = byte_classes(&self.states);
+        let mut classes = vec![Vec::new(); representatives.len()];
+        for byte in 0..=255u8 {
+            classes[class_of[byte as usize] as usize].push(byte);
+        }
+
+        // Same state numbers, with every transition turned around
+        let mut builder = PatternBuilder::new();
+        builder.anchored(true);
+        for _ in 1..self.states.len() {
+            builder.add_state(false);
+        }
+        for state in 0..self.states.len() {
+            for (&byte, bytes) in representatives.iter().zip(&classes) {
+                if let Some(target) = self.next_state(state, byte) {
+                    builder.add_transition_set(target, bytes, state)?;
+                }
+            }
+        }
+        builder.set_finality(self.initial_state, true, false, None)?;
+        builder.set_initial_states(&finals)?;
+
+        // The one attempt ends at its first match, dropping the states only reachable
+        // through one
+        let mut pattern = builder.build(self.id.clone())?;
+        for state in pattern.states.iter_mut().filter(|state| state.completes()) {
+            state.transitions.clear();
+            state.default_transition = None;
+            state.dense = None;
+        }
+        let mut pattern = pattern.minimize();
+        if let PatternKind::Literal(literal) = &self.kind {
+            pattern.kind = PatternKind::Literal(literal.iter().rev().copied().collect());
+        }
+        (pattern.min_match_len, pattern.max_match_len) = (self.min_match_len, self.max_match_len);
+        Ok(pattern)
+    }
+
+    // The state reached from `state` on `byte`, following failure links until a
+    // state has a transition for it, or None if none has
+    pub(crate) fn next_state(&self, mut state: usize, byte: u8) -> Option<usize> {
+        loop {
+            let current = &self.states[state];
+            let next = match &current.dense {
+                // The class map is indexed by a byte, so only the table is bounds checked
+                Some(table) => Some(table[self.class_of[byte as usize] as usize])
+                    .filter(|&next| next != NO_TRANSITION)
+                    .map(|next| next as usize),
+                None => current.next_state(byte),
+            };
+            if next.is_some() {
+                return next;
+            }
+            state = current.failure?;
+        }
+    }
+
+    // Like next_state, but as the matcher runs the pattern: unless it is anchored, a
+    // byte without a transition is tried again from the initial state, as it may
+    // start a new match itself. None means the pattern restarts (or ends when anchored)
+    pub(crate) fn search_next_state(&self, state: usize, byte: u8) -> Option<usize> {
+        match self.next_state(state, byte) {
+            None if !self.anchored && state != self.initial_state => {
+                self.next_state(self.initial_state, byte)
+            }
+            next => next,
+        }
+    }
+
+    // The pattern compiled again from its syntax tree to match only from the start of
+    // the stream, for one attempt. With `after_byte`, any one byte comes before that
+    // start, so that assertions such as '\b' see the byte before it. The automaton
+    // of compile_pattern starts an attempt at every byte and cannot tell them apart
+    // afterwards, so this takes the syntax tree, and is None without one
+    pub(crate) fn anchored_rebuild(&self, after_byte: bool) -> Option<Result<Pattern, Error>> {
+        let node = self.syntax.as_deref()?;
+        let mut nodes = vec![Node::Assert(Assertion::StartText)];
+        if after_byte {
+            let mut any = ByteSet::new();
+            any.insert_range(0, 255);
+            nodes.push(Node::Class(any));
+        }
+        nodes.push(node.clone());
+        let limit = DEFAULT_STATE_LIMIT.max(self.states.len());
+        Some(compile_node(&Node::Concat(nodes), self.id.clone(), limit))
+    }
+
+    // The pattern as MatcherConfig::anchored runs it, matching only from the start of
+    // the stream: literals and patterns with a syntax tree are rebuilt that way, while
+    // other automatons are only made dead after the first byte they have no
+    // transition for, instead of restarting
+    pub(crate) fn into_anchored(mut self) -> Pattern {
+        if let PatternKind::Literal(literal) = &self.kind
+            && !self.anchored
+        {
+            let mut builder = PatternBuilder::new();
+            builder.anchored(true).dense(self.dense);
+            let built = builder.append_literal(0, literal).and_then(|last| {
+                builder.states[last].is_final = true;
+                builder.build(self.id.clone())
+            });
+            if let Ok(built) = built {
+                let Pattern { metadata, number, negative, priority, kind, .. } = self;
+                return Pattern { metadata, number, negative, priority, kind, ..built };
+            }
+        }
+        if !self.anchored
+            && let Some(Ok(mut built)) = self.anchored_rebuild(false)
+        {
+            if self.dense {
+                built.dense = true;
+                built.update_dense_tables();
+            }
+            let Pattern { metadata, number, negative, priority, kind, .. } = self;
+            return Pattern { metadata, number, negative, priority, kind, ..built };
+        }
+        self.anchored = true;
+        self
+    }
+
+    // Whether the pattern was built without determinizing a nondeterministic automaton,
+    // so the matcher tracks every state it can be in, see PatternBuilder::determinize
+    pub fn is_nondeterministic(&self) -> bool {
+        self.branches.is_some()
+    }
+
+    // Like search_next_state, but from the sorted set of states `current` to the set
+    // in `next`, for nondeterministic patterns. An empty `next` means the pattern
+    // restarts (or ends when anchored). Each set stands for the state of the
+    // determinized pattern reached by the same bytes, so both match alike.
+    pub(crate) fn search_next_states(&self, current: &[usize], byte: u8, next: &mut Vec<usize>) {
+        let Some(branches) = self.branches.as_deref() else {
+            next.clear();
+            next.extend(current.first().and_then(|&state| self.search_next_state(state, byte)));
+            return;
+        };
+        branches.step(current, byte, next);
+        if next.is_empty() && !self.anchored && current != [self.initial_state] {
+            branches.step(&[self.initial_state], byte, next);
+        }
+    }
+
+    // The finality of a set of states, as the determinized state standing for it has
+    pub(crate) fn set_finality(&self, set: &[usize]) -> State {
+        let mut finality = State::new(false);
+        for &state in set {
+            finality.merge_final(&self.states[state]);
+        }
+        finality
+    }
+
+    // The bytes that take the pattern away from its initial state or complete a match
+    // there, as the matcher steps it. Any other byte leaves an unanchored pattern at
+    // its initial state, see StreamMatcher::prefilter
+    pub(crate) fn first_bytes(&self) -> ByteSet {
+        let mut bytes = ByteSet::new();
+        let mut next = Vec::new();
+        for byte in 0..=255u8 {
+            self.search_next_states(&[self.initial_state], byte, &mut next);
+            let leaves = next != [self.initial_state] || self.set_finality(&next).completes();
+            if !next.is_empty() && leaves {
+                bytes.insert(byte);
+            }
+        }
+        bytes
+    }
+
+    fn require_deterministic(&self, action: &str) -> Result<(), Error> {
+        if self.branches.is_none() {
+            return Ok(());
+        }
+        Err(Error::InvalidPattern(format!(
+            "Cannot {} pattern '{}', which was built nondeterministic; build it with \
+             PatternBuilder::determinize instead",
+            action, self.id
+        )))
+    }
+
+    // The states with every transition that failure links lead to made explicit
+    fn resolve_failures(&self) -> Vec<State> {
+        (0..self.states.len())
+            .map(|state| State {
+                transitions: (0..=255u8)
+                    .filter_map(|byte| Some((byte, self.next_state(state, byte)?)))
+                    .collect(),
+                default_transition: None,
+                excluded: ByteSet::new(),
+                failure: None,
+                dense: None,
+                ..self.states[state].clone()
+            })
+            .collect()
+    }
+
+    // Check that every state index is in bounds and that failure links end, so that
+    // matching cannot panic or hang on a pattern that was loaded rather than built
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        let count = self.states.len();
+        if self.initial_state >= count {
+            return Err(Error::Internal(format!(
+                "Initial state {} is out of range for {} states",
+                self.initial_state, count
+            )));
+        }
+        if let Some(branches) = &self.branches
+            && (branches.exact.len() != count || branches.defaults.len() != count)
+        {
+            return Err(Error::Internal(format!(
+                "Nondeterministic transitions are given for {} states, but there are {}",
+                branches.exact.len().max(branches.defaults.len()),
+                count
+            )));
+        }
+        for index in 0..count {
+            let mut successors = successors_of(&self.states, self.branches.as_deref(), index);
+            if let Some(target) = successors.find(|&target| target >= count) {
+                return Err(Error::Internal(format!(
+                    "State {} refers to state {}, but there are only {} states",
+                    index, target, count
+                )));
+            }
+        }
+        if let Some(start) = failure_cycle(&self.states) {
+            return Err(Error::Internal(format!(
+                "Failure links from state {} form a cycle",
+                start
+            )));
+        }
+        Ok(())
+    }
+
+    // Approximate bytes used by the compiled states
+    // Default transitions and their exclusion sets are part of each State, so only
+    // exact transitions add to the size.
+    pub fn memory_usage(&self) -> usize {
+        // Each map slot holds the entry and one control byte
+        let entry = std::mem::size_of::<(u8, usize)>() + 1;
+        let branches = self.branches.as_ref().map_or(0, |branches| branches.memory_usage());
+        self.states
+            .iter()
+            .map(|state| {
+                let dense = state.dense.as_ref().map_or(0, |table| table.len() * 4);
+                std::mem::size_of::<State>() + state.transitions.capacity() * entry + dense
+            })
+            .sum::<usize>()
+            + branches
+    }
+
+    // Give the states that should have one a dense table, and drop the others' tables
+    // Those are all states when `dense` is set, otherwise the ones with more than
+    // DENSE_THRESHOLD exact transitions. Needed whenever transitions change.
+    pub(crate) fn update_dense_tables(&mut self) {
+        // Targets have to fit below NO_TRANSITION, and the states of nondeterministic
+        // patterns have no transitions to put in a table
+        let fits = self.states.len() < NO_TRANSITION as usize && self.branches.is_none();
+        let wanted: Vec<bool> = self
+            .states
+            .iter()
+            .map(|state| fits && (self.dense || state.transitions.len() > DENSE_THRESHOLD))
+            .collect();
+        let representatives = if wanted.contains(&true) {
+            let (representatives, class_of) = byte_classes(&self.states);
+            self.class_of = class_of;
+            representatives
+        } else {
+            Vec::new()
+        };
+        for (state, wanted) in self.states.iter_mut().zip(wanted) {
+            state.dense = wanted.then(|| state.dense_table(&representatives));
+        }
+    }
+}
+
+// States with more exact transitions than this get a dense table even without
+// PatternBuilder::dense, as hash lookups get slower with more entries
+const DENSE_THRESHOLD: usize = 32;
+
+// Marks the bytes without a transition in a dense table
+const NO_TRANSITION: u32 = u32::MAX;
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct State {
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize::sorted_transitions"))]
     pub(crate) transitions: HashMap<u8, usize>,
+    // Taken when no exact transition exists and the byte is not excluded
+    pub(crate) default_transition: Option<usize>,
+    pub(crate) excluded: ByteSet,
+    // A match completes with the byte leading here
     pub(crate) is_final: bool,
+    // A match completes here if the stream ends (e.g. a pattern ending in '$')
+    pub(crate) accepts_at_end: bool,
+    // Reported with matches completing here, to tell alternatives of one pattern apart
+    pub(crate) final_tag: Option<u32>,
+    // A match completes here that ended before the byte leading here, which only
+    // confirmed an assertion after it, such as a trailing \b. Apart from is_final, as
+    // another match can end with that byte.
+    pub(crate) ends_before: bool,
+    // Where to retry a byte this state has no transition for, as in Aho-Corasick
+    pub(crate) failure: Option<usize>,
+    // The target for every byte class of the pattern (see Pattern::class_of), from the
+    // fields above, so Pattern::next_state takes two array lookups instead of a hash
+    // lookup. Rebuilt after loading, so never serialized.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) dense: Option<Box<[u32]>>,
+}
+
+impl State {
+    fn new(is_final: bool) -> Self {
+        State {
+            transitions: HashMap::new(),
+            default_transition: None,
+            excluded: ByteSet::new(),
+            is_final,
+            accepts_at_end: false,
+            final_tag: None,
+            ends_before: false,
+            failure: None,
+            dense: None,
+        }
+    }
+
+    // Take on the finality of a state this one is merged with
+    // When both are tagged the lower tag wins.
+    fn merge_final(&mut self, other: &State) {
+        self.is_final |= other.is_final;
+        self.accepts_at_end |= other.accepts_at_end;
+        self.final_tag = self.final_tag.into_iter().chain(other.final_tag).min();
+        self.ends_before |= other.ends_before;
+    }
+
+    // Exact byte transitions win over the default transition
+    pub(crate) fn next_state(&self, byte: u8) -> Option<usize> {
+        match self.transitions.get(&byte) {
+            Some(&next) => Some(next),
+            None if self.excluded.contains(byte) => None,
+            None => self.default_transition,
+        }
+    }
+
+    // The target for the representative byte of each class
+    fn dense_table(&self, representatives: &[u8]) -> Box<[u32]> {
+        representatives
+            .iter()
+            .map(|&byte| self.next_state(byte).map_or(NO_TRANSITION, |next| next as u32))
+            .collect()
+    }
+
+    fn successors(&self) -> impl Iterator<Item = usize> + '_ {
+        let direct = self.transitions.values().copied().chain(self.default_transition);
+        direct.chain(self.failure)
+    }
+
+    // Whether a match completes on entering this state, wherever it ends
+    pub(crate) fn completes(&self) -> bool {
+        self.is_final || self.ends_before
+    }
+
+    fn accepts(&self) -> bool {
+        self.completes() || self.accepts_at_end
+    }
+}
+
+// The transitions of a nondeterministic pattern, where a byte can lead from a state
+// to several
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Branches {
+    // The targets of each state on each byte, sorted
+    pub(crate) exact: Vec<BTreeMap<u8, Vec<usize>>>,
+    // Targets on the bytes that are not excluded and have no exact targets
+    pub(crate) defaults: Vec<Vec<(ByteSet, usize)>>,
+}
+
+impl Branches {
+    fn new(builder: &PatternBuilder) -> Self {
+        let count = builder.states.len();
+        let mut exact: Vec<BTreeMap<u8, Vec<usize>>> = vec![BTreeMap::new(); count];
+        for &(from, byte, to) in &builder.transitions {
+            exact[from].entry(byte).or_default().push(to);
+        }
+        for targets in exact.iter_mut().flat_map(BTreeMap::values_mut) {
+            targets.sort_unstable();
+            targets.dedup();
+        }
+        let mut defaults = vec![Vec::new(); count];
+        for &(from, excluded, to) in &builder.default_transitions {
+            defaults[from].push((excluded, to));
+        }
+        Branches { exact, defaults }
+    }
+
+    // The states the set `current` leads to on `byte`, sorted, in `next`
+    // Within one state, exact transitions still take precedence over defaults.
+    pub(crate) fn step(&self, current: &[usize], byte: u8, next: &mut Vec<usize>) {
+        next.clear();
+        for &state in current {
+            match self.exact[state].get(&byte) {
+                Some(targets) => next.extend(targets),
+                None => next.extend(
+                    self.defaults[state]
+                        .iter()
+                        .filter(|(excluded, _)| !excluded.contains(byte))
+                        .map(|&(_, to)| to),
+                ),
+            }
+        }
+        next.sort_unstable();
+        next.dedup();
+    }
+
+    fn successors(&self, state: usize) -> impl Iterator<Item = usize> + '_ {
+        let exact = self.exact[state].values().flatten().copied();
+        exact.chain(self.defaults[state].iter().map(|&(_, to)| to))
+    }
+
+    // Every target of every byte, plus one for each default transition
+    fn transition_count(&self) -> usize {
+        let exact = self.exact.iter().flat_map(BTreeMap::values).map(Vec::len);
+        exact.sum::<usize>() + self.defaults.iter().map(Vec::len).sum::<usize>()
+    }
+
+    // Approximate bytes used by the tables, counting a tree node per byte with targets
+    fn memory_usage(&self) -> usize {
+        let node = 2 * std::mem::size_of::<(u8, Vec<usize>)>();
+        let default = std::mem::size_of::<(ByteSet, usize)>();
+        let per_state = std::mem::size_of::<(BTreeMap<u8, Vec<usize>>, Vec<(ByteSet, usize)>)>();
+        self.exact
+            .iter()
+            .zip(&self.defaults)
+            .map(|(exact, defaults)| {
+                let targets: usize = exact.values().map(|targets| targets.capacity()).sum();
+                per_state
+                    + exact.len() * node
+                    + targets * std::mem::size_of::<usize>()
+                    + defaults.capacity() * default
+            })
+            .sum()
+    }
+
+    // Keep the transitions of the states marked in `keep`, with the targets renumbered
+    // by `index`
+    fn retain(&mut self, keep: &[bool], index: &[usize]) {
+        let mut keep_exact = keep.iter();
+        self.exact.retain(|_| *keep_exact.next().unwrap());
+        let mut keep_default = keep.iter();
+        self.defaults.retain(|_| *keep_default.next().unwrap());
+        for targets in self.exact.iter_mut().flat_map(BTreeMap::values_mut) {
+            for target in targets {
+                *target = index[*target];
+            }
+        }
+        for (_, target) in self.defaults.iter_mut().flatten() {
+            *target = index[*target];
+        }
+    }
+}
+
+// The states `state` leads to, through its own transitions and failure link or those
+// of `branches`
+fn successors_of<'s>(
+    states: &'s [State],
+    branches: Option<&'s Branches>,
+    state: usize,
+) -> impl Iterator<Item = usize> + 's {
+    let branched = branches.into_iter().flat_map(move |branches| branches.successors(state));
+    states[state].successors().chain(branched)
 }
 
+#[derive(Debug, Clone)]
 pub struct PatternBuilder {
     states: Vec<State>,
     transitions: Vec<(usize, u8, usize)>,
+    default_transitions: Vec<(usize, ByteSet, usize)>,
+    epsilon_transitions: Vec<(usize, usize)>,
+    case_insensitive: bool,
+    anchored: bool,
+    determinize: bool,
+    state_limit: usize,
+    minimize: bool,
+    reject_unmatchable: bool,
+    metadata: BTreeMap<String, String>,
+    initial_states: Vec<usize>,
+    dense: bool,
+    // Give tries failure links, see add_trie_failures. Off when building the result of
+    // determinization, which has to match like the automaton it came from
+    trie_failures: bool,
 }
 
 impl PatternBuilder {
     pub fn new() -> Self {
         PatternBuilder {
-            states: vec![State {
-                transitions: HashMap::new(),
-                is_final: false,
-            }],
+            states: vec![State::new(false)],
             transitions: Vec::new(),
+            default_transitions: Vec::new(),
+            epsilon_transitions: Vec::new(),
+            case_insensitive: false,
+            anchored: false,
+            determinize: true,
+            state_limit: DEFAULT_STATE_LIMIT,
+            minimize: false,
+            reject_unmatchable: false,
+            metadata: BTreeMap::new(),
+            initial_states: vec![0],
+            dense: false,
+            trie_failures: true,
         }
     }
 
-    pub fn add_state(&mut self, is_final: bool) -> usize {
-        let state_idx = self.states.len();
-        self.states.push(State {
-            transitions: HashMap::new(),
-            is_final,
-        });
-        state_idx
+    // Make every transition on an ASCII letter also accept the other case
+    // Explicit transitions on the other case take precedence
+    pub fn case_insensitive(&mut self, enabled: bool) -> &mut Self {
+        self.case_insensitive = enabled;
+        self
     }
 
-    pub fn add_transition(&mut self, from: usize, byte: u8, to: usize) -> &mut Self {
-        if from >= self.states.len() || to >= self.states.len() {
-            panic!("Invalid state index");
-        }
-        self.transitions.push((from, byte, to));
+    // Only match from the start of the stream: a byte without a transition ends the
+    // pattern for the rest of the stream, so it costs nothing until StreamMatcher::finish
+    pub fn anchored(&mut self, enabled: bool) -> &mut Self {
+        self.anchored = enabled;
         self
     }
 
-    pub fn build(mut self, id: String) -> Result<Pattern, Error> {
-        // Validate pattern before building
-        if self.states.is_empty() {
-            return Err(Error::InvalidPattern("Pattern must have at least one state".into()));
-        }
+    // Let build() turn a nondeterministic automaton, where a byte can lead from one
+    // state to several, into a deterministic one by subset construction (the default)
+    // Without it such automatons are kept as they are, and the matcher tracks the set
+    // of states they can be in, one step per state in the set for every byte. They
+    // match exactly like the determinized pattern, trading speed for not needing up to
+    // exponentially many states, and cannot be minimized, reversed or combined.
+    // Deterministic automatons are kept as built either way.
+    pub fn determinize(&mut self, enabled: bool) -> &mut Self {
+        self.determinize = enabled;
+        self
+    }
 
-        // Build transitions
-        for (from, byte, to) in self.transitions {
-            self.states[from].transitions.insert(byte, to);
-        }
+    // Fail with Error::PatternTooComplex when the pattern has more than `state_limit`
+    // states, or would need more once determinized (DEFAULT_STATE_LIMIT by default)
+    // Determinization stops as soon as it reaches the limit.
+    pub fn state_limit(&mut self, state_limit: usize) -> &mut Self {
+        self.state_limit = state_limit;
+        self
+    }
 
-        Ok(Pattern {
-            id,
-            states: self.states,
-            initial_state: 0,
-        })
+    // Minimize the built pattern, see Pattern::minimize
+    pub fn minimize(&mut self, enabled: bool) -> &mut Self {
+        self.minimize = enabled;
+        self
     }
-}
 
-impl Default for PatternBuilder {
-    fn default() -> Self {
-        Self::new()
+    // Fail with Error::InvalidPattern when no final state can be reached, instead of
+    // logging a warning and building a pattern that never matches
+    pub fn reject_unmatchable(&mut self, enabled: bool) -> &mut Self {
+        self.reject_unmatchable = enabled;
+        self
     }
-}
 
-// Convert a string pattern into a state machine
-// This is a simplified implementation - a real one would parse regex syntax
-pub fn compile_pattern(pattern: &str) -> Result<Pattern, Error> {
-    let mut builder = PatternBuilder::new();
-    let mut current_state = 0;
+    // Give every state a table with the target of each byte class, so matching takes
+    // two array lookups per byte instead of a hash lookup
+    // Bytes that lead to the same state from every state share a class, so tables
+    // take 4 bytes per class, e.g. 40 bytes for ten classes rather than 1 KiB for all
+    // 256 bytes. Without it only states with more than 32 exact transitions get one.
+    pub fn dense(&mut self, enabled: bool) -> &mut Self {
+        self.dense = enabled;
+        self
+    }
 
-    for (i, byte) in pattern.bytes().enumerate() {
-        let next_state = builder.add_state(i == pattern.len() - 1);
-        builder.add_transition(current_state, byte, next_state);
-        current_state = next_state;
+    // Store `value` for `key` on the built pattern, see Pattern::metadata
+    pub fn metadata(&mut self, key: &str, value: &str) -> &mut Self {
+        self.metadata.insert(key.to_string(), value.to_string());
+        self
     }
 
-    builder.build(pattern.to_string())
-}
+    // Start matching from `state` instead of state 0, e.g. for automatons generated by
+    // other tools. Unanchored patterns also restart there.
+    pub fn set_initial_state(&mut self, state: usize) -> Result<&mut Self, Error> {
+        self.set_initial_states(&[state])
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // Start matching in all of `states` at once, as if an epsilon transition led from
+    // the initial state to each
+    // build() adds a new initial state for them, which may make the automaton
+    // nondeterministic and so require determinization.
+    pub fn set_initial_states(&mut self, states: &[usize]) -> Result<&mut Self, Error> {
+        if states.is_empty() {
+            return Err(Error::InvalidPattern("At least one initial state is required".into()));
+        }
+        self.check_states(states)?;
+        self.initial_states.clear();
+        self.initial_states.extend_from_slice(states);
+        Ok(self)
+    }
 
-    #[test]
-    fn test_pattern_builder() {
-        let mut builder = PatternBuilder::new();
-        let s1 = builder.add_state(false);
-        let s2 = builder.add_state(true);
+    pub fn add_state(&mut self, is_final: bool) -> usize {
+        let state_idx = self.states.len();
+        self.states.push(State::new(is_final));
+        state_idx
+    }
 
-        builder
-            .add_transition(0, b'a', s1)
-            .add_transition(s1, b'b', s2);
+    // Add a final state whose matches are reported with `tag`
+    // Lets one pattern tell its callbacks which alternative matched, e.g. a different
+    // tag for each of GET, POST and PUT. See StreamMatcher::add_tagged_callback.
+    pub fn add_state_tagged(&mut self, tag: u32) -> usize {
+        let state = self.add_state(true);
+        self.states[state].final_tag = Some(tag);
+        state
+    }
 
-        let pattern = builder.build("test".into()).unwrap();
+    // Fails with Error::InvalidState if `from` or `to` has not been added, as do all
+    // methods taking state indices, so automatons read from untrusted rule files can
+    // be rejected instead of aborting
+    pub fn add_transition(&mut self, from: usize, byte: u8, to: usize) -> Result<&mut Self, Error> {
+        self.check_states(&[from, to])?;
+        self.transitions.push((from, byte, to));
+        Ok(self)
+    }
 
-        assert_eq!(pattern.states.len(), 3);
-        assert!(pattern.states[s2].is_final);
+    fn check_states(&self, states: &[usize]) -> Result<(), Error> {
+        match states.iter().find(|&&state| state >= self.states.len()) {
+            Some(&index) => Err(Error::InvalidState {
+                index,
+                state_count: self.states.len(),
+            }),
+            None => Ok(()),
+        }
     }
 
-    #[test]
-    fn test_compile_pattern() {
-        let pattern = compile_pattern("abc").unwrap();
-        assert_eq!(pattern.states.len(), 4); // initial + 3 states
-        assert!(pattern.states.last().unwrap().is_final);
+    // Transitions on every byte of `range`, e.g. b'a'..=b'z'
+    pub fn add_transition_range(
+        &mut self,
+        from: usize,
+        range: RangeInclusive<u8>,
+        to: usize,
+    ) -> Result<&mut Self, Error> {
+        self.check_states(&[from, to])?;
+        for byte in range {
+            self.transitions.push((from, byte, to));
+        }
+        Ok(self)
+    }
+
+    // Transitions on each byte of `bytes`, e.g. b"+-"
+    pub fn add_transition_set(
+        &mut self,
+        from: usize,
+        bytes: &[u8],
+        to: usize,
+    ) -> Result<&mut Self, Error> {
+        self.check_states(&[from, to])?;
+        for &byte in bytes {
+            self.transitions.push((from, byte, to));
+        }
+        Ok(self)
     }
-}
\ No newline at end of file
+
+    // Transition on `byte` and, if it is an ASCII letter, on its other case
+    pub fn add_caseless_transition(
+        &mut self,
+        from: usize,
+        byte: u8,
+        to: usize,
+    ) -> Result<&mut Self, Error> {
+        self.add_transition(from, byte, to)?;
+        if let Some(other) = ascii_other_case(byte) {
+            self.add_transition(from, other, to)?;
+        }
+        Ok(self)
+    }
+
+    // Let every transition added so far on an ASCII letter also accept the other case
+    // Explicit transitions on the other case take precedence, and letters excluded
+    // from a default transition are excluded in both cases. case_insensitive(true)
+    // does this when the pattern is built.
+    pub fn make_caseless(&mut self) -> &mut Self {
+        let explicit: HashSet<(usize, u8)> =
+            self.transitions.iter().map(|&(from, byte, _)| (from, byte)).collect();
+        for index in 0..self.transitions.len() {
+            let (from, byte, to) = self.transitions[index];
+            if let Some(other) = ascii_other_case(byte)
+                && !explicit.contains(&(from, other))
+            {
+                self.transitions.push((from, other, to));
+            }
+        }
+        for (_, excluded, _) in &mut self.default_transitions {
+            *excluded = excluded.fold_ascii_case();
+        }
+        self
+    }
+
+    // Add a chain of new, non-final states spelling out `bytes` from `from`
+    // Returns the state reached after the last byte (`from` itself for no bytes), to
+    // continue building from.
+    pub fn append_literal(&mut self, from: usize, bytes: &[u8]) -> Result<usize, Error> {
+        self.check_states(&[from])?;
+        Ok(bytes.iter().fold(from, |state, &byte| {
+            let next = self.add_state(false);
+            self.transitions.push((state, byte, next));
+            next
+        }))
+    }
+
+    pub(crate) fn set_accepts_at_end(&mut self, state: usize) -> Result<&mut Self, Error> {
+        self.check_states(&[state])?;
+        self.states[state].accepts_at_end = true;
+        Ok(self)
+    }
+
+    pub(crate) fn set_finality(
+        &mut self,
+        state: usize,
+        is_final: bool,
+        accepts_at_end: bool,
+        tag: Option<u32>,
+    ) -> Result<&mut Self, Error> {
+        self.check_states(&[state])?;
+        let state = &mut self.states[state];
+        (state.is_final, state.accepts_at_end, state.final_tag) = (is_final, accepts_at_end, tag);
+        state.ends_before = false;
+        Ok(self)
+    }
+
+    // Matches completing in `state` end one byte before it, see State::ends_before
+    pub(crate) fn set_ends_before(&mut self, state: usize) -> Result<&mut Self, Error> {
+        self.check_states(&[state])?;
+        self.states[state].ends_before = true;
+        Ok(self)
+    }
+
+    // Transition taken on any byte without an exact transition from `from`
+    // Patterns then restart only on bytes that have neither, as with '.' or a hex '??'.
+    pub fn add_wildcard_transition(&mut self, from: usize, to: usize) -> Result<&mut Self, Error> {
+        self.add_default_transition(from, ByteSet::new(), to)
+    }
+
+    // Transition taken on every byte except `excluded`, e.g. for [^"]
+    // Stored as one default transition and a 32-byte exclusion set instead of a
+    // transition per byte; exact transitions from `from` still take precedence.
+    pub fn add_negated_transition(
+        &mut self,
+        from: usize,
+        excluded: &[u8],
+        to: usize,
+    ) -> Result<&mut Self, Error> {
+        let mut set = ByteSet::new();
+        for &byte in excluded {
+            set.insert(byte);
+        }
+        self.add_default_transition(from, set, to)
+    }
+
+    // Transition taken on every byte except `excluded`, unless an exact transition exists
+    pub(crate) fn add_default_transition(
+        &mut self,
+        from: usize,
+        excluded: ByteSet,
+        to: usize,
+    ) -> Result<&mut Self, Error> {
+        self.check_states(&[from, to])?;
+        self.default_transitions.push((from, excluded, to));
+        Ok(self)
+    }
+
+    // Transition taken without consuming a byte: whenever the pattern is in `from`,
+    // it is also in `to`
+    // build() removes epsilon transitions by giving every state the transitions and
+    // finality of the states it reaches through them, so the result must still have
+    // at most one target per byte and state.
+    // On a byte `state` has no transition for, continue from `target` with the same
+    // byte instead of restarting, so an attempt that fails can hand over to one that
+    // started later (an Aho-Corasick failure link). Links are followed until a state
+    // has a transition or the initial state is reached, and must not form a cycle.
+    // Unanchored tries without failure links, where every state but the initial one is
+    // reached by a single transition, get them from build() (see add_trie_failures).
+    pub fn set_failure(&mut self, state: usize, target: usize) -> Result<&mut Self, Error> {
+        self.check_states(&[state, target])?;
+        self.states[state].failure = Some(target);
+        Ok(self)
+    }
+
+    pub fn add_epsilon_transition(&mut self, from: usize, to: usize) -> Result<&mut Self, Error> {
+        self.check_states(&[from, to])?;
+        self.epsilon_transitions.push((from, to));
+        Ok(self)
+    }
+
+    // Build one pattern that finds every literal, as an Aho-Corasick automaton
+    // Literals share the states of common prefixes, and the final state of each is
+    // tagged with its index in `literals` for StreamMatcher::add_tagged_callback.
+    // Where several literals end at the same byte only the longest is reported. The
+    // pattern id joins the literal ids with '|'.
+    pub fn from_literals(literals: &[(&str, &[u8])]) -> Result<Pattern, Error> {
+        if literals.is_empty() {
+            return Err(Error::InvalidPattern("At least one literal is required".into()));
+        }
+
+        // The trie of all literals, with the tag of the literal ending at each node
+        let mut children: Vec<HashMap<u8, usize>> = vec![HashMap::new()];
+        let mut tags: Vec<Option<u32>> = vec![None];
+        for (tag, &(id, bytes)) in literals.iter().enumerate() {
+            if bytes.is_empty() {
+                return Err(Error::InvalidPattern(format!("Literal '{}' is empty", id)));
+            }
+            let mut node = 0;
+            for &byte in bytes {
+                node = match children[node].get(&byte) {
+                    Some(&child) => child,
+                    None => {
+                        let child = children.len();
+                        children.push(HashMap::new());
+                        tags.push(None);
+                        children[node].insert(byte, child);
+                        child
+                    }
+                };
+            }
+            tags[node].get_or_insert(tag as u32);
+        }
+
+        // The failure link of a node is the longest proper suffix of its bytes that is
+        // in the trie, found in breadth-first order from the links of shallower nodes
+        let mut fail = vec![0; children.len()];
+        let mut queue: VecDeque<usize> =
+            (0..=255u8).filter_map(|byte| children[0].get(&byte).copied()).collect();
+        while let Some(node) = queue.pop_front() {
+            for byte in 0..=255u8 {
+                let Some(&child) = children[node].get(&byte) else {
+                    continue;
+                };
+                let mut suffix = fail[node];
+                fail[child] = loop {
+                    if let Some(&next) = children[suffix].get(&byte) {
+                        break next;
+                    }
+                    if suffix == 0 {
+                        break 0;
+                    }
+                    suffix = fail[suffix];
+                };
+                queue.push_back(child);
+            }
+            if tags[node].is_none() {
+                tags[node] = tags[fail[node]];
+            }
+        }
+
+        let mut builder = PatternBuilder::new();
+        for &tag in &tags[1..] {
+            match tag {
+                Some(tag) => builder.add_state_tagged(tag),
+                None => builder.add_state(false),
+            };
+        }
+        for (node, row) in children.iter().enumerate() {
+            for (&byte, &child) in row {
+                builder.add_transition(node, byte, child)?;
+            }
+            if node != 0 {
+                builder.set_failure(node, fail[node])?;
+            }
+        }
+        let id = literals.iter().map(|&(id, _)| id).collect::<Vec<_>>().join("|");
+        let mut pattern = builder.build(id)?;
+        let lengths = literals.iter().map(|&(_, bytes)| bytes.len());
+        pattern.min_match_len = lengths.clone().min().unwrap_or(0);
+        pattern.max_match_len = lengths.max();
+        Ok(pattern)
+    }
+
+    // Build a pattern from what has been added so far, keeping the builder as it is
+    // so it can be extended and built again, or reset for the next pattern
+    pub fn build_ref(&mut self, id: String) -> Result<Pattern, Error> {
+        self.clone().build(id)
+    }
+
+    // Remove all states but a fresh initial state, all transitions and the metadata,
+    // keeping the memory they used for the next pattern
+    // Options such as anchored or state_limit stay as they are. Compiling many patterns
+    // with one builder, using build_ref and reset, saves growing new buffers each time.
+    pub fn reset(&mut self) -> &mut Self {
+        self.states.clear();
+        self.states.push(State::new(false));
+        self.transitions.clear();
+        self.default_transitions.clear();
+        self.epsilon_transitions.clear();
+        self.metadata.clear();
+        self.initial_states.clear();
+        self.initial_states.push(0);
+        self
+    }
+
+    pub fn build(mut self, id: String) -> Result<Pattern, Error> {
+        // Validate pattern before building
+        if self.states.is_empty() {
+            return Err(Error::InvalidPattern("Pattern must have at least one state".into()));
+        }
+        nfa::check_expansion(self.states.len(), self.state_limit)?;
+        self.check_states(&self.initial_states)?;
+        let initial = match self.initial_states[..] {
+            [state] => state,
+            _ => {
+                let entry = self.add_state(false);
+                for index in 0..self.initial_states.len() {
+                    self.epsilon_transitions.push((entry, self.initial_states[index]));
+                }
+                entry
+            }
+        };
+        self.eliminate_epsilons();
+        if self.case_insensitive {
+            self.make_caseless();
+        }
+        if self.is_nondeterministic() {
+            if self.states.iter().any(|state| state.failure.is_some()) {
+                return Err(Error::InvalidPattern(
+                    "Failure links require a deterministic automaton".into(),
+                ));
+            }
+            return match self.determinize {
+                true => self.build_determinized(id, initial),
+                false => self.build_nondeterministic(id, initial),
+            };
+        }
+
+        // Build transitions, each byte leading from a state to a single other
+        for &(from, byte, to) in &self.transitions {
+            self.states[from].transitions.insert(byte, to);
+        }
+        for (from, excluded, to) in self.default_transitions {
+            let state = &mut self.states[from];
+            match state.default_transition {
+                // A byte is only excluded if every default transition excludes it
+                Some(_) => {
+                    let mut covered = state.excluded.negate();
+                    covered.union_with(&excluded.negate());
+                    state.excluded = covered.negate();
+                }
+                None => {
+                    state.default_transition = Some(to);
+                    state.excluded = excluded;
+                }
+            }
+        }
+
+        // Failure links only hand over to shorter attempts, so the lengths are those of
+        // the trie
+        let (min_match_len, max_match_len) = path_len_bounds(&self.states, None, initial);
+        if !self.anchored && self.trie_failures {
+            add_trie_failures(&mut self.states, initial);
+        }
+        let (states, initial, prune_stats) = prune_unreachable(self.states, None, initial);
+        if let Some(start) = failure_cycle(&states) {
+            return Err(Error::InvalidPattern(format!(
+                "Failure links from state {} form a cycle",
+                start
+            )));
+        }
+        check_matchable(&id, prune_stats, self.reject_unmatchable)?;
+
+        let mut pattern = Pattern {
+            id,
+            states,
+            initial_state: initial,
+            kind: PatternKind::Automaton,
+            anchored: self.anchored,
+            min_match_len,
+            max_match_len,
+            unminimized_state_count: None,
+            prune_stats,
+            metadata: self.metadata,
+            number: None,
+            negative: false,
+            priority: 0,
+            dense: self.dense,
+            class_of: [0; 256],
+            branches: None,
+            syntax: None,
+        };
+        if self.minimize {
+            return Ok(pattern.minimize());
+        }
+        pattern.update_dense_tables();
+        Ok(pattern)
+    }
+
+    // Add the transitions of one state from its target for every byte, using a default
+    // transition when most bytes share the same target
+    pub(crate) fn add_state_transitions(
+        &mut self,
+        from: usize,
+        targets: &[Option<usize>; 256],
+    ) -> Result<(), Error> {
+        let mut counts: HashMap<usize, usize> = HashMap::new();
+        for &target in targets.iter().flatten() {
+            *counts.entry(target).or_insert(0) += 1;
+        }
+        let default = counts
+            .into_iter()
+            .filter(|&(_, count)| count > 128)
+            .map(|(target, _)| target)
+            .next();
+
+        if let Some(default) = default {
+            let mut excluded = ByteSet::new();
+            for (byte, target) in (0..=255u8).zip(targets) {
+                if target.is_none() {
+                    excluded.insert(byte);
+                }
+            }
+            self.add_default_transition(from, excluded, default)?;
+        }
+
+        for (byte, &target) in (0..=255u8).zip(targets) {
+            if let Some(target) = target.filter(|&target| Some(target) != default) {
+                self.add_transition(from, byte, target)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Copy the states of `pattern`, returning the index of its initial state and the
+    // range of the copy
+    // With `search`, bytes the pattern has no transition for lead back to its initial
+    // state as when StreamMatcher runs it on its own (unless it is anchored). Without,
+    // they end the attempt of the copy, and a literal is copied as its bytes alone.
+    pub(crate) fn add_pattern(
+        &mut self,
+        pattern: &Pattern,
+        search: bool,
+    ) -> Result<(usize, Range<usize>), Error> {
+        pattern.require_deterministic("combine")?;
+        let start = self.states.len();
+        if let (false, PatternKind::Literal(literal)) = (search, &pattern.kind) {
+            let initial = self.add_state(false);
+            let last = self.append_literal(initial, literal)?;
+            self.states[last].is_final = true;
+            return Ok((initial, start..self.states.len()));
+        }
+
+        let restart = search && !pattern.anchored;
+        for state in &pattern.states {
+            let copy = self.add_state(false);
+            self.states[copy].merge_final(state);
+        }
+        for state in 0..pattern.states.len() {
+            let mut targets = [None; 256];
+            for (byte, target) in (0..=255u8).zip(targets.iter_mut()) {
+                let next = match restart {
+                    true => pattern.search_next_state(state, byte).or(Some(pattern.initial_state)),
+                    false => pattern.next_state(state, byte),
+                };
+                *target = next.map(|next| start + next);
+            }
+            self.add_state_transitions(start + state, &targets)?;
+        }
+        Ok((start + pattern.initial_state, start..self.states.len()))
+    }
+
+    // Whether some state has more than one target for a byte
+    fn is_nondeterministic(&self) -> bool {
+        let mut exact = HashMap::new();
+        for &(from, byte, to) in &self.transitions {
+            if *exact.entry((from, byte)).or_insert(to) != to {
+                return true;
+            }
+        }
+        let mut defaults = HashMap::new();
+        for &(from, _, to) in &self.default_transitions {
+            if *defaults.entry(from).or_insert(to) != to {
+                return true;
+            }
+        }
+        false
+    }
+
+    // Subset construction: each state of the result stands for the set of states the
+    // automaton can be in
+    // Within one state, exact transitions still take precedence over defaults.
+    fn build_determinized(self, id: String, initial: usize) -> Result<Pattern, Error> {
+        let branches = Branches::new(&self);
+        let step = |set: &[usize], byte: u8| {
+            let mut next = Vec::new();
+            branches.step(set, byte, &mut next);
+            next
+        };
+
+        let mut builder = PatternBuilder::new();
+        builder.anchored(self.anchored).determinize(false).minimize(self.minimize);
+        builder.trie_failures = false;
+        builder.state_limit(self.state_limit).dense(self.dense);
+        builder.metadata = self.metadata.clone();
+        builder.reject_unmatchable(self.reject_unmatchable);
+        builder.states[0].merge_final(&self.states[initial]);
+        let mut sets = vec![vec![initial]];
+        let mut index = HashMap::from([(vec![initial], 0)]);
+        let mut current = 0;
+        while current < sets.len() {
+            let mut targets = [None; 256];
+            for (byte, target) in (0..=255u8).zip(targets.iter_mut()) {
+                let next = step(&sets[current], byte);
+                if next.is_empty() {
+                    continue;
+                }
+                *target = Some(match index.get(&next) {
+                    Some(&state) => state,
+                    None => {
+                        nfa::check_expansion(sets.len() + 1, self.state_limit)?;
+                        let state = builder.add_state(false);
+                        for &member in &next {
+                            builder.states[state].merge_final(&self.states[member]);
+                        }
+                        index.insert(next.clone(), state);
+                        sets.push(next);
+                        state
+                    }
+                });
+            }
+            builder.add_state_transitions(current, &targets)?;
+            current += 1;
+        }
+        builder.build(id)
+    }
+
+    // Keep a nondeterministic automaton as it is, with its transitions in the Branches
+    // of the pattern, see determinize
+    fn build_nondeterministic(self, id: String, initial: usize) -> Result<Pattern, Error> {
+        let mut branches = Branches::new(&self);
+        let (min_match_len, max_match_len) =
+            path_len_bounds(&self.states, Some(&branches), initial);
+        let (states, initial, prune_stats) =
+            prune_unreachable(self.states, Some(&mut branches), initial);
+        check_matchable(&id, prune_stats, self.reject_unmatchable)?;
+        Ok(Pattern {
+            id,
+            states,
+            initial_state: initial,
+            kind: PatternKind::Automaton,
+            anchored: self.anchored,
+            min_match_len,
+            max_match_len,
+            unminimized_state_count: None,
+            prune_stats,
+            metadata: self.metadata,
+            number: None,
+            negative: false,
+            priority: 0,
+            dense: self.dense,
+            class_of: [0; 256],
+            branches: Some(Box::new(branches)),
+            syntax: None,
+        })
+    }
+
+    // Give every state the transitions and finality of the states in its epsilon closure
+    fn eliminate_epsilons(&mut self) {
+        if self.epsilon_transitions.is_empty() {
+            return;
+        }
+        let count = self.states.len();
+        let mut epsilons = vec![Vec::new(); count];
+        for &(from, to) in &self.epsilon_transitions {
+            epsilons[from].push(to);
+        }
+        let mut exact = vec![Vec::new(); count];
+        for &(from, byte, to) in &self.transitions {
+            exact[from].push((byte, to));
+        }
+        let mut defaults = vec![Vec::new(); count];
+        for &(from, excluded, to) in &self.default_transitions {
+            defaults[from].push((excluded, to));
+        }
+
+        for state in 0..count {
+            let mut in_closure = vec![false; count];
+            in_closure[state] = true;
+            let mut stack = epsilons[state].clone();
+            while let Some(other) = stack.pop() {
+                if in_closure[other] {
+                    continue;
+                }
+                in_closure[other] = true;
+                stack.extend(&epsilons[other]);
+
+                let other_state = self.states[other].clone();
+                self.states[state].merge_final(&other_state);
+                for &(byte, to) in &exact[other] {
+                    self.transitions.push((state, byte, to));
+                }
+                for &(excluded, to) in &defaults[other] {
+                    self.default_transitions.push((state, excluded, to));
+                }
+            }
+
+            // A default transition only gives way to exact transitions of its own state,
+            // so it becomes exact where other states of the closure have exact ones
+            let members: Vec<usize> = (0..count).filter(|&member| in_closure[member]).collect();
+            let mut exact_bytes = ByteSet::new();
+            for &member in &members {
+                for &(byte, _) in &exact[member] {
+                    exact_bytes.insert(byte);
+                }
+            }
+            for &member in &members {
+                for &(excluded, to) in &defaults[member] {
+                    for byte in 0..=255u8 {
+                        if exact_bytes.contains(byte)
+                            && !excluded.contains(byte)
+                            && !exact[member].iter().any(|&(other, _)| other == byte)
+                        {
+                            self.transitions.push((state, byte, to));
+                        }
+                    }
+                }
+            }
+        }
+        self.epsilon_transitions.clear();
+    }
+}
+
+// Give the states of a trie the failure links of Aho-Corasick, so that overlapping
+// matches are found as with compiled patterns: a chain for "aa" matches "aaaa" three
+// times instead of twice, and one for "aab" matches "aaab" instead of losing the
+// second 'a' to the restart.
+// Only patterns whose states, apart from `initial`, are each reached by a single
+// transition qualify, with no default transitions or failure links of their own.
+// States also take on the finality of their link, so a suffix of the bytes leading to
+// them that matches is reported too.
+fn add_trie_failures(states: &mut [State], initial: usize) {
+    let mut incoming = vec![0usize; states.len()];
+    for state in states.iter() {
+        if state.default_transition.is_some() || state.failure.is_some() {
+            return;
+        }
+        for &target in state.transitions.values() {
+            incoming[target] += 1;
+        }
+    }
+    if incoming[initial] > 0 || incoming.iter().any(|&count| count > 1) {
+        return;
+    }
+
+    // Breadth-first, so the link of a state is known before its children need it
+    let mut queue = VecDeque::from([initial]);
+    while let Some(node) = queue.pop_front() {
+        let mut children: Vec<(u8, usize)> =
+            states[node].transitions.iter().map(|(&byte, &child)| (byte, child)).collect();
+        children.sort_unstable();
+        for (byte, child) in children {
+            let link = if node == initial {
+                initial
+            } else {
+                let mut suffix = states[node].failure.unwrap_or(initial);
+                loop {
+                    if let Some(&next) = states[suffix].transitions.get(&byte) {
+                        break next;
+                    }
+                    match states[suffix].failure {
+                        Some(shorter) => suffix = shorter,
+                        None => break initial,
+                    }
+                }
+            };
+            states[child].failure = Some(link);
+            let mut finality = State::new(false);
+            finality.merge_final(&states[link]);
+            states[child].merge_final(&finality);
+            queue.push_back(child);
+        }
+    }
+}
+
+// A state whose failure links lead into a cycle, if there is one
+fn failure_cycle(states: &[State]) -> Option<usize> {
+    (0..states.len()).find(|&start| {
+        let mut state = start;
+        for _ in 0..states.len() {
+            match states[state].failure {
+                Some(target) => state = target,
+                None => return false,
+            }
+        }
+        true
+    })
+}
+
+// Warn about a pattern without a reachable final state, or reject it
+fn check_matchable(id: &str, prune_stats: PruneStats, reject: bool) -> Result<(), Error> {
+    if prune_stats.final_states > 0 {
+        return Ok(());
+    }
+    if reject {
+        return Err(Error::InvalidPattern(format!(
+            "Pattern '{}' has no reachable final state",
+            id
+        )));
+    }
+    tracing::warn!("Pattern '{}' has no reachable final state and never matches", id);
+    Ok(())
+}
+
+// Remove the states that cannot be reached from `initial`, keeping the order of the
+// rest, and return the new index of `initial`
+// The transitions of `branches`, if given, are pruned and renumbered along with them.
+fn prune_unreachable(
+    states: Vec<State>,
+    branches: Option<&mut Branches>,
+    initial: usize,
+) -> (Vec<State>, usize, PruneStats) {
+    let mut reachable = vec![false; states.len()];
+    reachable[initial] = true;
+    let mut stack = vec![initial];
+    while let Some(state) = stack.pop() {
+        for successor in successors_of(&states, branches.as_deref(), state) {
+            if !reachable[successor] {
+                reachable[successor] = true;
+                stack.push(successor);
+            }
+        }
+    }
+
+    let mut index = Vec::with_capacity(states.len());
+    let mut next = 0;
+    for &reachable in &reachable {
+        index.push(next);
+        next += reachable as usize;
+    }
+    let stats = PruneStats {
+        unreachable_states: states.len() - next,
+        final_states: (0..states.len())
+            .filter(|&state| reachable[state] && states[state].accepts())
+            .count(),
+    };
+    if stats.unreachable_states == 0 {
+        return (states, initial, stats);
+    }
+    if let Some(branches) = branches {
+        branches.retain(&reachable, &index);
+    }
+
+    let states = states
+        .into_iter()
+        .zip(reachable)
+        .filter(|&(_, reachable)| reachable)
+        .map(|(mut state, _)| {
+            for target in state.transitions.values_mut() {
+                *target = index[*target];
+            }
+            state.default_transition = state.default_transition.map(|target| index[target]);
+            state.failure = state.failure.map(|target| index[target]);
+            state
+        })
+        .collect();
+    (states, index[initial], stats)
+}
+
+// Partition all bytes into classes that every state treats alike
+// Returns one representative byte per class and the class of every byte, numbered
+// in the order of their representatives
+pub(crate) fn byte_classes<'s>(
+    states: impl IntoIterator<Item = &'s State>,
+) -> (Vec<u8>, [u8; 256]) {
+    let mut class_of = [0usize; 256];
+    for state in states {
+        let mut split: HashMap<(usize, Option<usize>), usize> = HashMap::new();
+        for byte in 0..=255u8 {
+            let key = (class_of[byte as usize], state.next_state(byte));
+            let next_id = split.len();
+            class_of[byte as usize] = *split.entry(key).or_insert(next_id);
+        }
+    }
+
+    let mut representatives = Vec::new();
+    for byte in 0..=255u8 {
+        if class_of[byte as usize] == representatives.len() {
+            representatives.push(byte);
+        }
+    }
+    // At most 256 classes, numbered from 0
+    (representatives, class_of.map(|class| class as u8))
+}
+
+// Shortest and longest path from `initial` to a state where a match completes
+// Each attempt restarts at `initial`, so these bound the length of a match. The
+// longest path is None when it can pass through a cycle.
+fn path_len_bounds(
+    states: &[State],
+    branches: Option<&Branches>,
+    initial: usize,
+) -> (usize, Option<usize>) {
+    let successors = |state| successors_of(states, branches, state);
+    // Breadth-first distances give the shortest path and the reachable states
+    let mut distance = vec![None; states.len()];
+    distance[initial] = Some(0);
+    let mut queue = VecDeque::from([initial]);
+    let mut min = None;
+    while let Some(state) = queue.pop_front() {
+        let next = distance[state].unwrap() + 1;
+        if states[state].accepts() && min.is_none() {
+            min = distance[state];
+        }
+        for successor in successors(state) {
+            if distance[successor].is_none() {
+                distance[successor] = Some(next);
+                queue.push_back(successor);
+            }
+        }
+    }
+    let Some(min) = min else {
+        return (0, Some(0));
+    };
+
+    // Only states on some path to a match count for the longest path
+    let mut predecessors = vec![Vec::new(); states.len()];
+    for (state, _) in distance.iter().enumerate().filter(|(_, distance)| distance.is_some()) {
+        for successor in successors(state) {
+            predecessors[successor].push(state);
+        }
+    }
+    let mut live = vec![false; states.len()];
+    let mut stack: Vec<usize> = (0..states.len())
+        .filter(|&state| distance[state].is_some() && states[state].accepts())
+        .collect();
+    while let Some(state) = stack.pop() {
+        if !live[state] {
+            live[state] = true;
+            stack.extend(&predecessors[state]);
+        }
+    }
+
+    // Longest path in topological order; a cycle leaves some live state unvisited
+    let mut in_degree = vec![0; states.len()];
+    for state in (0..states.len()).filter(|&state| live[state]) {
+        for successor in successors(state).filter(|&successor| live[successor]) {
+            in_degree[successor] += 1;
+        }
+    }
+    let mut longest = vec![0; states.len()];
+    let mut ready: Vec<usize> = (0..states.len())
+        .filter(|&state| live[state] && in_degree[state] == 0)
+        .collect();
+    let mut visited = 0;
+    let mut max = 0;
+    while let Some(state) = ready.pop() {
+        visited += 1;
+        if states[state].accepts() {
+            max = max.max(longest[state]);
+        }
+        for successor in successors(state).filter(|&successor| live[successor]) {
+            longest[successor] = longest[successor].max(longest[state] + 1);
+            in_degree[successor] -= 1;
+            if in_degree[successor] == 0 {
+                ready.push(successor);
+            }
+        }
+    }
+    let acyclic = visited == live.iter().filter(|&&live| live).count();
+    (min, acyclic.then_some(max))
+}
+
+impl Default for PatternBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Default upper bound on the number of states a compiled pattern may use
+pub const DEFAULT_STATE_LIMIT: usize = 10_000;
+
+// Options for compile_pattern_with
+// The defaults give the behavior of compile_pattern. Set the fields directly or chain
+// the setters: CompileOptions::new().case_insensitive(true).state_limit(500)
+// The flags only set the starting point: the inline (?i), (?s), (?m) and (?x) flags take
+// precedence where they appear, so "(?-i)abc" stays case-sensitive even with
+// case_insensitive set, and "a(?i)b" is case-insensitive from the 'b' on without it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompileOptions {
+    pub state_limit: usize,
+    // Like (?i): ASCII letters match both cases
+    pub case_insensitive: bool,
+    // Like (?s): '.' also matches '\n'
+    pub dot_matches_newline: bool,
+    // Like (?m): '^' and '$' also match at line starts and ends
+    pub multi_line: bool,
+    // Like (?x): whitespace outside classes is ignored and '#' starts a comment
+    pub ignore_whitespace: bool,
+    // Like a leading '^': the pattern only matches at the start of the stream, and is
+    // skipped once it can no longer match there
+    pub anchored: bool,
+    // Give every state a dense table, see PatternBuilder::dense
+    pub dense: bool,
+}
+
+impl CompileOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn state_limit(mut self, state_limit: usize) -> Self {
+        self.state_limit = state_limit;
+        self
+    }
+
+    pub fn case_insensitive(mut self, enabled: bool) -> Self {
+        self.case_insensitive = enabled;
+        self
+    }
+
+    pub fn dot_matches_newline(mut self, enabled: bool) -> Self {
+        self.dot_matches_newline = enabled;
+        self
+    }
+
+    pub fn multi_line(mut self, enabled: bool) -> Self {
+        self.multi_line = enabled;
+        self
+    }
+
+    pub fn ignore_whitespace(mut self, enabled: bool) -> Self {
+        self.ignore_whitespace = enabled;
+        self
+    }
+
+    pub fn anchored(mut self, enabled: bool) -> Self {
+        self.anchored = enabled;
+        self
+    }
+
+    pub fn dense(mut self, enabled: bool) -> Self {
+        self.dense = enabled;
+        self
+    }
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            state_limit: DEFAULT_STATE_LIMIT,
+            case_insensitive: false,
+            dot_matches_newline: false,
+            multi_line: false,
+            ignore_whitespace: false,
+            anchored: false,
+            dense: false,
+        }
+    }
+}
+
+// Convert a string pattern into a state machine
+// Supports literal bytes, bracketed character classes such as [a-z0-9_] or [^\r\n],
+// the ASCII shorthand classes \d, \w, \s and their negations \D, \W, \S
+// (also inside brackets), POSIX classes such as [[:alpha:]] inside brackets,
+// the byte escapes \xNN, \n, \r, \t and \0,
+// the inline flags (?i) for ASCII case-insensitive matching, (?s) to let '.' match
+// '\n', (?m) to let '^' and '$' match at line starts and ends ("\n", "\r" and
+// "\r\n" all end a line) and (?x) for verbose patterns, where whitespace outside
+// brackets is ignored and '#' starts a comment running to the end of the line (write
+// "\ " or "\#" for the literal bytes), which apply to the rest of the enclosing
+// group (or only inside (?flags:...)),
+// '.' for any byte except '\n', the '*', '+' and '?' quantifiers, counted
+// repetition {m}, {m,} and {m,n}, alternation with '|', grouping with parentheses,
+// the anchors '^' (start of stream) and '$' (end of stream, see StreamMatcher::finish),
+// and the word boundaries \b and \B
+// Multi-byte UTF-8 characters are single atoms, also inside brackets, but '.', the
+// shorthand classes and negated ASCII classes match single bytes
+// With the `unicode` feature, \p{L}, \p{N} and \p{Alphabetic} (also \pL, \pN and the
+// negated \P forms) match whole characters; other properties are rejected with
+// Error::Syntax
+// A backslash before a metacharacter matches it literally (e.g. \. or \(); any
+// other unknown escape is rejected with Error::Syntax
+// Backreferences, lookaround and atomic groups are rejected with Error::Syntax
+//
+// The pattern is matched anywhere in the stream. Each match is reported at the
+// earliest byte where it completes; matches starting at different offsets may overlap.
+// Quantifiers therefore never wait for the longest run: "a+" on "aaa" reports three
+// matches (one per starting 'a'), while "a+b" on "aaab" reports one. For the same
+// reason the lazy forms *?, +?, ?? and {m,n}? are accepted and behave like the
+// greedy ones.
+pub fn compile_pattern(pattern: &str) -> Result<Pattern, Error> {
+    compile_pattern_with_limit(pattern, DEFAULT_STATE_LIMIT)
+}
+
+// Like compile_pattern, but fails with Error::PatternTooComplex when the pattern
+// needs more than `state_limit` states
+pub fn compile_pattern_with_limit(pattern: &str, state_limit: usize) -> Result<Pattern, Error> {
+    compile_pattern_with(pattern, &CompileOptions::new().state_limit(state_limit))
+}
+
+// Like compile_pattern, with the limit and starting flags taken from `options`
+pub fn compile_pattern_with(pattern: &str, options: &CompileOptions) -> Result<Pattern, Error> {
+    compile_bytes_with_options(pattern.as_bytes(), pattern.to_string(), options)
+}
+
+// Like compile_pattern, for patterns that are not valid UTF-8 (e.g. signatures read
+// from binary rule files)
+// Bytes that don't form UTF-8 characters match themselves, and escapes such as \xNN
+// work as usual. Matches are reported with `id`, since the pattern may not be
+// printable.
+pub fn compile_pattern_bytes(pattern: &[u8], id: &str) -> Result<Pattern, Error> {
+    compile_bytes_with_options(pattern, id.to_string(), &CompileOptions::default())
+}
+
+fn compile_bytes_with_options(
+    pattern: &[u8],
+    id: String,
+    options: &CompileOptions,
+) -> Result<Pattern, Error> {
+    let flags = Flags {
+        case_insensitive: options.case_insensitive,
+        dot_matches_newline: options.dot_matches_newline,
+        multi_line: options.multi_line,
+        ignore_whitespace: options.ignore_whitespace,
+    };
+    let mut node = parser::parse(pattern, flags).map_err(|error| error.with_pattern_id(&id))?;
+    if options.anchored {
+        node = Node::Concat(vec![Node::Assert(Assertion::StartText), node]);
+    }
+    let mut pattern = compile_node(&node, id, options.state_limit)?;
+    if options.dense {
+        pattern.dense = true;
+        pattern.update_dense_tables();
+    }
+    Ok(pattern)
+}
+
+// Build the state machine for a parsed pattern, shared by all frontends
+pub(crate) fn compile_node(node: &Node, id: String, state_limit: usize) -> Result<Pattern, Error> {
+    let nfa = Nfa::new(node, state_limit)?;
+    let mut pattern = nfa.determinize()?.build(id)?;
+    pattern.syntax = Some(Arc::new(node.clone()));
+    if let Some(literal) = literal_bytes(node).filter(|literal| !literal.is_empty()) {
+        pattern.kind = PatternKind::Literal(literal);
+    }
+    // The automaton starts a new attempt at every byte, so its paths can be longer
+    // than any match; the syntax tree gives the exact bounds
+    (pattern.min_match_len, pattern.max_match_len) = match_len_bounds(node);
+    Ok(pattern)
+}
+
+// Fewest and most bytes a node can match
+fn match_len_bounds(node: &Node) -> (usize, Option<usize>) {
+    match node {
+        Node::Byte(_) | Node::Class(_) => (1, Some(1)),
+        Node::Assert(_) => (0, Some(0)),
+        Node::Concat(nodes) => {
+            let (mut min, mut max) = (0usize, Some(0usize));
+            for node in nodes {
+                let (node_min, node_max) = match_len_bounds(node);
+                min = min.saturating_add(node_min);
+                max = max.zip(node_max).map(|(max, node_max)| max.saturating_add(node_max));
+            }
+            (min, max)
+        }
+        Node::Alternate(branches) => {
+            let bounds: Vec<_> = branches.iter().map(match_len_bounds).collect();
+            let min = bounds.iter().map(|&(min, _)| min).min().unwrap_or(0);
+            let max = bounds.iter().map(|&(_, max)| max).collect::<Option<Vec<_>>>();
+            (min, max.map(|max| max.into_iter().max().unwrap_or(0)))
+        }
+        Node::Repeat { node, min, max } => {
+            repeat_len_bounds(match_len_bounds(node), *min, *max)
+        }
+    }
+}
+
+// Fewest and most bytes `min` to `max` repetitions of a match within `bounds` span
+fn repeat_len_bounds(
+    (node_min, node_max): (usize, Option<usize>),
+    min: usize,
+    max: Option<usize>,
+) -> (usize, Option<usize>) {
+    let max = match (node_max, max) {
+        (Some(0), _) => Some(0),
+        (Some(node_max), Some(max)) => Some(node_max.saturating_mul(max)),
+        _ => None,
+    };
+    (node_min.saturating_mul(min), max)
+}
+
+// The bytes a node matches when it is only a sequence of fixed bytes
+fn literal_bytes(node: &Node) -> Option<Vec<u8>> {
+    match node {
+        Node::Byte(byte) => Some(vec![*byte]),
+        Node::Concat(nodes) => {
+            let mut literal = Vec::new();
+            for node in nodes {
+                literal.extend(literal_bytes(node)?);
+            }
+            Some(literal)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{StreamMatcher, compile_glob, compile_hex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    fn count_matches_in_chunks(pattern: &str, chunks: &[&[u8]]) -> usize {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_callback(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        matcher.add_pattern(compile_pattern(pattern).unwrap());
+        for chunk in chunks {
+            matcher.process_chunk(chunk);
+        }
+        matcher.finish();
+        count.load(Ordering::Relaxed)
+    }
+
+    // Offsets of the bytes at which matches are reported
+    fn match_ends(pattern: &str, input: &[u8]) -> Vec<usize> {
+        pattern_match_ends(compile_pattern(pattern).unwrap(), input)
+    }
+
+    fn pattern_match_ends(pattern: Pattern, input: &[u8]) -> Vec<usize> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_callback(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        matcher.add_pattern(pattern);
+
+        let mut ends = Vec::new();
+        for (offset, byte) in input.iter().enumerate() {
+            let before = count.load(Ordering::Relaxed);
+            matcher.process_chunk(std::slice::from_ref(byte));
+            ends.extend((before..count.load(Ordering::Relaxed)).map(|_| offset));
+        }
+        ends
+    }
+
+    fn count_matches(pattern: &str, input: &[u8]) -> usize {
+        count_matches_in_chunks(pattern, &[input])
+    }
+
+    fn count_matches_bytewise(pattern: &str, input: &[u8]) -> usize {
+        let chunks: Vec<&[u8]> = input.chunks(1).collect();
+        count_matches_in_chunks(pattern, &chunks)
+    }
+
+    #[test]
+    fn test_pattern_builder() {
+        let mut builder = PatternBuilder::new();
+        let s1 = builder.add_state(false);
+        let s2 = builder.add_state(true);
+
+        builder.add_transition(0, b'a', s1).unwrap();
+        builder.add_transition(s1, b'b', s2).unwrap();
+
+        let pattern = builder.build("test".into()).unwrap();
+
+        assert_eq!(pattern.states.len(), 3);
+        assert!(pattern.states[s2].is_final);
+    }
+
+    #[test]
+    fn test_compile_pattern() {
+        let pattern = compile_pattern("abc").unwrap();
+        assert_eq!(pattern.states.len(), 4); // initial + 3 states
+        assert!(pattern.states.last().unwrap().is_final);
+    }
+
+    #[test]
+    fn test_compile_character_class() {
+        let pattern = compile_pattern("[0-9]x").unwrap();
+        assert_eq!(pattern.states.len(), 3);
+        assert_eq!(pattern.states[0].transitions.len(), 10);
+        assert!(pattern.states[0].transitions.values().all(|&to| to == 1));
+        assert_eq!(pattern.states[1].transitions.get(&b'x'), Some(&2));
+    }
+
+    #[test]
+    fn test_compile_unterminated_class() {
+        match compile_pattern("[abc") {
+            Err(Error::Syntax(error)) => assert!(error.message.contains("Unterminated")),
+            other => panic!("expected a syntax error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_compile_negated_class() {
+        let pattern = compile_pattern("=[^ab]").unwrap();
+        let state = &pattern.states[1];
+        assert!(state.transitions.len() <= 1);
+        assert_eq!(state.next_state(b'c'), Some(2));
+        assert_eq!(state.next_state(0xff), Some(2));
+        assert_eq!(state.next_state(b'a'), None);
+        assert_eq!(state.next_state(b'b'), None);
+    }
+
+    #[test]
+    fn test_compile_dot() {
+        let pattern = compile_pattern("a.c").unwrap();
+        assert!(pattern.states[1].transitions.len() <= 1);
+        assert!(pattern.states[1].default_transition.is_some());
+
+        assert_eq!(count_matches("a.c", b"abc"), 1);
+        assert_eq!(count_matches("a.c", b"a.c"), 1);
+        assert_eq!(count_matches("a.c", b"a\xffc"), 1);
+        assert_eq!(count_matches("a.c", b"a\nc"), 0);
+    }
+
+    #[test]
+    fn test_compile_escaped_dot() {
+        assert_eq!(count_matches(r"a\.c", b"a.c"), 1);
+        assert_eq!(count_matches(r"a\.c", b"abc"), 0);
+    }
+
+    #[test]
+    fn test_compile_escaped_metacharacters() {
+        assert_eq!(count_matches("eval\\(.*\\)", b"x = eval(atob(s));"), 1);
+        assert_eq!(count_matches("eval\\(.*\\)", b"evaluate(x)"), 0);
+        assert_eq!(count_matches(r"1\+1\*2\?", b"1+1*2?"), 1);
+        assert_eq!(count_matches(r"C:\\Windows", b"C:\\Windows"), 1);
+        assert_eq!(count_matches(r"[\[\]]", b"[]"), 2);
+    }
+
+    #[test]
+    fn test_compile_rejects_unknown_escapes() {
+        for pattern in [r"\q", r"a\y", r"[\q]", "abc\\"] {
+            assert!(
+                matches!(compile_pattern(pattern), Err(Error::Syntax(_))),
+                "{}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn test_compile_star() {
+        for input in [&b"ac"[..], b"abc", b"abbbbc", b"xxabbcxx"] {
+            assert_eq!(count_matches("ab*c", input), 1, "{:?}", input);
+            assert_eq!(count_matches_bytewise("ab*c", input), 1, "{:?}", input);
+        }
+        assert_eq!(count_matches("ab*c", b"abxc"), 0);
+        assert_eq!(count_matches_in_chunks("ab*c", &[b"ab", b"bb", b"bc"]), 1);
+    }
+
+    #[test]
+    fn test_compile_star_on_class_and_dot() {
+        assert_eq!(count_matches("<script.*>", b"<script type=\"text/javascript\">"), 1);
+        assert_eq!(count_matches("<script.*>", b"<script\n>"), 0);
+        assert_eq!(count_matches("x[0-9]*y", b"x0123y xy x1a2y"), 2);
+    }
+
+    #[test]
+    fn test_compile_restarts_after_partial_match() {
+        assert_eq!(count_matches("aab", b"aaab"), 1);
+        assert_eq!(count_matches("nene", b"nenene"), 2);
+    }
+
+    #[test]
+    fn test_compile_dangling_star() {
+        assert!(matches!(compile_pattern("*abc"), Err(Error::Syntax(_))));
+    }
+
+    #[test]
+    fn test_compile_plus() {
+        assert_eq!(count_matches("a+b+c+", b"aabcc"), 1);
+        assert_eq!(count_matches("a+b+c+", b"aaabbbccc"), 1);
+        assert_eq!(count_matches("a+b+c+", b"abbc"), 1);
+        assert_eq!(count_matches("a+b+c+", b"bcc ac"), 0);
+        assert_eq!(count_matches_in_chunks("a+b+c+", &[b"xaa", b"aab", b"bbc"]), 1);
+        assert_eq!(count_matches_bytewise("a+b+c+", b"aaabbbccc"), 1);
+    }
+
+    #[test]
+    fn test_compile_plus_reports_earliest_completion() {
+        // Every starting 'a' completes a match as soon as it is seen
+        assert_eq!(count_matches("a+", b"aaaa b"), 4);
+        // Runs before the terminator fold into a single attempt
+        assert_eq!(count_matches("a+ b", b"aaaa b"), 1);
+        assert_eq!(count_matches_in_chunks("a+ b", &[b"aa", b"aa", b" b"]), 1);
+    }
+
+    #[test]
+    fn test_compile_optional() {
+        let pattern = compile_pattern("https?://").unwrap();
+        // After "http" both 's' and ':' leave the same state
+        let after_http = b"http".iter().fold(0, |state, &byte| {
+            pattern.states[state].next_state(byte).unwrap()
+        });
+        assert!(pattern.states[after_http].next_state(b's').is_some());
+        assert!(pattern.states[after_http].next_state(b':').is_some());
+
+        assert_eq!(count_matches("https?://", b"http://a https://b httpx://c"), 2);
+        assert_eq!(count_matches_in_chunks("https?://", &[b"http", b"s:/", b"/"]), 1);
+    }
+
+    #[test]
+    fn test_compile_optional_positions() {
+        // Start
+        assert_eq!(count_matches("x?yz", b"yz xyz"), 2);
+        // Middle
+        assert_eq!(count_matches("colou?r", b"color colour colouur"), 2);
+        // End: the shorter alternative completes first
+        assert_eq!(count_matches("abc?", b"ab abc"), 2);
+    }
+
+    #[test]
+    fn test_compile_dangling_optional() {
+        match compile_pattern("?abc") {
+            Err(error) => assert!(error.to_string().contains("offset 0"), "{}", error),
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_compile_counted_repetition() {
+        let card = "[0-9]{4}-[0-9]{4}";
+        assert_eq!(count_matches(card, b"card 1234-5678 end"), 1);
+        assert_eq!(count_matches(card, b"123-5678 1234-567x"), 0);
+        assert_eq!(count_matches_bytewise(card, b"1234-5678"), 1);
+
+        assert_eq!(count_matches("ab{2,}c", b"abc abbc abbbbc"), 2);
+        assert_eq!(count_matches("ab{1,3}c", b"ac abc abbbc abbbbc"), 2);
+        assert_eq!(count_matches("x{0}y", b"y"), 1);
+    }
+
+    #[test]
+    fn test_compile_repetition_limit() {
+        match compile_pattern_with_limit("a{1,1000}", 100) {
+            Err(Error::PatternTooComplex(msg)) => assert!(msg.contains("2000"), "{}", msg),
+            other => panic!("expected PatternTooComplex, got {:?}", other),
+        }
+        assert!(compile_pattern_with_limit("a{1,10}", 100).is_ok());
+        assert!(matches!(
+            compile_pattern("[0-9]{1000000}"),
+            Err(Error::PatternTooComplex(_))
+        ));
+    }
+
+    #[test]
+    fn test_compile_alternation() {
+        let pattern = compile_pattern("GET /admin|POST /admin|DELETE /admin").unwrap();
+        let ids = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = ids.clone();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_callback(move |id| seen.lock().unwrap().push(id.to_string()));
+        matcher.add_pattern(pattern);
+        matcher.process_chunk(b"GET /admin\nPOST /admin\nPUT /admin\nDELETE /admin\n");
+
+        let ids = ids.lock().unwrap();
+        assert_eq!(ids.len(), 3);
+        assert!(ids.iter().all(|id| id == "GET /admin|POST /admin|DELETE /admin"));
+    }
+
+    #[test]
+    fn test_compile_alternation_precedence() {
+        assert_eq!(count_matches("abc|def", b"abc def"), 2);
+        assert_eq!(count_matches("abc|def", b"abef adef"), 1);
+    }
+
+    #[test]
+    fn test_compile_alternation_branch_lengths() {
+        assert_eq!(count_matches("x|longer", b"a longer x"), 2);
+        assert_eq!(count_matches_bytewise("x|longer", b"longer"), 1);
+        // The shorter branch completes first and ends the attempt
+        assert_eq!(count_matches("ab|abcd", b"abcd"), 1);
+    }
+
+    #[test]
+    fn test_compile_alternation_shared_prefix() {
+        let pattern = "SELECT \\*|SELECT COUNT|SELECT id";
+        assert_eq!(count_matches(pattern, b"SELECT * FROM t"), 1);
+        assert_eq!(count_matches(pattern, b"SELECT COUNT(1)"), 1);
+        assert_eq!(count_matches(pattern, b"SELECT name"), 0);
+        assert_eq!(count_matches_in_chunks(pattern, &[b"SEL", b"ECT CO", b"UNT"]), 1);
+    }
+
+    #[test]
+    fn test_compile_groups() {
+        assert_eq!(count_matches("foo(bar|baz)qux", b"foobarqux foobazqux fooquxqux"), 2);
+        assert_eq!(count_matches("(GET|POST|PUT|DELETE) /admin", b"PUT /admin GET /index"), 1);
+        assert_eq!(count_matches("x(ab)+y", b"xaby xababy xaay"), 2);
+        assert_eq!(count_matches_bytewise("foo(bar|baz)qux", b"foobazqux"), 1);
+    }
+
+    #[test]
+    fn test_compile_nested_groups() {
+        let pattern = "a(b(c|d(e|f))g)h";
+        for input in [&b"abcgh"[..], b"abdegh", b"abdfgh"] {
+            assert_eq!(count_matches(pattern, input), 1, "{:?}", input);
+            assert_eq!(count_matches_bytewise(pattern, input), 1, "{:?}", input);
+        }
+        assert_eq!(count_matches(pattern, b"abdgh"), 0);
+        assert_eq!(count_matches_in_chunks(pattern, &[b"ab", b"d", b"fg", b"h"]), 1);
+    }
+
+    #[test]
+    fn test_compile_quantified_groups() {
+        assert_eq!(match_ends("x(abc)+y", b"xabcabcy xaby"), vec![7]);
+        assert_eq!(count_matches("x(abc)*y", b"xy xabcy xabcaby"), 2);
+        assert_eq!(count_matches("x(ab|cd){2}y", b"xabcdy xaby xcdcdy"), 2);
+
+        let phone = r"(\d{3}[-. ]){2}\d{4}";
+        assert_eq!(count_matches(phone, b"call 555-123-4567 or 555.123.4567"), 2);
+        assert_eq!(count_matches(phone, b"555-1234567"), 0);
+        let chunks: [&[u8]; 3] = [b"call 55", b"5-12", b"3-4567"];
+        assert_eq!(count_matches_in_chunks(phone, &chunks), 1);
+    }
+
+    #[test]
+    fn test_compile_nested_quantified_groups() {
+        let pattern = "^((ab)+c)*d";
+        for input in [&b"d"[..], b"abcd", b"ababcd", b"abcababcd"] {
+            assert_eq!(count_matches(pattern, input), 1, "{:?}", input);
+            assert_eq!(count_matches_bytewise(pattern, input), 1, "{:?}", input);
+        }
+        for input in [&b"acd"[..], b"abd", b"abcabd"] {
+            assert_eq!(count_matches(pattern, input), 0, "{:?}", input);
+        }
+        // The repeated group spans chunk boundaries
+        assert_eq!(count_matches_in_chunks(pattern, &[b"aba", b"bcab", b"c", b"d"]), 1);
+    }
+
+    #[test]
+    fn test_compile_unbalanced_groups() {
+        assert!(matches!(compile_pattern("(abc"), Err(Error::Syntax(_))));
+        assert!(matches!(compile_pattern("abc)"), Err(Error::Syntax(_))));
+    }
+
+    #[test]
+    fn test_compile_start_anchor() {
+        assert_eq!(count_matches("^ERROR", b"ERROR: disk full"), 1);
+        assert_eq!(count_matches("^ERROR", b"INFO: ERROR later"), 0);
+        assert_eq!(count_matches("^ERROR", b"ERRORERROR"), 1);
+        assert_eq!(count_matches_in_chunks("^ERROR", &[b"ER", b"ROR"]), 1);
+        assert_eq!(count_matches_in_chunks("^ERROR", &[b" ", b"ERROR"]), 0);
+    }
+
+    #[test]
+    fn test_compile_end_anchor() {
+        assert_eq!(count_matches("foo$", b"foo"), 1);
+        assert_eq!(count_matches("foo$", b"foo bar"), 0);
+        assert_eq!(count_matches("foo$", b"foo bar foo"), 1);
+        assert_eq!(count_matches_in_chunks("foo$", &[b"xf", b"o", b"o"]), 1);
+        assert_eq!(count_matches("^abc$", b"abc"), 1);
+        assert_eq!(count_matches("^abc$", b"abcabc"), 0);
+        assert_eq!(count_matches("a$|b", b"ba"), 2);
+    }
+
+    #[test]
+    fn test_end_anchor_waits_for_finish() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_callback(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        matcher.add_pattern(compile_pattern("foo$").unwrap());
+
+        matcher.process_chunk(b"a foo");
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+        matcher.finish();
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_finish_skips_end_already_reported() {
+        for pattern in ["ab|b$", r"ab|b\b"] {
+            for combine in [false, true] {
+                let mut matcher = StreamMatcher::new();
+                matcher.add_pattern(compile_pattern(pattern).unwrap());
+                if combine {
+                    matcher.compile().unwrap();
+                }
+                let mut matches = matcher.process_chunk_collect(b"ab");
+                matcher.finish_into(&mut matches);
+                let ends: Vec<u64> = matches.iter().map(|found| found.end).collect();
+                assert_eq!(ends, [2], "{}", pattern);
+            }
+        }
+        assert_eq!(count_matches("ab|b$", b"ab ab"), 2);
+    }
+
+    #[test]
+    fn test_finish_starts_a_new_stream() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_callback(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        matcher.add_pattern(compile_pattern("^ab").unwrap());
+
+        matcher.process_chunk(b"a");
+        matcher.finish();
+        // The partial match from the first stream must not carry over
+        matcher.process_chunk(b"b ab");
+        matcher.finish();
+        matcher.process_chunk(b"ab");
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_compile_word_boundary() {
+        let card = r"\b\d{4}-\d{4}-\d{4}-\d{4}\b";
+        assert_eq!(count_matches(card, b"card 1234-5678-9012-3456 ok"), 1);
+        assert_eq!(count_matches(card, b"91234-5678-9012-3456 x"), 0);
+        assert_eq!(count_matches(card, b"1234-5678-9012-34567 x"), 0);
+
+        assert_eq!(count_matches(r"\bcat\b", b"cat concat cats cat_ cat."), 2);
+        assert_eq!(count_matches(r"\Bcat", b"cat concat"), 1);
+        assert_eq!(count_matches(r"cat\B", b"cat cats"), 1);
+    }
+
+    #[test]
+    fn test_word_boundary_at_stream_edges() {
+        // The start and end of the stream count as non-word positions
+        assert_eq!(count_matches(r"\bcat", b"cat"), 1);
+        assert_eq!(count_matches(r"cat\b", b"cat"), 1);
+        assert_eq!(count_matches(r"\Bcat", b"cat"), 0);
+        assert_eq!(count_matches(r"cat\B", b"cat"), 0);
+    }
+
+    #[test]
+    fn test_word_boundary_across_chunks() {
+        assert_eq!(count_matches_in_chunks(r"\bcat\b", &[b"con", b"cat "]), 0);
+        assert_eq!(count_matches_in_chunks(r"\bcat\b", &[b"a ", b"cat", b"s"]), 0);
+        assert_eq!(count_matches_in_chunks(r"\bcat\b", &[b"a ", b"cat", b" "]), 1);
+        assert_eq!(count_matches_bytewise(r"\bcat\b", b"cat concat cats cat."), 2);
+    }
+
+    #[test]
+    fn test_word_boundary_waits_for_finish() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_callback(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        matcher.add_pattern(compile_pattern(r"\d+\b").unwrap());
+
+        // Whether the number ends here depends on the next byte
+        matcher.process_chunk(b"id 42");
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+        matcher.finish();
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_word_boundary_confirmed_with_another_match() {
+        // Through the patterns' own automatons, byte by byte, and the combined one
+        let ends = |pattern: &str, input: &[u8]| {
+            let mut ends = Vec::new();
+            for (chunk, combine) in [(input.len(), false), (1, false), (input.len(), true)] {
+                let mut matcher = StreamMatcher::new();
+                matcher.add_pattern(compile_pattern(pattern).unwrap());
+                if combine {
+                    matcher.compile().unwrap();
+                }
+                let mut matches = Vec::new();
+                for chunk in input.chunks(chunk) {
+                    matcher.process_chunk_into(chunk, &mut matches);
+                }
+                matcher.finish_into(&mut matches);
+                let found: Vec<u64> = matches.iter().map(|found| found.end).collect();
+                assert!(ends.is_empty() || ends == found, "{} {:?}", pattern, found);
+                ends = found;
+            }
+            ends
+        };
+        // The byte confirming one match late completes another
+        assert_eq!(ends(r"1\B|a", b"1a"), [1, 2]);
+        assert_eq!(ends(r"\d+\b|,", b"12,"), [2, 3]);
+        // A late confirmation of an end already reported is dropped
+        assert_eq!(ends(r"ab|b\b", b"ab c"), [2]);
+        assert_eq!(ends(r"b{2}|\w\b", b"bb\n"), [2]);
+    }
+
+    #[test]
+    fn test_compile_shorthand_classes() {
+        assert_eq!(count_matches(r"password=\w+", b"password=secret123"), 1);
+        assert_eq!(count_matches(r"password=\w+", b"password= x"), 0);
+        assert_eq!(count_matches(r"\d{3}-\d{4}", b"call 555-1234 now"), 1);
+        assert_eq!(count_matches(r"a\sb", b"a b a\tb a\nb axb"), 3);
+        assert_eq!(count_matches(r"[\w.-]+@example", b"first.last-1@example"), 1);
+    }
+
+    #[test]
+    fn test_compile_negated_shorthand_classes() {
+        assert_eq!(count_matches(r"key=\S+", b"key=abc def"), 1);
+        assert_eq!(count_matches(r"key=\S+", b"key= abc"), 0);
+        // Raw bytes >= 0x80 are outside every ASCII class
+        assert_eq!(count_matches(r"key=\S", b"key=\xc3\xa9"), 1);
+        assert_eq!(count_matches(r"a\Wb", b"a\xffb a_b"), 1);
+        assert_eq!(count_matches(r"\D\d", b"\x80"), 0);
+        assert_eq!(count_matches(r"\D\d", b"\x807"), 1);
+    }
+
+    #[test]
+    fn test_compile_negated_shorthand_stays_compact() {
+        let pattern = compile_pattern(r"=\S").unwrap();
+        let after_eq = pattern.states[0].next_state(b'=').unwrap();
+        let state = &pattern.states[after_eq];
+        assert!(state.default_transition.is_some());
+        assert!(state.transitions.len() <= 1);
+    }
+
+    #[test]
+    fn test_compile_case_insensitive() {
+        let pattern = "(?i)select.*from";
+        assert_eq!(count_matches(pattern, b"SELECT * FROM users"), 1);
+        assert_eq!(count_matches(pattern, b"SeLeCt id FrOm users"), 1);
+        assert_eq!(count_matches(pattern, b"select 1 from t"), 1);
+        assert_eq!(count_matches("select.*from", b"SELECT * FROM users"), 0);
+        assert_eq!(count_matches_bytewise(pattern, b"sElEcT x fRoM"), 1);
+    }
+
+    #[test]
+    fn test_compile_case_insensitive_classes() {
+        assert_eq!(count_matches("(?i)id=[a-f]+;", b"ID=DEAD; id=beef;"), 2);
+        assert_eq!(count_matches("(?i)x[^a]", b"xA xa xb"), 1);
+    }
+
+    #[test]
+    fn test_builder_case_insensitive() {
+        let mut builder = PatternBuilder::new();
+        let s1 = builder.add_state(false);
+        let s2 = builder.add_state(true);
+        builder.add_transition(0, b'o', s1).unwrap();
+        builder.add_transition(s1, b'k', s2).unwrap();
+        builder.case_insensitive(true);
+        let pattern = builder.build("ok".into()).unwrap();
+
+        assert_eq!(pattern.states[0].next_state(b'O'), Some(s1));
+        assert_eq!(pattern.states[s1].next_state(b'K'), Some(s2));
+        assert_eq!(pattern.states[0].next_state(b'x'), None);
+    }
+
+    #[test]
+    fn test_builder_case_insensitive_keeps_explicit_transitions() {
+        let mut builder = PatternBuilder::new();
+        let lower = builder.add_state(true);
+        let upper = builder.add_state(true);
+        builder.add_transition(0, b'a', lower).unwrap();
+        builder.add_transition(0, b'A', upper).unwrap();
+        builder.case_insensitive(true);
+        let pattern = builder.build("a".into()).unwrap();
+
+        assert_eq!(pattern.states[0].next_state(b'a'), Some(lower));
+        assert_eq!(pattern.states[0].next_state(b'A'), Some(upper));
+    }
+
+    #[test]
+    fn test_compile_byte_escapes() {
+        let pattern = compile_pattern(r"\x00\x01MAGIC\xff").unwrap();
+        let mut state = pattern.initial_state;
+        for &byte in b"\x00\x01MAGIC\xff" {
+            state = pattern.states[state].next_state(byte).unwrap();
+        }
+        assert!(pattern.states[state].is_final);
+        assert_eq!(pattern.states[pattern.initial_state].next_state(b'\\'), None);
+
+        assert_eq!(count_matches(r"\x00\x01MAGIC\xff", b"..\x00\x01MAGIC\xff.."), 1);
+        assert_eq!(count_matches(r"a\r\nb\tc", b"a\r\nb\tc a\nb c"), 1);
+    }
+
+    #[test]
+    fn test_compile_multibyte_literals() {
+        let naive = "naïve".as_bytes();
+        let japanese = "日本語".as_bytes();
+        assert_eq!(count_matches("naïve", naive), 1);
+        assert_eq!(count_matches_bytewise("naïve", naive), 1);
+        assert_eq!(count_matches_in_chunks("naïve", &[&naive[..3], &naive[3..]]), 1);
+        assert_eq!(count_matches_bytewise("日本語", japanese), 1);
+        let chunks = [&japanese[..4], &japanese[4..8], &japanese[8..]];
+        assert_eq!(count_matches_in_chunks("日本語", &chunks), 1);
+    }
+
+    #[test]
+    fn test_compile_quantified_multibyte_characters() {
+        // The quantifier applies to the whole character, not its last byte
+        assert_eq!(count_matches("caf(é)?s", "cafs cafés".as_bytes()), 2);
+        assert_eq!(count_matches("café?s", "cafs cafés".as_bytes()), 2);
+        assert_eq!(count_matches("café?s", b"caf\xc3s"), 0);
+        assert_eq!(count_matches("x日+y", "x日日日y x日本y".as_bytes()), 1);
+        assert_eq!(count_matches("(日|本)語", "日語 本語 語".as_bytes()), 2);
+    }
+
+    #[test]
+    fn test_compile_multibyte_classes() {
+        assert_eq!(count_matches("[äöü]x", "äx öx ux".as_bytes()), 2);
+        assert_eq!(count_matches("[a-ü]x", "ax éx ÿx".as_bytes()), 2);
+        assert_eq!(count_matches("<[ぁ-ん]+>", "<ひらがな> <カタカナ>".as_bytes()), 1);
+        // Negated classes with characters match whole characters
+        assert_eq!(count_matches("a[^é]b", "aéb aüb axb".as_bytes()), 2);
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn test_compile_unicode_property_classes() {
+        assert_eq!(count_matches(r"<\p{L}+>", "<abc> <日本語> <ünï> <12>".as_bytes()), 3);
+        assert_eq!(count_matches(r"#\pN", "#7 #٣ #x".as_bytes()), 2);
+        assert_eq!(count_matches(r"<\p{Alphabetic}>", "<Ⅻ> <é> <1>".as_bytes()), 2);
+        assert_eq!(count_matches(r"<\P{L}>", "<1> <é> <€>".as_bytes()), 2);
+        assert_eq!(count_matches(r"<[\p{L}0-9]+>", "<ab12> <été> <->".as_bytes()), 2);
+
+        let word = "Größe".as_bytes();
+        assert_eq!(count_matches_bytewise(r"\bGr\p{L}+e", word), 1);
+        assert_eq!(count_matches_in_chunks(r"Gr\p{L}+e", &[&word[..3], &word[3..]]), 1);
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn test_compile_unsupported_property_class() {
+        let error = compile_pattern(r"\p{Han}").unwrap_err();
+        assert!(matches!(error, Error::Syntax(_)));
+        assert_eq!(error.offset(), Some(0));
+    }
+
+    #[test]
+    fn test_compile_posix_classes() {
+        assert_eq!(count_matches("id=[[:alnum:]]+;", b"id=abc123; id=;"), 1);
+        assert_eq!(count_matches("[[:digit:]a-f]{4}", b"beef 12ab xyz1"), 2);
+        assert_eq!(count_matches("[[:upper:]][[:lower:]]", b"Ab aB"), 1);
+        assert!(matches!(compile_pattern("[[:foo:]]"), Err(Error::Syntax(_))));
+    }
+
+    #[test]
+    fn test_compile_dot_matches_newline_flag() {
+        let payload: [&[u8]; 3] = [
+            b"<p>hi</p><script>\nvar a = 1;\n",
+            b"steal(a);\n</scr",
+            b"ipt>",
+        ];
+        assert_eq!(count_matches_in_chunks("<script(?s).*</script>", &payload), 1);
+        assert_eq!(count_matches_in_chunks("<script.*</script>", &payload), 0);
+
+        // (?s) only changes '.', negated classes match '\n' either way
+        assert_eq!(count_matches("a.b", b"a\nb"), 0);
+        assert_eq!(count_matches("(?s)a.b", b"a\nb"), 1);
+        assert_eq!(count_matches("a[^x]b", b"a\nb"), 1);
+        assert_eq!(count_matches("(?s:a.)b.c", b"a\nbxc a\nb\nc"), 1);
+    }
+
+    #[test]
+    fn test_compile_with_options() {
+        let options = CompileOptions {
+            dot_matches_newline: true,
+            ..CompileOptions::default()
+        };
+        let pattern = compile_pattern_with("a.b", &options).unwrap();
+        let mut state = pattern.initial_state;
+        for &byte in b"a\nb" {
+            state = pattern.states[state].next_state(byte).unwrap();
+        }
+        assert!(pattern.states[state].is_final);
+
+        let options = CompileOptions {
+            case_insensitive: true,
+            ..CompileOptions::default()
+        };
+        let pattern = compile_pattern_with("ab(?-i)c", &options).unwrap();
+        let s1 = pattern.states[pattern.initial_state].next_state(b'A').unwrap();
+        let s2 = pattern.states[s1].next_state(b'B').unwrap();
+        assert!(pattern.states[s2].next_state(b'C').is_none());
+        assert!(pattern.states[pattern.states[s2].next_state(b'c').unwrap()].is_final);
+
+        let options = CompileOptions {
+            state_limit: 10,
+            ..CompileOptions::default()
+        };
+        assert!(matches!(
+            compile_pattern_with("a{20}", &options),
+            Err(Error::PatternTooComplex(_))
+        ));
+    }
+
+    fn count_with(pattern: &str, options: CompileOptions, input: &[u8]) -> usize {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_callback(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        matcher.add_pattern(compile_pattern_with(pattern, &options).unwrap());
+        matcher.process_chunk(input);
+        matcher.finish();
+        count.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn test_compile_options_setters() {
+        let options = CompileOptions::new().case_insensitive(true).anchored(true).state_limit(50);
+        assert_eq!(
+            options,
+            CompileOptions {
+                state_limit: 50,
+                case_insensitive: true,
+                anchored: true,
+                ..CompileOptions::default()
+            }
+        );
+
+        assert_eq!(count_with("get ", options, b"GET / get "), 1);
+        assert_eq!(count_with("get ", options, b" GET /"), 0);
+        assert_eq!(count_with("a.b", CompileOptions::new().dot_matches_newline(true), b"a\nb"), 1);
+        assert_eq!(count_with("^x", CompileOptions::new().multi_line(true), b"x\nx"), 2);
+        // Anchoring keeps its meaning in multi-line mode
+        let anchored = CompileOptions::new().multi_line(true).anchored(true);
+        assert_eq!(count_with("x", anchored, b"x\nx"), 1);
+        assert!(matches!(
+            compile_pattern_with("a{60}", &options),
+            Err(Error::PatternTooComplex(_))
+        ));
+    }
+
+    #[test]
+    fn test_inline_flags_override_options() {
+        let insensitive = CompileOptions::new().case_insensitive(true);
+        assert_eq!(count_with("abc", insensitive, b"ABC"), 1);
+        assert_eq!(count_with("(?-i)abc", insensitive, b"ABC abc"), 1);
+        assert_eq!(count_with("a(?-i:b)c", insensitive, b"AbC ABC"), 1);
+        assert_eq!(count_with("a(?i)b", CompileOptions::new(), b"aB AB"), 1);
+
+        let dotall = CompileOptions::new().dot_matches_newline(true);
+        assert_eq!(count_with("(?-s)a.b", dotall, b"a\nb"), 0);
+        let multi_line = CompileOptions::new().multi_line(true);
+        assert_eq!(count_with("(?-m)^x", multi_line, b"x\nx"), 1);
+    }
+
+    #[test]
+    fn test_compile_multi_line_anchors() {
+        let log = b"ERROR one\nINFO ERROR two\nERROR three";
+        assert_eq!(count_matches("(?m)^ERROR", log), 2);
+        assert_eq!(count_matches("^ERROR", log), 1);
+        assert_eq!(count_matches(r"(?m)\w+$", b"one\ntwo three\nfour"), 3);
+        assert_eq!(count_matches("(?m)^-+$", b"--\n-\n-x\n"), 2);
+        assert_eq!(count_matches("(?m)^a$", b"a\nab\nba\na"), 2);
+    }
+
+    #[test]
+    fn test_multi_line_anchors_with_crlf() {
+        let log = b"ERROR one\r\nERROR two\r\n\r\nok ERROR\r\nERROR";
+        assert_eq!(count_matches("(?m)^ERROR", log), 3);
+        assert_eq!(count_matches("(?m)ERROR$", log), 2);
+        assert_eq!(count_matches("(?m)two$", b"two\r\n"), 1);
+        // A line starts after a whole CRLF, not between its '\r' and '\n'
+        assert_eq!(count_matches("(?m)^\n", b"a\r\n"), 0);
+        assert_eq!(count_matches("(?m)^\n", b"a\n\n"), 1);
+        assert_eq!(count_matches("(?m)\r$", b"a\r\n"), 0);
+    }
+
+    #[test]
+    fn test_multi_line_anchors_across_chunks() {
+        assert_eq!(count_matches_in_chunks("(?m)^ERROR", &[b"INFO ok\n", b"ERROR x"]), 1);
+        assert_eq!(count_matches_in_chunks("(?m)^ERROR", &[b"INFO ok\r", b"\nERROR x"]), 1);
+        assert_eq!(count_matches_in_chunks("(?m)ok$", &[b"INFO ok", b"\r\nERROR"]), 1);
+        assert_eq!(count_matches_in_chunks("(?m)ok$", &[b"INFO ok", b" ERROR"]), 0);
+        assert_eq!(count_matches_bytewise("(?m)^E.*x$", b"INFO\r\nERROR x\r\n"), 1);
+    }
+
+    #[test]
+    fn test_compile_lazy_quantifiers() {
+        let html = br#"<a href="x">y</a><a href="z">"#;
+        // Matches end at the first possible byte, so lazy and greedy forms agree
+        assert_eq!(match_ends(r#"<a href=".*?">"#, html), vec![11, 28]);
+        assert_eq!(match_ends(r#"<a href=".*">"#, html), vec![11, 28]);
+        assert_eq!(match_ends("a+?", b"aa"), vec![0, 1]);
+        assert_eq!(match_ends("x{2,}?y", b"xxxy"), vec![3]);
+    }
+
+    #[test]
+    fn test_literal_detection() {
+        let literal = |pattern: &str| compile_pattern(pattern).unwrap().kind().clone();
+        assert_eq!(literal("X-Forwarded-For:"), PatternKind::Literal(b"X-Forwarded-For:".to_vec()));
+        assert_eq!(literal(r"cmd\.exe"), PatternKind::Literal(b"cmd.exe".to_vec()));
+        assert_eq!(literal(r"\x00\xffMZ"), PatternKind::Literal(b"\x00\xffMZ".to_vec()));
+        assert_eq!(literal("(?:日本)語"), PatternKind::Literal("日本語".as_bytes().to_vec()));
+        assert!(compile_glob("payload.bin").unwrap().is_literal());
+
+        for pattern in ["cmd.exe", "a|b", "ab+", "(?i)abc", "^abc", r"\d", ""] {
+            assert!(!compile_pattern(pattern).unwrap().is_literal(), "{}", pattern);
+        }
+        let mut builder = PatternBuilder::new();
+        let end = builder.add_state(true);
+        builder.add_transition(0, b'a', end).unwrap();
+        assert_eq!(builder.build("a".to_string()).unwrap().kind(), &PatternKind::Automaton);
+    }
+
+    #[test]
+    fn test_compile_pattern_bytes() {
+        let ids = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = ids.clone();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_callback(move |id| sink.lock().unwrap().push(id.to_string()));
+        // Raw bytes that aren't UTF-8, quantified and mixed with escapes and classes
+        matcher.add_pattern(compile_pattern_bytes(b"\xfe\xff+\\x00[\x80-\x9f]", "bom").unwrap());
+        matcher.add_pattern(compile_pattern_bytes(b"\xc3(\xa9|e)", "e").unwrap());
+        matcher.process_chunk(b"\xfe\xff\xff\x00\x85 \xfe\x00\x85 \xc3e \xc3\xa9");
+        assert_eq!(*ids.lock().unwrap(), ["bom", "e", "e"]);
+
+        let pattern = compile_pattern_bytes(b"MZ\x90\x00", "mz").unwrap();
+        assert_eq!(pattern.kind(), &PatternKind::Literal(b"MZ\x90\x00".to_vec()));
+        assert!(matches!(
+            compile_pattern_bytes(b"\xff(", "bad"),
+            Err(Error::Syntax(_))
+        ));
+    }
+
+    #[test]
+    fn test_syntax_error_position() {
+        let Err(Error::Syntax(error)) = compile_pattern(r"GET /(a|b\q") else {
+            panic!("expected a syntax error");
+        };
+        assert_eq!(error.id, r"GET /(a|b\q");
+        assert_eq!(error.offset, 9);
+        assert_eq!(error.caret(), "GET /(a|b\\q\n         ^");
+        assert_eq!(
+            Error::Syntax(error).to_string(),
+            r"Invalid pattern: Unknown escape '\q' at offset 9 in 'GET /(a|b\q'"
+        );
+
+        let error = compile_pattern_bytes(b"\xff[a-", "rule 17").unwrap_err();
+        assert_eq!(error.offset(), Some(1));
+        assert!(error.to_string().contains("expected ']'"), "{}", error);
+        assert!(error.to_string().contains("'rule 17'"), "{}", error);
+
+        // The caret counts characters, not bytes
+        let Err(Error::Syntax(error)) = compile_pattern("é{2,1}") else {
+            panic!("expected a syntax error");
+        };
+        assert_eq!(error.caret(), "é{2,1}\n ^");
+        assert_eq!(compile_pattern_with_limit("a{20}", 5).unwrap_err().offset(), None);
+    }
+
+    #[test]
+    fn test_compile_verbose() {
+        let verbose = r"(?x)
+            # user agents of common scanners
+            User-Agent: \  (?i: sqlmap | nikto | nmap )
+            [/ ] \d+       # version
+        ";
+        let compact = r"User-Agent: (?i:sqlmap|nikto|nmap)[/ ]\d+";
+        let input = b"User-Agent: Nikto/2 User-Agent: nmap 7 User-Agent:nmap/7";
+        assert_eq!(count_matches(verbose, input), 2);
+        assert_eq!(count_matches(compact, input), 2);
+        assert_eq!(
+            compile_pattern(verbose).unwrap().states.len(),
+            compile_pattern(compact).unwrap().states.len()
+        );
+
+        let options = CompileOptions::new().ignore_whitespace(true);
+        assert_eq!(count_with("a b # c", options, b"ab a b"), 1);
+        assert_eq!(count_with("(?-x)a b", options, b"ab a b"), 1);
+    }
+
+    #[test]
+    fn test_anchored_patterns() {
+        let anchored = CompileOptions::new().anchored(true);
+        let tls = compile_pattern_with(r"\x16\x03[\x00-\x03]", &anchored).unwrap();
+        assert!(tls.is_anchored());
+        assert!(compile_pattern("^GET ").unwrap().is_anchored());
+        assert!(compile_pattern("^(GET|POST) ").unwrap().is_anchored());
+        for pattern in ["GET ", "^a|b", "(?m)^GET ", r"\bGET"] {
+            assert!(!compile_pattern(pattern).unwrap().is_anchored(), "{}", pattern);
+        }
+        // Nothing is kept for the state where every attempt has failed
+        assert_eq!(compile_pattern("^abc").unwrap().states.len(), 4);
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_callback(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        matcher.add_pattern(tls);
+        matcher.add_pattern(compile_pattern("^GET ").unwrap());
+        let streams = [&b"GET / GET "[..], b"\x16\x03\x01\x16\x03\x01", b"xGET ", b" \x16\x03\x01"];
+        // The last stream arrives in two chunks
+        for stream in streams.into_iter().chain([&b"GE"[..], b"T "]) {
+            matcher.process_chunk(stream);
+            if stream != b"GE" {
+                matcher.finish();
+            }
+        }
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_anchored_builder() {
+        let build = |anchored| {
+            let mut builder = PatternBuilder::new();
+            let s1 = builder.add_state(false);
+            let s2 = builder.add_state(true);
+            builder.anchored(anchored).add_transition(0, b'a', s1).unwrap();
+            builder.add_transition(s1, b'b', s2).unwrap();
+            builder.build("ab".to_string()).unwrap()
+        };
+        assert!(build(true).is_anchored());
+        assert!(!build(false).is_anchored());
+
+        let count_ab = |pattern: Pattern, stream: &[u8]| {
+            let count = Arc::new(AtomicUsize::new(0));
+            let counter = count.clone();
+            let mut matcher = StreamMatcher::new();
+            matcher.add_callback(move |_| {
+                counter.fetch_add(1, Ordering::Relaxed);
+            });
+            matcher.add_pattern(pattern);
+            matcher.process_chunk(stream);
+            count.load(Ordering::Relaxed)
+        };
+        assert_eq!(count_ab(build(true), b"ab"), 1);
+        assert_eq!(count_ab(build(true), b"xab"), 0);
+        assert_eq!(count_ab(build(false), b"xab"), 1);
+    }
+
+    #[test]
+    fn test_match_len_bounds() {
+        let bounds = |pattern: &str| {
+            let pattern = compile_pattern(pattern).unwrap();
+            (pattern.min_match_len(), pattern.max_match_len())
+        };
+        assert_eq!(bounds("abc"), (3, Some(3)));
+        assert_eq!(bounds(r"^\bab?c$"), (2, Some(3)));
+        assert_eq!(bounds("a(bc|d){2,3}e"), (4, Some(8)));
+        assert_eq!(bounds("x[0-9]+"), (2, None));
+        assert_eq!(bounds("(ab)*"), (0, None));
+        assert_eq!(bounds("é."), (3, Some(3)));
+        assert_eq!(bounds(r"\x00{0}q"), (1, Some(1)));
+        assert_eq!(compile_hex("4D 5A ?? ??").unwrap().max_match_len(), Some(4));
+        assert_eq!(compile_glob("*.exe").unwrap().max_match_len(), None);
+
+        // Builder patterns are measured along their states
+        let mut builder = PatternBuilder::new();
+        let s1 = builder.add_state(false);
+        let s2 = builder.add_state(true);
+        let s3 = builder.add_state(true);
+        builder.add_transition(0, b'a', s1).unwrap().add_transition(s1, b'b', s2).unwrap();
+        builder.add_transition(s2, b'c', s3).unwrap().add_transition(0, b'x', s2).unwrap();
+        let pattern = builder.build("abc".to_string()).unwrap();
+        assert_eq!((pattern.min_match_len(), pattern.max_match_len()), (1, Some(3)));
+
+        let mut builder = PatternBuilder::new();
+        let (s1, s2) = (builder.add_state(false), builder.add_state(true));
+        builder.add_transition(0, b'a', s1).unwrap().add_transition(s1, b'a', s1).unwrap();
+        builder.add_transition(s1, b'b', s2).unwrap();
+        // A cycle that cannot lead to a match doesn't make the length unbounded
+        let dead_end = builder.add_state(false);
+        builder.add_transition(s2, b'z', dead_end).unwrap();
+        builder.add_transition(dead_end, b'z', dead_end).unwrap();
+        let pattern = builder.build("a+b".to_string()).unwrap();
+        assert_eq!((pattern.min_match_len(), pattern.max_match_len()), (2, None));
+
+        let mut matcher = StreamMatcher::new();
+        assert_eq!(matcher.min_match_len(), None);
+        matcher.add_pattern(compile_pattern("abcd").unwrap());
+        matcher.add_pattern(compile_pattern("x[0-9]+").unwrap());
+        assert_eq!(matcher.min_match_len(), Some(2));
+    }
+
+    #[test]
+    fn test_builder_transition_ranges() {
+        // [a-z_][a-z0-9_]*= as a builder pattern
+        let mut builder = PatternBuilder::new();
+        let name = builder.add_state(false);
+        let end = builder.add_state(true);
+        builder.add_transition_range(0, b'a'..=b'z', name).unwrap();
+        builder.add_transition(0, b'_', name).unwrap();
+        builder.add_transition_range(name, b'a'..=b'z', name).unwrap();
+        builder.add_transition_range(name, b'0'..=b'9', name).unwrap();
+        builder.add_transition_set(name, b"_", name).unwrap();
+        builder.add_transition(name, b'=', end).unwrap();
+        let pattern = builder.build("assignment".to_string()).unwrap();
+        assert_eq!(pattern.states[0].transitions.len(), 27);
+        assert_eq!(pattern.states[name].transitions.len(), 38);
+
+        let mut matcher = StreamMatcher::new();
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        matcher.add_callback(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        matcher.add_pattern(pattern);
+        matcher.process_chunk(b"x1=2 _y= 9=");
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+
+        // Overlaps are fine when they agree, and leave the pattern nondeterministic
+        // without determinization when they don't
+        let mut builder = PatternBuilder::new();
+        let s1 = builder.add_state(true);
+        builder.add_transition_range(0, b'a'..=b'f', s1).unwrap();
+        builder.add_transition_set(0, b"abc", s1).unwrap();
+        assert!(builder.build("agree".to_string()).is_ok());
+
+        let mut builder = PatternBuilder::new();
+        let (s1, s2) = (builder.add_state(true), builder.add_state(true));
+        builder.add_transition_range(0, b'a'..=b'f', s1).unwrap();
+        builder.add_transition_range(0, b'e'..=b'z', s2).unwrap().determinize(false);
+        let pattern = builder.build("overlap".to_string()).unwrap();
+        assert!(pattern.is_nondeterministic());
+        assert_eq!(pattern.transition_count(), 6 + 22);
+    }
+
+    #[test]
+    fn test_builder_wildcard_transitions() {
+        // "a", any byte, "c", with an exact 'b' that wins over the wildcard
+        let mut builder = PatternBuilder::new();
+        let after_a = builder.add_state(false);
+        let any = builder.add_state(false);
+        let end = builder.add_state(true);
+        let ab = builder.add_state(true);
+        builder.add_transition(0, b'a', after_a).unwrap();
+        builder.add_wildcard_transition(after_a, any).unwrap();
+        builder.add_transition(any, b'c', end).unwrap().add_transition(after_a, b'b', ab).unwrap();
+        let pattern = builder.build("a?c".to_string()).unwrap();
+        assert_eq!(pattern.states[after_a].transitions.len(), 1);
+
+        let ends = |input: &[u8]| {
+            let mut state = pattern.initial_state;
+            let mut ends = Vec::new();
+            for (offset, &byte) in input.iter().enumerate() {
+                state = pattern.states[state].next_state(byte).unwrap_or(pattern.initial_state);
+                if pattern.states[state].is_final {
+                    ends.push(offset);
+                }
+            }
+            ends
+        };
+        assert_eq!(ends(b"a\x00c a\nc"), vec![2, 6]);
+        assert_eq!(ends(b"abc"), vec![1]);
+        // The mismatch only resets when neither transition exists
+        assert_eq!(ends(b"axd"), Vec::<usize>::new());
+
+        let mut builder = PatternBuilder::new();
+        let (s1, s2) = (builder.add_state(true), builder.add_state(true));
+        builder.add_wildcard_transition(0, s1).unwrap().add_wildcard_transition(0, s2).unwrap();
+        builder.determinize(false);
+        assert!(builder.build("two".to_string()).unwrap().is_nondeterministic());
+
+        // Compiled literals have exact transitions only
+        let pattern = compile_pattern("cmd.exe").unwrap();
+        assert!(pattern.states.iter().any(|state| state.default_transition.is_some()));
+        let pattern = compile_pattern(r"cmd\.exe").unwrap();
+        assert!(pattern.states.iter().all(|state| state.default_transition.is_none()));
+        assert_eq!(count_matches(r"cmd\.exe", b"cmd.exe cmdxexe cmd.exe"), 2);
+    }
+
+    #[test]
+    fn test_builder_negated_transitions() {
+        // "[^"]*" by hand: the body state loops on every byte except the quote
+        let build = |negated: bool| {
+            let mut builder = PatternBuilder::new();
+            let body = builder.add_state(false);
+            let end = builder.add_state(true);
+            builder.add_transition(0, b'"', body).unwrap().add_transition(body, b'"', end).unwrap();
+            if negated {
+                builder.add_negated_transition(body, b"\"", body).unwrap();
+            } else {
+                let others: Vec<u8> = (0..=255).filter(|&byte| byte != b'"').collect();
+                builder.add_transition_set(body, &others, body).unwrap();
+            }
+            builder.build("quoted".to_string()).unwrap()
+        };
+        let (negated, enumerated) = (build(true), build(false));
+        assert_eq!(negated.states[1].transitions.len(), 1);
+        assert!(negated.memory_usage() * 10 < enumerated.memory_usage());
+
+        for pattern in [&negated, &enumerated] {
+            let mut state = pattern.initial_state;
+            let mut ends = Vec::new();
+            for (offset, &byte) in br#"x "a\nb" "" y"#.iter().enumerate() {
+                state = pattern.states[state].next_state(byte).unwrap_or(pattern.initial_state);
+                if pattern.states[state].is_final {
+                    ends.push(offset);
+                }
+            }
+            assert_eq!(ends, vec![7, 10]);
+        }
+
+        let mut matcher = StreamMatcher::new();
+        matcher.add_pattern(negated.clone());
+        assert_eq!(matcher.memory_usage(), negated.memory_usage());
+    }
+
+    #[test]
+    fn test_builder_epsilon_transitions() {
+        // Thompson construction of x(ab|cd)*e
+        let mut builder = PatternBuilder::new();
+        let states: Vec<usize> = (1..=8).map(|state| builder.add_state(state == 8)).collect();
+        let [after_x, loop_start, a, b, c, d, exit, end] = states[..] else {
+            unreachable!()
+        };
+        builder.add_transition(0, b'x', after_x).unwrap();
+        builder.add_epsilon_transition(after_x, loop_start).unwrap();
+        builder.add_epsilon_transition(loop_start, a).unwrap();
+        builder.add_epsilon_transition(loop_start, c).unwrap();
+        builder.add_epsilon_transition(loop_start, exit).unwrap();
+        builder.add_transition(a, b'a', b).unwrap().add_transition(b, b'b', loop_start).unwrap();
+        builder.add_transition(c, b'c', d).unwrap().add_transition(d, b'd', loop_start).unwrap();
+        builder.add_transition(exit, b'e', end).unwrap();
+        let pattern = builder.build("x(ab|cd)*e".to_string()).unwrap();
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_callback(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        matcher.add_pattern(pattern);
+        matcher.process_chunk(b"xe xabcdabe xace xcde");
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+
+        // Finality follows epsilon transitions, including chains of them
+        let mut builder = PatternBuilder::new();
+        let s1 = builder.add_state(false);
+        let s2 = builder.add_state(false);
+        let s3 = builder.add_state(true);
+        builder.add_transition(0, b'a', s1).unwrap().add_epsilon_transition(s1, s2).unwrap();
+        builder.add_epsilon_transition(s2, s3).unwrap().add_epsilon_transition(s3, s1).unwrap();
+        let pattern = builder.build("a".to_string()).unwrap();
+        assert!(pattern.states[s1].is_final);
+        assert_eq!(pattern.min_match_len(), 1);
+
+        // Epsilon transitions that make a byte lead to two states need determinization, or
+        // leave the pattern nondeterministic
+        let a_or_a = |determinize: bool| {
+            let mut builder = PatternBuilder::new();
+            let (s1, s2) = (builder.add_state(true), builder.add_state(true));
+            builder.add_epsilon_transition(0, s1).unwrap().add_epsilon_transition(0, s2).unwrap();
+            builder.add_transition(s1, b'a', s1).unwrap().add_transition(s2, b'a', s2).unwrap();
+            builder.determinize(determinize);
+            builder.build("a|a".to_string())
+        };
+        assert!(a_or_a(true).is_ok());
+        assert!(a_or_a(false).unwrap().is_nondeterministic());
+
+        // An exact transition of one state does not hide the wildcard of another
+        let mut builder = PatternBuilder::new();
+        let (any, b) = (builder.add_state(false), builder.add_state(false));
+        let end = builder.add_state(true);
+        builder.add_wildcard_transition(0, any).unwrap().add_transition(any, b'z', end).unwrap();
+        builder.add_epsilon_transition(0, b).unwrap().add_transition(b, b'a', end).unwrap();
+        let pattern = builder.build(".z|a".to_string()).unwrap();
+        assert_eq!(pattern_match_ends(pattern, b"a az"), vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn test_builder_determinize() {
+        // GET|GEM|POST|PUT as one branch per word from the initial state, so 'G' and 'P'
+        // each lead to several states
+        let words: [&[u8]; 4] = [b"GET", b"GEM", b"POST", b"PUT"];
+        let alternation = |determinize: bool, state_limit: usize| {
+            let mut builder = PatternBuilder::new();
+            for word in words {
+                let mut from = 0;
+                for (index, &byte) in word.iter().enumerate() {
+                    let to = builder.add_state(index == word.len() - 1);
+                    builder.add_transition(from, byte, to).unwrap();
+                    from = to;
+                }
+            }
+            builder.determinize(determinize).state_limit(state_limit);
+            builder.build("methods".to_string())
+        };
+        // The same words as a trie, which needs no determinization
+        let trie = || {
+            let mut builder = PatternBuilder::new();
+            let states: Vec<usize> = (1..=10).map(|state| builder.add_state(state > 6)).collect();
+            let [g, ge, p, po, pos, pu, get, gem, post, put] = states[..] else {
+                unreachable!()
+            };
+            builder.add_transition(0, b'G', g).unwrap().add_transition(g, b'E', ge).unwrap();
+            builder.add_transition(ge, b'T', get).unwrap().add_transition(ge, b'M', gem).unwrap();
+            builder.add_transition(0, b'P', p).unwrap().add_transition(p, b'O', po).unwrap();
+            builder.add_transition(po, b'S', pos).unwrap().add_transition(pos, b'T', post).unwrap();
+            builder.add_transition(p, b'U', pu).unwrap().add_transition(pu, b'T', put).unwrap();
+            builder.determinize(false);
+            builder.build("trie".to_string())
+        };
+        let ends = |pattern: &Pattern, input: &[u8]| {
+            let mut state = pattern.initial_state;
+            let mut ends = Vec::new();
+            for (offset, &byte) in input.iter().enumerate() {
+                state = pattern.states[state].next_state(byte).unwrap_or(pattern.initial_state);
+                if pattern.states[state].is_final {
+                    ends.push(offset);
+                }
+            }
+            ends
+        };
+
+        let determinized = alternation(true, DEFAULT_STATE_LIMIT).unwrap();
+        let trie = trie().unwrap();
+        let input = b"GET GEM GEX POST PUT PAT POS PUTT GEGET";
+        assert_eq!(ends(&determinized, input), ends(&trie, input));
+        assert_eq!(ends(&determinized, input), vec![2, 6, 15, 19, 31]);
+        assert!(alternation(false, DEFAULT_STATE_LIMIT).unwrap().is_nondeterministic());
+        assert!(matches!(alternation(true, 3), Err(Error::PatternTooComplex(_))));
+
+        // Deterministic automatons keep their states as built
+        let mut builder = PatternBuilder::new();
+        let s1 = builder.add_state(false);
+        let s2 = builder.add_state(true);
+        builder.add_transition(0, b'a', s1).unwrap().add_transition(s1, b'b', s2).unwrap();
+        let pattern = builder.build("ab".to_string()).unwrap();
+        assert_eq!(pattern.states.len(), 3);
+        assert!(pattern.states[s2].is_final);
+    }
+
+    #[test]
+    fn test_minimize() {
+        // cmd.exe|run.exe as two chains that end alike, from "cmd" and "run" on
+        let mut builder = PatternBuilder::new();
+        for word in [b"cmd.exe", b"run.exe"] {
+            let mut from = 0;
+            for (index, &byte) in word.iter().enumerate() {
+                let to = builder.add_state(index == word.len() - 1);
+                builder.add_transition(from, byte, to).unwrap();
+                from = to;
+            }
+        }
+        let pattern = builder.build("exe".to_string()).unwrap();
+        let minimized = pattern.minimize();
+        assert_eq!(pattern.state_count(), 15);
+        assert_eq!(pattern.unminimized_state_count(), None);
+        assert_eq!(minimized.state_count(), 10);
+        assert_eq!(minimized.unminimized_state_count(), Some(15));
+        assert!(minimized.memory_usage() < pattern.memory_usage());
+        assert_eq!(minimized.minimize().state_count(), 10);
+
+        // The 'r' of "rcmd" is a failed attempt at "run", which hands over to "cmd"
+        let input = b"cmd.exe run.exe cmd.ex run.exexe rcmd.exe";
+        let ends = pattern_match_ends(pattern, input);
+        assert_eq!(ends, vec![6, 14, 29, 40]);
+        assert_eq!(pattern_match_ends(minimized, input), ends);
+
+        // The builder option gives the same states
+        let mut builder = PatternBuilder::new();
+        let s1 = builder.add_state(false);
+        let (s2, s3) = (builder.add_state(true), builder.add_state(true));
+        builder.add_transition(0, b'a', s1).unwrap();
+        builder.add_transition(s1, b'b', s2).unwrap().add_transition(s1, b'c', s3).unwrap();
+        builder.minimize(true);
+        let pattern = builder.build("a[bc]".to_string()).unwrap();
+        assert_eq!(pattern.state_count(), 3);
+        assert_eq!(pattern.unminimized_state_count(), Some(4));
+
+        // Compiled patterns keep their matches, including anchored and end-of-stream ones
+        let input = b"GET /index.html POST /a.htm\nPUT /b.html GET /x.html";
+        for source in [r"(GET|POST|PUT) /\w+\.html?", r"^GET /\w+", r"html?$", r"(a|b)*abb"] {
+            let pattern = compile_pattern(source).unwrap();
+            let minimized = pattern.minimize();
+            assert!(minimized.state_count() <= pattern.state_count(), "{}", source);
+            let ends = pattern_match_ends(pattern, input);
+            assert_eq!(pattern_match_ends(minimized, input), ends, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_builder_prunes_unreachable_states() {
+        // "ab" with a leftover state and a branch only that leftover leads into
+        let mut builder = PatternBuilder::new();
+        let s1 = builder.add_state(false);
+        let orphan = builder.add_state(false);
+        let branch = builder.add_state(true);
+        let s2 = builder.add_state(true);
+        builder.add_transition(0, b'a', s1).unwrap().add_transition(s1, b'b', s2).unwrap();
+        builder.add_transition(orphan, b'x', branch).unwrap();
+        builder.add_transition(branch, b'a', s1).unwrap();
+        let pattern = builder.build("ab".to_string()).unwrap();
+        assert_eq!(pattern.state_count(), 3);
+        assert_eq!(
+            pattern.prune_stats(),
+            PruneStats {
+                unreachable_states: 2,
+                final_states: 1
+            }
+        );
+        assert_eq!(pattern_match_ends(pattern, b"ab xab axb"), vec![1, 5]);
+
+        // Nothing to prune in compiled patterns
+        let stats = compile_pattern("a(b|c)*d").unwrap().prune_stats();
+        assert_eq!(stats.unreachable_states, 0);
+
+        // A final state that cannot be reached leaves a pattern that never matches
+        let unmatchable = |reject: bool| {
+            let mut builder = PatternBuilder::new();
+            let s1 = builder.add_state(false);
+            let unreachable = builder.add_state(true);
+            builder.add_transition(0, b'a', s1).unwrap();
+            builder.add_transition(unreachable, b'a', s1).unwrap();
+            builder.reject_unmatchable(reject);
+            builder.build("never".to_string())
+        };
+        let pattern = unmatchable(false).unwrap();
+        assert_eq!(pattern.prune_stats().final_states, 0);
+        assert_eq!(pattern_match_ends(pattern, b"aaa"), Vec::<usize>::new());
+        let Err(Error::InvalidPattern(message)) = unmatchable(true) else {
+            panic!("expected the unmatchable pattern to be rejected");
+        };
+        assert!(message.contains("'never'"), "{}", message);
+    }
+
+    #[test]
+    fn test_builder_tagged_states() {
+        // GET|POST|PUT with one tag per method, built as a trie or through epsilons
+        let methods: [&[u8]; 3] = [b"GET", b"POST", b"PUT"];
+        let build = |epsilons: bool| {
+            let mut builder = PatternBuilder::new();
+            for (tag, method) in methods.iter().enumerate() {
+                let mut from = 0;
+                if epsilons {
+                    from = builder.add_state(false);
+                    builder.add_epsilon_transition(0, from).unwrap();
+                }
+                for (index, &byte) in method.iter().enumerate() {
+                    let to = if index == method.len() - 1 {
+                        builder.add_state_tagged(tag as u32)
+                    } else {
+                        builder.add_state(false)
+                    };
+                    builder.add_transition(from, byte, to).unwrap();
+                    from = to;
+                }
+            }
+            builder.minimize(true);
+            builder.build("method".to_string()).unwrap()
+        };
+
+        for pattern in [build(false), build(true)] {
+            let matches = Arc::new(Mutex::new(Vec::new()));
+            let found = matches.clone();
+            let mut matcher = StreamMatcher::new();
+            matcher.add_tagged_callback(move |id, tag| {
+                found.lock().unwrap().push((id.to_string(), tag));
+            });
+            matcher.add_pattern(pattern);
+            matcher.process_chunk(b"PUT GET POST PUSH");
+            let tags: Vec<_> = matches.lock().unwrap().iter().map(|&(_, tag)| tag).collect();
+            assert_eq!(tags, vec![Some(2), Some(0), Some(1)]);
+            assert_eq!(matches.lock().unwrap()[0].0, "method");
+        }
+
+        // Untagged final states report no tag
+        let mut matcher = StreamMatcher::new();
+        let tags = Arc::new(Mutex::new(Vec::new()));
+        let found = tags.clone();
+        matcher.add_tagged_callback(move |_, tag| found.lock().unwrap().push(tag));
+        matcher.add_pattern(compile_pattern("ab").unwrap());
+        matcher.process_chunk(b"ab");
+        assert_eq!(*tags.lock().unwrap(), vec![None]);
+    }
+
+    #[test]
+    fn test_builder_from_literals() {
+        let literals: [(&str, &[u8]); 4] = [
+            ("select", b"SELECT "),
+            ("select-all", b"SELECT *"),
+            ("count", b"SELECT COUNT"),
+            ("lect", b"LECT"),
+        ];
+        let pattern = PatternBuilder::from_literals(&literals).unwrap();
+        // "SELECT " is shared, then "*", "COUNT" and "LECT"
+        assert_eq!(pattern.state_count(), 1 + 7 + 1 + 5 + 4);
+        assert_eq!(pattern.id, "select|select-all|count|lect");
+        assert_eq!((pattern.min_match_len(), pattern.max_match_len()), (4, Some(12)));
+
+        let matches = Arc::new(Mutex::new(Vec::new()));
+        let found = matches.clone();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_tagged_callback(move |_, tag| found.lock().unwrap().push(tag.unwrap()));
+        matcher.add_pattern(pattern);
+        // The second statement starts inside a failed attempt at the first
+        matcher.process_chunk(b"SELECSELECT * FROM t; SELECT COUNT(*)");
+        assert_eq!(*matches.lock().unwrap(), vec![3, 0, 1, 3, 0, 2]);
+
+        // Overlapping occurrences, and a literal that ends inside a longer one
+        let pattern = PatternBuilder::from_literals(&[("abab", b"abab")]).unwrap();
+        assert_eq!(pattern_match_ends(pattern, b"ababab"), vec![3, 5]);
+        let pattern = PatternBuilder::from_literals(&[("she", b"she"), ("he", b"he")]);
+        assert_eq!(pattern_match_ends(pattern.unwrap(), b"ushers ahe"), vec![3, 9]);
+
+        assert!(PatternBuilder::from_literals(&[]).is_err());
+        assert!(PatternBuilder::from_literals(&[("a", b"a"), ("empty", b"")]).is_err());
+    }
+
+    #[test]
+    fn test_builder_failure_links() {
+        // abab as a chain, with failure links set by hand or added by build()
+        let build = |failures: bool| {
+            let mut builder = PatternBuilder::new();
+            let states: Vec<usize> = (1..=4).map(|state| builder.add_state(state == 4)).collect();
+            let [a, ab, aba, abab] = states[..] else {
+                unreachable!()
+            };
+            builder.add_transition(0, b'a', a).unwrap().add_transition(a, b'b', ab).unwrap();
+            builder.add_transition(ab, b'a', aba).unwrap().add_transition(aba, b'b', abab).unwrap();
+            if failures {
+                builder.set_failure(a, 0).unwrap().set_failure(ab, 0).unwrap();
+                builder.set_failure(aba, a).unwrap().set_failure(abab, ab).unwrap();
+            }
+            builder.build("abab".to_string()).unwrap()
+        };
+        assert_eq!(pattern_match_ends(build(false), b"ababab aabab"), vec![3, 5, 11]);
+        let pattern = build(true);
+        assert_eq!(pattern_match_ends(pattern.clone(), b"ababab aabab"), vec![3, 5, 11]);
+        let minimized = pattern.minimize();
+        assert!(minimized.states.iter().all(|state| state.failure.is_none()));
+        assert_eq!(pattern_match_ends(minimized, b"ababab aabab"), vec![3, 5, 11]);
+
+        // Literal tries only need transitions for their own bytes
+        let literals: [(&str, &[u8]); 2] = [("she", b"she"), ("hers", b"hers")];
+        let pattern = PatternBuilder::from_literals(&literals).unwrap();
+        let transitions: usize = pattern.states.iter().map(|state| state.transitions.len()).sum();
+        assert_eq!(transitions, pattern.state_count() - 1);
+
+        let mut builder = PatternBuilder::new();
+        let (s1, s2) = (builder.add_state(false), builder.add_state(true));
+        builder.add_transition(0, b'a', s1).unwrap().add_transition(s1, b'b', s2).unwrap();
+        builder.set_failure(s1, s2).unwrap().set_failure(s2, s1).unwrap();
+        assert!(matches!(builder.build("cycle".to_string()), Err(Error::InvalidPattern(_))));
+    }
+
+    #[test]
+    fn test_introspection() {
+        let pattern = compile_pattern(r"cmd\.exe").unwrap();
+        assert_eq!(pattern.id(), r"cmd\.exe");
+        assert_eq!(pattern.state_count(), 8);
+        assert_eq!(pattern.final_state_count(), 1);
+        assert!(pattern.transition_count() >= 7);
+        assert!(pattern.estimated_memory() > pattern.memory_usage());
+
+        let mut builder = PatternBuilder::new();
+        let (body, end) = (builder.add_state(false), builder.add_state(true));
+        builder.add_transition(0, b'"', body).unwrap().add_transition(body, b'"', end).unwrap();
+        builder.add_negated_transition(body, b"\"", body).unwrap();
+        let quoted = builder.build("quoted".to_string()).unwrap();
+        assert_eq!(quoted.transition_count(), 3);
+
+        let mut matcher = StreamMatcher::new();
+        matcher.add_pattern(pattern);
+        matcher.add_pattern(quoted);
+        assert_eq!(matcher.pattern_ids().collect::<Vec<_>>(), vec![r"cmd\.exe", "quoted"]);
+        let states: usize = matcher.patterns().map(Pattern::state_count).sum();
+        assert_eq!(states, 11);
+    }
+
+    #[test]
+    fn test_builder_append_literal() {
+        // GET /[a-z]+ HTTP, with the literal parts appended
+        let mut builder = PatternBuilder::new();
+        let path = builder.append_literal(0, b"GET /").unwrap();
+        let word = builder.add_state(false);
+        builder.add_transition_range(path, b'a'..=b'z', word).unwrap();
+        builder.add_transition_range(word, b'a'..=b'z', word).unwrap();
+        let last = builder.append_literal(word, b" HTTP").unwrap();
+        let end = builder.add_state(true);
+        builder.add_epsilon_transition(last, end).unwrap();
+        assert_eq!(builder.append_literal(end, b"").unwrap(), end);
+        let appended = builder.build("request".to_string()).unwrap();
+
+        let mut builder = PatternBuilder::new();
+        let mut from = 0;
+        for &byte in b"GET /" {
+            let to = builder.add_state(false);
+            builder.add_transition(from, byte, to).unwrap();
+            from = to;
+        }
+        let word = builder.add_state(false);
+        builder.add_transition_range(from, b'a'..=b'z', word).unwrap();
+        builder.add_transition_range(word, b'a'..=b'z', word).unwrap();
+        from = word;
+        for (index, &byte) in b" HTTP".iter().enumerate() {
+            let to = builder.add_state(index == 4);
+            builder.add_transition(from, byte, to).unwrap();
+            from = to;
+        }
+        let manual = builder.build("request".to_string()).unwrap();
+
+        let input = b"GET /index HTTP GET /a HTTP GET / HTTP GET /x1 HTTP";
+        assert_eq!(pattern_match_ends(appended.minimize(), input), vec![14, 26]);
+        assert_eq!(pattern_match_ends(manual, input), vec![14, 26]);
+
+        // A wildcard between two literals
+        let mut builder = PatternBuilder::new();
+        let key = builder.append_literal(0, b"key=").unwrap();
+        let value = builder.add_state(false);
+        builder.add_wildcard_transition(key, value).unwrap();
+        let last = builder.append_literal(value, b";").unwrap();
+        let end = builder.add_state(true);
+        builder.add_epsilon_transition(last, end).unwrap();
+        let pattern = builder.build("key".to_string()).unwrap();
+        assert_eq!(pattern_match_ends(pattern, b"key=1; key=;; key=ab;"), vec![5, 12]);
+    }
+
+    #[test]
+    fn test_concat() {
+        let anchored = CompileOptions::new().anchored(true);
+        let key = compile_pattern("key=").unwrap();
+        let value = compile_pattern_with("[0-9]+;", &anchored).unwrap();
+        let pattern = key.concat(value, "key-value".to_string()).unwrap();
+        assert_eq!(pattern.id(), "key-value");
+        assert_eq!((pattern.min_match_len(), pattern.max_match_len()), (6, None));
+        let input = b"key=12; key= 3; kkey=4; key=x5; key=key=6;";
+        assert_eq!(pattern_match_ends(pattern.clone(), input), vec![6, 22, 41]);
+
+        // The junction and the value may be split across chunks
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_callback(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        matcher.add_pattern(pattern);
+        for chunk in [&b"xkey"[..], b"=", b"1", b"2;key=", b";"] {
+            matcher.process_chunk(chunk);
+        }
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+
+        // Literals stay literals, and the second one must follow immediately
+        let pattern = compile_pattern("ab")
+            .unwrap()
+            .concat(compile_pattern("cd").unwrap(), "abcd".to_string())
+            .unwrap();
+        assert_eq!(pattern.kind(), &PatternKind::Literal(b"abcd".to_vec()));
+        assert_eq!(pattern_match_ends(pattern, b"abcd abccd abcabcd"), vec![3, 17]);
+
+        // Hand-built patterns, anchored ones and tags
+        let mut builder = PatternBuilder::new();
+        let last = builder.append_literal(0, b"GET").unwrap();
+        let end = builder.add_state_tagged(7);
+        builder.add_transition(last, b' ', end).unwrap();
+        let get = builder.build("get".to_string()).unwrap();
+        let slash = compile_pattern("/").unwrap();
+        let pattern = get.clone().concat(slash, "get".to_string()).unwrap();
+        assert_eq!(pattern_match_ends(pattern, b"GET / GET x/"), vec![4]);
+        let start = compile_pattern("^<").unwrap();
+        let pattern = start.concat(get, "start".to_string()).unwrap();
+        assert!(pattern.is_anchored());
+        assert_eq!(pattern_match_ends(pattern.clone(), b"<GET <GET "), vec![4]);
+        assert_eq!(pattern.states.iter().filter_map(|state| state.final_tag).max(), Some(7));
+    }
+
+    #[test]
+    fn test_union() {
+        let mut builder = PatternBuilder::new();
+        let last = builder.append_literal(0, b"DELETE").unwrap();
+        let end = builder.add_state_tagged(9);
+        builder.add_transition(last, b' ', end).unwrap();
+        let patterns = vec![
+            compile_pattern("GET /[a-z]+").unwrap(),
+            compile_pattern("POST").unwrap(),
+            builder.build("delete".to_string()).unwrap(),
+            compile_pattern("^HEAD").unwrap(),
+        ];
+        let ids: Vec<String> = patterns.iter().map(|pattern| pattern.id().to_string()).collect();
+        let pattern = Pattern::union(patterns, "methods".to_string()).unwrap();
+        assert!(!pattern.is_anchored());
+        assert_eq!((pattern.min_match_len(), pattern.max_match_len()), (4, None));
+
+        let matched = Arc::new(Mutex::new(Vec::new()));
+        let sink = matched.clone();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_tagged_callback(move |id, tag| {
+            sink.lock().unwrap().push((id.to_string(), tag));
+        });
+        matcher.add_pattern(pattern);
+        matcher.process_chunk(b"HEAD GET /ab POST DELETE HEAD");
+        let matched: Vec<_> = matched
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, tag)| (id.clone(), ids[tag.unwrap() as usize].clone()))
+            .collect();
+        let expected = ["^HEAD", "GET /[a-z]+", "POST", "delete"];
+        let expected: Vec<_> =
+            expected.iter().map(|id| ("methods".to_string(), id.to_string())).collect();
+        assert_eq!(matched, expected);
+
+        // Anchored patterns stay anchored together
+        let patterns = vec![compile_pattern("^a").unwrap(), compile_pattern("^bc").unwrap()];
+        let pattern = Pattern::union(patterns, "start".to_string()).unwrap();
+        assert!(pattern.is_anchored());
+        assert_eq!(pattern_match_ends(pattern.clone(), b"bc a"), vec![1]);
+        assert_eq!(pattern_match_ends(pattern, b"a"), vec![0]);
+
+        assert!(matches!(
+            Pattern::union(Vec::new(), "none".to_string()),
+            Err(Error::InvalidPattern(_))
+        ));
+    }
+
+    #[test]
+    fn test_repeat() {
+        let octet = compile_pattern("[0-9]+[.]").unwrap();
+        let pattern = octet.repeat(3, Some(3), "octets".to_string()).unwrap();
+        assert_eq!((pattern.min_match_len(), pattern.max_match_len()), (6, None));
+        let input = b"1.22.3. 1.2.x.3. 10.20.30.40.";
+        assert_eq!(pattern_match_ends(pattern, input), vec![6, 25, 28]);
+
+        // Bounded and unbounded repetition of a hand-built pattern
+        let mut builder = PatternBuilder::new();
+        let last = builder.append_literal(0, b"a").unwrap();
+        let end = builder.add_state(true);
+        builder.add_transition(last, b'b', end).unwrap();
+        let ab = builder.build("ab".to_string()).unwrap();
+        let pattern = ab.clone().repeat(2, Some(3), "ab{2,3}".to_string()).unwrap();
+        assert_eq!((pattern.min_match_len(), pattern.max_match_len()), (4, Some(6)));
+        assert_eq!(pattern_match_ends(pattern, b"ab abab ababab abxab"), vec![6, 11, 13]);
+        let pattern = ab.clone().repeat(2, None, "ab{2,}".to_string()).unwrap();
+        assert_eq!(pattern_match_ends(pattern, b"ab abab ababab"), vec![6, 11, 13]);
+        let pattern = ab.clone().repeat(0, None, "ab*".to_string()).unwrap();
+        assert_eq!(pattern_match_ends(pattern, b"ab abab"), vec![1, 4, 6]);
+
+        // The same as the counted repetition of compile_pattern
+        let pattern = compile_pattern("ab").unwrap().repeat(2, Some(3), "ab".to_string()).unwrap();
+        let input = b"ab abab ababab abababab aabab";
+        assert_eq!(pattern_match_ends(pattern, input), match_ends("(ab){2,3}", input));
+
+        // Counted against the same limit as {m,n}
+        match ab.clone().repeat(1, Some(5000), "many".to_string()) {
+            Err(Error::PatternTooComplex(message)) => {
+                assert!(message.contains("limit of 10000"), "{}", message)
+            }
+            other => panic!("expected PatternTooComplex, got {:?}", other),
+        }
+        assert!(matches!(
+            ab.clone().repeat(3, Some(2), "ab".to_string()),
+            Err(Error::InvalidPattern(_))
+        ));
+        assert!(matches!(ab.repeat(0, Some(0), "ab".to_string()), Err(Error::InvalidPattern(_))));
+    }
+
+    #[test]
+    fn test_builder_caseless_helpers() {
+        let input = b"Get GET get gEt GeT";
+        let expected = match_ends("(?i)g(?-i)e(?i)t", input);
+        assert_eq!(expected, vec![2, 10, 18]);
+
+        let mut builder = PatternBuilder::new();
+        let (g, e) = (builder.add_state(false), builder.add_state(false));
+        let t = builder.add_state(true);
+        builder.add_caseless_transition(0, b'g', g).unwrap().add_transition(g, b'e', e).unwrap();
+        builder.add_caseless_transition(e, b'T', t).unwrap();
+        // Bytes other than letters get a single transition
+        builder.add_caseless_transition(0, b'1', g).unwrap();
+        assert_eq!(builder.transitions.len(), 6);
+        let pattern = builder.build("get".to_string()).unwrap();
+        assert_eq!(pattern_match_ends(pattern, input), expected);
+
+        // Folding afterwards, keeping explicit transitions and excluding both cases
+        let mut builder = PatternBuilder::new();
+        let (lower, upper) = (builder.add_state(true), builder.add_state(true));
+        let other = builder.add_state(false);
+        builder.add_transition(0, b'a', lower).unwrap().add_transition(0, b'A', upper).unwrap();
+        builder.add_transition(0, b'b', lower).unwrap();
+        builder.add_negated_transition(0, b"xy", other).unwrap();
+        builder.add_transition(other, b'z', lower).unwrap();
+        builder.make_caseless();
+        let pattern = builder.build("ab".to_string()).unwrap();
+        let initial = &pattern.states[0];
+        assert_eq!((initial.next_state(b'a'), initial.next_state(b'A')), (Some(1), Some(2)));
+        assert_eq!(initial.next_state(b'B'), Some(1));
+        assert_eq!((initial.next_state(b'X'), initial.next_state(b'q')), (None, Some(3)));
+        assert_eq!(pattern.states[3].next_state(b'Z'), Some(1));
+    }
+
+    #[test]
+    fn test_state_limit() {
+        let message = |result: Result<Pattern, Error>| match result {
+            Err(Error::PatternTooComplex(message)) => message,
+            other => panic!("expected PatternTooComplex, got {:?}", other),
+        };
+
+        // Stops during determinization, naming the limit and the count reached
+        let blowup = "[ab]*a[ab]{13}";
+        let error = message(compile_pattern_with_limit(blowup, 1000));
+        assert!(error.contains("1001 states") && error.contains("limit of 1000"), "{}", error);
+        // A higher limit carries through to the deterministic automaton
+        let large = compile_pattern_with_limit(blowup, 20_000).unwrap();
+        assert!(large.state_count() > DEFAULT_STATE_LIMIT);
+
+        // Hand-built automatons are counted as well
+        let mut builder = PatternBuilder::new();
+        let last = builder.append_literal(0, b"four").unwrap();
+        builder.add_epsilon_transition(last, 0).unwrap().state_limit(4);
+        let error = message(builder.build("four".to_string()));
+        assert!(error.contains("5 states") && error.contains("limit of 4"), "{}", error);
+
+        // Combinators check their expansion before copying
+        let small = compile_pattern("x").unwrap();
+        let error = message(large.clone().concat(small.clone(), "large".to_string()));
+        assert!(error.contains("limit of 10000"), "{}", error);
+        let error = message(Pattern::union(vec![large, small], "large".to_string()));
+        assert!(error.contains("limit of 10000"), "{}", error);
+    }
+
+    #[test]
+    fn test_builder_metadata() {
+        // Kept through determinization and minimization
+        let mut builder = PatternBuilder::new();
+        let (a, b) = (builder.add_state(true), builder.add_state(true));
+        builder.add_transition(0, b'x', a).unwrap().add_transition(0, b'x', b).unwrap();
+        builder.metadata("severity", "high").metadata("cve", "CVE-2024-0001");
+        builder.metadata("severity", "critical").minimize(true);
+        let pattern = builder.build("x".to_string()).unwrap();
+        assert_eq!(pattern.metadata("severity"), Some("critical"));
+        assert_eq!(pattern.metadata("remediation"), None);
+        let entries: Vec<_> = pattern.metadata_entries().collect();
+        assert_eq!(entries, vec![("cve", "CVE-2024-0001"), ("severity", "critical")]);
+
+        let pattern = compile_pattern("x").unwrap().with_metadata("owner", "netsec");
+        assert_eq!(pattern.minimize().metadata("owner"), Some("netsec"));
+    }
+
+    #[test]
+    fn test_builder_reuse() {
+        let words: [&[u8]; 3] = [b"alpha", b"beta", b"gamma"];
+        let mut builder = PatternBuilder::new();
+        builder.anchored(true);
+        let mut patterns = Vec::new();
+        for word in words {
+            let last = builder.append_literal(0, word).unwrap();
+            builder.set_finality(last, true, false, None).unwrap();
+            builder.metadata("word", std::str::from_utf8(word).unwrap());
+            patterns.push(builder.build_ref(String::from_utf8(word.to_vec()).unwrap()).unwrap());
+            builder.reset();
+        }
+        for (pattern, word) in patterns.iter().zip(words) {
+            assert!(pattern.is_anchored());
+            assert_eq!(pattern.state_count(), word.len() + 1);
+            assert_eq!(pattern.metadata("word").map(str::as_bytes), Some(word));
+            assert_eq!(pattern_match_ends(pattern.clone(), word), vec![word.len() - 1]);
+        }
+
+        // Reset keeps the buffers but nothing in them
+        let capacity = builder.states.capacity();
+        assert!(capacity >= 6);
+        assert_eq!((builder.states.len(), builder.transitions.len()), (1, 0));
+        assert_eq!(builder.metadata.len(), 0);
+
+        // Building from a reference leaves the builder as it was
+        let end = builder.add_state(true);
+        builder.add_transition(0, b'x', end).unwrap();
+        let x = builder.build_ref("x".to_string()).unwrap();
+        builder.add_transition(0, b'y', end).unwrap();
+        let xy = builder.build_ref("xy".to_string()).unwrap();
+        assert_eq!((x.transition_count(), xy.transition_count()), (1, 2));
+        assert_eq!(builder.states.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_builder_invalid_state() {
+        let mut builder = PatternBuilder::new();
+        let end = builder.add_state(true);
+        fn invalid(result: Result<&mut PatternBuilder, Error>) -> (usize, usize) {
+            match result {
+                Err(Error::InvalidState { index, state_count }) => (index, state_count),
+                other => panic!("expected InvalidState, got {:?}", other.map(|_| ())),
+            }
+        }
+        assert_eq!(invalid(builder.add_transition(0, b'a', 5)), (5, 2));
+        assert_eq!(invalid(builder.add_transition_range(9, b'a'..=b'z', end)), (9, 2));
+        assert_eq!(invalid(builder.add_epsilon_transition(end, 2)), (2, 2));
+        assert_eq!(invalid(builder.set_failure(3, 0)), (3, 2));
+        assert!(matches!(
+            builder.append_literal(4, b"abc"),
+            Err(Error::InvalidState { index: 4, state_count: 2 })
+        ));
+        let error = builder.add_wildcard_transition(7, end).unwrap_err().to_string();
+        assert!(error.contains("Invalid state index 7"), "{}", error);
+
+        // Nothing was added by the failed calls
+        builder.add_transition(0, b'a', end).unwrap();
+        let pattern = builder.build("a".to_string()).unwrap();
+        assert_eq!(pattern.states.len(), 2);
+        assert_eq!(pattern.states[0].next_state(b'b'), None);
+    }
+
+    #[test]
+    fn test_builder_initial_states() {
+        // ab, starting from state 1 so that state 0 is the one in the middle
+        let mut builder = PatternBuilder::new();
+        let start = builder.add_state(false);
+        let end = builder.add_state(true);
+        builder.add_transition(start, b'a', 0).unwrap();
+        builder.add_transition(0, b'b', end).unwrap();
+        builder.set_initial_state(start).unwrap();
+        let pattern = builder.build("ab".to_string()).unwrap();
+        assert_eq!(pattern.initial_state(), start);
+        assert!(format!("{:?}", pattern).contains("initial_state: 1,"));
+        assert_eq!(pattern_match_ends(pattern, b"abxab"), [1, 4]);
+
+        // GET and GEM from entry points of their own, which share their first bytes
+        let mut builder = PatternBuilder::new();
+        let get = builder.add_state(false);
+        let gem = builder.add_state(false);
+        for (entry, literal) in [(get, b"GET"), (gem, b"GEM")] {
+            let last = builder.append_literal(entry, literal).unwrap();
+            builder.set_finality(last, true, false, None).unwrap();
+        }
+        builder.add_transition(0, b'x', get).unwrap();
+        builder.set_initial_states(&[get, gem]).unwrap();
+        let pattern = builder.build("get_or_gem".to_string()).unwrap();
+        assert_eq!(pattern_match_ends(pattern, b"GET GEM GEX xGEM"), [2, 6, 15]);
+
+        let mut builder = PatternBuilder::new();
+        assert!(matches!(
+            builder.set_initial_state(9),
+            Err(Error::InvalidState { index: 9, state_count: 1 })
+        ));
+        assert!(matches!(builder.set_initial_states(&[]), Err(Error::InvalidPattern(_))));
+    }
+
+    #[test]
+    fn test_dense_tables() {
+        let input = b"SELECT a FROM t WHERE id_7=1; eval(x) user_name=admin";
+        for pattern in ["SELECT.*FROM.*WHERE", "eval\\(.*\\)", "[a-z_]+[0-9]?=", "(?i)admin"] {
+            let sparse = compile_pattern(pattern).unwrap();
+            let dense = compile_pattern_with(pattern, &CompileOptions::new().dense(true)).unwrap();
+            assert!(dense.states.iter().all(|state| state.dense.is_some()), "{}", pattern);
+            // Tables are indexed by byte class, far smaller than 1 KiB for all bytes
+            let tables = dense.memory_usage() - sparse.memory_usage();
+            assert!(tables > 0 && tables < dense.state_count() * 1024 / 5, "{}", pattern);
+            let minimized = dense.minimize();
+            assert!(minimized.states.iter().all(|state| state.dense.is_some()));
+            assert_eq!(
+                pattern_match_ends(dense, input),
+                pattern_match_ends(sparse, input),
+                "{}",
+                pattern
+            );
+        }
+
+        // Only states with many exact transitions get a table by default
+        let pattern = compile_pattern("x[a-z0-9_]+=").unwrap();
+        let dense: Vec<bool> = pattern.states.iter().map(|state| state.dense.is_some()).collect();
+        assert!(dense.contains(&true) && dense.contains(&false), "{:?}", dense);
+        assert_eq!(pattern_match_ends(pattern, b"xa_1= x=xb="), [4, 10]);
+
+        let mut builder = PatternBuilder::new();
+        let end = builder.add_state(true);
+        builder.add_negated_transition(0, b"\n", end).unwrap().dense(true);
+        let pattern = builder.build("line".to_string()).unwrap();
+        let table = pattern.states[0].dense.as_ref().unwrap();
+        let class = |byte: u8| pattern.class_of[byte as usize] as usize;
+        assert_eq!((table[class(b'\n')], table[class(b'x')]), (NO_TRANSITION, end as u32));
+        // \n, every other byte, so two classes
+        assert_eq!(table.len(), 2);
+        assert_eq!(pattern_match_ends(pattern, b"ab\ncd"), [0, 1, 3, 4]);
+    }
+
+    #[test]
+    fn test_reverse() {
+        // Fed backwards from the 'c' that ends a match, it finds where the match started
+        let reversed = compile_pattern("ab+c").unwrap().reverse().unwrap();
+        assert!(reversed.is_anchored());
+        let input = b"xxabbbcyy";
+        let backwards: Vec<u8> = input[..=6].iter().rev().copied().collect();
+        assert_eq!(pattern_match_ends(reversed.clone(), &backwards), [4]);
+        // Not a match end, and only from the position it was started at
+        assert!(pattern_match_ends(reversed, b"bbbax cbax").is_empty());
+
+        let reversed = compile_pattern("GET").unwrap().reverse().unwrap();
+        assert_eq!(reversed.kind(), &PatternKind::Literal(b"TEG".to_vec()));
+        assert_eq!(pattern_match_ends(reversed, b"TEGTEG"), [2]);
+
+        // Tagged alternatives of different lengths, the nearest start first
+        let literals: [(&str, &[u8]); 2] = [("ab", b"ab"), ("xab", b"xab")];
+        let reversed = PatternBuilder::from_literals(&literals).unwrap().reverse().unwrap();
+        assert_eq!(pattern_match_ends(reversed, b"bax"), [1]);
+
+        // [ab]{13}a[ab]* reversed has to remember whether each of the last 14 bytes was
+        // an 'a', which is more than the state limit allows
+        let mut builder = PatternBuilder::new();
+        let mut state = 0;
+        for _ in 0..13 {
+            let next = builder.add_state(false);
+            builder.add_transition_set(state, b"ab", next).unwrap();
+            state = next;
+        }
+        let end = builder.add_state(true);
+        builder.add_transition(state, b'a', end).unwrap();
+        builder.add_transition_set(end, b"ab", end).unwrap();
+        builder.anchored(true);
+        let pattern = builder.build("grows".to_string()).unwrap();
+        assert!(matches!(pattern.reverse(), Err(Error::PatternTooComplex(_))));
+
+        let mut builder = PatternBuilder::new();
+        builder.add_state(false);
+        let never = builder.build("never".to_string()).unwrap();
+        assert!(matches!(never.reverse(), Err(Error::InvalidPattern(_))));
+    }
+
+    #[test]
+    fn test_complexity() {
+        let report = compile_pattern("GET").unwrap().complexity();
+        assert_eq!(
+            report,
+            ComplexityReport {
+                state_count: 4,
+                max_out_degree: 2,
+                unbounded: false,
+                min_match_len: 3,
+                cost_class: CostClass::Literal,
+            }
+        );
+
+        let report = compile_pattern("id=[0-9]{2,4}").unwrap().complexity();
+        assert_eq!((report.unbounded, report.min_match_len), (false, 5));
+        assert_eq!(report.cost_class, CostClass::Bounded);
+
+        let report = compile_pattern("SELECT.*FROM").unwrap().complexity();
+        assert!(report.unbounded);
+        assert_eq!(report.cost_class, CostClass::Unbounded);
+        assert!(CostClass::Literal < CostClass::Bounded);
+        assert!(CostClass::Bounded < CostClass::Unbounded);
+    }
+
+    #[test]
+    fn test_overlapping_matches() {
+        let cases: [(&str, &[u8], usize); 13] = [
+            ("aa", b"aaaa", 3),
+            ("aaa", b"aaaaa", 3),
+            ("abab", b"ababab", 2),
+            ("aba", b"ababa", 2),
+            ("xyx", b"xyxyxyx", 3),
+            ("nene", b"nenene", 2),
+            ("aab", b"aaab", 1),
+            ("aaab", b"aaaaab", 1),
+            ("ab", b"aab", 1),
+            ("abc", b"ababc", 1),
+            ("abcabd", b"abcabcabd", 1),
+            ("abaab", b"abaabaab", 2),
+            ("aabaa", b"aabaabaa", 2),
+        ];
+        // A hand-built chain, which build() gives failure links
+        let chain = |literal: &[u8]| {
+            let mut builder = PatternBuilder::new();
+            let (&last_byte, prefix) = literal.split_last().unwrap();
+            let last = builder.append_literal(0, prefix).unwrap();
+            let end = builder.add_state(true);
+            builder.add_transition(last, last_byte, end).unwrap();
+            builder.build("chain".to_string()).unwrap()
+        };
+        for (literal, input, expected) in cases {
+            let bytes = literal.as_bytes();
+            let patterns = [
+                compile_pattern(literal).unwrap(),
+                chain(bytes),
+                PatternBuilder::from_literals(&[(literal, bytes)]).unwrap(),
+            ];
+            for pattern in patterns {
+                let ends = pattern_match_ends(pattern, input);
+                assert_eq!(ends.len(), expected, "{} in {:?}", literal, input.escape_ascii());
+            }
+        }
+    }
+
+    #[test]
+    fn test_mismatching_byte_starts_new_match() {
+        // Without failure links (the first transition of each is a range, so they are
+        // no tries), the byte that breaks an attempt is tried again from the start
+        let build = |first: RangeInclusive<u8>, rest: &[u8]| {
+            let mut builder = PatternBuilder::new();
+            let second = builder.add_state(false);
+            builder.add_transition_range(0, first, second).unwrap();
+            let (&last_byte, middle) = rest.split_last().unwrap();
+            let last = builder.append_literal(second, middle).unwrap();
+            let end = builder.add_state(true);
+            builder.add_transition(last, last_byte, end).unwrap();
+            builder.build("range".to_string()).unwrap()
+        };
+        assert_eq!(pattern_match_ends(build(b'a'..=b'b', b"c"), b"aac bbc"), [2, 6]);
+        assert_eq!(pattern_match_ends(build(b'x'..=b'y', b"1y"), b"xx1y x1x1y"), [3, 9]);
+        // Only failure links find matches that started before the breaking byte, such
+        // as the overlapping second "nene"
+        assert_eq!(pattern_match_ends(build(b'n'..=b'o', b"ene"), b"nenene"), [3]);
+
+        // With failure links, or compiled, repeated prefixes are not lost either
+        let cases: [(&str, &[u8], &[usize]); 2] =
+            [("aab", b"aaab", &[3]), ("nene", b"nenene", &[3, 5])];
+        for (pattern, input, ends) in cases {
+            assert_eq!(match_ends(pattern, input), ends);
+            let literal = [(pattern, pattern.as_bytes())];
+            let trie = PatternBuilder::from_literals(&literal).unwrap();
+            assert_eq!(pattern_match_ends(trie, input), ends);
+        }
+    }
+}
@@ -0,0 +1,255 @@
+// Streaming redaction: the bytes of a stream pass through with every match masked
+//
+// Bytes are written out as soon as no match still to come can cover them: right away
+// while no pattern is partway through a match, and otherwise once the stream is the
+// longest match of any pattern past them. That bound is why every pattern needs one.
+
+use std::io::Write;
+use std::ops::{ControlFlow, Range};
+
+use crate::Result;
+use crate::database::{PatternDatabase, StreamState};
+use crate::error::Error;
+use crate::matcher::{Match, MatcherConfig};
+use crate::pattern::Pattern;
+use crate::starts::MatchStarts;
+
+// Rewrites a stream with the matches of its patterns replaced by a mask byte, as a
+// scrubber of card numbers or other PII in a proxy would
+pub struct RedactingMatcher {
+    database: PatternDatabase,
+    stream: StreamState,
+    // For each pattern whose matches vary in length, where they start
+    starts: Vec<Option<MatchStarts>>,
+    mask: u8,
+    // Most bytes a match of any pattern spans
+    longest: usize,
+    // The bytes not written yet, from offset `held_from` of the stream, and the one
+    // before them, None at its start
+    held: Vec<u8>,
+    held_from: u64,
+    before: Option<u8>,
+    // The parts of the stream to mask from `held_from` on, sorted and apart
+    masked: Vec<Range<u64>>,
+    // The matches of the chunk being scanned, kept to save allocating for every chunk
+    found: Vec<Match>,
+}
+
+impl RedactingMatcher {
+    // A matcher replacing every byte of a match of `patterns` with `mask`. Fails for
+    // patterns with unbounded matches (see Pattern::max_match_len), as any number of
+    // bytes would have to be held back for them, and for those of variable length
+    // that were not compiled from a regular expression, as where their matches start
+    // cannot be found
+    pub fn new(patterns: Vec<Pattern>, mask: u8) -> Result<Self> {
+        let mut starts = Vec::with_capacity(patterns.len());
+        let mut longest = 0;
+        for pattern in &patterns {
+            let Some(max) = pattern.max_match_len() else {
+                return Err(Error::InvalidPattern(format!(
+                    "Pattern '{}' has matches of unbounded length, which cannot be redacted",
+                    pattern.id()
+                )));
+            };
+            longest = longest.max(max);
+            let fixed = pattern.min_match_len() == max;
+            starts.push(if fixed { None } else { Some(MatchStarts::new(pattern)?) });
+        }
+        let database = PatternDatabase::new(patterns, MatcherConfig::default());
+        Ok(RedactingMatcher {
+            stream: StreamState::new(&database),
+            database,
+            starts,
+            mask,
+            longest,
+            held: Vec::new(),
+            held_from: 0,
+            before: None,
+            masked: Vec::new(),
+            found: Vec::new(),
+        })
+    }
+
+    // Pass the next chunk of the stream on to `out`, masked, except for the bytes at its
+    // end a match may still cover, which come with a later chunk or with finish
+    pub fn process_chunk<W: Write>(&mut self, data: &[u8], out: &mut W) -> Result<()> {
+        self.held.extend_from_slice(data);
+        let found = &mut self.found;
+        self.database.scan(&mut self.stream, data, |event| {
+            found.push(event.to_match());
+            ControlFlow::Continue(())
+        });
+        self.mask_found(false);
+        let position = self.stream.bytes_processed();
+        let safe = match self.stream.progress.idle {
+            true => position,
+            false => position.saturating_sub(self.longest as u64),
+        };
+        self.write_until(safe, out)
+    }
+
+    // End the stream, masking the matches that complete at its end, and write the
+    // rest of it to `out`. The next chunk starts a new stream
+    pub fn finish<W: Write>(&mut self, out: &mut W) -> Result<()> {
+        let found = &mut self.found;
+        self.database.finish(&mut self.stream, |event| {
+            found.push(event.to_match());
+            ControlFlow::Continue(())
+        });
+        self.mask_found(true);
+        self.write_until(u64::MAX, out)?;
+        (self.held_from, self.before) = (0, None);
+        Ok(())
+    }
+
+    // Bytes held back until the stream is past the matches that may cover them
+    pub fn held_bytes(&self) -> usize {
+        self.held.len()
+    }
+
+    // Mask the matches found, `at_end` of the stream for those finish reports
+    fn mask_found(&mut self, at_end: bool) {
+        let mut matches = std::mem::take(&mut self.found);
+        for found in matches.drain(..) {
+            let held_end = (found.end - self.held_from) as usize;
+            // Patterns are numbered in order from 0
+            let index = found.pattern as usize;
+            let start = match (found.start, &mut self.starts[index]) {
+                (Some(start), _) => Some(start),
+                (None, Some(starts)) => starts
+                    .farthest(self.before, &self.held, 0, held_end, at_end)
+                    .map(|start| self.held_from + start as u64),
+                (None, None) => None,
+            };
+            // Masking too much rather than too little, should there be no start
+            let start = start.unwrap_or(found.end.saturating_sub(self.longest as u64));
+            self.mask_range(start.max(self.held_from)..found.end);
+        }
+        self.found = matches;
+    }
+
+    fn mask_range(&mut self, mut range: Range<u64>) {
+        if range.is_empty() {
+            return;
+        }
+        // Overlapping or touching ranges merge into one
+        self.masked.retain(|other| {
+            let apart = other.end < range.start || range.end < other.start;
+            if !apart {
+                range = range.start.min(other.start)..range.end.max(other.end);
+            }
+            apart
+        });
+        let index = self.masked.partition_point(|other| other.start < range.start);
+        self.masked.insert(index, range);
+    }
+
+    // Write the held bytes before offset `until`, masked, to `out`
+    fn write_until<W: Write>(&mut self, until: u64, out: &mut W) -> Result<()> {
+        let count = until.saturating_sub(self.held_from).min(self.held.len() as u64) as usize;
+        let (from, until) = (self.held_from, self.held_from + count as u64);
+        if count > 0 {
+            self.before = Some(self.held[count - 1]);
+        }
+        for range in &self.masked {
+            let start = range.start.max(from);
+            let end = range.end.min(until);
+            if start < end {
+                self.held[(start - from) as usize..(end - from) as usize].fill(self.mask);
+            }
+        }
+        out.write_all(&self.held[..count])?;
+        self.held.drain(..count);
+        self.held_from = until;
+        self.masked.retain(|range| range.end > until);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile_pattern;
+    use crate::pattern::PatternBuilder;
+
+    fn redact(patterns: &[&str], chunks: &[&[u8]]) -> Vec<u8> {
+        let patterns = patterns.iter().map(|pattern| compile_pattern(pattern).unwrap());
+        let mut matcher = RedactingMatcher::new(patterns.collect(), b'#').unwrap();
+        let mut out = Vec::new();
+        for chunk in chunks {
+            matcher.process_chunk(chunk, &mut out).unwrap();
+        }
+        matcher.finish(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_redaction() {
+        let card = r"\d{4}-\d{4}-\d{4}-\d{4}";
+        let ssn = r"\d{3}-\d{2}-\d{4}";
+        let input = b"card 1234-5678-9012-3456, ssn 123-45-6789 and 12-34 or 1234-5678 end";
+        let expected = b"card ###################, ssn ########### and 12-34 or 1234-5678 end";
+        // Every split, so masks and failed partial matches span chunk boundaries
+        for split in 0..=input.len() {
+            let (first, second) = input.split_at(split);
+            assert_eq!(redact(&[card, ssn], &[first, second]), expected, "split at {}", split);
+        }
+        let bytes: Vec<&[u8]> = input.chunks(1).collect();
+        assert_eq!(redact(&[card, ssn], &bytes), expected);
+
+        // Overlapping candidates of patterns of variable length mask their union
+        let input = b"x abcabcab y aab";
+        assert_eq!(
+            redact(&["(abc){1,2}ab", "a{1,2}b$"], &[&input[..5], &input[5..]]),
+            b"x ######## y ###"
+        );
+        assert_eq!(redact(&["a{1,2}b$"], &[b"xa", b"ab"]), b"x###");
+        assert_eq!(redact(&["a{1,2}b"], &[b"aaab"]), b"a###");
+
+        // The bytes before a match can be ones the pattern matches too, without being
+        // part of the match, and '\b' sees the byte before where it starts
+        let cases: [(&str, &[u8], &[u8]); 4] = [
+            ("ab{1,3}", b"qaaabq", b"qaa##q"),
+            ("(xb|c)", b"qxxcq", b"qxx#q"),
+            ("a{1,3}b", b"aaaaab", b"aa####"),
+            (r"\ba{1,2}b", b"xaab aab", b"xaab ###"),
+        ];
+        for (pattern, input, expected) in cases {
+            for split in 0..=input.len() {
+                let (first, second) = input.split_at(split);
+                let redacted = redact(&[pattern], &[first, second]);
+                assert_eq!(redacted, expected, "{} split at {}", pattern, split);
+            }
+        }
+    }
+
+    #[test]
+    fn test_redaction_holds_back_only_partial_matches() {
+        let pattern = compile_pattern(r"\d{3}-\d{4}").unwrap();
+        let mut matcher = RedactingMatcher::new(vec![pattern], b'*').unwrap();
+        let mut out = Vec::new();
+        // Nothing in progress, so nothing is held back
+        matcher.process_chunk(b"call ", &mut out).unwrap();
+        assert_eq!((&out[..], matcher.held_bytes()), (&b"call "[..], 0));
+        matcher.process_chunk(b"555-12", &mut out).unwrap();
+        assert_eq!((&out[..], matcher.held_bytes()), (&b"call "[..], 6));
+        // A partial match fails on the byte after it, and its bytes go out, while only as
+        // many as the longest match are held back for the next one
+        matcher.process_chunk(b"x 555-123", &mut out).unwrap();
+        assert_eq!((&out[..], matcher.held_bytes()), (&b"call 555-12x"[..], 8));
+        matcher.process_chunk(b"4 now", &mut out).unwrap();
+        assert_eq!((&out[..], matcher.held_bytes()), (&b"call 555-12x ******** now"[..], 0));
+        matcher.finish(&mut out).unwrap();
+        assert_eq!(out.len(), 25);
+
+        let unbounded = compile_pattern("a+").unwrap();
+        assert!(matches!(
+            RedactingMatcher::new(vec![unbounded], b'*'),
+            Err(Error::InvalidPattern(_))
+        ));
+        // Nor for matches of variable length without a syntax tree to find their starts
+        let literals: [(&str, &[u8]); 2] = [("ab", b"ab"), ("xab", b"xab")];
+        let built = PatternBuilder::from_literals(&literals).unwrap();
+        assert!(matches!(RedactingMatcher::new(vec![built], b'*'), Err(Error::InvalidPattern(_))));
+    }
+}
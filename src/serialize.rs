@@ -0,0 +1,148 @@
+// Serde support for compiled patterns, behind the "serde" feature
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::error::Error;
+use crate::pattern::{Branches, Pattern, PatternKind, PruneStats, State};
+
+// Bumped whenever the serialized form of a Pattern changes
+const FORMAT_VERSION: u32 = 5;
+
+// A Pattern as it is serialized, with the format version in front
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SerializedPattern {
+    version: u32,
+    id: String,
+    states: Vec<State>,
+    initial_state: usize,
+    kind: PatternKind,
+    anchored: bool,
+    min_match_len: usize,
+    max_match_len: Option<usize>,
+    unminimized_state_count: Option<usize>,
+    prune_stats: PruneStats,
+    metadata: BTreeMap<String, String>,
+    // Only for nondeterministic patterns
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    branches: Option<Box<Branches>>,
+}
+
+impl From<Pattern> for SerializedPattern {
+    fn from(pattern: Pattern) -> Self {
+        SerializedPattern {
+            version: FORMAT_VERSION,
+            id: pattern.id,
+            states: pattern.states,
+            initial_state: pattern.initial_state,
+            kind: pattern.kind,
+            anchored: pattern.anchored,
+            min_match_len: pattern.min_match_len,
+            max_match_len: pattern.max_match_len,
+            unminimized_state_count: pattern.unminimized_state_count,
+            prune_stats: pattern.prune_stats,
+            metadata: pattern.metadata,
+            branches: pattern.branches,
+        }
+    }
+}
+
+impl TryFrom<SerializedPattern> for Pattern {
+    type Error = Error;
+
+    fn try_from(serialized: SerializedPattern) -> Result<Self, Error> {
+        if serialized.version != FORMAT_VERSION {
+            return Err(Error::Internal(format!(
+                "Pattern '{}' was serialized in format version {}, but this version of \
+                 StreamRegex reads version {}",
+                serialized.id, serialized.version, FORMAT_VERSION
+            )));
+        }
+        let mut pattern = Pattern {
+            id: serialized.id,
+            states: serialized.states,
+            initial_state: serialized.initial_state,
+            kind: serialized.kind,
+            anchored: serialized.anchored,
+            min_match_len: serialized.min_match_len,
+            max_match_len: serialized.max_match_len,
+            unminimized_state_count: serialized.unminimized_state_count,
+            prune_stats: serialized.prune_stats,
+            metadata: serialized.metadata,
+            number: None,
+            negative: false,
+            priority: 0,
+            dense: false,
+            class_of: [0; 256],
+            branches: serialized.branches,
+            syntax: None,
+        };
+        pattern.validate()?;
+        pattern.update_dense_tables();
+        Ok(pattern)
+    }
+}
+
+// Transitions in byte order, so the same pattern always serializes the same way
+pub(crate) fn sorted_transitions<S: Serializer>(
+    transitions: &HashMap<u8, usize>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    transitions
+        .iter()
+        .collect::<BTreeMap<_, _>>()
+        .serialize(serializer)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Pattern, PatternBuilder, compile_pattern};
+
+    #[test]
+    fn test_serde_round_trip() {
+        let literals: [(&str, &[u8]); 2] = [("get", b"GET"), ("post", b"POST")];
+        let mut builder = PatternBuilder::new();
+        let (a, ab) = (builder.add_state(true), builder.add_state(false));
+        builder.add_transition(0, b'a', a).unwrap().add_transition(0, b'a', ab).unwrap();
+        builder.add_transition(ab, b'b', a).unwrap().determinize(false);
+        let patterns = [
+            compile_pattern("(?i)a[0-9]+b$").unwrap(),
+            compile_pattern(r#"^"[^"]*""#).unwrap(),
+            PatternBuilder::from_literals(&literals).unwrap(),
+            compile_pattern("cve")
+                .unwrap()
+                .with_metadata("severity", "high"),
+            builder.build("nondeterministic".to_string()).unwrap(),
+        ];
+        for pattern in patterns {
+            let json = serde_json::to_string(&pattern).unwrap();
+            let loaded: Pattern = serde_json::from_str(&json).unwrap();
+            assert_eq!(loaded.to_dot(), pattern.to_dot());
+            assert_eq!(loaded.kind(), pattern.kind());
+            assert!(loaded.metadata_entries().eq(pattern.metadata_entries()));
+            assert_eq!(loaded.max_match_len(), pattern.max_match_len());
+            assert_eq!(loaded.is_nondeterministic(), pattern.is_nondeterministic());
+            // Transitions come out in the same order every time
+            assert_eq!(serde_json::to_string(&loaded).unwrap(), json);
+        }
+    }
+
+    #[test]
+    fn test_serde_rejects_other_versions() {
+        let json = serde_json::to_string(&compile_pattern("ab").unwrap()).unwrap();
+        assert!(json.starts_with(r#"{"version":5,"#), "{}", json);
+        let other = json.replacen(r#""version":5"#, r#""version":4"#, 1);
+        let error = serde_json::from_str::<Pattern>(&other)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("format version 4"), "{}", error);
+
+        // States that do not exist are caught on loading rather than while matching
+        let broken = json.replacen(r#""initial_state":0"#, r#""initial_state":7"#, 1);
+        let error = serde_json::from_str::<Pattern>(&broken)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("Initial state 7"), "{}", error);
+    }
+}
@@ -0,0 +1,463 @@
+// Snapshots of where a stream is, see StreamMatcher::save_state
+//
+// Layout, all integers little-endian:
+//   magic "SRXS", format version u32, pattern set hash u64, position u64, truncated
+//   u8, total match count u64, pattern count u32, then per pattern: state u32 (DEAD
+//   as u32::MAX), whether it is enabled u8, match count u64, and the count u32 and
+//   states u32 of its set, empty unless the pattern is nondeterministic; then per
+//   pattern again the matches u64 the rate limit dropped, the window u64 and matches
+//   u64 reported in it, and the match count u64 of its coalescing group, 0 without
+//   one, and for a group its end u64 and tag; then the count u32 of the matches held back for the priority window,
+//   each with the index u32 of its pattern, end u64, whether it is suppressed u8 and
+//   tag; then the count u32 and bytes of the ring of context bytes, and the count u32
+//   of the matches waiting for their context, each with its pattern number u32, end
+//   u64, whether it has a start u8, the start u64, whether it is absent u8, its count
+//   u64 and tag. A tag is whether there is one u8 and the tag u32.
+// The patterns are left out: the hash identifies them, see pattern_set_hash.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use crate::database::{DEAD, Group, Held, PatternDatabase, Rate, StreamState};
+use crate::error::Error;
+use crate::literals;
+use crate::matcher::Match;
+use crate::pattern::Pattern;
+
+const MAGIC: &[u8; 4] = b"SRXS";
+// Bumped whenever the layout changes
+const FORMAT_VERSION: u32 = 6;
+
+// A snapshot read back and checked against the patterns, see read
+#[derive(Debug)]
+pub(crate) struct Snapshot {
+    position: u64,
+    truncated: bool,
+    total: u64,
+    states: Vec<usize>,
+    // Whether each pattern was enabled, see StreamMatcher::set_enabled
+    enabled: Vec<bool>,
+    counts: Vec<u64>,
+    sets: Vec<Vec<usize>>,
+    groups: Vec<Option<Group>>,
+    rates: Vec<Rate>,
+    suppressed: Vec<u64>,
+    held: VecDeque<Held>,
+    ring: Vec<u8>,
+    pending: VecDeque<Match>,
+}
+
+impl Snapshot {
+    // Put `stream` where the snapshot was taken. The database is to run every pattern
+    // on its own, without the automatons of compile and of the literals. Patterns
+    // enabled or disabled in it since start over or stop, as after set_enabled
+    pub(crate) fn apply(mut self, database: &PatternDatabase, stream: &mut StreamState) {
+        let flags = self.enabled.iter().zip(&database.enabled);
+        for (index, (&was, &enabled)) in flags.enumerate() {
+            if was == enabled {
+                continue;
+            }
+            let pattern = &database.patterns[index];
+            let fresh = enabled && !(pattern.anchored && self.position > 0);
+            self.states[index] = if fresh { pattern.initial_state } else { DEAD };
+            if pattern.is_nondeterministic() {
+                self.sets[index] = vec![pattern.initial_state];
+            }
+        }
+        let progress = &mut stream.progress;
+        progress.states = self.states;
+        progress.sets = self.sets;
+        progress.combined = 0;
+        progress.node = literals::ROOT;
+        progress.idle = false;
+        stream.position = self.position;
+        stream.caps.by_number = database.numbers().zip(self.counts.iter().copied()).collect();
+        stream.caps.counts = self.counts;
+        stream.caps.total = self.total;
+        stream.caps.truncated = self.truncated;
+        stream.caps.groups = self.groups;
+        stream.caps.rates = self.rates;
+        stream.caps.suppressed = self.suppressed;
+        stream.caps.held = self.held;
+        // Into the ring allocated for the stream
+        stream.caps.context.ring.clear();
+        stream.caps.context.ring.extend(self.ring);
+        stream.caps.context.pending = self.pending;
+        // The next flush finds the earliest
+        stream.caps.deadline = 0;
+    }
+}
+
+// The snapshot of `stream`, scanned with `database`
+pub(crate) fn save(database: &PatternDatabase, stream: &StreamState) -> Vec<u8> {
+    let patterns = &database.patterns;
+    let progress = &stream.progress;
+    // The state of every pattern on its own, as the automatons of compile and of the
+    // literals hand them back when dropped
+    let mut states = progress.states.clone();
+    if let Some(combined) = &database.combined {
+        combined.write_states(progress.combined, patterns, &mut states);
+    } else if let Some(set) = &database.literals {
+        let bytes = set.bytes(progress.node);
+        for ((pattern, state), &routed) in patterns.iter().zip(&mut states).zip(&database.routed) {
+            if routed {
+                *state = literals::run(pattern, pattern.initial_state, &bytes);
+            }
+        }
+    }
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&pattern_set_hash(patterns).to_le_bytes());
+    bytes.extend_from_slice(&stream.position.to_le_bytes());
+    bytes.push(stream.caps.truncated as u8);
+    bytes.extend_from_slice(&stream.caps.total.to_le_bytes());
+    bytes.extend_from_slice(&(patterns.len() as u32).to_le_bytes());
+    let running = states.iter().zip(&database.enabled).zip(&stream.caps.counts);
+    for (((&state, &enabled), &count), set) in running.zip(&progress.sets) {
+        bytes.extend_from_slice(&(state as u32).to_le_bytes());
+        bytes.push(enabled as u8);
+        bytes.extend_from_slice(&count.to_le_bytes());
+        bytes.extend_from_slice(&(set.len() as u32).to_le_bytes());
+        for &state in set {
+            bytes.extend_from_slice(&(state as u32).to_le_bytes());
+        }
+    }
+    let caps = &stream.caps;
+    for ((group, rate), &suppressed) in caps.groups.iter().zip(&caps.rates).zip(&caps.suppressed) {
+        bytes.extend_from_slice(&suppressed.to_le_bytes());
+        bytes.extend_from_slice(&rate.window.to_le_bytes());
+        bytes.extend_from_slice(&rate.reported.to_le_bytes());
+        let Some(group) = group else {
+            bytes.extend_from_slice(&0u64.to_le_bytes());
+            continue;
+        };
+        bytes.extend_from_slice(&group.count.to_le_bytes());
+        bytes.extend_from_slice(&group.end.to_le_bytes());
+        write_tag(&mut bytes, group.tag);
+    }
+    bytes.extend_from_slice(&(caps.held.len() as u32).to_le_bytes());
+    for held in &caps.held {
+        bytes.extend_from_slice(&(held.index as u32).to_le_bytes());
+        bytes.extend_from_slice(&held.end.to_le_bytes());
+        bytes.push(held.suppressed as u8);
+        write_tag(&mut bytes, held.tag);
+    }
+    let context = &caps.context;
+    bytes.extend_from_slice(&(context.ring.len() as u32).to_le_bytes());
+    bytes.extend(&context.ring);
+    bytes.extend_from_slice(&(context.pending.len() as u32).to_le_bytes());
+    for found in &context.pending {
+        bytes.extend_from_slice(&found.pattern.to_le_bytes());
+        bytes.extend_from_slice(&found.end.to_le_bytes());
+        bytes.push(found.start.is_some() as u8);
+        bytes.extend_from_slice(&found.start.unwrap_or_default().to_le_bytes());
+        bytes.push(found.absent as u8);
+        bytes.extend_from_slice(&found.count.to_le_bytes());
+        write_tag(&mut bytes, found.tag);
+    }
+    bytes
+}
+
+fn write_tag(bytes: &mut Vec<u8>, tag: Option<u32>) {
+    bytes.push(tag.is_some() as u8);
+    bytes.extend_from_slice(&tag.unwrap_or_default().to_le_bytes());
+}
+
+// Read a snapshot written by save, for a stream scanned for `patterns`
+// Fails with Error::PatternSetMismatch when it was taken with other patterns, and with
+// Error::Internal for data that is not a valid snapshot.
+pub(crate) fn read(patterns: &[Pattern], bytes: &[u8]) -> Result<Snapshot, Error> {
+    let mut reader = Reader { bytes };
+    if reader.take(4)? != MAGIC {
+        return Err(Error::Internal("Not a StreamRegex stream snapshot".into()));
+    }
+    let version = reader.u32()?;
+    if version != FORMAT_VERSION {
+        return Err(Error::Internal(format!(
+            "Stream snapshot has format version {}, but this version of StreamRegex reads \
+             version {}",
+            version, FORMAT_VERSION
+        )));
+    }
+    let (found, expected) = (reader.u64()?, pattern_set_hash(patterns));
+    if found != expected {
+        return Err(Error::PatternSetMismatch { expected, found });
+    }
+    let mut snapshot = Snapshot {
+        position: reader.u64()?,
+        truncated: reader.take(1)?[0] != 0,
+        total: reader.u64()?,
+        states: Vec::with_capacity(patterns.len()),
+        enabled: Vec::with_capacity(patterns.len()),
+        counts: Vec::with_capacity(patterns.len()),
+        sets: Vec::with_capacity(patterns.len()),
+        groups: Vec::with_capacity(patterns.len()),
+        rates: Vec::with_capacity(patterns.len()),
+        suppressed: Vec::with_capacity(patterns.len()),
+        held: VecDeque::new(),
+        ring: Vec::new(),
+        pending: VecDeque::new(),
+    };
+    if reader.u32()? as usize != patterns.len() {
+        return Err(Error::Internal("Stream snapshot has the wrong pattern count".into()));
+    }
+    let state = |pattern: &Pattern, state: u32| match state {
+        u32::MAX => Ok(DEAD),
+        state if (state as usize) < pattern.states.len() => Ok(state as usize),
+        state => Err(Error::Internal(format!(
+            "Stream snapshot has state {} for pattern '{}' of {} states",
+            state,
+            pattern.id,
+            pattern.states.len()
+        ))),
+    };
+    for pattern in patterns {
+        snapshot.states.push(state(pattern, reader.u32()?)?);
+        snapshot.enabled.push(reader.take(1)?[0] != 0);
+        snapshot.counts.push(reader.u64()?);
+        let count = reader.u32()? as usize;
+        if (count > 0 && !pattern.is_nondeterministic()) || count > pattern.states.len() {
+            return Err(Error::Internal(format!(
+                "Stream snapshot has a set of {} states for pattern '{}'",
+                count, pattern.id
+            )));
+        }
+        let set = (0..count).map(|_| state(pattern, reader.u32()?)).collect::<Result<_, _>>()?;
+        snapshot.sets.push(set);
+    }
+    for _ in patterns {
+        snapshot.suppressed.push(reader.u64()?);
+        snapshot.rates.push(Rate { window: reader.u64()?, reported: reader.u64()? });
+        let group = match reader.u64()? {
+            0 => None,
+            count => {
+                let end = reader.u64()?;
+                Some(Group { tag: reader.tag()?, end, count })
+            }
+        };
+        snapshot.groups.push(group);
+    }
+    for _ in 0..reader.u32()? {
+        let index = reader.u32()? as usize;
+        if index >= patterns.len() {
+            return Err(Error::Internal(format!(
+                "Stream snapshot holds a match of pattern {} of {}",
+                index,
+                patterns.len()
+            )));
+        }
+        let (end, suppressed) = (reader.u64()?, reader.take(1)?[0] != 0);
+        snapshot.held.push_back(Held { index, tag: reader.tag()?, end, suppressed });
+    }
+    let count = reader.u32()? as usize;
+    snapshot.ring = reader.take(count)?.to_vec();
+    for _ in 0..reader.u32()? {
+        let (pattern, end) = (reader.u32()?, reader.u64()?);
+        let started = reader.take(1)?[0] != 0;
+        let start = Some(reader.u64()?).filter(|_| started);
+        let (absent, count) = (reader.take(1)?[0] != 0, reader.u64()?);
+        let tag = reader.tag()?;
+        snapshot.pending.push_back(Match { pattern, tag, end, start, absent, count });
+    }
+    if !reader.bytes.is_empty() {
+        return Err(Error::Internal("Stream snapshot has bytes after its end".into()));
+    }
+    Ok(snapshot)
+}
+
+// Identifies a set of patterns across processes: FNV-1a over their binary format (see
+// Pattern::serialize_all_into), which covers ids, metadata and every state
+fn pattern_set_hash(patterns: &[Pattern]) -> u64 {
+    let mut hasher = Fnv(0xcbf2_9ce4_8422_2325);
+    // Patterns too large for the binary format still hash the same up to where it stops
+    let _ = Pattern::serialize_all_into(patterns, &mut hasher);
+    hasher.0
+}
+
+struct Fnv(u64);
+
+impl Write for Fnv {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, count: usize) -> Result<&'b [u8], Error> {
+        if self.bytes.len() < count {
+            return Err(Error::Internal("Stream snapshot ends unexpectedly".into()));
+        }
+        let (taken, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap_or_default()))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap_or_default()))
+    }
+
+    fn tag(&mut self) -> Result<Option<u32>, Error> {
+        let tagged = self.take(1)?[0] != 0;
+        Ok(Some(self.u32()?).filter(|_| tagged))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Error, Match, PatternBuilder, StreamMatcher, compile_pattern};
+
+    fn matcher(compiled: bool) -> StreamMatcher<'static> {
+        let literals: [(&str, &[u8]); 3] = [("he", b"he"), ("she", b"she"), ("hers", b"hers")];
+        let mut matcher = StreamMatcher::new();
+        for pattern in ["a[0-9]+b$", "cve", r"foo\b", "x.*y", "^GET"] {
+            matcher.add_pattern(compile_pattern(pattern).unwrap());
+        }
+        matcher.add_pattern(PatternBuilder::from_literals(&literals).unwrap());
+        if compiled {
+            matcher.compile().unwrap();
+        } else {
+            // Kept nondeterministic, so it rules out compile
+            let mut builder = PatternBuilder::new();
+            let (a, aa) = (builder.add_state(true), builder.add_state(false));
+            builder.add_transition(0, b'e', a).unwrap().add_transition(0, b'e', aa).unwrap();
+            builder.add_transition(aa, b'f', a).unwrap();
+            builder.determinize(false);
+            matcher.add_pattern(builder.build("ef?".to_string()).unwrap());
+        }
+        matcher
+    }
+
+    #[test]
+    fn test_save_and_restore() {
+        let mut seed = 5u32;
+        let mut input: Vec<u8> = (0..3000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                b"a0b\ncvefoxyshr "[(seed >> 16) as usize % 15]
+            })
+            .collect();
+        input.splice(0..0, b"GET cve x".iter().copied());
+        input.extend_from_slice(b"a12b");
+
+        for compiled in [false, true] {
+            let mut expected = Vec::new();
+            let mut uninterrupted = matcher(compiled);
+            uninterrupted.process_chunk_into(&input, &mut expected);
+            uninterrupted.finish_into(&mut expected);
+
+            // The stream moves to a new matcher partway, even in the middle of a match
+            for split in [1, 3, 6, 1000, 2177, 3011] {
+                let mut first = matcher(compiled);
+                let mut matches = first.process_chunk_collect(&input[..split]);
+                let snapshot = first.save_state();
+                let mut second = matcher(compiled);
+                second.restore_state(&snapshot).unwrap();
+                assert_eq!(second.bytes_processed(), split as u64);
+                second.process_chunk_into(&input[split..], &mut matches);
+                second.finish_into(&mut matches);
+                assert_eq!(matches, expected, "split at {}", split);
+            }
+        }
+
+        // The caps carry over too
+        let config = crate::MatcherConfig::new().max_matches_total(1);
+        let mut first = StreamMatcher::with_config(config);
+        first.add_pattern(compile_pattern("ab").unwrap());
+        assert_eq!(first.process_chunk_collect(b"abab").len(), 1);
+        let mut second = StreamMatcher::with_config(config);
+        second.add_pattern(compile_pattern("ab").unwrap());
+        second.restore_state(&first.save_state()).unwrap();
+        assert!(second.truncated());
+        assert_eq!(second.process_chunk_collect(b"ab"), Vec::<Match>::new());
+    }
+
+    #[test]
+    fn test_restore_with_other_patterns_enabled() {
+        let build = |disabled: Option<&str>, compiled: bool| {
+            let mut matcher = StreamMatcher::new();
+            matcher.add_pattern(compile_pattern("abc").unwrap());
+            matcher.add_pattern(compile_pattern("abc$").unwrap());
+            if compiled {
+                matcher.compile().unwrap();
+            }
+            if let Some(id) = disabled {
+                matcher.set_enabled(id, false);
+            }
+            matcher
+        };
+        let ends = |matches: Vec<Match>| -> Vec<(u32, u64)> {
+            matches.iter().map(|found| (found.pattern, found.end)).collect()
+        };
+        for compiled in [false, true] {
+            // Disabled when saved, the pattern starts over where it is enabled
+            let mut first = build(Some("abc"), compiled);
+            first.process_chunk(b"xy");
+            let mut second = build(None, compiled);
+            second.restore_state(&first.save_state()).unwrap();
+            let matches = second.process_chunk_collect(b"abc abc");
+            assert_eq!(ends(matches), [(0, 5), (0, 9)], "compiled: {}", compiled);
+
+            // and partway through a match when saved, it stops where it is disabled
+            let mut first = build(None, compiled);
+            first.process_chunk(b"ab");
+            let mut second = build(Some("abc$"), compiled);
+            second.restore_state(&first.save_state()).unwrap();
+            let mut matches = second.process_chunk_collect(b"c");
+            second.finish_into(&mut matches);
+            assert_eq!(ends(matches), [(0, 3)], "compiled: {}", compiled);
+        }
+    }
+
+    #[test]
+    fn test_restore_rejects_other_patterns() {
+        let mut original = matcher(false);
+        original.process_chunk(b"GET a1");
+        let snapshot = original.save_state();
+
+        let mut other = StreamMatcher::new();
+        other.add_pattern(compile_pattern("cve").unwrap());
+        let restored = other.restore_state(&snapshot);
+        assert!(matches!(restored, Err(Error::PatternSetMismatch { .. })));
+        // Renaming a pattern or changing its metadata makes another set, too
+        let mut renamed = StreamMatcher::new();
+        for pattern in original.patterns() {
+            renamed.add_pattern(pattern.clone().with_metadata("rule", "1"));
+        }
+        let restored = renamed.restore_state(&snapshot);
+        assert!(matches!(restored, Err(Error::PatternSetMismatch { .. })));
+
+        // Damaged snapshots fail cleanly and leave the stream as it was
+        let mut fresh = matcher(false);
+        fresh.process_chunk(b"a1");
+        for length in 0..snapshot.len() {
+            let restored = fresh.restore_state(&snapshot[..length]);
+            assert!(matches!(restored, Err(Error::Internal(_))), "truncated to {}", length);
+        }
+        let mut out_of_bounds = snapshot.clone();
+        out_of_bounds[37..41].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(matches!(fresh.restore_state(&out_of_bounds), Err(Error::Internal(_))));
+        assert_eq!(fresh.bytes_processed(), 2);
+        assert_eq!(fresh.process_chunk_collect(b"b").len(), 0);
+        fresh.finish();
+        fresh.restore_state(&snapshot).unwrap();
+        let mut matches = fresh.process_chunk_collect(b"b");
+        fresh.finish_into(&mut matches);
+        assert_eq!(matches.iter().map(|found| found.end).collect::<Vec<_>>(), [7]);
+    }
+}
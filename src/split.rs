@@ -0,0 +1,198 @@
+// Splitting a stream into records at the matches of a delimiter pattern
+//
+// The bytes after the last delimiter are kept until the next one completes, however
+// many chunks later, so a delimiter split across chunks is found like any other match.
+
+use std::ops::ControlFlow;
+
+use crate::Result;
+use crate::database::{PatternDatabase, StreamState};
+use crate::error::Error;
+use crate::matcher::{Match, MatcherConfig};
+use crate::pattern::Pattern;
+use crate::starts::MatchStarts;
+
+// The bytes between two delimiters, or between one and the start or end of the stream
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Record {
+    pub bytes: Vec<u8>,
+    // Offset of the first byte in the stream
+    pub start: u64,
+}
+
+// Splits a stream into the records between the matches of a delimiter, such as
+// "\r\n--boundary\r\n" in a multipart body
+pub struct StreamSplitter {
+    database: PatternDatabase,
+    stream: StreamState,
+    // For a delimiter of variable length, where its matches start
+    starts: Option<MatchStarts>,
+    // The bytes of the record in progress, from offset `record_from` of the stream,
+    // and the one before them, None at its start
+    record: Vec<u8>,
+    record_from: u64,
+    before: Option<u8>,
+    found: Vec<Match>,
+}
+
+impl StreamSplitter {
+    // A splitter cutting at the matches of `delimiter`, which are left out of the
+    // records. Fails for a delimiter with unbounded matches (see
+    // Pattern::max_match_len), and for one of variable length that was not compiled
+    // from a regular expression, as there is no telling where those start
+    pub fn new(delimiter: Pattern) -> Result<Self> {
+        let Some(max) = delimiter.max_match_len() else {
+            return Err(Error::InvalidPattern(format!(
+                "Delimiter '{}' has matches of unbounded length, which cannot be split on",
+                delimiter.id()
+            )));
+        };
+        let fixed = delimiter.min_match_len() == max;
+        let starts = if fixed { None } else { Some(MatchStarts::new(&delimiter)?) };
+        let database = PatternDatabase::new(vec![delimiter], MatcherConfig::default());
+        Ok(StreamSplitter {
+            stream: StreamState::new(&database),
+            database,
+            starts,
+            record: Vec::new(),
+            record_from: 0,
+            before: None,
+            found: Vec::new(),
+        })
+    }
+
+    // Feed the next chunk of the stream, returning the records its delimiters complete
+    pub fn push(&mut self, chunk: &[u8]) -> impl Iterator<Item = Record> + use<> {
+        self.record.extend_from_slice(chunk);
+        let found = &mut self.found;
+        self.database.scan(&mut self.stream, chunk, |event| {
+            found.push(event.to_match());
+            ControlFlow::Continue(())
+        });
+        self.split(false).into_iter()
+    }
+
+    // End the stream, returning the records cut by delimiters that can only match at
+    // its end and then the rest of it, unless that is empty as the stream ended with a
+    // delimiter. The next chunk starts a new stream
+    pub fn finish(&mut self) -> impl Iterator<Item = Record> + use<> {
+        let found = &mut self.found;
+        self.database.finish(&mut self.stream, |event| {
+            found.push(event.to_match());
+            ControlFlow::Continue(())
+        });
+        let mut records = self.split(true);
+        let rest = Record { bytes: std::mem::take(&mut self.record), start: self.record_from };
+        if !rest.bytes.is_empty() {
+            records.push(rest);
+        }
+        (self.record_from, self.before) = (0, None);
+        records.into_iter()
+    }
+
+    // Bytes of the record in progress, kept until a delimiter ends it
+    pub fn pending_bytes(&self) -> usize {
+        self.record.len()
+    }
+
+    // Cut the record in progress at the delimiters found, `at_end` of the stream for
+    // those finish reports
+    fn split(&mut self, at_end: bool) -> Vec<Record> {
+        let (mut records, mut cut) = (Vec::new(), 0);
+        for found in self.found.drain(..) {
+            let end = (found.end - self.record_from) as usize;
+            let start = match (found.start, &mut self.starts) {
+                (Some(start), _) => start.checked_sub(self.record_from).map(|start| start as usize),
+                (None, Some(starts)) => {
+                    starts.farthest(self.before, &self.record, cut, end, at_end)
+                }
+                (None, None) => None,
+            };
+            // A delimiter overlapping the one before it does not end a record
+            let Some(start) = start.filter(|&start| start >= cut) else {
+                continue;
+            };
+            let bytes = self.record[cut..start].to_vec();
+            records.push(Record { bytes, start: self.record_from + cut as u64 });
+            cut = end;
+        }
+        if cut > 0 {
+            self.before = Some(self.record[cut - 1]);
+        }
+        self.record.drain(..cut);
+        self.record_from += cut as u64;
+        records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile_pattern;
+
+    fn split(delimiter: &str, chunks: &[&[u8]]) -> Vec<(String, u64)> {
+        let mut splitter = StreamSplitter::new(compile_pattern(delimiter).unwrap()).unwrap();
+        let mut records: Vec<Record> = Vec::new();
+        for chunk in chunks {
+            records.extend(splitter.push(chunk));
+        }
+        records.extend(splitter.finish());
+        let text = |record: Record| (String::from_utf8(record.bytes).unwrap(), record.start);
+        records.into_iter().map(text).collect()
+    }
+
+    #[test]
+    fn test_split() {
+        let input = b"head\r\n--b\r\nfirst part\r\n--b\r\n\r\n--b\r\nlast";
+        let expected = [("head", 0), ("first part", 11), ("", 28), ("last", 35)];
+        let expected: Vec<(String, u64)> = expected.map(|(s, at)| (s.to_string(), at)).into();
+        // Every split, so delimiters straddle chunk boundaries
+        for at in 0..=input.len() {
+            let (first, second) = input.split_at(at);
+            assert_eq!(split(r"\r\n--b\r\n", &[first, second]), expected, "split at {}", at);
+        }
+        let bytes: Vec<&[u8]> = input.chunks(1).collect();
+        assert_eq!(split(r"\r\n--b\r\n", &bytes), expected);
+
+        // Delimiters of variable length are cut from their farthest start, one
+        // overlapping the one before is part of no record, and a delimiter ending the
+        // stream leaves no empty record after it
+        let records = split(r"\r?\n", &[b"a\r", b"\nb\nc\r\n"]);
+        assert_eq!(records, [("a".to_string(), 0), ("b".to_string(), 3), ("c".to_string(), 5)]);
+        assert_eq!(split("aa", &[b"xaaay"]), [("x".to_string(), 0), ("ay".to_string(), 3)]);
+        assert_eq!(split("x$", &[b"axbx"]), [("axb".to_string(), 0)]);
+
+        // The bytes before a delimiter can be ones it matches too, without being part
+        // of it
+        let cases = [
+            ("ab{1,3}", &b"r1aaabr2"[..], [("r1aa", 0), ("r2", 6)]),
+            (";|--", b"one-;two", [("one-", 0), ("two", 5)]),
+            (r"\r?\n", b"a\r\r\nb", [("a\r", 0), ("b", 4)]),
+        ];
+        for (delimiter, input, expected) in cases {
+            let expected: Vec<(String, u64)> = expected.map(|(s, at)| (s.to_string(), at)).into();
+            for at in 0..=input.len() {
+                let (first, second) = input.split_at(at);
+                assert_eq!(split(delimiter, &[first, second]), expected, "{} at {}", delimiter, at);
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_pending() {
+        let mut splitter = StreamSplitter::new(compile_pattern(";;").unwrap()).unwrap();
+        assert_eq!(splitter.push(b"ab;").count(), 0);
+        assert_eq!(splitter.pending_bytes(), 3);
+        let records: Vec<Record> = splitter.push(b";cd").collect();
+        assert_eq!(records, [Record { bytes: b"ab".to_vec(), start: 0 }]);
+        assert_eq!(splitter.pending_bytes(), 2);
+        assert_eq!(splitter.finish().next().map(|record| record.start), Some(4));
+        assert_eq!(splitter.finish().count(), 0);
+        // The next stream starts from offset 0 again
+        let records: Vec<Record> = splitter.push(b"e;;").collect();
+        assert_eq!(records, [Record { bytes: b"e".to_vec(), start: 0 }]);
+
+        let unbounded = compile_pattern("\n+").unwrap();
+        assert!(matches!(StreamSplitter::new(unbounded), Err(Error::InvalidPattern(_))));
+    }
+}
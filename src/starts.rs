@@ -0,0 +1,95 @@
+// Finding where a match starts from where it ends, for patterns whose matches vary in
+// length and so are reported without a start (see MatchEvent::start)
+//
+// The automaton of a pattern starts an attempt at every byte and keeps no record of
+// which one completed, so each offset the match may start at is tried in turn with
+// the pattern rebuilt to match from its first byte only (see Pattern::anchored_rebuild).
+
+use std::ops::ControlFlow;
+
+use crate::Result;
+use crate::database::{PatternDatabase, StreamState};
+use crate::error::Error;
+use crate::matcher::{MatchEvent, MatcherConfig};
+use crate::pattern::Pattern;
+
+pub(crate) struct MatchStarts {
+    // The pattern anchored at the start of the stream, and after one byte of context
+    at_start: (PatternDatabase, StreamState),
+    after_byte: (PatternDatabase, StreamState),
+    shortest: usize,
+    longest: usize,
+}
+
+impl MatchStarts {
+    // Fails for patterns without a syntax tree, see Pattern::anchored_rebuild
+    pub(crate) fn new(pattern: &Pattern) -> Result<Self> {
+        let rebuild = |after_byte| {
+            let rebuilt = pattern.anchored_rebuild(after_byte).ok_or_else(|| {
+                Error::InvalidPattern(format!(
+                    "Pattern '{}' was not compiled from a regular expression, so where its \
+                     matches start cannot be found",
+                    pattern.id()
+                ))
+            })??;
+            let database = PatternDatabase::new(vec![rebuilt], MatcherConfig::default());
+            let stream = StreamState::new(&database);
+            Ok::<_, Error>((database, stream))
+        };
+        Ok(MatchStarts {
+            at_start: rebuild(false)?,
+            after_byte: rebuild(true)?,
+            shortest: pattern.min_match_len().max(1),
+            longest: pattern.max_match_len().unwrap_or(usize::MAX),
+        })
+    }
+
+    // The farthest offset in `bytes`, from `from` on, where a match of the pattern
+    // ending at offset `end` starts, or None if none does. `before` is the byte before
+    // `bytes`, None at the start of the stream, and with `at_end` the stream ends
+    // after them, for the matches at its end ('$')
+    pub(crate) fn farthest(
+        &mut self,
+        before: Option<u8>,
+        bytes: &[u8],
+        from: usize,
+        end: usize,
+        at_end: bool,
+    ) -> Option<usize> {
+        let last = end.checked_sub(self.shortest)?;
+        let first = end.saturating_sub(self.longest).max(from);
+        (first..=last).find(|&start| {
+            let before = match start {
+                0 => before,
+                start => Some(bytes[start - 1]),
+            };
+            self.matches(before, &bytes[start..], end - start, at_end)
+        })
+    }
+
+    // Whether the match from the start of `bytes` ends at `end`. The byte after the
+    // end is scanned as well, as it confirms matches such as "a\b"
+    fn matches(&mut self, before: Option<u8>, bytes: &[u8], end: usize, at_end: bool) -> bool {
+        let (database, stream) = match before {
+            Some(_) => &mut self.after_byte,
+            None => &mut self.at_start,
+        };
+        database.reset(stream);
+        let until = bytes.len().min(end + 1);
+        // The byte of context comes first in the stream
+        let end = (end + usize::from(before.is_some())) as u64;
+        let mut found = false;
+        let mut check = |event: &MatchEvent| {
+            found |= event.end == end;
+            ControlFlow::Continue(())
+        };
+        if let Some(byte) = before {
+            database.scan(stream, &[byte], &mut check);
+        }
+        database.scan(stream, &bytes[..until], &mut check);
+        if at_end && until == bytes.len() {
+            database.finish(stream, &mut check);
+        }
+        found
+    }
+}
@@ -0,0 +1,143 @@
+// Alternative frontend that parses patterns with the regex-syntax crate
+
+use regex_syntax::ParserBuilder;
+use regex_syntax::hir::{Class, Hir, HirKind, Look};
+
+use crate::byteset::ByteSet;
+use crate::error::Error;
+use crate::parser::{self, Assertion, Node};
+use crate::pattern::{DEFAULT_STATE_LIMIT, Pattern, compile_node};
+
+// Compile a pattern written in the syntax of the regex crate
+// Parsing follows regex::bytes::Regex, so Unicode classes are the default and (?-u)
+// allows raw bytes. Only the subset the streaming automaton supports is lowered:
+// literals, classes, concatenation, alternation, repetition, groups, the text and
+// CRLF line anchors, and ASCII word boundaries. Anything else fails with
+// Error::PatternTooComplex naming the construct. Matches are reported like those of
+// compile_pattern, at their earliest end.
+pub fn compile_regex(pattern: &str) -> Result<Pattern, Error> {
+    let hir = ParserBuilder::new()
+        .utf8(false)
+        .build()
+        .parse(pattern)
+        .map_err(|error| syntax_error(&error, pattern))?;
+    let node = lower(&hir)?;
+    compile_node(&node, pattern.to_string(), DEFAULT_STATE_LIMIT)
+}
+
+fn syntax_error(error: &regex_syntax::Error, pattern: &str) -> Error {
+    let (offset, message) = match error {
+        regex_syntax::Error::Parse(error) => (error.span().start.offset, error.kind().to_string()),
+        regex_syntax::Error::Translate(error) => {
+            (error.span().start.offset, error.kind().to_string())
+        }
+        other => return Error::InvalidPattern(other.to_string()),
+    };
+    Error::syntax(offset, message).with_pattern_id(pattern)
+}
+
+fn lower(hir: &Hir) -> Result<Node, Error> {
+    Ok(match hir.kind() {
+        HirKind::Empty => Node::Concat(Vec::new()),
+        HirKind::Literal(literal) => {
+            Node::Concat(literal.0.iter().map(|&byte| Node::Byte(byte)).collect())
+        }
+        HirKind::Class(Class::Bytes(class)) => {
+            let mut set = ByteSet::new();
+            for range in class.ranges() {
+                set.insert_range(range.start(), range.end());
+            }
+            Node::Class(set)
+        }
+        HirKind::Class(Class::Unicode(class)) => {
+            let ranges: Vec<(u32, u32)> = class
+                .ranges()
+                .iter()
+                .map(|range| (range.start() as u32, range.end() as u32))
+                .collect();
+            parser::codepoint_class(&ranges)
+        }
+        HirKind::Look(look) => Node::Assert(match look {
+            Look::Start => Assertion::StartText,
+            Look::End => Assertion::EndText,
+            Look::StartCRLF => Assertion::StartLine,
+            Look::EndCRLF => Assertion::EndLine,
+            Look::WordAscii => Assertion::WordBoundary,
+            Look::WordAsciiNegate => Assertion::NotWordBoundary,
+            other => {
+                return Err(Error::PatternTooComplex(format!(
+                    "Unsupported look-around assertion {:?}",
+                    other
+                )));
+            }
+        }),
+        // Lazy repetition reports the same earliest ends as greedy repetition
+        HirKind::Repetition(repetition) => Node::Repeat {
+            node: Box::new(lower(&repetition.sub)?),
+            min: repetition.min as usize,
+            max: repetition.max.map(|max| max as usize),
+        },
+        HirKind::Capture(capture) => lower(&capture.sub)?,
+        HirKind::Concat(hirs) => Node::Concat(hirs.iter().map(lower).collect::<Result<_, _>>()?),
+        HirKind::Alternation(hirs) => {
+            Node::Alternate(hirs.iter().map(lower).collect::<Result<_, _>>()?)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StreamMatcher;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn count_matches(pattern: &str, input: &[u8]) -> usize {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let mut matcher = StreamMatcher::new();
+        matcher.add_callback(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        matcher.add_pattern(compile_regex(pattern).unwrap());
+        for chunk in input.chunks(3) {
+            matcher.process_chunk(chunk);
+        }
+        matcher.finish();
+        count.load(Ordering::Relaxed)
+    }
+
+    #[test]
+    fn test_compile_regex() {
+        assert_eq!(count_matches("foo(bar|baz)+", b"foobar foobaz foo"), 2);
+        assert_eq!(count_matches(r"(?:\d{3}[-. ]){2}\d{4}", b"555-123-4567"), 1);
+        assert_eq!(count_matches("(?i)select", b"SELECT Select"), 2);
+        assert_eq!(count_matches("^ab$", b"ab"), 1);
+        assert_eq!(count_matches("^ab$", b"abc"), 0);
+        assert_eq!(count_matches(r"(?-u)\bcat\b", b"cat concat cats"), 1);
+        assert_eq!(count_matches("(?Rm)^x$", b"x\r\nx\r\nxx"), 2);
+    }
+
+    #[test]
+    fn test_compile_regex_unicode_classes() {
+        assert_eq!(count_matches(r"<\w+>", "<日本語> <abc> <->".as_bytes()), 2);
+        assert_eq!(count_matches(r"<\p{Greek}+>", "<αβγ> <abc>".as_bytes()), 1);
+        assert_eq!(count_matches("a.c", "aéc a\nc".as_bytes()), 1);
+        assert_eq!(count_matches(r"(?-u:\xff)", b"\x00\xff"), 1);
+    }
+
+    #[test]
+    fn test_compile_regex_errors() {
+        assert_eq!(compile_regex("ab(c").unwrap_err().offset(), Some(2));
+        assert!(matches!(compile_regex(r"(a)\1"), Err(Error::Syntax(_))));
+
+        let Err(Error::PatternTooComplex(message)) = compile_regex(r"\bcat\b") else {
+            panic!("expected an error");
+        };
+        assert!(message.contains("WordUnicode"), "{}", message);
+        assert!(matches!(
+            compile_regex("(?m)^x"),
+            Err(Error::PatternTooComplex(_))
+        ));
+    }
+}
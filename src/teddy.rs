@@ -0,0 +1,247 @@
+// Teddy, the prefilter Hyperscan uses for a few literals, see StreamMatcher::prefilter
+//
+// Literal i goes to bucket i % 8, and a window of the first bytes of the literals is
+// compared against all buckets at once: for byte k of the window, lo[k][n] has the
+// bits of the buckets with a literal whose byte k has the low nibble n, and hi[k] the
+// same for high nibbles. A literal may start where some bucket keeps its bit through
+// every byte of the window. With the simd feature, byte shuffles look up the nibbles
+// of 32 (AVX2) or 16 (SSSE3) positions at a time, as the CPU allows. Literals sharing
+// a bucket let through windows that start none of them, which the matcher then steps
+// through as without a prefilter.
+
+// Literals beyond this fill the buckets until most windows get through
+pub(crate) const MAX_LITERALS: usize = 64;
+const MAX_WINDOW: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    Scalar,
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    Ssse3,
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    Avx2,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Teddy {
+    lo: [[u8; 16]; MAX_WINDOW],
+    hi: [[u8; 16]; MAX_WINDOW],
+    // The buckets of each byte at each position of the window, lo and hi combined
+    buckets: [[u8; 256]; MAX_WINDOW],
+    // Bytes of each literal compared, as many as the shortest one has up to MAX_WINDOW
+    window: usize,
+    backend: Backend,
+}
+
+impl Teddy {
+    // The prefilter for non-empty `literals`
+    pub(crate) fn new(literals: &[&[u8]]) -> Self {
+        let shortest = literals.iter().map(|literal| literal.len()).min().unwrap_or(1);
+        let mut teddy = Teddy {
+            lo: [[0; 16]; MAX_WINDOW],
+            hi: [[0; 16]; MAX_WINDOW],
+            buckets: [[0; 256]; MAX_WINDOW],
+            window: shortest.clamp(1, MAX_WINDOW),
+            backend: detect(),
+        };
+        for (index, literal) in literals.iter().enumerate() {
+            let bucket = 1 << (index % 8);
+            for (k, &byte) in literal[..teddy.window].iter().enumerate() {
+                teddy.lo[k][(byte & 0x0f) as usize] |= bucket;
+                teddy.hi[k][(byte >> 4) as usize] |= bucket;
+            }
+        }
+        for k in 0..teddy.window {
+            for byte in 0..=255u8 {
+                let (low, high) = ((byte & 0x0f) as usize, (byte >> 4) as usize);
+                teddy.buckets[k][byte as usize] = teddy.lo[k][low] & teddy.hi[k][high];
+            }
+        }
+        teddy
+    }
+
+    // The instructions the search runs on
+    pub(crate) fn backend(&self) -> &'static str {
+        match self.backend {
+            Backend::Scalar => "scalar",
+            #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+            Backend::Ssse3 => "ssse3",
+            #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+            Backend::Avx2 => "avx2",
+        }
+    }
+
+    // The first position in `data` where a literal may start. The last positions,
+    // whose window runs past the end of `data`, may all start one.
+    pub(crate) fn find(&self, data: &[u8]) -> usize {
+        match self.backend {
+            Backend::Scalar => self.find_scalar(data, 0),
+            // Only detected where the CPU has the instructions
+            #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+            Backend::Ssse3 => unsafe { self.find_ssse3(data) },
+            #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+            Backend::Avx2 => unsafe { self.find_avx2(data) },
+        }
+    }
+
+    fn find_scalar(&self, data: &[u8], from: usize) -> usize {
+        let end = data.len().saturating_sub(self.window - 1);
+        let starts = |&position: &usize| {
+            let window = data[position..position + self.window].iter().zip(&self.buckets);
+            window.fold(0xff, |buckets, (&byte, table)| buckets & table[byte as usize]) != 0
+        };
+        (from..end).find(starts).unwrap_or(end)
+    }
+
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    #[target_feature(enable = "ssse3")]
+    fn find_ssse3(&self, data: &[u8]) -> usize {
+        use std::arch::x86_64::*;
+
+        let nibbles = _mm_set1_epi8(0x0f);
+        let tables: Vec<_> = (0..self.window)
+            .map(|k| unsafe {
+                let lo = _mm_loadu_si128(self.lo[k].as_ptr() as *const __m128i);
+                let hi = _mm_loadu_si128(self.hi[k].as_ptr() as *const __m128i);
+                (lo, hi)
+            })
+            .collect();
+        let mut position = 0;
+        while position + 16 + self.window - 1 <= data.len() {
+            let mut buckets = _mm_set1_epi8(-1);
+            for (k, &(lo, hi)) in tables.iter().enumerate() {
+                let bytes = unsafe {
+                    _mm_loadu_si128(data.as_ptr().add(position + k) as *const __m128i)
+                };
+                let low = _mm_and_si128(bytes, nibbles);
+                let high = _mm_and_si128(_mm_srli_epi16(bytes, 4), nibbles);
+                let found = _mm_and_si128(_mm_shuffle_epi8(lo, low), _mm_shuffle_epi8(hi, high));
+                buckets = _mm_and_si128(buckets, found);
+            }
+            let empty = _mm_movemask_epi8(_mm_cmpeq_epi8(buckets, _mm_setzero_si128())) as u32;
+            if empty != 0xffff {
+                return position + (!empty).trailing_zeros() as usize;
+            }
+            position += 16;
+        }
+        self.find_scalar(data, position)
+    }
+
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx2")]
+    fn find_avx2(&self, data: &[u8]) -> usize {
+        use std::arch::x86_64::*;
+
+        let nibbles = _mm256_set1_epi8(0x0f);
+        // Shuffles look up within each 128-bit lane, so both get the tables
+        let tables: Vec<_> = (0..self.window)
+            .map(|k| unsafe {
+                let lo = _mm_loadu_si128(self.lo[k].as_ptr() as *const __m128i);
+                let hi = _mm_loadu_si128(self.hi[k].as_ptr() as *const __m128i);
+                (_mm256_broadcastsi128_si256(lo), _mm256_broadcastsi128_si256(hi))
+            })
+            .collect();
+        let mut position = 0;
+        while position + 32 + self.window - 1 <= data.len() {
+            let mut buckets = _mm256_set1_epi8(-1);
+            for (k, &(lo, hi)) in tables.iter().enumerate() {
+                let bytes = unsafe {
+                    _mm256_loadu_si256(data.as_ptr().add(position + k) as *const __m256i)
+                };
+                let low = _mm256_and_si256(bytes, nibbles);
+                let high = _mm256_and_si256(_mm256_srli_epi16(bytes, 4), nibbles);
+                let found =
+                    _mm256_and_si256(_mm256_shuffle_epi8(lo, low), _mm256_shuffle_epi8(hi, high));
+                buckets = _mm256_and_si256(buckets, found);
+            }
+            let empty = _mm256_movemask_epi8(_mm256_cmpeq_epi8(buckets, _mm256_setzero_si256()));
+            if empty != -1 {
+                return position + (!empty).trailing_zeros() as usize;
+            }
+            position += 32;
+        }
+        self.find_scalar(data, position)
+    }
+}
+
+// The widest instructions the CPU has, checked once per prefilter
+fn detect() -> Backend {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return Backend::Avx2;
+        }
+        if is_x86_feature_detected!("ssse3") {
+            return Backend::Ssse3;
+        }
+    }
+    Backend::Scalar
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backends_agree() {
+        let literals: [&[u8]; 10] = [
+            b"GET", b"POST", b"\x7fELF", b"MZ\x90", b"<script", b"eval(", b"cmd.exe", b"\xff\xfe",
+            b"SELECT", b"union",
+        ];
+        let alphabet = b"GETPOS\x7fELMZ\x90<scre(.x\xff\xfeSELun  ";
+        let mut seed = 9u32;
+        let data: Vec<u8> = (0..5000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                alphabet[(seed >> 16) as usize % alphabet.len()]
+            })
+            .collect();
+        let backends = vec![Backend::Scalar];
+        #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+        let backends = {
+            let mut backends = backends;
+            if is_x86_feature_detected!("ssse3") {
+                backends.push(Backend::Ssse3);
+            }
+            if is_x86_feature_detected!("avx2") {
+                backends.push(Backend::Avx2);
+            }
+            assert_eq!(detect(), *backends.last().unwrap());
+            backends
+        };
+
+        for count in [1, 2, 10] {
+            let literals = &literals[..count];
+            let mut teddy = Teddy::new(literals);
+            assert_eq!(teddy.window, if count < 10 { 3 } else { 2 });
+            let candidates = |teddy: &Teddy| {
+                let mut candidates = Vec::new();
+                let mut position = 0;
+                while position < data.len() {
+                    position += teddy.find(&data[position..]);
+                    candidates.push(position);
+                    position += 1;
+                }
+                candidates
+            };
+            let expected = candidates(&teddy);
+            for &backend in &backends {
+                teddy.backend = backend;
+                assert_eq!(candidates(&teddy), expected);
+            }
+            // Every occurrence is a candidate, and most positions are not
+            for start in 0..data.len() {
+                if literals.iter().any(|literal| data[start..].starts_with(literal)) {
+                    assert!(expected.binary_search(&start).is_ok());
+                }
+            }
+            assert!(expected.len() < data.len() / 3);
+        }
+
+        // Windows running past the end may start a literal
+        let teddy = Teddy::new(&literals);
+        assert_eq!(teddy.find(b"xxxx"), 3);
+        assert_eq!(teddy.find(b"xxGE"), 2);
+        assert_eq!(teddy.find(b"x"), 0);
+    }
+}
@@ -0,0 +1,136 @@
+// UTF-8 helpers for codepoint-aware parsing
+
+const SURROGATES: (u32, u32) = (0xd800, 0xdfff);
+
+// Decode the multi-byte UTF-8 sequence starting at `pos`
+// Returns the codepoint and the offset just past it, or None for ASCII and invalid input
+pub(crate) fn decode(pattern: &[u8], pos: usize) -> Option<(u32, usize)> {
+    let len = match pattern.get(pos)? {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return None,
+    };
+    let bytes = pattern.get(pos..pos + len)?;
+    let c = std::str::from_utf8(bytes).ok()?.chars().next()?;
+    Some((c as u32, pos + len))
+}
+
+// Split the codepoint range `start..=end` into sequences of byte ranges, so that a
+// codepoint is in the range exactly when its encoding matches one of the sequences
+// Surrogates have no encoding and are skipped.
+pub(crate) fn sequences(start: u32, end: u32) -> Vec<Vec<(u8, u8)>> {
+    let mut sequences = Vec::new();
+    push_sequences(start, end.min(char::MAX as u32), &mut sequences);
+    sequences
+}
+
+fn push_sequences(start: u32, end: u32, sequences: &mut Vec<Vec<(u8, u8)>>) {
+    if start > end {
+        return;
+    }
+    if start < SURROGATES.0 && end > SURROGATES.1 {
+        push_sequences(start, SURROGATES.0 - 1, sequences);
+        push_sequences(SURROGATES.1 + 1, end, sequences);
+        return;
+    }
+    let (start, end) = match (start, end) {
+        (start, end) if (SURROGATES.0..=SURROGATES.1).contains(&start) => (SURROGATES.1 + 1, end),
+        (start, end) if (SURROGATES.0..=SURROGATES.1).contains(&end) => (start, SURROGATES.0 - 1),
+        range => range,
+    };
+    if start > end {
+        return;
+    }
+
+    // Both ends must encode to the same number of bytes
+    for max in [0x7f, 0x7ff, 0xffff] {
+        if start <= max && end > max {
+            push_sequences(start, max, sequences);
+            push_sequences(max + 1, end, sequences);
+            return;
+        }
+    }
+
+    // Every byte after the first differing one must span all continuation bytes
+    for i in 1..4 {
+        let mask = (1u32 << (6 * i)) - 1;
+        if start & !mask != end & !mask {
+            if start & mask != 0 {
+                push_sequences(start, start | mask, sequences);
+                push_sequences((start | mask) + 1, end, sequences);
+                return;
+            }
+            if end & mask != mask {
+                push_sequences(start, (end & !mask) - 1, sequences);
+                push_sequences(end & !mask, end, sequences);
+                return;
+            }
+        }
+    }
+
+    let (mut low, mut high) = ([0; 4], [0; 4]);
+    let low = encode(start, &mut low);
+    let high = encode(end, &mut high);
+    sequences.push(low.iter().copied().zip(high.iter().copied()).collect());
+}
+
+fn encode(codepoint: u32, buffer: &mut [u8; 4]) -> &[u8] {
+    let c = char::from_u32(codepoint).expect("surrogates are split off before encoding");
+    c.encode_utf8(buffer).as_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Check every codepoint of the range against its sequences
+    fn assert_covers(start: u32, end: u32) {
+        let sequences = sequences(start, end);
+        for codepoint in 0..=0x10ffffu32 {
+            let Some(c) = char::from_u32(codepoint) else {
+                continue;
+            };
+            let mut buffer = [0; 4];
+            let bytes = c.encode_utf8(&mut buffer).as_bytes();
+            let matched = sequences.iter().any(|sequence| {
+                sequence.len() == bytes.len()
+                    && sequence
+                        .iter()
+                        .zip(bytes)
+                        .all(|(&(low, high), &byte)| (low..=high).contains(&byte))
+            });
+            assert_eq!(
+                matched,
+                (start..=end).contains(&codepoint),
+                "{:#x} in {:#x}..={:#x}",
+                codepoint,
+                start,
+                end
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let text = "aé日😀".as_bytes();
+        assert_eq!(decode(text, 0), None);
+        assert_eq!(decode(text, 1), Some(('é' as u32, 3)));
+        assert_eq!(decode(text, 3), Some(('日' as u32, 6)));
+        assert_eq!(decode(text, 6), Some(('😀' as u32, 10)));
+        assert_eq!(decode(text, 2), None);
+        assert_eq!(decode(&text[..2], 1), None);
+    }
+
+    #[test]
+    fn test_sequences() {
+        assert_eq!(
+            sequences(0xe9, 0xe9),
+            vec![vec![(0xc3, 0xc3), (0xa9, 0xa9)]]
+        );
+        assert_covers(0x80, 0x10ffff);
+        assert_covers(0xe9, 0x3042);
+        assert_covers(0xd000, 0xe100);
+        assert_covers(0x1f600, 0x1f64f);
+    }
+}
@@ -1,18 +1,11 @@
 use streamregex::prelude::*;
 use std::io::Cursor;
-use std::sync::mpsc;
 use std::thread;
 
 #[test]
 fn test_large_stream_processing() {
     let pattern = compile_pattern("needle").unwrap();
     let mut matcher = StreamMatcher::new();
-    let (tx, rx) = mpsc::channel();
-
-    matcher.add_callback(move |pattern_id| {
-        tx.send(pattern_id.to_string()).unwrap();
-    });
-
     matcher.add_pattern(pattern);
 
     // Create a large stream with known matches
@@ -21,23 +14,20 @@ fn test_large_stream_processing() {
     data[750_000..750_006].copy_from_slice(b"needle");
 
     // Process in chunks
-    let mut cursor = Cursor::new(data);
-    let mut buffer = vec![0; 1024];
-
-    while let Ok(n) = cursor.read(&mut buffer) {
-        if n == 0 { break; }
-        matcher.process_chunk(&buffer[..n]);
-    }
+    let ends: Vec<_> = matcher
+        .matches_in_reader(Cursor::new(data), 1024)
+        .map(|found| found.unwrap().end)
+        .collect();
 
     // Check we found exactly two matches
-    let matches: Vec<_> = rx.try_iter().collect();
-    assert_eq!(matches.len(), 2);
+    assert_eq!(ends, vec![500_006, 750_006]);
 }
 
 #[test]
 fn test_concurrent_processing() {
     let pattern = compile_pattern("test").unwrap();
-    let matcher = StreamMatcher::new();
+    let mut matcher = StreamMatcher::new();
+    matcher.add_pattern(pattern);
     let matcher = std::sync::Arc::new(std::sync::Mutex::new(matcher));
 
     let mut handles = vec![];
@@ -55,6 +45,9 @@ fn test_concurrent_processing() {
     for handle in handles {
         handle.join().unwrap();
     }
+
+    // Every thread's chunk holds one complete match
+    assert_eq!(matcher.lock().unwrap().total_matches(), 4);
 }
 
 #[test]
@@ -91,20 +84,21 @@ fn test_pattern_matching_accuracy() {
         ("multi", "multimulti", 2),
     ];
 
-    for (pattern, input, expected_matches) in test_cases {
-        let pattern = compile_pattern(pattern).unwrap();
+    for (source, input, expected_matches) in test_cases {
+        let pattern = compile_pattern(source).unwrap();
         let mut matcher = StreamMatcher::new();
         let mut matches = 0;
 
-        matcher.add_callback(move |_| matches += 1);
+        matcher.add_callback(|_| matches += 1);
         matcher.add_pattern(pattern);
         matcher.process_chunk(input.as_bytes());
+        drop(matcher);
 
         assert_eq!(
             matches,
             expected_matches,
             "Pattern '{}' on input '{}' produced {} matches, expected {}",
-            pattern,
+            source,
             input,
             matches,
             expected_matches